@@ -0,0 +1,142 @@
+/**
+    xenstore-rs provides a Rust based xenstore implementation.
+    Copyright (C) 2016 Star Lab Corp.
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License along
+    with this program; if not, see <http://www.gnu.org/licenses/>.
+**/
+
+// Counts the reads a growing number of threads get through while a slow
+// writer keeps the system busy. Reads made outside of a transaction should
+// keep up with the number of threads, while reads inside one have to wait
+// their turn behind the writer.
+//
+// Run with `cargo bench`, it doesn't need the unstable test crate.
+
+extern crate libxenstore;
+
+use libxenstore::domain::DomainList;
+use libxenstore::handler::Handler;
+use libxenstore::store::{ChangeSet, Store, Value, DOM0_DOMAIN_ID};
+use libxenstore::path::Path;
+use libxenstore::system::System;
+use libxenstore::transaction::TransactionList;
+use libxenstore::watch::WatchList;
+use libxenstore::wire;
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The nodes in the store being read
+const NODES: usize = 10000;
+/// How long each measurement runs for
+const RUN_TIME_MS: u64 = 1000;
+/// How long the writer holds on to the system at a time
+const WRITE_TIME_MS: u64 = 1;
+
+fn path_of(i: usize) -> String {
+    format!("/bench/{}/{}", i / 100, i % 100)
+}
+
+fn request(msg_type: u32, tx_id: wire::TxId, body: &[u8]) -> (wire::Header, wire::Body) {
+    (wire::Header {
+         msg_type: msg_type,
+         req_id: 0,
+         tx_id: tx_id,
+         len: body.len() as u32,
+     },
+     wire::Body::from(vec![body.to_owned()]))
+}
+
+/// Build a handler for a store holding `NODES` nodes below `/bench`
+fn handler() -> Handler {
+    let mut store = Store::new();
+    let mut changes = ChangeSet::new(&store);
+    for i in 0..NODES {
+        let path = Path::try_from(DOM0_DOMAIN_ID, &path_of(i)).unwrap();
+        changes = store.write(&changes, DOM0_DOMAIN_ID, path, Value::from("value")).unwrap();
+    }
+    store.apply(changes).unwrap();
+
+    let system = System::new(store, WatchList::new(), TransactionList::new(), DomainList::new());
+    Handler::new(Arc::new(Mutex::new(system)))
+}
+
+/// Read from `readers` threads at once for `RUN_TIME_MS`, inside a
+/// transaction if `in_transaction`, returning the total reads made
+fn run(handler: &Handler, readers: usize, in_transaction: bool) -> usize {
+    let done = Arc::new(AtomicBool::new(false));
+
+    let writer = {
+        let handler = handler.clone();
+        let done = done.clone();
+        thread::spawn(move || while !done.load(Ordering::SeqCst) {
+                          // a slow request, such as committing a large transaction
+                          let _sys = handler.system().lock().unwrap();
+                          thread::sleep(Duration::from_millis(WRITE_TIME_MS));
+                      })
+    };
+
+    let threads = (0..readers)
+        .map(|_| {
+            let handler = handler.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                let conn = handler.system().lock().unwrap().new_connection(DOM0_DOMAIN_ID);
+                let tx_id = if in_transaction {
+                    let start = request(wire::XS_TRANSACTION_START, 0, b"\0");
+                    let rsp = handler.process(conn, start, |rsp| rsp);
+                    let id = rsp.1.to_vec();
+                    str::from_utf8(&id).unwrap().trim_right_matches('\0').parse().unwrap()
+                } else {
+                    0
+                };
+
+                let mut reads = 0;
+                while !done.load(Ordering::SeqCst) {
+                    let path = format!("{}\0", path_of(reads % NODES));
+                    let read = request(wire::XS_READ, tx_id, path.as_bytes());
+                    handler.process(conn, read, |rsp| assert_eq!(rsp.0.msg_type, wire::XS_READ));
+                    reads += 1;
+                }
+                reads
+            })
+        })
+        .collect::<Vec<_>>();
+
+    thread::sleep(Duration::from_millis(RUN_TIME_MS));
+    done.store(true, Ordering::SeqCst);
+
+    writer.join().unwrap();
+    threads.into_iter().map(|thread| thread.join().unwrap()).sum()
+}
+
+fn main() {
+    let handler = handler();
+    let started = Instant::now();
+
+    for &readers in &[1, 2, 4, 8] {
+        for &in_transaction in &[false, true] {
+            let reads = run(&handler, readers, in_transaction);
+            println!("{:>2} readers {:<16} {:>10} reads/s",
+                     readers,
+                     if in_transaction { "in transaction" } else { "" },
+                     reads as u64 * 1000 / RUN_TIME_MS);
+        }
+    }
+
+    let elapsed = started.elapsed();
+    println!("finished in {}.{:03}s", elapsed.as_secs(), elapsed.subsec_nanos() / 1_000_000);
+}
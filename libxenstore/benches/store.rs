@@ -0,0 +1,106 @@
+/**
+    xenstore-rs provides a Rust based xenstore implementation.
+    Copyright (C) 2016 Star Lab Corp.
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License along
+    with this program; if not, see <http://www.gnu.org/licenses/>.
+**/
+
+// Times the store operations that happen on every request against stores of
+// growing size. They should stay roughly flat as the store grows.
+//
+// Run with `cargo bench`, it doesn't need the unstable test crate.
+
+extern crate libxenstore;
+
+use libxenstore::path::Path;
+use libxenstore::store::{ChangeSet, Store, Value, DOM0_DOMAIN_ID};
+use std::time::Instant;
+
+/// How many times each operation is repeated
+const ROUNDS: u32 = 1000;
+
+/// Spread the nodes over directories of a hundred entries each
+fn path_of(top: &str, i: usize) -> Path {
+    Path::try_from(DOM0_DOMAIN_ID, &format!("/{}/{}/{}", top, i / 100, i % 100)).unwrap()
+}
+
+/// Build a store holding `size` nodes below `/bench`
+fn store_of(size: usize) -> Store {
+    let mut store = Store::new();
+    let mut changes = ChangeSet::new(&store);
+    for i in 0..size {
+        let path = path_of("bench", i);
+        changes = store.write(&changes, DOM0_DOMAIN_ID, path, Value::from("value")).unwrap();
+    }
+    store.apply(changes).unwrap();
+    store
+}
+
+/// Report the average time `op` takes over `ROUNDS` runs
+fn bench<F: FnMut(u32)>(name: &str, size: usize, mut op: F) {
+    let start = Instant::now();
+    for round in 0..ROUNDS {
+        op(round);
+    }
+    let elapsed = start.elapsed();
+    let total = elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64;
+    println!("{:<24} {:>8} nodes {:>10} ns/op", name, size, total / ROUNDS as u64);
+}
+
+fn main() {
+    for &size in &[1000, 10000, 100000] {
+        let store = store_of(size);
+
+        // a write to a changeset that already holds a lot of changes, like
+        // a domain filling in its tree inside one transaction
+        let full = (0..size).fold(ChangeSet::new(&store), |changes, i| {
+            let path = path_of("full", i);
+            store.write(&changes, DOM0_DOMAIN_ID, path, Value::from("value")).unwrap()
+        });
+        bench("write in transaction", size, |round| {
+            let path = path_of("full", round as usize);
+            store.write(&full, DOM0_DOMAIN_ID, path, Value::from("new")).unwrap();
+        });
+
+        // a write below a directory that already has `size` children, like
+        // the backends listing every device of a large host
+        let wide = (0..size).fold(ChangeSet::new(&store), |changes, i| {
+            let path = Path::try_from(DOM0_DOMAIN_ID, &format!("/wide/{}", i)).unwrap();
+            store.write(&changes, DOM0_DOMAIN_ID, path, Value::from("value")).unwrap()
+        });
+        bench("write in wide directory", size, |round| {
+            let path = Path::try_from(DOM0_DOMAIN_ID, &format!("/wide/new{}", round)).unwrap();
+            store.write(&wide, DOM0_DOMAIN_ID, path, Value::from("new")).unwrap();
+        });
+
+        bench("read", size, |round| {
+            let path = path_of("bench", round as usize);
+            store.read(&full, DOM0_DOMAIN_ID, &path).unwrap();
+        });
+
+        bench("directory", size, |round| {
+            let dir = round as usize % (size / 100);
+            let path = Path::try_from(DOM0_DOMAIN_ID, &format!("/bench/{}", dir)).unwrap();
+            store.directory(&full, DOM0_DOMAIN_ID, &path).unwrap();
+        });
+
+        bench("transaction start", size, |_| {
+            ChangeSet::new(&store);
+        });
+
+        bench("snapshot", size, |_| {
+            store.snapshot();
+        });
+    }
+}
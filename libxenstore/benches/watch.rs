@@ -0,0 +1,94 @@
+/**
+    xenstore-rs provides a Rust based xenstore implementation.
+    Copyright (C) 2016 Star Lab Corp.
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License along
+    with this program; if not, see <http://www.gnu.org/licenses/>.
+**/
+
+// Times firing the watches for a commit against growing numbers of watches,
+// each on its own path like the frontends and backends of a large host. It
+// should stay roughly flat, only the watches on the changed paths matter.
+//
+// Run with `cargo bench`, it doesn't need the unstable test crate.
+
+extern crate libxenstore;
+extern crate mio;
+
+use libxenstore::connection::ConnId;
+use libxenstore::path::Path;
+use libxenstore::store::{AppliedChange, Value, Written, DOM0_DOMAIN_ID};
+use libxenstore::watch::{WatchList, WPath, WToken};
+use mio::Token;
+use std::time::Instant;
+
+/// How many times each operation is repeated
+const ROUNDS: u32 = 1000;
+
+/// Spread the watches over directories of a hundred entries each
+fn path_of(i: usize) -> Path {
+    Path::try_from(DOM0_DOMAIN_ID, &format!("/bench/{}/{}", i / 100, i % 100)).unwrap()
+}
+
+/// Register `size` watches, one on each path
+fn watches_of(size: usize) -> WatchList {
+    let mut watch_list = WatchList::new();
+    let conn = ConnId::new(Token(0), DOM0_DOMAIN_ID);
+    for i in 0..size {
+        watch_list.watch(conn, WPath::Normal(path_of(i)), WToken::from("token")).unwrap();
+    }
+    watch_list
+}
+
+/// Report the average time `op` takes over `ROUNDS` runs
+fn bench<F: FnMut(u32)>(name: &str, size: usize, mut op: F) {
+    let start = Instant::now();
+    for round in 0..ROUNDS {
+        op(round);
+    }
+    let elapsed = start.elapsed();
+    let total = elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64;
+    println!("{:<24} {:>8} watches {:>10} ns/op", name, size, total / ROUNDS as u64);
+}
+
+fn main() {
+    for &size in &[100, 1000, 10000] {
+        let watch_list = watches_of(size);
+
+        // a commit of a few writes, each firing a single watch
+        bench("fire writes", size, |round| {
+            let changes = (0..4)
+                .map(|i| {
+                         let path = path_of((round as usize * 4 + i) % size).push("state");
+                         AppliedChange::Write(path, Vec::new(), Written::Created(Value::new()))
+                     })
+                .collect();
+            assert_eq!(watch_list.fire(Some(changes)).len(), 4);
+        });
+
+        // a write nobody watches
+        bench("fire unwatched write", size, |_| {
+            let path = Path::try_from(DOM0_DOMAIN_ID, "/elsewhere/node").unwrap();
+            let change = AppliedChange::Write(path, Vec::new(), Written::Created(Value::new()));
+            watch_list.fire(Some(vec![change]));
+        });
+
+        // removing a directory fires the hundred watches below it
+        bench("fire removed subtree", size, |round| {
+            let dir = round as usize % (size / 100);
+            let path = Path::try_from(DOM0_DOMAIN_ID, &format!("/bench/{}", dir)).unwrap();
+            let change = AppliedChange::RemoveSubtree(path, Vec::new());
+            assert_eq!(watch_list.fire(Some(vec![change])).len(), 100);
+        });
+    }
+}
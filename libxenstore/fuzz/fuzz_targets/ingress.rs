@@ -0,0 +1,50 @@
+/**
+    xenstore-rs provides a Rust based xenstore implementation.
+    Copyright (C) 2016 Star Lab Corp.
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License along
+    with this program; if not, see <http://www.gnu.org/licenses/>.
+**/
+
+// Feeds arbitrary requests to the ingress parser. The first byte picks the
+// domain sending them, the header follows with the length of the rest.
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate libxenstore;
+extern crate mio;
+
+use libxenstore::connection::ConnId;
+use libxenstore::message::ingress;
+use libxenstore::wire;
+use mio::Token;
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 1 + wire::HEADER_SIZE {
+        return;
+    }
+    let conn = ConnId::new(Token(0), data[0] as wire::DomainId);
+    let (header, body) = data[1..].split_at(wire::HEADER_SIZE);
+    let header = wire::Header::parse(header).unwrap();
+
+    // mostly stick to the message types there are
+    let header = wire::Header {
+        msg_type: header.msg_type % (wire::XS_TYPE_COUNT + 1),
+        len: body.len() as u32,
+        ..header
+    };
+    let body = wire::Body::parse(&header, body).unwrap();
+
+    let _ = ingress::parse(conn, &header, body);
+});
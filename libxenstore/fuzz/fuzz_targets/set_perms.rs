@@ -0,0 +1,64 @@
+/**
+    xenstore-rs provides a Rust based xenstore implementation.
+    Copyright (C) 2016 Star Lab Corp.
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License along
+    with this program; if not, see <http://www.gnu.org/licenses/>.
+**/
+
+// Feeds arbitrary bodies to XS_SET_PERMS, whose permissions are only parsed
+// once the request is processed.
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate libxenstore;
+extern crate mio;
+
+use libxenstore::connection::ConnId;
+use libxenstore::domain::DomainList;
+use libxenstore::handler::Handler;
+use libxenstore::store::{Permission, Store, DOM0_DOMAIN_ID};
+use libxenstore::system::System;
+use libxenstore::transaction::TransactionList;
+use libxenstore::watch::WatchList;
+use libxenstore::wire;
+use mio::Token;
+use std::str;
+use std::sync::{Arc, Mutex};
+
+fuzz_target!(|data: &[u8]| {
+    let header = wire::Header {
+        msg_type: wire::XS_SET_PERMS,
+        req_id: 0,
+        tx_id: 0,
+        len: data.len() as u32,
+    };
+    let body = wire::Body::parse(&header, data).unwrap();
+
+    // any permission that parses prints as something that parses the same
+    for field in body.fields().into_iter().skip(1) {
+        let perm = str::from_utf8(field).ok().and_then(|s| Permission::try_from(s).ok());
+        if let Some(perm) = perm {
+            assert_eq!(Permission::try_from(&perm.to_string()).ok(), Some(perm));
+        }
+    }
+
+    let system = System::new(Store::new(),
+                             WatchList::new(),
+                             TransactionList::new(),
+                             DomainList::new());
+    let handler = Handler::new(Arc::new(Mutex::new(system)));
+    let conn = ConnId::new(Token(0), DOM0_DOMAIN_ID);
+    handler.process(conn, (header, body), |rsp| rsp);
+});
@@ -0,0 +1,149 @@
+/**
+    xenstore-rs provides a Rust based xenstore implementation.
+    Copyright (C) 2016 Star Lab Corp.
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License along
+    with this program; if not, see <http://www.gnu.org/licenses/>.
+**/
+
+// Drives sequences of well formed requests from a few domains through the
+// handler. Each four bytes of input make one request: what it is, which
+// connection sends it, the path it names and one more byte for whatever else
+// it needs. After every request the store has to still hang together, which
+// debug builds also check as the changes are applied.
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate libxenstore;
+extern crate mio;
+
+use libxenstore::connection::ConnId;
+use libxenstore::domain::DomainList;
+use libxenstore::handler::Handler;
+use libxenstore::store::Store;
+use libxenstore::system::System;
+use libxenstore::transaction::TransactionList;
+use libxenstore::watch::WatchList;
+use libxenstore::wire;
+use mio::Token;
+use std::str;
+use std::sync::{Arc, Mutex};
+
+const OPS: &'static [u32] = &[wire::XS_READ,
+                              wire::XS_WRITE,
+                              wire::XS_MKDIR,
+                              wire::XS_RM,
+                              wire::XS_DIRECTORY,
+                              wire::XS_DIRECTORY_PART,
+                              wire::XS_GET_PERMS,
+                              wire::XS_SET_PERMS,
+                              wire::XS_WATCH,
+                              wire::XS_UNWATCH,
+                              wire::XS_TRANSACTION_START,
+                              wire::XS_TRANSACTION_END,
+                              wire::XS_INTRODUCE,
+                              wire::XS_RELEASE];
+
+const PATHS: &'static [&'static str] = &["/a",
+                                         "/a/b",
+                                         "/a/b/c",
+                                         "/d",
+                                         "@introduceDomain",
+                                         "@releaseDomain",
+                                         "a/relative"];
+
+const DOMAINS: &'static [wire::DomainId] = &[0, 1, 2];
+
+fn body(msg_type: u32, path: &str, arg: u8) -> Vec<Vec<u8>> {
+    let path = path.as_bytes().to_owned();
+    let arg_str = arg.to_string().into_bytes();
+    match msg_type {
+        wire::XS_WRITE => vec![path, arg_str],
+        wire::XS_DIRECTORY_PART => vec![path, arg_str],
+        wire::XS_SET_PERMS => {
+            let owner = format!("n{}", DOMAINS[arg as usize % DOMAINS.len()]).into_bytes();
+            let other = format!("r{}", DOMAINS[(arg as usize >> 4) % DOMAINS.len()]).into_bytes();
+            vec![path, owner, other]
+        }
+        wire::XS_WATCH | wire::XS_UNWATCH => vec![path, arg_str],
+        wire::XS_TRANSACTION_START => vec![],
+        wire::XS_TRANSACTION_END => vec![if arg & 1 == 0 { b"T" } else { b"F" }.to_vec()],
+        wire::XS_INTRODUCE => {
+            let dom_id = (DOMAINS[arg as usize % DOMAINS.len()]).to_string().into_bytes();
+            vec![dom_id, b"0".to_vec(), b"0".to_vec()]
+        }
+        wire::XS_RELEASE => vec![(DOMAINS[arg as usize % DOMAINS.len()]).to_string().into_bytes()],
+        _ => vec![path],
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let system = System::new(Store::new(),
+                             WatchList::new(),
+                             TransactionList::new(),
+                             DomainList::new());
+    let handler = Handler::new(Arc::new(Mutex::new(system)));
+
+    let conns = DOMAINS.iter()
+        .enumerate()
+        .map(|(n, dom_id)| ConnId::new(Token(n), *dom_id))
+        .collect::<Vec<_>>();
+    let mut tx_ids = vec![0; conns.len()];
+    for conn in &conns {
+        handler.open(*conn);
+    }
+
+    for (req_id, chunk) in data.chunks(4).enumerate() {
+        if chunk.len() < 4 {
+            break;
+        }
+        let msg_type = OPS[chunk[0] as usize % OPS.len()];
+        let n = chunk[1] as usize % conns.len();
+        let path = PATHS[chunk[2] as usize % PATHS.len()];
+
+        // most of the time stay in whatever transaction is open
+        let tx_id = if chunk[1] as usize / conns.len() % 4 == 0 { 0 } else { tx_ids[n] };
+
+        let body = wire::Body::from(body(msg_type, path, chunk[3]));
+        let header = wire::Header {
+            msg_type: msg_type,
+            req_id: req_id as u32,
+            tx_id: tx_id,
+            len: body.to_vec().len() as u32,
+        };
+
+        let (rsp_header, rsp_body) = handler.process(conns[n], (header, body), |rsp| rsp);
+        match rsp_header.msg_type {
+            wire::XS_TRANSACTION_START => {
+                let id = rsp_body.fields()
+                    .first()
+                    .and_then(|id| str::from_utf8(id).ok())
+                    .and_then(|id| id.parse::<u32>().ok());
+                tx_ids[n] = id.expect("transaction started without an id");
+            }
+            wire::XS_TRANSACTION_END => tx_ids[n] = 0,
+            _ => (),
+        }
+
+        for conn in &conns {
+            while let Some(_) = handler.next_event(*conn).unwrap() {}
+        }
+
+        handler.system()
+            .lock()
+            .unwrap()
+            .do_all(|store, _, _, _| store.check_invariants())
+            .unwrap();
+    }
+});
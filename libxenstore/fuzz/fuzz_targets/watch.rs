@@ -0,0 +1,72 @@
+/**
+    xenstore-rs provides a Rust based xenstore implementation.
+    Copyright (C) 2016 Star Lab Corp.
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License along
+    with this program; if not, see <http://www.gnu.org/licenses/>.
+**/
+
+// Feeds arbitrary bodies to XS_WATCH and XS_UNWATCH, processing them like
+// any other request so that the watch paths are parsed too.
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate libxenstore;
+extern crate mio;
+
+use libxenstore::connection::ConnId;
+use libxenstore::domain::DomainList;
+use libxenstore::handler::Handler;
+use libxenstore::store::Store;
+use libxenstore::system::System;
+use libxenstore::transaction::TransactionList;
+use libxenstore::watch::{WPath, WatchList};
+use libxenstore::wire;
+use mio::Token;
+use std::str;
+use std::sync::{Arc, Mutex};
+
+fuzz_target!(|data: &[u8]| {
+    if data.is_empty() {
+        return;
+    }
+
+    let mut system = System::new(Store::new(),
+                                 WatchList::new(),
+                                 TransactionList::new(),
+                                 DomainList::new());
+    // so wildcard patterns are parsed and matched too
+    system.set_wildcard_watches(true).unwrap();
+    let handler = Handler::new(Arc::new(Mutex::new(system)));
+    // the first byte picks the domain watching
+    let conn = ConnId::new(Token(0), data[0] as wire::DomainId % 2);
+    handler.open(conn);
+
+    let body = &data[1..];
+    if let Some(Ok(node)) = body.split(|b| *b == 0).next().map(str::from_utf8) {
+        let _ = WPath::try_from(conn.dom_id, node);
+    }
+
+    for msg_type in &[wire::XS_WATCH, wire::XS_UNWATCH] {
+        let header = wire::Header {
+            msg_type: *msg_type,
+            req_id: 0,
+            tx_id: 0,
+            len: body.len() as u32,
+        };
+        let body = wire::Body::parse(&header, body).unwrap();
+        handler.process(conn, (header, body), |rsp| rsp);
+        while let Some(_) = handler.next_event(conn).unwrap() {}
+    }
+});
@@ -0,0 +1,45 @@
+/**
+    xenstore-rs provides a Rust based xenstore implementation.
+    Copyright (C) 2016 Star Lab Corp.
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License along
+    with this program; if not, see <http://www.gnu.org/licenses/>.
+**/
+
+// Feeds arbitrary bytes to the body parser, after a header that may or may
+// not agree with them on their length.
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate libxenstore;
+
+use libxenstore::wire;
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < wire::HEADER_SIZE {
+        return;
+    }
+    let (header, body) = data.split_at(wire::HEADER_SIZE);
+    let header = match wire::Header::parse(header) {
+        Ok(header) => header,
+        Err(_) => return,
+    };
+
+    let _ = wire::Body::parse(&header, body);
+
+    let header = wire::Header { len: body.len() as u32, ..header };
+    let parsed = wire::Body::parse(&header, body).unwrap();
+    assert_eq!(parsed.to_vec(), body);
+    assert!(parsed.fields().iter().all(|field| !field.is_empty() && !field.contains(&0)));
+});
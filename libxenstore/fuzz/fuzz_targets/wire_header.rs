@@ -0,0 +1,33 @@
+/**
+    xenstore-rs provides a Rust based xenstore implementation.
+    Copyright (C) 2016 Star Lab Corp.
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License along
+    with this program; if not, see <http://www.gnu.org/licenses/>.
+**/
+
+// Feeds arbitrary bytes to the header parser.
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate libxenstore;
+
+use libxenstore::wire;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(header) = wire::Header::parse(data) {
+        // whatever was parsed goes back out the way it came in
+        assert_eq!(&header.to_vec()[..], &data[..wire::HEADER_SIZE]);
+    }
+});
@@ -0,0 +1,134 @@
+/**
+    xenstore-rs provides a Rust based xenstore implementation.
+    Copyright (C) 2016 Star Lab Corp.
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License along
+    with this program; if not, see <http://www.gnu.org/licenses/>.
+**/
+
+// Deciding who may touch which node, kept apart from the store so another
+// policy (e.g. one based on security labels) can be put in its place.
+
+use super::error::{Error, Result};
+use super::path::Path;
+use super::store::{DOM0_DOMAIN_ID, Perm, Permission};
+use super::wire;
+
+/// Decides whether a domain may access a node.
+///
+/// Every message reaches the nodes it reads or changes through the
+/// `Store`, which asks its `Authorizer` before handing each node out. The
+/// watches ask it too before telling a domain about a change.
+pub trait Authorizer: Send + Sync {
+    /// Check that `dom_id`, acting on behalf of `target` if it has one, may
+    /// `perm` the node at `path` which has `permissions`.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::EACCES` if it may not
+    fn check(&self,
+             dom_id: wire::DomainId,
+             target: Option<wire::DomainId>,
+             perm: Perm,
+             path: &Path,
+             permissions: &[Permission])
+             -> Result<()>;
+
+    /// Check that `dom_id`, acting on behalf of `target` if it has one, may
+    /// replace the `old` permissions of the node at `path` with `new`.
+    ///
+    /// This comes on top of `check` for writing the node.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::EACCES` if it may not
+    fn check_set_perms(&self,
+                       dom_id: wire::DomainId,
+                       target: Option<wire::DomainId>,
+                       path: &Path,
+                       old: &[Permission],
+                       new: &[Permission])
+                       -> Result<()>;
+}
+
+/// The `PermissionAuthorizer` type.
+///
+/// The usual xenstore policy: dom0 and a node's owner may do anything with
+/// it, the domains listed in its permissions may do what is listed, and
+/// everyone else gets what the owner's entry says. Only the owner and dom0
+/// may change a node's permissions, and only dom0 may hand it to a new
+/// owner.
+pub struct PermissionAuthorizer;
+
+impl Authorizer for PermissionAuthorizer {
+    fn check(&self,
+             dom_id: wire::DomainId,
+             target: Option<wire::DomainId>,
+             perm: Perm,
+             path: &Path,
+             permissions: &[Permission])
+             -> Result<()> {
+        if perms_ok(dom_id, target, permissions, perm) {
+            Ok(())
+        } else {
+            Err(Error::EACCES(format!("failed to verify permissions for {:?}", path)))
+        }
+    }
+
+    fn check_set_perms(&self,
+                       dom_id: wire::DomainId,
+                       target: Option<wire::DomainId>,
+                       path: &Path,
+                       old: &[Permission],
+                       new: &[Permission])
+                       -> Result<()> {
+        if dom_id == DOM0_DOMAIN_ID {
+            return Ok(());
+        }
+
+        let owner = old[0].id;
+        if owner != dom_id && target != Some(owner) {
+            return Err(Error::EACCES(format!("domain {} doesn't own {:?}", dom_id, path)));
+        }
+        if new[0].id != owner {
+            return Err(Error::EACCES(format!("domain {} may not give {:?} to domain {}",
+                                             dom_id,
+                                             path,
+                                             new[0].id)));
+        }
+        Ok(())
+    }
+}
+
+/// Whether `permissions` let `dom_id`, acting on behalf of `target` if it
+/// has one, `perm` a node.
+pub fn perms_ok(dom_id: wire::DomainId,
+                target: Option<wire::DomainId>,
+                permissions: &[Permission],
+                perm: Perm)
+                -> bool {
+    let mask = Perm::Both;
+
+    // a domain with a target (e.g. a stub domain) acts on behalf of it
+    let is_caller = |id: wire::DomainId| id == dom_id || Some(id) == target;
+
+    if dom_id == DOM0_DOMAIN_ID || is_caller(permissions[0].id) {
+        return mask.allowed(&perm);
+    }
+
+    if let Some(p) = permissions.iter().find(|p| is_caller(p.id)) {
+        return p.perm.allowed(&perm);
+    }
+
+    permissions[0].perm.allowed(&perm)
+}
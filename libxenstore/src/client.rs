@@ -0,0 +1,922 @@
+/**
+    xenstore-rs provides a Rust based xenstore implementation.
+    Copyright (C) 2016 Star Lab Corp.
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License along
+    with this program; if not, see <http://www.gnu.org/licenses/>.
+**/
+use error::{Error, Result};
+use futures::{future, Async, AsyncSink, Future, Poll, Sink, Stream};
+use futures::future::Loop;
+use futures::sync::{mpsc, oneshot};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::io;
+use std::rc::Rc;
+use store::Permission;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::Framed;
+use transaction::ROOT_TRANSACTION;
+use wire;
+
+type Reply = oneshot::Sender<(wire::Header, wire::Body)>;
+
+// where to send the events for each watch token
+type Watches = Rc<RefCell<HashMap<String, mpsc::UnboundedSender<String>>>>;
+
+/// The eventual result of a request sent by a `Client`
+pub type Response<T> = Box<Future<Item = T, Error = Error>>;
+
+/// The paths that fire a watch set up by a `Client`
+pub type Events = Box<Stream<Item = String, Error = Error>>;
+
+/// Issues requests to a xenstore daemon.
+///
+/// A `Client` only queues up requests; the connection itself is serviced by
+/// the `Driver` returned from `Client::new`, which must be run for any of
+/// the responses to arrive. Clones share the same connection.
+#[derive(Clone)]
+pub struct Client {
+    requests: mpsc::UnboundedSender<(wire::Header, wire::Body, Reply)>,
+    next_req_id: Rc<Cell<wire::ReqId>>,
+    watches: Watches,
+    // the transaction every request is made in
+    tx_id: wire::TxId,
+}
+
+impl Client {
+    /// Start talking to the daemon over `io`.
+    pub fn new<T>(io: T) -> (Client, Driver<T>)
+        where T: AsyncRead + AsyncWrite
+    {
+        let (tx, rx) = mpsc::unbounded();
+        let watches = Rc::new(RefCell::new(HashMap::new()));
+        let client = Client {
+            requests: tx,
+            next_req_id: Rc::new(Cell::new(0)),
+            watches: watches.clone(),
+            tx_id: ROOT_TRANSACTION,
+        };
+        let driver = Driver {
+            transport: io.framed(wire::XenStoreCodec),
+            requests: rx,
+            outgoing: None,
+            pending: HashMap::new(),
+            watches: watches,
+            closed: false,
+        };
+
+        (client, driver)
+    }
+
+    /// Read the value at `path`, which may hold any bytes at all.
+    pub fn read(&self, path: &str) -> Response<Vec<u8>> {
+        let body = vec![to_field(path)];
+        // the value is the whole reply, NUL characters included
+        Box::new(self.request(wire::XS_READ, body).map(|body| body.to_vec()))
+    }
+
+    /// Write `value` to `path`, creating it and any missing parents.
+    pub fn write(&self, path: &str, value: &[u8]) -> Response<()> {
+        // the value runs to the end of the message so it isn't NUL terminated
+        let body = vec![to_field(path), value.to_owned()];
+        Box::new(self.request(wire::XS_WRITE, body).map(|_| ()))
+    }
+
+    /// Create an empty node at `path`.
+    pub fn mkdir(&self, path: &str) -> Response<()> {
+        let body = vec![to_field(path)];
+        Box::new(self.request(wire::XS_MKDIR, body).map(|_| ()))
+    }
+
+    /// Remove `path` along with everything below it.
+    pub fn rm(&self, path: &str) -> Response<()> {
+        let body = vec![to_field(path)];
+        Box::new(self.request(wire::XS_RM, body).map(|_| ()))
+    }
+
+    /// List the children of `path`.
+    pub fn directory(&self, path: &str) -> Response<Vec<String>> {
+        let body = vec![to_field(path)];
+        Box::new(self.request(wire::XS_DIRECTORY, body).and_then(to_strings))
+    }
+
+    /// Get the permissions of `path`, owner first.
+    pub fn get_perms(&self, path: &str) -> Response<Vec<Permission>> {
+        let body = vec![to_field(path)];
+        Box::new(self.request(wire::XS_GET_PERMS, body).and_then(|body| {
+            to_strings(body)
+                .and_then(|perms| perms.iter().map(|p| Permission::try_from(p)).collect())
+        }))
+    }
+
+    /// Replace the permissions of `path`, owner first.
+    pub fn set_perms(&self, path: &str, perms: &[Permission]) -> Response<()> {
+        let mut body = vec![to_field(path)];
+        body.extend(perms.iter().map(|p| to_field(&p.to_string())));
+        Box::new(self.request(wire::XS_SET_PERMS, body).map(|_| ()))
+    }
+
+    /// Look up the quota `name`, as it applies to `dom_id` if one is given.
+    /// Only dom0 may ask.
+    pub fn get_quota(&self, dom_id: Option<wire::DomainId>, name: &str) -> Response<usize> {
+        let mut body = Vec::new();
+        if let Some(dom_id) = dom_id {
+            body.push(to_field(&dom_id.to_string()));
+        }
+        body.push(to_field(name));
+
+        Box::new(self.request(wire::XS_GET_QUOTA, body).and_then(|body| {
+            let value = try!(to_strings(body)).into_iter().next().unwrap_or(String::new());
+            value.parse::<usize>()
+                .map_err(|_| Error::EINVAL(format!("bad quota returned by xenstored: {}", value)))
+        }))
+    }
+
+    /// Watch `path` and everything below it, telling the watch apart from
+    /// others by `token`.
+    ///
+    /// The stream yields the path of each node that changes, starting with
+    /// `path` itself once the watch is in place. It ends when the watch is
+    /// removed with `unwatch` or the connection goes away.
+    pub fn watch(&self, path: &str, token: &str) -> Events {
+        if self.watches.borrow().contains_key(token) {
+            let err = Error::EEXIST(format!("already watching with token {}", token));
+            return Box::new(future::err(err).into_stream());
+        }
+
+        let (tx, rx) = mpsc::unbounded();
+        self.watches.borrow_mut().insert(token.to_owned(), tx);
+
+        let watches = self.watches.clone();
+        let token = token.to_owned();
+        let body = vec![to_field(path), to_field(&token)];
+        let events = rx.map_err(|_| Error::EIO("connection to xenstored was lost".into()));
+        Box::new(self.request(wire::XS_WATCH, body)
+                     .then(move |res| {
+                               if res.is_err() {
+                                   watches.borrow_mut().remove(&token);
+                               }
+                               res.map(|_| events)
+                           })
+                     .flatten_stream())
+    }
+
+    /// Remove the watch on `path` with `token`, ending its stream of events.
+    pub fn unwatch(&self, path: &str, token: &str) -> Response<()> {
+        let watches = self.watches.clone();
+        let token = token.to_owned();
+        let body = vec![to_field(path), to_field(&token)];
+        Box::new(self.request(wire::XS_UNWATCH, body).map(move |_| {
+                                                            watches.borrow_mut().remove(&token);
+                                                        }))
+    }
+
+    /// Run `thunk` inside a transaction, starting over whenever committing
+    /// it fails with `Error::EAGAIN` because of a conflicting change.
+    ///
+    /// `thunk` is given a `Client` whose requests are all made inside the
+    /// transaction. If the future it returns fails, the transaction is
+    /// aborted and the error is passed on.
+    pub fn transaction<F, R>(&self, thunk: F) -> Response<R>
+        where F: Fn(&Client) -> Response<R> + 'static,
+              R: 'static
+    {
+        let client = self.clone();
+        Box::new(future::loop_fn(thunk, move |thunk| {
+            client.transaction_start().and_then(move |txn| {
+                thunk(&txn).then(move |res| {
+                    txn.transaction_end(res.is_ok()).then(move |end| match (res, end) {
+                        (Ok(r), Ok(())) => Ok(Loop::Break(r)),
+                        (Ok(_), Err(Error::EAGAIN(_))) => Ok(Loop::Continue(thunk)),
+                        (Ok(_), Err(e)) => Err(e),
+                        (Err(e), _) => Err(e),
+                    })
+                })
+            })
+        }))
+    }
+
+    /// Start a transaction, giving back a `Client` that makes its requests in it.
+    fn transaction_start(&self) -> Response<Client> {
+        let client = self.clone();
+        let body = vec![to_field("")];
+        Box::new(self.request(wire::XS_TRANSACTION_START, body)
+                     .and_then(to_strings)
+                     .and_then(move |fields| {
+            let tx_id = fields.get(0).and_then(|tx_id| tx_id.parse::<wire::TxId>().ok());
+            match tx_id {
+                Some(tx_id) => Ok(Client { tx_id: tx_id, ..client }),
+                None => Err(Error::EINVAL(format!("bad transaction id returned by xenstored"))),
+            }
+        }))
+    }
+
+    /// Commit the transaction this client is in, or abort it.
+    fn transaction_end(&self, commit: bool) -> Response<()> {
+        let body = vec![to_field(if commit { "T" } else { "F" })];
+        Box::new(self.request(wire::XS_TRANSACTION_END, body).map(|_| ()))
+    }
+
+    /// Send a request in this client's transaction.
+    fn request(&self, msg_type: u32, body: Vec<Vec<u8>>) -> Response<wire::Body> {
+        let req_id = self.next_req_id.get();
+        self.next_req_id.set(req_id.wrapping_add(1));
+
+        let body = wire::Body::from(body);
+        let header = wire::Header {
+            msg_type: msg_type,
+            req_id: req_id,
+            tx_id: self.tx_id,
+            len: body.len() as u32,
+        };
+
+        let (tx, rx) = oneshot::channel();
+        if self.requests.unbounded_send((header, body, tx)).is_err() {
+            return Box::new(future::err(Error::EIO("connection to xenstored has closed".into())));
+        }
+
+        Box::new(rx.then(|reply| match reply {
+                             Ok((header, body)) => {
+                                 if header.msg_type == wire::XS_ERROR {
+                                     Err(to_error(body))
+                                 } else {
+                                     Ok(body)
+                                 }
+                             }
+                             Err(_) => Err(Error::EIO("connection to xenstored was lost".into())),
+                         }))
+    }
+}
+
+/// Writes out the requests queued by a `Client` and hands back the responses.
+///
+/// Finishes when the daemon hangs up, or once the `Client` is gone, every
+/// request it made has been answered and none of its watches are left.
+pub struct Driver<T> {
+    transport: Framed<T, wire::XenStoreCodec>,
+    requests: mpsc::UnboundedReceiver<(wire::Header, wire::Body, Reply)>,
+    // a request the transport wasn't ready to take
+    outgoing: Option<(wire::Header, wire::Body)>,
+    pending: HashMap<wire::ReqId, Reply>,
+    watches: Watches,
+    closed: bool,
+}
+
+impl<T> Driver<T> {
+    /// Pass a watch event on to the stream for its token
+    fn fire(&mut self, body: wire::Body) {
+        let (path, token) = match to_strings(body) {
+            Ok(ref fields) if fields.len() >= 2 => (fields[0].clone(), fields[1].clone()),
+            _ => return,
+        };
+
+        let mut watches = self.watches.borrow_mut();
+        let gone = match watches.get(&token) {
+            Some(events) => events.unbounded_send(path).is_err(),
+            None => false,
+        };
+
+        // nobody is listening any more
+        if gone {
+            watches.remove(&token);
+        }
+    }
+}
+
+impl<T> Future for Driver<T>
+    where T: AsyncRead + AsyncWrite
+{
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        // send everything that has been asked for
+        loop {
+            if let Some(msg) = self.outgoing.take() {
+                if let AsyncSink::NotReady(msg) = try!(self.transport.start_send(msg)) {
+                    self.outgoing = Some(msg);
+                    break;
+                }
+            }
+
+            if self.closed {
+                break;
+            }
+
+            match self.requests.poll() {
+                Ok(Async::Ready(Some((header, body, reply)))) => {
+                    self.pending.insert(header.req_id, reply);
+                    self.outgoing = Some((header, body));
+                }
+                Ok(Async::Ready(None)) | Err(()) => self.closed = true,
+                Ok(Async::NotReady) => break,
+            }
+        }
+        try!(self.transport.poll_complete());
+
+        // hand each response to whoever is waiting for it
+        loop {
+            match try!(self.transport.poll()) {
+                Async::Ready(Some((header, body))) => {
+                    // watch events have no request waiting on them
+                    if header.msg_type == wire::XS_WATCH_EVENT {
+                        self.fire(body);
+                    } else if let Some(reply) = self.pending.remove(&header.req_id) {
+                        let _ = reply.send((header, body));
+                    }
+                }
+                Async::Ready(None) => {
+                    // end every stream of watch events
+                    self.watches.borrow_mut().clear();
+                    return Ok(Async::Ready(()));
+                }
+                Async::NotReady => break,
+            }
+        }
+
+        if self.closed && self.outgoing.is_none() && self.pending.is_empty() &&
+           self.watches.borrow().is_empty() {
+            return Ok(Async::Ready(()));
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+fn to_field(s: &str) -> Vec<u8> {
+    let mut field = s.as_bytes().to_owned();
+    field.push(b'\0');
+    field
+}
+
+fn to_strings(body: wire::Body) -> Result<Vec<String>> {
+    body.fields()
+        .into_iter()
+        .map(|field| {
+                 String::from_utf8(field.to_vec())
+                     .map_err(|_| Error::EINVAL(format!("bad string returned by xenstored")))
+             })
+        .collect()
+}
+
+fn to_error(body: wire::Body) -> Error {
+    match to_strings(body) {
+        Ok(ref names) if !names.is_empty() => Error::from_wire(&names[0]),
+        _ => Error::EIO("xenstored returned an unnamed error".into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tokio_core;
+    extern crate tokio_uds;
+
+    use domain::DomainList;
+    use error::Error;
+    use futures::{Async, Future, Poll};
+    use quota;
+    use self::tokio_core::reactor::Core;
+    use server::{XenStoredNewService, MAX_PENDING_REPLIES};
+    use std::cell::Cell;
+    use std::io::{self, Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use store::{Perm, Permission, Store};
+    use system::System;
+    use tokio_io::{AsyncRead, AsyncWrite};
+    use tokio_io::io::{read_exact, read_to_end, write_all};
+    use transaction::TransactionList;
+    use watch::WatchList;
+    use wire;
+    use super::*;
+
+    /// A plain blocking socket, good enough for one request at a time
+    struct Blocking(UnixStream);
+
+    impl Read for Blocking {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for Blocking {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    impl AsyncRead for Blocking {}
+
+    impl AsyncWrite for Blocking {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    /// Answer a single request with `msg_type` and `reply`, then hang up
+    fn serve_one(mut sock: UnixStream,
+                 msg_type: u32,
+                 reply: &'static [u8])
+                 -> thread::JoinHandle<(wire::Header, Vec<u8>)> {
+        thread::spawn(move || {
+            let mut header = [0; wire::HEADER_SIZE];
+            sock.read_exact(&mut header).unwrap();
+            let header = wire::Header::parse(&header).unwrap();
+            let mut body = vec![0; header.len()];
+            sock.read_exact(&mut body).unwrap();
+
+            let response = wire::Header {
+                msg_type: msg_type,
+                req_id: header.req_id,
+                tx_id: header.tx_id,
+                len: reply.len() as u32,
+            };
+            sock.write_all(&response.to_vec()).unwrap();
+            sock.write_all(reply).unwrap();
+
+            (header, body)
+        })
+    }
+
+    fn client(msg_type: u32,
+              reply: &'static [u8])
+              -> (Client, Driver<Blocking>, thread::JoinHandle<(wire::Header, Vec<u8>)>) {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let (client, driver) = Client::new(Blocking(ours));
+        (client, driver, serve_one(theirs, msg_type, reply))
+    }
+
+    #[test]
+    fn read() {
+        let (client, driver, server) = client(wire::XS_READ, b"value");
+
+        let value = client.read("/basic");
+        driver.wait().unwrap();
+        assert_eq!(value.wait().unwrap(), b"value");
+
+        let (header, body) = server.join().unwrap();
+        assert_eq!(header.msg_type, wire::XS_READ);
+        assert_eq!(body, b"/basic\0");
+    }
+
+    #[test]
+    fn write() {
+        let (client, driver, server) = client(wire::XS_WRITE, b"OK\0");
+
+        let done = client.write("/basic", b"value");
+        driver.wait().unwrap();
+        done.wait().unwrap();
+
+        let (header, body) = server.join().unwrap();
+        assert_eq!(header.msg_type, wire::XS_WRITE);
+        assert_eq!(body, b"/basic\0value");
+    }
+
+    #[test]
+    fn directory() {
+        let (client, driver, _) = client(wire::XS_DIRECTORY, b"path1\0path2\0");
+
+        let subdirs = client.directory("/basic");
+        driver.wait().unwrap();
+        assert_eq!(subdirs.wait().unwrap(),
+                   vec![String::from("path1"), String::from("path2")]);
+    }
+
+    #[test]
+    fn get_perms() {
+        let (client, driver, _) = client(wire::XS_GET_PERMS, b"w0\0r1\0");
+
+        let perms = client.get_perms("/basic");
+        driver.wait().unwrap();
+        assert_eq!(perms.wait().unwrap(),
+                   vec![Permission {
+                            id: 0,
+                            perm: Perm::Write,
+                        },
+                        Permission {
+                            id: 1,
+                            perm: Perm::Read,
+                        }]);
+    }
+
+    #[test]
+    fn error_reply() {
+        let (client, driver, _) = client(wire::XS_ERROR, b"ENOENT\0");
+
+        let value = client.read("/basic");
+        driver.wait().unwrap();
+        match value.wait() {
+            Err(Error::ENOENT(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "read a missing node"),
+        }
+    }
+
+    /// Run `test` against a real server over a socket pair
+    fn with_server<F, R>(test: F) -> R
+        where F: FnOnce(&Client) -> Response<R>
+    {
+        with_service(XenStoredNewService::new, test)
+    }
+
+    /// Run `test` against the server that `service` creates
+    fn with_service<F, R>(service: fn(Arc<Mutex<System>>) -> XenStoredNewService, test: F) -> R
+        where F: FnOnce(&Client) -> Response<R>
+    {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let system = System::new(Store::new(),
+                                 WatchList::new(),
+                                 TransactionList::new(),
+                                 DomainList::new());
+        let service = service(Arc::new(Mutex::new(system)));
+
+        let (ours, theirs) = tokio_uds::UnixStream::pair(&handle).unwrap();
+        handle.spawn(service.serve(theirs).map_err(|e| panic!("server failed: {}", e)));
+        let (client, driver) = Client::new(ours);
+        handle.spawn(driver.map_err(|e| panic!("client failed: {}", e)));
+
+        core.run(test(&client)).unwrap()
+    }
+
+    #[test]
+    fn hang_up() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let system = Arc::new(Mutex::new(System::new(Store::new(),
+                                                     WatchList::new(),
+                                                     TransactionList::new(),
+                                                     DomainList::new())));
+        let service = XenStoredNewService::new(system.clone());
+
+        let (ours, theirs) = tokio_uds::UnixStream::pair(&handle).unwrap();
+        let (done_tx, done_rx) = oneshot::channel();
+        handle.spawn(service.serve(theirs).then(move |_| done_tx.send(())));
+
+        // set up a watch by hand and wait for the server to accept it
+        let body = b"/basic\0token\0";
+        let mut request = wire::Header {
+                msg_type: wire::XS_WATCH,
+                req_id: 0,
+                tx_id: 0,
+                len: body.len() as u32,
+            }
+            .to_vec();
+        request.extend_from_slice(body);
+        let ours = core.run(write_all(ours, request)
+                                .and_then(|(ours, _)| read_exact(ours, [0; 19]))
+                                .map(|(ours, _)| ours))
+            .unwrap();
+
+        // leave a transaction open too
+        {
+            let mut sys = system.lock().unwrap();
+            let conn = sys.do_watch_mut(|watches| watches.iter().next().unwrap().conn);
+            sys.do_transaction_mut(|txns, store| txns.start(conn, store)).unwrap();
+        }
+
+        // the client dies without cleaning up after itself
+        drop(ours);
+        core.run(done_rx).unwrap();
+
+        system.lock().unwrap().do_all(|_, watches, txns, _| {
+                                          assert_eq!(watches.iter().count(), 0);
+                                          assert!(txns.list().is_empty());
+                                      });
+    }
+
+    /// Send `requests` to a fresh server as one burst, without reading
+    /// anything back until it hangs up, and return the replies it sent
+    fn flood(requests: Vec<(wire::Header, Vec<u8>)>) -> Vec<(wire::Header, Vec<u8>)> {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let system = System::new(Store::new(),
+                                 WatchList::new(),
+                                 TransactionList::new(),
+                                 DomainList::new());
+        let service = XenStoredNewService::new(Arc::new(Mutex::new(system)));
+
+        let (ours, theirs) = tokio_uds::UnixStream::pair(&handle).unwrap();
+        let (done_tx, done_rx) = oneshot::channel();
+        handle.spawn(service.serve(theirs)
+                         .then(move |res| done_tx.send(res.is_err()).map_err(|_| ())));
+
+        let mut burst = Vec::new();
+        for (header, body) in requests {
+            burst.extend(header.to_vec());
+            burst.extend(body);
+        }
+        let (_, bytes) = core.run(write_all(ours, burst)
+                                      .and_then(|(ours, _)| read_to_end(ours, Vec::new())))
+            .unwrap();
+        assert!(core.run(done_rx).unwrap(), "the client was not disconnected");
+
+        let mut replies = Vec::new();
+        let mut rest = &bytes[..];
+        while !rest.is_empty() {
+            let header = wire::Header::parse(rest).unwrap();
+            let end = wire::HEADER_SIZE + header.len();
+            replies.push((header, rest[wire::HEADER_SIZE..end].to_vec()));
+            rest = &rest[end..];
+        }
+        replies
+    }
+
+    fn raw_request(msg_type: u32, req_id: u32, body: &[u8]) -> (wire::Header, Vec<u8>) {
+        (wire::Header {
+             msg_type: msg_type,
+             req_id: req_id,
+             tx_id: 0,
+             len: body.len() as u32,
+         },
+         body.to_vec())
+    }
+
+    #[test]
+    fn unknown_message_type() {
+        let replies = flood(vec![raw_request(wire::XS_READ, 1, b"/\0"),
+                                 raw_request(0x1234, 2, b""),
+                                 raw_request(wire::XS_READ, 3, b"/\0")]);
+
+        // the first request is answered, the second refused and the third
+        // never looked at
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[0].0.req_id, 1);
+        assert_eq!(replies[1].0.msg_type, wire::XS_ERROR);
+        assert_eq!(replies[1].0.req_id, 2);
+        assert_eq!(replies[1].1, b"ENOSYS\0".to_vec());
+    }
+
+    #[test]
+    fn too_many_pending_replies() {
+        // replies big enough to back up once the socket buffers are full
+        let mut write = b"/big\0".to_vec();
+        write.extend(vec![b'a'; 4000]);
+        let mut requests = vec![raw_request(wire::XS_WRITE, 0, &write)];
+        for req_id in 1..(MAX_PENDING_REPLIES as u32 + 200) {
+            requests.push(raw_request(wire::XS_READ, req_id, b"/big\0"));
+        }
+
+        let replies = flood(requests);
+
+        // everything answered before the flood was noticed still goes out
+        let (last, answered) = replies.split_last().unwrap();
+        assert!(answered.iter().all(|&(ref header, _)| header.msg_type != wire::XS_ERROR));
+        assert!(answered.len() >= MAX_PENDING_REPLIES);
+        assert_eq!(last.0.msg_type, wire::XS_ERROR);
+        assert_eq!(last.0.req_id, answered.len() as u32);
+        assert_eq!(last.1, b"EINVAL\0".to_vec());
+    }
+
+    /// A server that removes the paths of released domains
+    fn release_cleanup(system: Arc<Mutex<System>>) -> XenStoredNewService {
+        system.lock().unwrap().set_release_cleanup(true);
+        XenStoredNewService::new(system)
+    }
+
+    #[test]
+    fn get_quota() {
+        let (watches, dom0_watches) = with_server(|client| {
+            Box::new(client.get_quota(None, "watches").join(client.get_quota(Some(0), "watches")))
+        });
+        assert_eq!(watches, quota::DEFAULT_MAX_WATCHES);
+        assert_eq!(dom0_watches, 0);
+
+        let res = with_server(|client| Box::new(client.get_quota(None, "nonsense").then(Ok)));
+        match res {
+            Err(Error::EINVAL(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "looked up an unknown quota"),
+        }
+    }
+
+    fn introduce(client: &Client, dom_id: &str) -> Response<()> {
+        let body = vec![to_field(dom_id), to_field("4096"), to_field("5")];
+        Box::new(client.request(wire::XS_INTRODUCE, body).map(|_| ()))
+    }
+
+    fn release(client: &Client, dom_id: &str) -> Response<()> {
+        Box::new(client.request(wire::XS_RELEASE, vec![to_field(dom_id)]).map(|_| ()))
+    }
+
+    #[test]
+    fn introduce_creates_domain_path() {
+        let perms = with_server(|client| {
+            let client = client.clone();
+            Box::new(introduce(&client, "1").and_then(move |_| client.get_perms("/local/domain/1")))
+        });
+
+        // the domain owns its own path
+        assert_eq!(perms,
+                   vec![Permission {
+                            id: 1,
+                            perm: Perm::None,
+                        }]);
+    }
+
+    #[test]
+    fn release_cleanup_removes_domain_path() {
+        fn released(service: fn(Arc<Mutex<System>>) -> XenStoredNewService) -> Result<Vec<u8>> {
+            with_service(service, |client| {
+                let (writer, releaser, reader) = (client.clone(), client.clone(), client.clone());
+                Box::new(introduce(client, "1")
+                             .and_then(move |_| writer.write("/local/domain/1/name", b"guest"))
+                             .and_then(move |_| release(&releaser, "1"))
+                             .and_then(move |_| reader.read("/local/domain/1/name").then(Ok)))
+            })
+        }
+
+        // the path is left for the toolstack unless asked otherwise
+        assert_eq!(released(XenStoredNewService::new).unwrap(), b"guest");
+        match released(release_cleanup) {
+            Err(Error::ENOENT(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "the released domain's path is still there"),
+        }
+    }
+
+    fn restrict(client: &Client, dom_id: &str) -> Response<()> {
+        Box::new(client.request(wire::XS_RESTRICT, vec![to_field(dom_id)]).map(|_| ()))
+    }
+
+    #[test]
+    fn restrict_to_guest() {
+        let res = with_server(|client| {
+            let client = client.clone();
+            let setup = client.write("/secret", b"value")
+                .join(introduce(&client, "1"))
+                .and_then({
+                              let client = client.clone();
+                              move |_| restrict(&client, "1")
+                          });
+            Box::new(setup.and_then(move |_| {
+                let secret = client.read("/secret").then(Ok);
+                let own = client.write("/local/domain/1/name", b"guest").then(Ok);
+                let again = restrict(&client, "0").then(Ok);
+                secret.join3(own, again)
+            }))
+        });
+
+        // the connection is now domain 1, for good
+        match res {
+            (Err(Error::EACCES(_)), Ok(()), Err(Error::EACCES(_))) => assert!(true),
+            (secret, own, again) => {
+                assert!(false, format!("unexpected results {:?} {:?} {:?}", secret, own, again))
+            }
+        }
+    }
+
+    fn guest(system: Arc<Mutex<System>>) -> XenStoredNewService {
+        XenStoredNewService::with_domain(system, 1)
+    }
+
+    #[test]
+    fn guests_may_not_manage_domains() {
+        let res = with_service(guest, |client| {
+            let client = client.clone();
+            Box::new(introduce(&client, "2").then(move |introduced| {
+                client.get_quota(None, "nodes").then(|quota| Ok((introduced, quota)))
+            }))
+        });
+
+        match res {
+            (Err(Error::EACCES(_)), Err(Error::EACCES(_))) => assert!(true),
+            (introduced, quota) => {
+                assert!(false, format!("unexpected results {:?} {:?}", introduced, quota))
+            }
+        }
+    }
+
+    #[test]
+    fn binary_values() {
+        let value = with_server(|client| {
+            let client = client.clone();
+            Box::new(client.write("/binary", b"\xff\0\x01")
+                         .and_then(move |_| client.read("/binary")))
+        });
+
+        // what goes in comes back out, whether or not it is text
+        assert_eq!(value, b"\xff\0\x01");
+    }
+
+    #[test]
+    fn transaction() {
+        let value = with_server(|client| {
+            let write = client.transaction(|txn| txn.write("/basic", b"value"));
+            let client = client.clone();
+            Box::new(write.and_then(move |_| client.read("/basic")))
+        });
+        assert_eq!(value, b"value");
+    }
+
+    #[test]
+    fn transaction_abort() {
+        let res = with_server(|client| {
+            let failed = client.transaction(|txn| {
+                let txn = txn.clone();
+                Box::new(txn.write("/basic", b"value").and_then(move |_| txn.read("/missing")))
+            });
+            let client = client.clone();
+            Box::new(failed.then(move |res| client.read("/basic").then(|read| Ok((res, read)))))
+        });
+
+        // the failure is passed on and nothing was committed
+        match res {
+            (Err(Error::ENOENT(_)), Err(Error::ENOENT(_))) => assert!(true),
+            (failed, read) => assert!(false, format!("unexpected results {:?} {:?}", failed, read)),
+        }
+    }
+
+    #[test]
+    fn transaction_retry() {
+        let attempts = Rc::new(Cell::new(0));
+        let count = attempts.clone();
+
+        let value = with_server(move |client| {
+            let other = client.clone();
+            let write = client.transaction(move |txn| {
+                count.set(count.get() + 1);
+
+                // sneak in a conflicting write the first time around
+                let conflict: Response<()> = if count.get() == 1 {
+                    other.write("/basic", b"other")
+                } else {
+                    Box::new(future::ok(()))
+                };
+                let txn = txn.clone();
+                Box::new(txn.read("/basic")
+                             .then(|_| conflict)
+                             .and_then(move |_| txn.write("/basic", b"value")))
+            });
+            let client = client.clone();
+            Box::new(write.and_then(move |_| client.read("/basic")))
+        });
+
+        assert_eq!(value, b"value");
+        assert_eq!(attempts.get(), 2);
+    }
+
+    #[test]
+    fn read_only() {
+        let res = with_service(XenStoredNewService::read_only, |client| {
+            let client = client.clone();
+            Box::new(client.write("/basic", b"value")
+                         .then(move |write| client.directory("/").then(|dir| Ok((write, dir)))))
+        });
+
+        // reads are still answered, anything that changes the store isn't
+        match res {
+            (Err(Error::EACCES(_)), Ok(_)) => assert!(true),
+            (write, dir) => assert!(false, format!("unexpected results {:?} {:?}", write, dir)),
+        }
+    }
+
+    #[test]
+    fn watch() {
+        let events = with_server(|client| {
+            let client = client.clone();
+            Box::new(client.watch("/basic", "token")
+                         .into_future()
+                         .map_err(|(e, _)| e)
+                         .and_then(move |(first, events)| {
+                let rest = events.take(1).collect();
+                client.write("/basic/child", b"value")
+                    .and_then(|_| rest)
+                    .map(move |rest| first.into_iter().chain(rest).collect::<Vec<_>>())
+            }))
+        });
+
+        // the watch fires once when it's set up and again for the write
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0], "/basic");
+    }
+
+    #[test]
+    fn unwatch() {
+        let events = with_server(|client| {
+            let events = client.watch("/basic", "token");
+            let client = client.clone();
+            Box::new(client.unwatch("/basic", "token").and_then(move |_| events.collect()))
+        });
+
+        // the stream ends once the watch is gone, after the initial event
+        assert_eq!(events, vec![String::from("/basic")]);
+    }
+}
@@ -0,0 +1,470 @@
+/**
+    xenstore-rs provides a Rust based xenstore implementation.
+    Copyright (C) 2016 Star Lab Corp.
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License along
+    with this program; if not, see <http://www.gnu.org/licenses/>.
+**/
+
+// Reading the daemon's configuration file.
+//
+// The file is written in the part of TOML that a configuration needs:
+// tables, arrays of tables, and keys holding strings, integers or arrays of
+// strings on a single line. For example
+//
+//     [quota]
+//     max-entries = 2000
+//
+//     [sockets]
+//     socket-path = ["/var/run/xenstored/socket"]
+//
+//     [[node]]
+//     path = "/local/domain/0/name"
+//     value = "Domain-0"
+//     perms = ["n0", "r1"]
+//
+// As in TOML, a key may only be given once in each table and a table only
+// once in the file, though there may be any number of [[node]]s.
+
+use std::char;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use super::path::Path;
+use super::quota::Quota;
+use super::store::{Perm, Permission, StoreBuilder, Value, DOM0_DOMAIN_ID};
+
+fn invalid(line: usize, msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", line, msg))
+}
+
+/// A node to seed the store with.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NodeConfig {
+    pub path: Path,
+    pub value: Value,
+    pub permissions: Vec<Permission>,
+}
+
+/// The `Config` type.
+///
+/// What the daemon is told to set up by its configuration file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub quota: Quota,
+    /// Nodes created on top of the default tree, in the order given.
+    ///
+    /// They only go into a store built afresh. A store loaded from a file
+    /// or carried over by a live update keeps the nodes it has.
+    pub nodes: Vec<NodeConfig>,
+    pub socket_paths: Vec<PathBuf>,
+    pub socket_ro_paths: Vec<PathBuf>,
+}
+
+/// What can be on the right of a `=`
+enum Item {
+    Str(String),
+    Int(u64),
+    List(Vec<String>),
+}
+
+/// Which table the keys that follow belong to
+enum Table {
+    Top,
+    Quota,
+    Sockets,
+    Node,
+}
+
+/// Read a basic string whose opening quote has been consumed, returning it
+/// and whatever follows the closing quote.
+fn parse_string(text: &str) -> Result<(String, &str), &'static str> {
+    let mut value = String::new();
+    let mut chars = text.char_indices();
+
+    while let Some((pos, c)) = chars.next() {
+        match c {
+            '"' => return Ok((value, &text[pos + 1..])),
+            '\\' => {
+                let escaped = match chars.next() {
+                    Some((_, '"')) => '"',
+                    Some((_, '\\')) => '\\',
+                    Some((_, 'n')) => '\n',
+                    Some((_, 't')) => '\t',
+                    Some((_, 'r')) => '\r',
+                    Some((_, 'u')) => {
+                        let hex = chars.by_ref().take(4).map(|(_, c)| c).collect::<String>();
+                        try!(u32::from_str_radix(&hex, 16)
+                                 .ok()
+                                 .and_then(char::from_u32)
+                                 .ok_or("bad unicode escape"))
+                    }
+                    _ => return Err("unknown escape in string"),
+                };
+                value.push(escaped);
+            }
+            _ => value.push(c),
+        }
+    }
+
+    Err("unterminated string")
+}
+
+/// Read the value of a key, returning it and whatever follows it.
+fn parse_item(text: &str) -> Result<(Item, &str), &'static str> {
+    let text = text.trim_left();
+
+    if text.starts_with('"') {
+        return parse_string(&text[1..]).map(|(s, rest)| (Item::Str(s), rest));
+    }
+
+    if text.starts_with('[') {
+        let mut list = Vec::new();
+        let mut rest = text[1..].trim_left();
+        loop {
+            if rest.starts_with(']') {
+                return Ok((Item::List(list), &rest[1..]));
+            }
+            if !rest.starts_with('"') {
+                return Err("arrays may only hold strings");
+            }
+            let (s, after) = try!(parse_string(&rest[1..]));
+            list.push(s);
+
+            rest = after.trim_left();
+            if rest.starts_with(',') {
+                rest = rest[1..].trim_left();
+            } else if !rest.starts_with(']') {
+                return Err("expected , or ] in array");
+            }
+        }
+    }
+
+    let end = text.find(|c: char| c.is_whitespace() || c == '#').unwrap_or(text.len());
+    let digits = text[..end].replace('_', "");
+    digits.parse::<u64>()
+        .map(|n| (Item::Int(n), &text[end..]))
+        .map_err(|_| "expected a string, an integer or an array of strings")
+}
+
+fn string(line: usize, item: Item) -> io::Result<String> {
+    match item {
+        Item::Str(s) => Ok(s),
+        _ => Err(invalid(line, "expected a string")),
+    }
+}
+
+fn int(line: usize, item: Item) -> io::Result<usize> {
+    match item {
+        Item::Int(n) => Ok(n as usize),
+        _ => Err(invalid(line, "expected an integer")),
+    }
+}
+
+fn list(line: usize, item: Item) -> io::Result<Vec<String>> {
+    match item {
+        Item::List(list) => Ok(list),
+        _ => Err(invalid(line, "expected an array of strings")),
+    }
+}
+
+/// A `[[node]]` as it is being read
+struct PartialNode {
+    line: usize,
+    path: Option<Path>,
+    value: Value,
+    permissions: Vec<Permission>,
+}
+
+impl PartialNode {
+    fn new(line: usize) -> PartialNode {
+        PartialNode {
+            line: line,
+            path: None,
+            value: Value::new(),
+            permissions: vec![Permission {
+                                  id: DOM0_DOMAIN_ID,
+                                  perm: Perm::None,
+                              }],
+        }
+    }
+
+    fn finish(self) -> io::Result<NodeConfig> {
+        match self.path {
+            Some(path) => {
+                Ok(NodeConfig {
+                       path: path,
+                       value: self.value,
+                       permissions: self.permissions,
+                   })
+            }
+            None => Err(invalid(self.line, "node has no path")),
+        }
+    }
+}
+
+impl Config {
+    /// The configuration used when there is no file: the default quota and
+    /// tree, and no sockets beyond the daemon's own defaults.
+    pub fn new() -> Config {
+        Config {
+            quota: Quota::new(),
+            nodes: Vec::new(),
+            socket_paths: Vec::new(),
+            socket_ro_paths: Vec::new(),
+        }
+    }
+
+    /// Read the configuration in `file`.
+    pub fn load<P: AsRef<::std::path::Path>>(file: P) -> io::Result<Config> {
+        let mut text = String::new();
+        try!(File::open(file).and_then(|mut file| file.read_to_string(&mut text)));
+        Config::parse(&text)
+    }
+
+    /// Parse a configuration.
+    ///
+    /// # Errors
+    ///
+    /// * `io::ErrorKind::InvalidData` naming the line that couldn't be
+    ///   understood
+    pub fn parse(text: &str) -> io::Result<Config> {
+        let mut config = Config::new();
+        let mut table = Table::Top;
+        let mut node: Option<PartialNode> = None;
+        let mut tables = HashSet::new();
+        let mut keys = HashSet::new();
+
+        for (n, line) in text.lines().enumerate() {
+            let line_no = n + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if line.starts_with('[') {
+                let header = line.splitn(2, '#').next().unwrap().trim();
+                if let Some(partial) = node.take() {
+                    config.nodes.push(try!(partial.finish()));
+                }
+                if header != "[[node]]" && !tables.insert(header) {
+                    return Err(invalid(line_no, &format!("{} was already given", header)));
+                }
+                keys.clear();
+                table = match header {
+                    "[quota]" => Table::Quota,
+                    "[sockets]" => Table::Sockets,
+                    "[[node]]" => {
+                        node = Some(PartialNode::new(line_no));
+                        Table::Node
+                    }
+                    _ => return Err(invalid(line_no, &format!("unknown table {}", header))),
+                };
+                continue;
+            }
+
+            let eq = try!(line.find('=').ok_or_else(|| invalid(line_no, "expected key = value")));
+            let key = line[..eq].trim();
+            let (item, rest) = try!(parse_item(&line[eq + 1..])
+                                        .map_err(|msg| invalid(line_no, msg)));
+            let rest = rest.trim();
+            if !rest.is_empty() && !rest.starts_with('#') {
+                return Err(invalid(line_no, "unexpected text after the value"));
+            }
+            if !keys.insert(key) {
+                return Err(invalid(line_no, &format!("{} was already given", key)));
+            }
+
+            match (&table, key) {
+                (&Table::Quota, "max-entries") => {
+                    config.quota.max_entries = try!(int(line_no, item))
+                }
+                (&Table::Quota, "max-entry-size") => {
+                    config.quota.max_entry_size = try!(int(line_no, item))
+                }
+                (&Table::Quota, "max-bytes") => config.quota.max_bytes = try!(int(line_no, item)),
+                (&Table::Quota, "max-watches") => {
+                    config.quota.max_watches = try!(int(line_no, item))
+                }
+                (&Table::Sockets, "socket-path") => {
+                    config.socket_paths = try!(list(line_no, item))
+                        .into_iter()
+                        .map(PathBuf::from)
+                        .collect()
+                }
+                (&Table::Sockets, "socket-ro-path") => {
+                    config.socket_ro_paths = try!(list(line_no, item))
+                        .into_iter()
+                        .map(PathBuf::from)
+                        .collect()
+                }
+                (&Table::Node, _) => {
+                    let partial = node.as_mut().unwrap();
+                    match key {
+                        "path" => {
+                            let path = try!(string(line_no, item));
+                            if !path.starts_with('/') {
+                                return Err(invalid(line_no, "node paths must be absolute"));
+                            }
+                            partial.path = Some(try!(Path::try_from(DOM0_DOMAIN_ID, &path)
+                                .map_err(|_| invalid(line_no, "invalid path"))));
+                        }
+                        "value" => partial.value = Value::from(try!(string(line_no, item))),
+                        "perms" => {
+                            let perms = try!(list(line_no, item));
+                            if perms.is_empty() {
+                                return Err(invalid(line_no, "a node needs an owner"));
+                            }
+                            partial.permissions = try!(perms.iter()
+                                .map(|perm| Permission::try_from(perm))
+                                .collect::<Result<Vec<_>, _>>()
+                                .map_err(|_| invalid(line_no, "invalid permission")));
+                        }
+                        _ => return Err(invalid(line_no, &format!("unknown key {}", key))),
+                    }
+                }
+                _ => return Err(invalid(line_no, &format!("unknown key {}", key))),
+            }
+        }
+
+        if let Some(partial) = node.take() {
+            config.nodes.push(try!(partial.finish()));
+        }
+
+        Ok(config)
+    }
+
+    /// A `StoreBuilder` for the default tree with the configured nodes and
+    /// quota on top.
+    pub fn store_builder(&self) -> StoreBuilder {
+        self.nodes.iter().fold(StoreBuilder::new().quota(self.quota), |builder, node| {
+            builder.node(node.path.clone(), node.value.clone(), node.permissions.clone())
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::path::PathBuf;
+    use super::super::path::Path;
+    use super::super::quota::Quota;
+    use super::super::store::{ChangeSet, Perm, Permission, Value, DOM0_DOMAIN_ID};
+    use super::*;
+
+    #[test]
+    fn parse_everything() {
+        let config = Config::parse(r#"
+# sockets for the toolstack
+[sockets]
+socket-path = ["/run/xenstored/socket", "/run/xenstored/other"]  # two of them
+socket-ro-path = []
+
+[quota]
+max-entries = 2_000
+max-watches = 64
+
+[[node]]
+path = "/local/domain/0/name"
+value = "Domain-0 \"dom0\"é"
+perms = ["n0", "r1"]
+
+[[node]]
+path = "/vm"
+"#)
+            .unwrap();
+
+        assert_eq!(config.socket_paths,
+                   vec![PathBuf::from("/run/xenstored/socket"),
+                        PathBuf::from("/run/xenstored/other")]);
+        assert!(config.socket_ro_paths.is_empty());
+        assert_eq!(config.quota,
+                   Quota {
+                       max_entries: 2000,
+                       max_watches: 64,
+                       ..Quota::new()
+                   });
+        assert_eq!(config.nodes,
+                   vec![NodeConfig {
+                            path: Path::try_from(DOM0_DOMAIN_ID, "/local/domain/0/name").unwrap(),
+                            value: Value::from("Domain-0 \"dom0\"\u{e9}"),
+                            permissions: vec![Permission {
+                                                  id: 0,
+                                                  perm: Perm::None,
+                                              },
+                                              Permission {
+                                                  id: 1,
+                                                  perm: Perm::Read,
+                                              }],
+                        },
+                        NodeConfig {
+                            path: Path::try_from(DOM0_DOMAIN_ID, "/vm").unwrap(),
+                            value: Value::new(),
+                            permissions: vec![Permission {
+                                                  id: 0,
+                                                  perm: Perm::None,
+                                              }],
+                        }]);
+
+        assert_eq!(Config::parse("").unwrap(), Config::new());
+    }
+
+    #[test]
+    fn parse_garbage() {
+        for text in &["[nonsense]",
+                      "max-entries = 1",
+                      "[quota]\nmax-entries = \"many\"",
+                      "[quota]\nmax-entries = 1 2",
+                      "[quota]\nmax-entries",
+                      "[sockets]\nsocket-path = [\"/a\" \"/b\"]",
+                      "[sockets]\nsocket-path = [\"/a\"",
+                      "[[node]]\nvalue = \"no path\"",
+                      "[[node]]\npath = \"relative\"",
+                      "[[node]]\npath = \"/a\"\nperms = [\"x1\"]",
+                      "[[node]]\npath = \"/a\"\nperms = []",
+                      "[[node]]\npath = \"/a\\q\"",
+                      "[quota]\nmax-entries = 1\nmax-entries = 2",
+                      "[quota]\nmax-entries = 1\n[quota]\nmax-bytes = 2",
+                      "[[node]]\npath = \"/a\"\npath = \"/b\""] {
+            match Config::parse(text) {
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidData => assert!(true),
+                Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+                Ok(_) => assert!(false, "parsed {:?}", text),
+            }
+        }
+    }
+
+    #[test]
+    fn bootstrap_store() {
+        let config = Config::parse(r#"
+[quota]
+max-entries = 5
+
+[[node]]
+path = "/local/domain/0/name"
+value = "Domain-0"
+perms = ["n0", "r1"]
+"#)
+            .unwrap();
+        let store = config.store_builder().build();
+        let changes = ChangeSet::new(&store);
+
+        // the configured nodes go on top of the default tree
+        let name = Path::try_from(DOM0_DOMAIN_ID, "/local/domain/0/name").unwrap();
+        assert_eq!(store.read(&changes, 1, &name).unwrap(), "Domain-0");
+        let vm = Path::try_from(DOM0_DOMAIN_ID, "/vm").unwrap();
+        store.read(&changes, DOM0_DOMAIN_ID, &vm).unwrap();
+        assert_eq!(store.quota().max_entries, 5);
+    }
+}
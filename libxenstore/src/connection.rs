@@ -18,13 +18,42 @@
 
 extern crate mio;
 
+use error::{Error, Result};
+use futures::task::{self, Task};
 use self::mio::Token;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use watch::Watch;
 use wire::DomainId;
 
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+/// The most watch events that may wait for delivery to a single connection
+pub const MAX_QUEUED_EVENTS: usize = 1024;
+
+/// Identifies a connection to the daemon.
+///
+/// Two `ConnId`s are the same connection when their tokens match, whatever
+/// domain they act as, since a connection may restrict itself to acting as
+/// a guest after it has set up watches and transactions.
+#[derive(Clone, Copy, Debug)]
 pub struct ConnId {
     pub token: Token,
     pub dom_id: DomainId,
+    // only requests that don't change anything are allowed
+    pub read_only: bool,
+}
+
+impl PartialEq for ConnId {
+    fn eq(&self, other: &ConnId) -> bool {
+        self.token == other.token
+    }
+}
+
+impl Eq for ConnId {}
+
+impl Hash for ConnId {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.token.hash(state);
+    }
 }
 
 impl ConnId {
@@ -32,6 +61,200 @@ impl ConnId {
         ConnId {
             token: token,
             dom_id: dom_id,
+            read_only: false,
         }
     }
+
+    /// The same connection, but limited to looking at the store.
+    pub fn read_only(self) -> ConnId {
+        ConnId { read_only: true, ..self }
+    }
+}
+
+/// The `Outbox` type.
+///
+/// Holds the watch events fired for a connection until its transport gets
+/// around to writing them out. The queue is bounded so that a client that
+/// never reads can't make the daemon grow without limit; once it overflows
+/// the connection is considered dead and should be dropped.
+pub struct Outbox {
+    events: VecDeque<Watch>,
+    limit: usize,
+    overflowed: bool,
+    // no more events will be queued
+    closed: bool,
+    // the transport task waiting for events to arrive, if any
+    task: Option<Task>,
+}
+
+impl Outbox {
+    /// Create a new `Outbox` holding at most `limit` events.
+    pub fn new(limit: usize) -> Outbox {
+        Outbox {
+            events: VecDeque::new(),
+            limit: limit,
+            overflowed: false,
+            closed: false,
+            task: None,
+        }
+    }
+
+    /// Queue an event for delivery.
+    ///
+    /// A domain event that is the same as one still waiting is dropped, so
+    /// releasing many domains at once doesn't flood their watchers.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::E2BIG` if the outbox is full
+    pub fn push(&mut self, watch: Watch) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+
+        if watch.node.is_special() && self.events.contains(&watch) {
+            return Ok(());
+        }
+
+        if self.overflowed || self.events.len() >= self.limit {
+            self.overflowed = true;
+            self.events.clear();
+        } else {
+            self.events.push_back(watch);
+        }
+
+        if let Some(task) = self.task.take() {
+            task.notify();
+        }
+
+        if self.overflowed {
+            Err(Error::E2BIG(format!("more than {} watch events queued", self.limit)))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Take the next event to deliver.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::E2BIG` if the outbox has overflowed
+    pub fn pop(&mut self) -> Result<Option<Watch>> {
+        if self.overflowed {
+            return Err(Error::E2BIG(format!("more than {} watch events queued", self.limit)));
+        }
+
+        Ok(self.events.pop_front())
+    }
+
+    /// Wake up the current task when the next event is queued.
+    ///
+    /// Must be called from within a task.
+    pub fn park(&mut self) {
+        self.task = Some(task::current());
+    }
+
+    /// Stop queueing events, the ones already queued are still delivered.
+    pub fn close(&mut self) {
+        self.closed = true;
+        if let Some(task) = self.task.take() {
+            task.notify();
+        }
+    }
+
+    /// The number of events waiting to be delivered.
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Check if the outbox is closed and has nothing left to deliver.
+    pub fn is_finished(&self) -> bool {
+        self.closed && self.events.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::error::Error;
+    use super::super::path::Path;
+    use super::super::store::DOM0_DOMAIN_ID;
+    use super::super::watch::{Watch, WPath, WToken};
+    use super::*;
+
+    fn watch(conn: ConnId, s: &str) -> Watch {
+        let path = Path::try_from(DOM0_DOMAIN_ID, s).unwrap();
+        Watch::new(conn, WPath::Normal(path.clone()), WToken::from(s))
+    }
+
+    #[test]
+    fn outbox_in_order() {
+        let conn = ConnId::new(Token(0), DOM0_DOMAIN_ID);
+        let mut outbox = Outbox::new(2);
+
+        outbox.push(watch(conn, "/a")).unwrap();
+        outbox.push(watch(conn, "/b")).unwrap();
+
+        assert_eq!(outbox.pop().unwrap(), Some(watch(conn, "/a")));
+        assert_eq!(outbox.pop().unwrap(), Some(watch(conn, "/b")));
+        assert_eq!(outbox.pop().unwrap(), None);
+    }
+
+    #[test]
+    fn outbox_coalesces_domain_events() {
+        let conn = ConnId::new(Token(0), DOM0_DOMAIN_ID);
+        let release = Watch::new(conn, WPath::ReleaseDomain, WToken::from("token"));
+        let mut outbox = Outbox::new(4);
+
+        outbox.push(watch(conn, "/a")).unwrap();
+        outbox.push(watch(conn, "/a")).unwrap();
+        outbox.push(release.clone()).unwrap();
+        outbox.push(release.clone()).unwrap();
+
+        // only the domain event is merged with the one already waiting
+        assert_eq!(outbox.pop().unwrap(), Some(watch(conn, "/a")));
+        assert_eq!(outbox.pop().unwrap(), Some(watch(conn, "/a")));
+        assert_eq!(outbox.pop().unwrap(), Some(release));
+        assert_eq!(outbox.pop().unwrap(), None);
+    }
+
+    #[test]
+    fn outbox_overflow() {
+        let conn = ConnId::new(Token(0), DOM0_DOMAIN_ID);
+        let mut outbox = Outbox::new(1);
+
+        outbox.push(watch(conn, "/a")).unwrap();
+
+        match outbox.push(watch(conn, "/b")) {
+            Err(Error::E2BIG(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "queued more events than the limit"),
+        }
+
+        // once it has overflowed the connection is done for
+        match outbox.pop() {
+            Err(Error::E2BIG(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "delivered events from an overflowed outbox"),
+        }
+    }
+
+    #[test]
+    fn outbox_close() {
+        let conn = ConnId::new(Token(0), DOM0_DOMAIN_ID);
+        let mut outbox = Outbox::new(2);
+
+        outbox.push(watch(conn, "/a")).unwrap();
+        outbox.close();
+        outbox.push(watch(conn, "/b")).unwrap();
+
+        // what was queued before closing still goes out
+        assert!(!outbox.is_finished());
+        assert_eq!(outbox.pop().unwrap(), Some(watch(conn, "/a")));
+        assert!(outbox.is_finished());
+        assert_eq!(outbox.pop().unwrap(), None);
+    }
 }
@@ -0,0 +1,221 @@
+/**
+    xenstore-rs provides a Rust based xenstore implementation.
+    Copyright (C) 2016 Star Lab Corp.
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License along
+    with this program; if not, see <http://www.gnu.org/licenses/>.
+**/
+
+use std::collections::HashMap;
+use std::collections::hash_map::Values;
+use std::mem;
+use super::error::{Error, Result};
+use super::message::{EvtChnPort, Mfn};
+use super::wire;
+
+/// An introduced domain.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Domain {
+    pub dom_id: wire::DomainId,
+    pub mfn: Mfn,
+    pub port: EvtChnPort,
+    /// the domain has shut down and has not been resumed since
+    pub shutdown: bool,
+}
+
+impl Domain {
+    /// Check if both are the same domain talking over the same ring.
+    pub fn same_ring(&self, other: &Domain) -> bool {
+        self.dom_id == other.dom_id && self.mfn == other.mfn && self.port == other.port
+    }
+}
+
+/// The `DomainList` type.
+///
+/// Used to track which domains have been introduced to the store.
+pub struct DomainList {
+    domains: HashMap<wire::DomainId, Domain>,
+}
+
+impl DomainList {
+    /// Create a new instance of the `DomainList`.
+    pub fn new() -> DomainList {
+        DomainList { domains: HashMap::new() }
+    }
+
+    /// Introduce a domain.
+    ///
+    /// Returns `true` if the domain was newly introduced and `false` if the
+    /// same domain was introduced again with the same ring page and event
+    /// channel.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::EEXIST` if the domain is already introduced with a different
+    ///   ring page or event channel
+    pub fn introduce(&mut self,
+                     dom_id: wire::DomainId,
+                     mfn: Mfn,
+                     port: EvtChnPort)
+                     -> Result<bool> {
+        let domain = Domain {
+            dom_id: dom_id,
+            mfn: mfn,
+            port: port,
+            shutdown: false,
+        };
+
+        if let Some(existing) = self.domains.get(&dom_id) {
+            if existing.same_ring(&domain) {
+                return Ok(false);
+            }
+
+            return Err(Error::EEXIST(format!("domain {} has already been introduced", dom_id)));
+        }
+
+        self.domains.insert(dom_id, domain);
+        Ok(true)
+    }
+
+    /// Release a domain.
+    ///
+    /// Returns the details of the released domain.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::ENOENT` if the domain has not been introduced
+    pub fn release(&mut self, dom_id: wire::DomainId) -> Result<Domain> {
+        self.domains
+            .remove(&dom_id)
+            .ok_or(Error::ENOENT(format!("domain {} has not been introduced", dom_id)))
+    }
+
+    /// Mark a domain as shut down.
+    ///
+    /// Returns `true` if the domain was not already shut down, so that
+    /// @releaseDomain watchers are only told about it once.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::ENOENT` if the domain has not been introduced
+    pub fn shutdown(&mut self, dom_id: wire::DomainId) -> Result<bool> {
+        self.get_mut(dom_id).map(|domain| !mem::replace(&mut domain.shutdown, true))
+    }
+
+    /// Resume a domain that was shut down, such as after a suspend.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::ENOENT` if the domain has not been introduced
+    pub fn resume(&mut self, dom_id: wire::DomainId) -> Result<()> {
+        self.get_mut(dom_id).map(|domain| domain.shutdown = false)
+    }
+
+    fn get_mut(&mut self, dom_id: wire::DomainId) -> Result<&mut Domain> {
+        self.domains
+            .get_mut(&dom_id)
+            .ok_or(Error::ENOENT(format!("domain {} has not been introduced", dom_id)))
+    }
+
+    /// Check if a domain has been introduced.
+    pub fn is_introduced(&self, dom_id: wire::DomainId) -> bool {
+        self.domains.contains_key(&dom_id)
+    }
+
+    /// Get the details of an introduced domain.
+    pub fn get(&self, dom_id: wire::DomainId) -> Option<&Domain> {
+        self.domains.get(&dom_id)
+    }
+
+    /// Iterate over all of the introduced domains.
+    pub fn iter(&self) -> Values<wire::DomainId, Domain> {
+        self.domains.values()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::error::Error;
+    use super::*;
+
+    #[test]
+    fn introduce_domain() {
+        let mut domains = DomainList::new();
+
+        assert_eq!(domains.is_introduced(1), false);
+        assert_eq!(domains.introduce(1, 0x1000, 5).unwrap(), true);
+        assert_eq!(domains.is_introduced(1), true);
+        assert_eq!(domains.get(1),
+                   Some(&Domain {
+                            dom_id: 1,
+                            mfn: 0x1000,
+                            port: 5,
+                            shutdown: false,
+                        }));
+    }
+
+    #[test]
+    fn reintroduce_domain() {
+        let mut domains = DomainList::new();
+
+        domains.introduce(1, 0x1000, 5).unwrap();
+
+        // the same introduction again is not an error
+        assert_eq!(domains.introduce(1, 0x1000, 5).unwrap(), false);
+
+        // but a conflicting one is
+        match domains.introduce(1, 0x2000, 6) {
+            Err(Error::EEXIST(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "reintroduced a domain with different values"),
+        }
+    }
+
+    #[test]
+    fn release_domain() {
+        let mut domains = DomainList::new();
+
+        domains.introduce(1, 0x1000, 5).unwrap();
+        domains.release(1).unwrap();
+        assert_eq!(domains.is_introduced(1), false);
+
+        // releasing it again fails
+        match domains.release(1) {
+            Err(Error::ENOENT(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "released a domain that was not introduced"),
+        }
+    }
+
+    #[test]
+    fn shutdown_and_resume() {
+        let mut domains = DomainList::new();
+
+        domains.introduce(1, 0x1000, 5).unwrap();
+        assert_eq!(domains.shutdown(1).unwrap(), true);
+        assert_eq!(domains.get(1).map(|domain| domain.shutdown), Some(true));
+        assert_eq!(domains.shutdown(1).unwrap(), false);
+
+        // a shut down domain can still be introduced again
+        assert_eq!(domains.introduce(1, 0x1000, 5).unwrap(), false);
+
+        domains.resume(1).unwrap();
+        assert_eq!(domains.get(1).map(|domain| domain.shutdown), Some(false));
+
+        match domains.resume(2) {
+            Err(Error::ENOENT(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "resumed a domain that was not introduced"),
+        }
+    }
+}
@@ -84,4 +84,28 @@ impl error::Error for Error {
     }
 }
 
+impl Error {
+    /// Turn an error name received in a `XS_ERROR` reply back into an `Error`.
+    pub fn from_wire(name: &str) -> Error {
+        let msg = String::from("returned by xenstored");
+        match name {
+            wire::XSE_EINVAL => Error::EINVAL(msg),
+            wire::XSE_EACCES => Error::EACCES(msg),
+            wire::XSE_EEXIST => Error::EEXIST(msg),
+            wire::XSE_EISDIR => Error::EISDIR(msg),
+            wire::XSE_ENOENT => Error::ENOENT(msg),
+            wire::XSE_ENOMEM => Error::ENOMEM(msg),
+            wire::XSE_ENOSPC => Error::ENOSPC(msg),
+            wire::XSE_ENOTEMPTY => Error::ENOTEMPTY(msg),
+            wire::XSE_ENOSYS => Error::ENOSYS(msg),
+            wire::XSE_EROFS => Error::EROFS(msg),
+            wire::XSE_EBUSY => Error::EBUSY(msg),
+            wire::XSE_EAGAIN => Error::EAGAIN(msg),
+            wire::XSE_EISCONN => Error::EISCONN(msg),
+            wire::XSE_E2BIG => Error::E2BIG(msg),
+            _ => Error::EIO(format!("unknown error {:?} returned by xenstored", name)),
+        }
+    }
+}
+
 pub type Result<T> = result::Result<T, Error>;
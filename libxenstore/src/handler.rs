@@ -0,0 +1,533 @@
+/**
+    xenstore-rs provides a Rust based xenstore implementation.
+    Copyright (C) 2016 Star Lab Corp.
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License along
+    with this program; if not, see <http://www.gnu.org/licenses/>.
+**/
+
+// Turning requests into responses and watch events, whichever transport
+// they arrived over.
+
+use std::io;
+use std::sync::{Arc, Mutex, RwLock};
+use super::connection::ConnId;
+use super::message;
+use super::message::egress::{Egress, WatchEvent};
+use super::system::{ReadView, System};
+use super::transport::Transport;
+use super::wire;
+
+/// The `Handler` type.
+///
+/// Processes requests against the `System` and hands back what should be
+/// sent to each connection, leaving how it gets there to the transport.
+/// Requests that only read the store are answered from the `System`'s
+/// `ReadView` instead, so they don't queue up behind a slow writer.
+#[derive(Clone)]
+pub struct Handler {
+    system: Arc<Mutex<System>>,
+    view: Arc<RwLock<ReadView>>,
+}
+
+impl Handler {
+    pub fn new(system: Arc<Mutex<System>>) -> Handler {
+        let view = {
+            // whatever was set up before we started serving has to be seen
+            // by the reads answered from the view too
+            let sys = system.lock().unwrap();
+            sys.publish();
+            sys.view()
+        };
+        Handler {
+            system: system,
+            view: view,
+        }
+    }
+
+    pub fn system(&self) -> &Arc<Mutex<System>> {
+        &self.system
+    }
+
+    /// Start queueing watch events for `conn`.
+    pub fn open(&self, conn: ConnId) {
+        self.system.lock().unwrap().open_outbox(conn);
+    }
+
+    /// Forget everything belonging to `conn` once its transport has gone.
+    pub fn close(&self, conn: ConnId) {
+        let mut sys = self.system.lock().unwrap();
+        sys.connection_closed(conn);
+        sys.publish();
+    }
+
+    /// Process a single request, passing the encoded response to `reply`
+    /// before handing any watch events it fired to the connections that
+    /// own them.
+    pub fn process<F, R>(&self, conn: ConnId, req: (wire::Header, wire::Body), reply: F) -> R
+        where F: FnOnce((wire::Header, wire::Body)) -> R
+    {
+        {
+            let view = self.view.read().unwrap();
+            if view.answers(&req.0) {
+                return reply(message::handle_read(&view, conn, &req.0, req.1));
+            }
+        }
+
+        let mut sys = self.system.lock().unwrap();
+
+        // parse the incoming request (header, body) and process it
+        let rsp = message::handle(&mut sys, conn, &req.0, req.1);
+
+        // let the reads that follow see whatever it changed
+        sys.publish();
+
+        // pass on the response encoded as (header, body)
+        let res = reply(rsp);
+
+        // only now, with the lock still held, let the watchers hear of it
+        sys.dispatch_fired();
+
+        res
+    }
+
+    /// Process a run of requests that only change the store as one batch,
+    /// passing each encoded response to `reply` before handing the watch
+    /// events they fired to the connections that own them.
+    pub fn process_batch<F>(&self,
+                            conn: ConnId,
+                            reqs: Vec<(wire::Header, wire::Body)>,
+                            mut reply: F)
+                            -> io::Result<()>
+        where F: FnMut((wire::Header, wire::Body)) -> io::Result<()>
+    {
+        let mut sys = self.system.lock().unwrap();
+
+        let ops = reqs.into_iter().map(|req| {
+            move |sys: &mut System| message::handle(sys, conn, &req.0, req.1)
+        });
+        let rsps = try!(sys.do_store_batch(ops)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+        sys.publish();
+
+        for rsp in rsps {
+            try!(reply(rsp));
+        }
+
+        sys.dispatch_fired();
+        Ok(())
+    }
+
+    /// Take the next watch event queued for `conn`, encoded for sending.
+    pub fn next_event(&self, conn: ConnId) -> io::Result<Option<(wire::Header, wire::Body)>> {
+        let mut sys = self.system.lock().unwrap();
+        match sys.do_outbox_mut(conn, |outbox| outbox.pop()).unwrap_or(Ok(None)) {
+            Ok(event) => Ok(event.map(|event| WatchEvent::new(event).encode())),
+            Err(e) => Err(io::Error::new(io::ErrorKind::Other, e)),
+        }
+    }
+
+    /// Answer every request waiting on `transport`, then send the watch
+    /// events queued for `conn`, whichever connection fired them. Runs of
+    /// requests that only change the store are processed as one batch.
+    pub fn service<T: Transport>(&self, conn: ConnId, transport: &mut T) -> io::Result<()> {
+        loop {
+            let mut burst = Vec::new();
+            let mut next = None;
+            while let Some(req) = try!(transport.recv()) {
+                if message::batches(&req.0) {
+                    burst.push(req);
+                } else {
+                    next = Some(req);
+                    break;
+                }
+            }
+
+            match burst.len() {
+                0 => (),
+                1 => try!(self.process(conn, burst.remove(0), |rsp| transport.send(rsp))),
+                _ => try!(self.process_batch(conn, burst, |rsp| transport.send(rsp))),
+            }
+
+            match next {
+                Some(req) => try!(self.process(conn, req, |rsp| transport.send(rsp))),
+                None => break,
+            }
+        }
+
+        while let Some(event) = try!(self.next_event(conn)) {
+            try!(transport.send(event));
+        }
+
+        transport.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate quickcheck;
+
+    use self::quickcheck::quickcheck;
+    use std::collections::VecDeque;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use super::*;
+    use super::super::domain::DomainList;
+    use super::super::store::{Store, DOM0_DOMAIN_ID};
+    use super::super::system::System;
+    use super::super::transaction::TransactionList;
+    use super::super::transport::Transport;
+    use super::super::watch::WatchList;
+    use super::super::wire;
+
+    /// Hands over requests prepared by the test and keeps what is sent back
+    struct Loopback {
+        requests: VecDeque<(wire::Header, wire::Body)>,
+        sent: Vec<(wire::Header, wire::Body)>,
+        flushed: usize,
+    }
+
+    impl Transport for Loopback {
+        fn recv(&mut self) -> io::Result<Option<(wire::Header, wire::Body)>> {
+            Ok(self.requests.pop_front())
+        }
+
+        fn send(&mut self, msg: (wire::Header, wire::Body)) -> io::Result<()> {
+            self.sent.push(msg);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushed = self.sent.len();
+            Ok(())
+        }
+    }
+
+    fn request(msg_type: u32, body: &[u8]) -> (wire::Header, wire::Body) {
+        (wire::Header {
+             msg_type: msg_type,
+             req_id: 0,
+             tx_id: 0,
+             len: body.len() as u32,
+         },
+         wire::Body::from(vec![body.to_owned()]))
+    }
+
+    fn handler() -> Handler {
+        let system = System::new(Store::new(),
+                                 WatchList::new(),
+                                 TransactionList::new(),
+                                 DomainList::new());
+        Handler::new(Arc::new(Mutex::new(system)))
+    }
+
+    /// Send `body` as a XS_SET_PERMS request from dom0 and give back the
+    /// type of the reply and its body
+    fn set_perms(body: &[u8]) -> (u32, Vec<u8>) {
+        let handler = handler();
+        let conn = handler.system().lock().unwrap().new_connection(DOM0_DOMAIN_ID);
+        handler.process(conn,
+                        request(wire::XS_SET_PERMS, body),
+                        |reply| (reply.0.msg_type, reply.1.to_vec()))
+    }
+
+    #[test]
+    fn view_sees_setup() {
+        let mut system = System::new(Store::new(),
+                                     WatchList::new(),
+                                     TransactionList::new(),
+                                     DomainList::new());
+        system.set_domain_ids(true).unwrap();
+        let handler = Handler::new(Arc::new(Mutex::new(system)));
+        let conn = handler.system().lock().unwrap().new_connection(DOM0_DOMAIN_ID);
+
+        // the feature written before the handler existed is read back
+        // without waiting for another change to the store
+        let path = b"/tool/xenstored/features/domain-ids\0";
+        assert_eq!(handler.process(conn,
+                                   request(wire::XS_READ, path),
+                                   |reply| (reply.0.msg_type, reply.1.to_vec())),
+                   (wire::XS_READ, b"1".to_vec()));
+    }
+
+    #[test]
+    fn malformed_set_perms() {
+        for body in &[&b"/\0"[..],
+                      b"/\0x1\0",
+                      b"/\0r\0",
+                      b"/\0r1x\0",
+                      b"/\0r99999999999\0",
+                      b"/\0\xc3\0",
+                      b"/\0\xff1\0"] {
+            assert_eq!(set_perms(body), (wire::XS_ERROR, b"EINVAL\0".to_vec()));
+        }
+
+        assert_eq!(set_perms(b"/\0n0\0r1\0"), (wire::XS_SET_PERMS, b"OK\0".to_vec()));
+    }
+
+    #[test]
+    fn set_perms_fuzz() {
+        fn prop(perms: Vec<u8>) -> bool {
+            // whatever follows the path gets an answer rather than a panic
+            let mut body = b"/\0".to_vec();
+            body.extend(perms);
+            match set_perms(&body) {
+                (wire::XS_SET_PERMS, _) => true,
+                (wire::XS_ERROR, ref err) => err == b"EINVAL\0",
+                _ => false,
+            }
+        }
+
+        quickcheck(prop as fn(Vec<u8>) -> bool);
+    }
+
+    #[test]
+    fn empty_write() {
+        let handler = handler();
+        let conn = handler.system().lock().unwrap().new_connection(DOM0_DOMAIN_ID);
+        let reply = |reply: (wire::Header, wire::Body)| (reply.0.msg_type, reply.1.to_vec());
+
+        // with or without the NUL after the path, there's no value to write
+        for body in &[&b"/a\0"[..], b"/b"] {
+            assert_eq!(handler.process(conn, request(wire::XS_WRITE, body), &reply),
+                       (wire::XS_WRITE, b"OK\0".to_vec()));
+        }
+
+        for path in &[&b"/a\0"[..], b"/b\0"] {
+            assert_eq!(handler.process(conn, request(wire::XS_READ, path), &reply),
+                       (wire::XS_READ, vec![]));
+        }
+
+        // an empty path is still no path at all
+        assert_eq!(handler.process(conn, request(wire::XS_WRITE, b""), &reply),
+                   (wire::XS_ERROR, b"EINVAL\0".to_vec()));
+    }
+
+    #[test]
+    fn watch_fields() {
+        let handler = handler();
+        let conn = handler.system().lock().unwrap().new_connection(DOM0_DOMAIN_ID);
+        let reply = |reply: (wire::Header, wire::Body)| reply.0.msg_type;
+
+        // a path and a token, with or without the NUL after the token,
+        // which may be empty
+        for body in &[&b"/a\0token\0"[..], b"/b\0token", b"/c\0\0"] {
+            assert_eq!(handler.process(conn, request(wire::XS_WATCH, body), &reply),
+                       wire::XS_WATCH);
+            assert_eq!(handler.process(conn, request(wire::XS_UNWATCH, body), &reply),
+                       wire::XS_UNWATCH);
+        }
+
+        for body in &[&b""[..], b"/a", b"/a\0", b"\0token\0", b"/a\0token\0extra\0"] {
+            assert_eq!(handler.process(conn, request(wire::XS_WATCH, body), &reply),
+                       wire::XS_ERROR);
+            assert_eq!(handler.process(conn, request(wire::XS_UNWATCH, body), &reply),
+                       wire::XS_ERROR);
+        }
+    }
+
+    #[test]
+    fn transaction_end_arguments() {
+        let handler = handler();
+        let conn = handler.system().lock().unwrap().new_connection(DOM0_DOMAIN_ID);
+        let reply = |reply: (wire::Header, wire::Body)| (reply.0.msg_type, reply.1.to_vec());
+        let einval = (wire::XS_ERROR, b"EINVAL\0".to_vec());
+
+        // there's no transaction to end outside of one
+        assert_eq!(handler.process(conn, request(wire::XS_TRANSACTION_END, b"T\0"), &reply),
+                   einval);
+
+        let tx_id = handler.process(conn, request(wire::XS_TRANSACTION_START, b"\0"), |reply| {
+            let tx_id = reply.1.to_vec();
+            String::from_utf8(tx_id[..tx_id.len() - 1].to_vec()).unwrap().parse().unwrap()
+        });
+        let end = |value: &[u8]| {
+            let (header, body) = request(wire::XS_TRANSACTION_END, value);
+            (wire::Header { tx_id: tx_id, ..header }, body)
+        };
+
+        // only T or F will do, and anything else leaves the transaction open
+        for value in &[&b"\0"[..], b"X\0", b"true\0", b"T\0F\0"] {
+            assert_eq!(handler.process(conn, end(value), &reply), einval);
+        }
+        assert_eq!(handler.process(conn, end(b"F\0"), &reply),
+                   (wire::XS_TRANSACTION_END, b"OK\0".to_vec()));
+    }
+
+    #[test]
+    fn unknown_transactions() {
+        let handler = handler();
+        let conn = handler.system().lock().unwrap().new_connection(DOM0_DOMAIN_ID);
+        let reply = |reply: (wire::Header, wire::Body)| (reply.0.msg_type, reply.1.to_vec());
+        let enoent = (wire::XS_ERROR, b"ENOENT\0".to_vec());
+
+        let ended = handler.process(conn, request(wire::XS_TRANSACTION_START, b"\0"), |reply| {
+            let tx_id = reply.1.to_vec();
+            String::from_utf8(tx_id[..tx_id.len() - 1].to_vec()).unwrap().parse().unwrap()
+        });
+        let (header, body) = request(wire::XS_TRANSACTION_END, b"F\0");
+        assert_eq!(handler.process(conn, (wire::Header { tx_id: ended, ..header }, body), &reply),
+                   (wire::XS_TRANSACTION_END, b"OK\0".to_vec()));
+
+        // every request naming a transaction that was never started, or has
+        // already ended, can't find it, as with C xenstored
+        let requests = vec![(wire::XS_READ, &b"/a\0"[..]),
+                            (wire::XS_WRITE, b"/a\0value"),
+                            (wire::XS_MKDIR, b"/a\0"),
+                            (wire::XS_RM, b"/a\0"),
+                            (wire::XS_DIRECTORY, b"/\0"),
+                            (wire::XS_GET_PERMS, b"/\0"),
+                            (wire::XS_SET_PERMS, b"/\0n0\0"),
+                            (wire::XS_WATCH, b"/a\0token\0"),
+                            (wire::XS_UNWATCH, b"/a\0token\0"),
+                            (wire::XS_TRANSACTION_END, b"T\0")];
+        for tx_id in &[ended, ended + 100] {
+            for &(msg_type, body) in &requests {
+                let (header, body) = request(msg_type, body);
+                let req = (wire::Header { tx_id: *tx_id, ..header }, body);
+                assert_eq!((msg_type, handler.process(conn, req, &reply)),
+                           (msg_type, enoent.clone()));
+            }
+        }
+    }
+
+    #[test]
+    fn watch_in_transaction() {
+        let handler = handler();
+        let conn = handler.system().lock().unwrap().new_connection(DOM0_DOMAIN_ID);
+        handler.open(conn);
+        let reply = |reply: (wire::Header, wire::Body)| (reply.0.msg_type, reply.1.to_vec());
+        let events = || {
+            let mut paths = Vec::new();
+            while let Some(event) = handler.next_event(conn).unwrap() {
+                paths.push(event.1.to_vec());
+            }
+            paths
+        };
+
+        let tx_id = handler.process(conn, request(wire::XS_TRANSACTION_START, b"\0"), |reply| {
+            let tx_id = reply.1.to_vec();
+            String::from_utf8(tx_id[..tx_id.len() - 1].to_vec()).unwrap().parse().unwrap()
+        });
+        let in_tx = |msg_type: u32, body: &[u8]| {
+            let (header, body) = request(msg_type, body);
+            (wire::Header { tx_id: tx_id, ..header }, body)
+        };
+
+        // the watch takes effect straight away and fires once as usual
+        assert_eq!(handler.process(conn, in_tx(wire::XS_WATCH, b"/a\0token\0"), &reply),
+                   (wire::XS_WATCH, b"OK\0".to_vec()));
+        assert_eq!(events(), vec![b"/a\0token\0".to_vec()]);
+
+        // but not for writes that are still in the transaction
+        assert_eq!(handler.process(conn, in_tx(wire::XS_WRITE, b"/a\0value"), &reply),
+                   (wire::XS_WRITE, b"OK\0".to_vec()));
+        assert_eq!(events(), Vec::<Vec<u8>>::new());
+
+        // only once they are applied to the store
+        assert_eq!(handler.process(conn, in_tx(wire::XS_TRANSACTION_END, b"T\0"), &reply),
+                   (wire::XS_TRANSACTION_END, b"OK\0".to_vec()));
+        assert_eq!(events(), vec![b"/a\0token\0".to_vec()]);
+
+        // and a transaction that isn't open is refused
+        assert_eq!(handler.process(conn, in_tx(wire::XS_WATCH, b"/b\0token\0"), &reply),
+                   (wire::XS_ERROR, b"ENOENT\0".to_vec()));
+        assert_eq!(handler.process(conn, in_tx(wire::XS_UNWATCH, b"/a\0token\0"), &reply),
+                   (wire::XS_ERROR, b"ENOENT\0".to_vec()));
+        assert_eq!(handler.process(conn, request(wire::XS_UNWATCH, b"/a\0token\0"), &reply),
+                   (wire::XS_UNWATCH, b"OK\0".to_vec()));
+
+        handler.close(conn);
+    }
+
+    #[test]
+    fn service_transport() {
+        let handler = handler();
+        let conn = handler.system().lock().unwrap().new_connection(DOM0_DOMAIN_ID);
+        handler.open(conn);
+
+        let mut transport = Loopback {
+            requests: vec![request(wire::XS_WATCH, b"/a\0token\0"),
+                           request(wire::XS_WRITE, b"/a\0value")]
+                .into_iter()
+                .collect(),
+            sent: Vec::new(),
+            flushed: 0,
+        };
+        handler.service(conn, &mut transport).unwrap();
+
+        // the responses go out in order, followed by the events they fired
+        let sent = transport.sent.iter().map(|msg| msg.0.msg_type).collect::<Vec<_>>();
+        assert_eq!(sent,
+                   vec![wire::XS_WATCH,
+                        wire::XS_WRITE,
+                        wire::XS_WATCH_EVENT,
+                        wire::XS_WATCH_EVENT]);
+        assert_eq!(transport.flushed, sent.len());
+
+        handler.close(conn);
+    }
+
+    #[test]
+    fn service_batches_writes() {
+        let handler = handler();
+        let conn = handler.system().lock().unwrap().new_connection(DOM0_DOMAIN_ID);
+        handler.open(conn);
+        let generation = handler.system().lock().unwrap().generation();
+
+        let mut transport = Loopback {
+            requests: vec![request(wire::XS_WATCH, b"/a\0token\0"),
+                           request(wire::XS_WRITE, b"/a\0value"),
+                           request(wire::XS_MKDIR, b"/a/b\0"),
+                           request(wire::XS_WRITE, b"/a/b/c\0value"),
+                           request(wire::XS_READ, b"/a/b/c\0")]
+                .into_iter()
+                .collect(),
+            sent: Vec::new(),
+            flushed: 0,
+        };
+        handler.service(conn, &mut transport).unwrap();
+
+        // the writes share a generation and fire the watch once between them
+        assert_eq!(handler.system().lock().unwrap().generation(), generation + 1);
+        let sent = transport.sent.iter().map(|msg| msg.0.msg_type).collect::<Vec<_>>();
+        assert_eq!(sent,
+                   vec![wire::XS_WATCH,
+                        wire::XS_WRITE,
+                        wire::XS_MKDIR,
+                        wire::XS_WRITE,
+                        wire::XS_READ,
+                        wire::XS_WATCH_EVENT,
+                        wire::XS_WATCH_EVENT]);
+        assert_eq!(transport.sent[4].1.to_vec(), b"value".to_vec());
+
+        handler.close(conn);
+    }
+
+    #[test]
+    fn reads_skip_the_system() {
+        let handler = handler();
+        let conn = handler.system().lock().unwrap().new_connection(DOM0_DOMAIN_ID);
+        let write = handler.process(conn, request(wire::XS_WRITE, b"/a\0value"), |rsp| rsp);
+        assert_eq!(write.0.msg_type, wire::XS_WRITE);
+
+        // with a writer holding on to the system, reads are still answered
+        let _writer = handler.system().lock().unwrap();
+        let read = handler.process(conn, request(wire::XS_READ, b"/a\0"), |rsp| rsp);
+        assert_eq!(read.0.msg_type, wire::XS_READ);
+        assert_eq!(read.1.to_vec(), b"value".to_vec());
+
+        let read = handler.process(conn, request(wire::XS_READ, b"/b\0"), |rsp| rsp);
+        assert_eq!(read.0.msg_type, wire::XS_ERROR);
+    }
+}
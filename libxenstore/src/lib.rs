@@ -18,20 +18,32 @@
 
 extern crate bytes;
 extern crate futures;
+extern crate libc;
 #[macro_use]
 extern crate log;
 extern crate rand;
 extern crate tokio_io;
-extern crate tokio_proto;
-extern crate tokio_service;
 
+pub mod authz;
+pub mod client;
+pub mod config;
 pub mod connection;
+pub mod domain;
 pub mod error;
+pub mod handler;
+pub mod logger;
 pub mod message;
+pub mod metrics;
+pub mod migration;
 pub mod path;
+pub mod persistence;
+pub mod quota;
 pub mod server;
 pub mod store;
 pub mod system;
+pub mod tracelog;
 pub mod transaction;
+pub mod transport;
+pub mod tree;
 pub mod watch;
 pub mod wire;
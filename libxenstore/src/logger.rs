@@ -0,0 +1,147 @@
+/**
+    xenstore-rs provides a Rust based xenstore implementation.
+    Copyright (C) 2016 Star Lab Corp.
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License along
+    with this program; if not, see <http://www.gnu.org/licenses/>.
+**/
+
+// A logger whose level and destination can be changed while the daemon runs.
+
+use log::{self, LogLevelFilter, LogMetadata, LogRecord, MaxLogLevelFilter, SetLoggerError};
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+type Output = Arc<Mutex<Box<Write + Send>>>;
+
+/// Turn the number of times `-v` was given into a level, starting at errors
+pub fn verbosity(count: usize) -> LogLevelFilter {
+    match count {
+        0 => LogLevelFilter::Error,
+        1 => LogLevelFilter::Warn,
+        2 => LogLevelFilter::Info,
+        3 => LogLevelFilter::Debug,
+        _ => LogLevelFilter::Trace,
+    }
+}
+
+struct Logger {
+    // only messages from these modules and those below them are logged
+    modules: Vec<String>,
+    output: Output,
+}
+
+impl Logger {
+    fn includes_module(&self, module_path: &str) -> bool {
+        self.modules.iter().any(|module| {
+            module_path == module || module_path.starts_with(&format!("{}::", module))
+        })
+    }
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        metadata.level() <= log::max_log_level()
+    }
+
+    fn log(&self, record: &LogRecord) {
+        if self.enabled(record.metadata()) &&
+           self.includes_module(record.location().module_path()) {
+            let mut output = self.output.lock().unwrap();
+            let _ = writeln!(output, "{} - {}", record.level(), record.args());
+        }
+    }
+}
+
+/// Changes the level and destination of the installed logger.
+pub struct LogHandle {
+    max_level: MaxLogLevelFilter,
+    output: Output,
+}
+
+impl LogHandle {
+    pub fn level(&self) -> LogLevelFilter {
+        self.max_level.get()
+    }
+
+    pub fn set_level(&self, level: LogLevelFilter) {
+        self.max_level.set(level);
+    }
+
+    /// Append log messages to the file at `path`, creating it if needed.
+    pub fn set_file(&self, path: &Path) -> io::Result<()> {
+        let file = try!(OpenOptions::new().create(true).append(true).open(path));
+        *self.output.lock().unwrap() = Box::new(file);
+        Ok(())
+    }
+
+    /// Go back to writing log messages to stderr.
+    pub fn set_stderr(&self) {
+        *self.output.lock().unwrap() = Box::new(io::stderr());
+    }
+}
+
+/// Install a logger for `modules` that writes messages at `level` or
+/// more severe to stderr, returning a handle to change it with later.
+///
+/// # Errors
+///
+/// * `SetLoggerError` if a logger has already been installed
+pub fn init(modules: &[&str], level: LogLevelFilter) -> Result<LogHandle, SetLoggerError> {
+    let output: Output = Arc::new(Mutex::new(Box::new(io::stderr())));
+    let mut handle = None;
+
+    try!(log::set_logger(|max_level| {
+        max_level.set(level);
+        handle = Some(LogHandle {
+            max_level: max_level,
+            output: output.clone(),
+        });
+
+        Box::new(Logger {
+            modules: modules.iter().map(|module| module.to_string()).collect(),
+            output: output.clone(),
+        })
+    }));
+
+    Ok(handle.unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn module_filter() {
+        let logger = Logger {
+            modules: vec![String::from("libxenstore")],
+            output: Arc::new(Mutex::new(Box::new(io::sink()))),
+        };
+
+        assert!(logger.includes_module("libxenstore"));
+        assert!(logger.includes_module("libxenstore::store"));
+        assert!(!logger.includes_module("libxenstorex"));
+        assert!(!logger.includes_module("tokio_core::reactor"));
+    }
+
+    #[test]
+    fn verbosity_levels() {
+        assert_eq!(verbosity(0), LogLevelFilter::Error);
+        assert_eq!(verbosity(2), LogLevelFilter::Info);
+        assert_eq!(verbosity(9), LogLevelFilter::Trace);
+    }
+}
@@ -0,0 +1,474 @@
+/**
+    xenstore-rs provides a Rust based xenstore implementation.
+    Copyright (C) 2016 Star Lab Corp.
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License along
+    with this program; if not, see <http://www.gnu.org/licenses/>.
+**/
+
+// The subcommands of XS_CONTROL, as sent by xenstore-control.
+
+use error::{Error, Result};
+use log::LogLevelFilter;
+use std::path::{Path, PathBuf};
+use store;
+use system::System;
+use wire;
+use super::{writable, Metadata};
+
+/// A subcommand of XS_CONTROL
+pub struct Command {
+    /// What xenstore-control calls it
+    pub name: &'static str,
+    /// The arguments it takes, as listed by `help`
+    pub args: &'static str,
+    run: fn(&mut System, &[String]) -> Result<String>,
+}
+
+/// Every subcommand we know about, in the order `help` lists them
+pub static COMMANDS: &'static [Command] = &[Command {
+                                                  name: "check",
+                                                  args: "",
+                                                  run: check,
+                                              },
+                                              Command {
+                                                  name: "diff",
+                                                  args: "<generation> [<generation>]",
+                                                  run: diff,
+                                              },
+                                              Command {
+                                                  name: "generation",
+                                                  args: "",
+                                                  run: generation,
+                                              },
+                                              Command {
+                                                  name: "help",
+                                                  args: "",
+                                                  run: help,
+                                              },
+                                              Command {
+                                                  name: "live-update",
+                                                  args: "",
+                                                  run: live_update,
+                                              },
+                                              Command {
+                                                  name: "log",
+                                                  args: "on|off",
+                                                  run: log,
+                                              },
+                                              Command {
+                                                  name: "logfile",
+                                                  args: "<file>|-",
+                                                  run: logfile,
+                                              },
+                                              Command {
+                                                  name: "loglevel",
+                                                  args: "[off|error|warn|info|debug|trace]",
+                                                  run: loglevel,
+                                              },
+                                              Command {
+                                                  name: "memreport",
+                                                  args: "",
+                                                  run: memreport,
+                                              },
+                                              Command {
+                                                  name: "metrics",
+                                                  args: "",
+                                                  run: metrics,
+                                              },
+                                              Command {
+                                                  name: "shutdown",
+                                                  args: "<domid>",
+                                                  run: shutdown,
+                                              },
+                                              Command {
+                                                  name: "snapshot",
+                                                  args: "",
+                                                  run: snapshot,
+                                              },
+                                              Command {
+                                                  name: "tracelog",
+                                                  args: "on [<file>]|off",
+                                                  run: tracelog,
+                                              }];
+
+/// Look up a subcommand by name
+pub fn find(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|cmd| cmd.name == name)
+}
+
+/// Run the subcommand named by the first of `args` with the rest of them,
+/// returning the text to reply with.
+///
+/// # Errors
+///
+/// * `Error::EACCES` if the request didn't come from a writable dom0
+///   connection
+/// * `Error::EINVAL` if there is no such subcommand or its arguments are bad
+pub fn dispatch(sys: &mut System, md: &Metadata, args: &[String]) -> Result<String> {
+    if md.conn.dom_id != store::DOM0_DOMAIN_ID {
+        return Err(Error::EACCES(format!("domain {} may not use control commands",
+                                         md.conn.dom_id)));
+    }
+    try!(writable(md));
+
+    let (name, args) = match args.split_first() {
+        Some((name, args)) => (name, args),
+        None => return Err(Error::EINVAL(format!("no control command given"))),
+    };
+
+    match find(name) {
+        Some(cmd) => (cmd.run)(sys, args),
+        None => Err(Error::EINVAL(format!("unknown control command: {}", name))),
+    }
+}
+
+/// List the subcommands and their arguments, one per line
+fn help(_sys: &mut System, _args: &[String]) -> Result<String> {
+    let lines = COMMANDS.iter()
+        .map(|cmd| if cmd.args.is_empty() {
+                 cmd.name.to_string()
+             } else {
+                 format!("{} {}", cmd.name, cmd.args)
+             })
+        .collect::<Vec<_>>();
+
+    Ok(lines.join("\n"))
+}
+
+/// Check that the nodes in the store hang together
+fn check(sys: &mut System, _args: &[String]) -> Result<String> {
+    try!(sys.do_all(|store, _, _, _| store.check_invariants()));
+    Ok(String::from("OK"))
+}
+
+/// Reply with the number of changes applied to the store so far
+fn generation(sys: &mut System, _args: &[String]) -> Result<String> {
+    Ok(sys.generation().to_string())
+}
+
+/// Turn logging of every request and reply on or off
+fn log(sys: &mut System, args: &[String]) -> Result<String> {
+    let trace = match args.first().map(|arg| arg.as_str()) {
+        Some("on") => true,
+        Some("off") => false,
+        _ => return Err(Error::EINVAL(format!("log needs to be turned on or off"))),
+    };
+
+    sys.set_trace(trace);
+    Ok(String::from("OK"))
+}
+
+/// Reply with the current log level, or change it to the one in `args`
+fn loglevel(sys: &mut System, args: &[String]) -> Result<String> {
+    let handle = match sys.log_handle() {
+        Some(handle) => handle,
+        None => return Err(Error::ENOSYS(format!("the log can't be changed"))),
+    };
+
+    match args.first() {
+        Some(arg) => {
+            let level = try!(arg.parse::<LogLevelFilter>()
+                .map_err(|_| Error::EINVAL(format!("bad log level: {}", arg))));
+            handle.set_level(level);
+            Ok(String::from("OK"))
+        }
+        None => Ok(handle.level().to_string().to_lowercase()),
+    }
+}
+
+/// Send log messages to the file named in `args`, or back to stderr for "-"
+fn logfile(sys: &mut System, args: &[String]) -> Result<String> {
+    let handle = match sys.log_handle() {
+        Some(handle) => handle,
+        None => return Err(Error::ENOSYS(format!("the log can't be changed"))),
+    };
+
+    match args.first().map(|arg| arg.as_str()) {
+        Some("-") => handle.set_stderr(),
+        Some(file) => {
+            try!(handle.set_file(Path::new(file))
+                .map_err(|e| Error::EIO(format!("can't log to {}: {}", file, e))))
+        }
+        None => return Err(Error::EINVAL(format!("logfile needs a file to log to"))),
+    }
+
+    Ok(String::from("OK"))
+}
+
+/// Turn the access log on, optionally at the file in `args`, or off
+fn tracelog(sys: &mut System, args: &[String]) -> Result<String> {
+    match args.first().map(|arg| arg.as_str()) {
+        Some("on") => {
+            let path = args.get(1).map(PathBuf::from);
+            try!(sys.start_tracelog(path)
+                .map_err(|e| Error::EIO(format!("can't open the access log: {}", e))));
+        }
+        Some("off") => sys.stop_tracelog(),
+        _ => return Err(Error::EINVAL(format!("tracelog needs to be turned on or off"))),
+    }
+
+    Ok(String::from("OK"))
+}
+
+/// List what each domain is using, one domain per line
+fn memreport(sys: &mut System, _args: &[String]) -> Result<String> {
+    let lines = sys.domain_usage()
+        .iter()
+        .map(|(dom_id, usage)| {
+                 format!("domain {}: nodes {}, bytes {}, watches {}, transactions {}, \
+                          queued events {}",
+                         dom_id,
+                         usage.store.entries,
+                         usage.store.bytes,
+                         usage.watches,
+                         usage.transactions,
+                         usage.queued_events)
+             })
+        .collect::<Vec<_>>();
+    let value = lines.join("\n");
+
+    // the reply has to fit in a single message along with its NUL
+    if value.len() >= wire::BODY_SIZE {
+        return Err(Error::E2BIG(format!("{} domains don't fit in a reply", lines.len())));
+    }
+
+    Ok(value)
+}
+
+/// Report counts of requests and errors along with how busy the daemon is
+fn metrics(sys: &mut System, _args: &[String]) -> Result<String> {
+    let value = sys.metrics();
+
+    // the reply has to fit in a single message along with its NUL
+    if value.len() >= wire::BODY_SIZE {
+        return Err(Error::E2BIG(format!("the metrics don't fit in a reply")));
+    }
+
+    Ok(value)
+}
+
+/// Ask for the daemon to hand over its state once this reply is sent
+fn live_update(sys: &mut System, _args: &[String]) -> Result<String> {
+    sys.request_live_update();
+    Ok(String::from("OK"))
+}
+
+/// Mark the domain in `args` as shut down, firing @releaseDomain the first
+/// time so the toolstack gets to clean up after it
+fn shutdown(sys: &mut System, args: &[String]) -> Result<String> {
+    let dom_id = match args.first() {
+        Some(arg) => {
+            try!(arg.parse::<wire::DomainId>()
+                .map_err(|_| Error::EINVAL(format!("bad domain id: {}", arg))))
+        }
+        None => return Err(Error::EINVAL(format!("shutdown needs a domain id"))),
+    };
+
+    if try!(sys.do_domain_mut(|domains, _| domains.shutdown(dom_id))) {
+        let change = store::AppliedChange::ReleaseDomain(dom_id);
+        let watch_events = sys.do_watch_mut(|watch_list| watch_list.fire_single(&change));
+        sys.fire(watch_events);
+    }
+    Ok(String::from("OK"))
+}
+
+/// Keep a snapshot of the store, replying with its generation
+fn snapshot(sys: &mut System, _args: &[String]) -> Result<String> {
+    Ok(sys.snapshot().to_string())
+}
+
+/// List what changed since the snapshot taken at the generation in `args`,
+/// or between the snapshots at two generations, one path per line
+fn diff(sys: &mut System, args: &[String]) -> Result<String> {
+    let generation = |arg: &String| {
+        arg.parse::<u64>().map_err(|_| Error::EINVAL(format!("bad generation: {}", arg)))
+    };
+
+    let from = match args.first() {
+        Some(arg) => try!(generation(arg)),
+        None => return Err(Error::EINVAL(format!("diff needs the generation of a snapshot"))),
+    };
+    let to = match args.get(1) {
+        Some(arg) => Some(try!(generation(arg))),
+        None => None,
+    };
+
+    let differences = try!(sys.diff(from, to));
+    let value = differences.iter().map(|d| d.to_string()).collect::<Vec<_>>().join("\n");
+
+    // the reply has to fit in a single message along with its NUL
+    if value.len() >= wire::BODY_SIZE {
+        return Err(Error::E2BIG(format!("{} differences don't fit in a reply",
+                                        differences.len())));
+    }
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod test {
+    extern crate mio;
+
+    use self::mio::Token;
+    use std::env;
+    use std::fs;
+    use super::super::super::connection::ConnId;
+    use super::super::super::domain::DomainList;
+    use super::super::super::error::Error;
+    use super::super::super::store::{self, Store};
+    use super::super::super::system::System;
+    use super::super::super::transaction::TransactionList;
+    use super::super::super::watch::{WPath, WToken, WatchList};
+    use super::super::Metadata;
+    use super::*;
+
+    fn system() -> System {
+        System::new(Store::new(),
+                    WatchList::new(),
+                    TransactionList::new(),
+                    DomainList::new())
+    }
+
+    fn metadata(dom_id: u32) -> Metadata {
+        Metadata {
+            conn: ConnId::new(Token(0), dom_id),
+            req_id: 0,
+            tx_id: 0,
+        }
+    }
+
+    fn args(strs: &[&str]) -> Vec<String> {
+        strs.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn help_lists_commands() {
+        let mut sys = system();
+        let help = dispatch(&mut sys, &metadata(store::DOM0_DOMAIN_ID), &args(&["help"]))
+            .unwrap();
+        let lines = help.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines.len(), COMMANDS.len());
+        assert!(lines.contains(&"help"));
+        assert!(lines.contains(&"log on|off"));
+    }
+
+    #[test]
+    fn check_store() {
+        let mut sys = system();
+        let md = metadata(store::DOM0_DOMAIN_ID);
+
+        assert_eq!(dispatch(&mut sys, &md, &args(&["check"])).unwrap(), "OK");
+    }
+
+    #[test]
+    fn log_toggles_trace() {
+        let mut sys = system();
+        let md = metadata(store::DOM0_DOMAIN_ID);
+
+        dispatch(&mut sys, &md, &args(&["log", "on"])).unwrap();
+        assert!(sys.trace());
+        dispatch(&mut sys, &md, &args(&["log", "off"])).unwrap();
+        assert!(!sys.trace());
+
+        match dispatch(&mut sys, &md, &args(&["log"])) {
+            Err(Error::EINVAL(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "log accepted no argument"),
+        }
+    }
+
+    #[test]
+    fn tracelog_toggles() {
+        let mut sys = system();
+        let md = metadata(store::DOM0_DOMAIN_ID);
+        let file = env::temp_dir()
+            .join(format!("rxenstored-test-{}.log", ::rand::random::<u32>()));
+        let file_arg = file.to_str().unwrap();
+
+        dispatch(&mut sys, &md, &args(&["tracelog", "on", file_arg])).unwrap();
+        assert!(sys.tracelog_enabled());
+        dispatch(&mut sys, &md, &args(&["tracelog", "off"])).unwrap();
+        assert!(!sys.tracelog_enabled());
+
+        // turning it back on reuses the last file
+        dispatch(&mut sys, &md, &args(&["tracelog", "on"])).unwrap();
+        assert!(sys.tracelog_enabled());
+
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn log_unchangeable() {
+        let mut sys = system();
+
+        match dispatch(&mut sys, &metadata(store::DOM0_DOMAIN_ID), &args(&["loglevel"])) {
+            Err(Error::ENOSYS(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "reported the level of a log that isn't there"),
+        }
+    }
+
+    #[test]
+    fn shutdown_fires_release() {
+        let mut domains = DomainList::new();
+        domains.introduce(1, 0x1000, 5).unwrap();
+        let mut sys = System::new(Store::new(),
+                                  WatchList::new(),
+                                  TransactionList::new(),
+                                  domains);
+        let md = metadata(store::DOM0_DOMAIN_ID);
+        sys.do_watch_mut(|watch_list| {
+                             watch_list.watch(md.conn, WPath::ReleaseDomain, WToken::from("token"))
+                         })
+            .unwrap();
+
+        // only the first shutdown tells the watchers
+        assert_eq!(dispatch(&mut sys, &md, &args(&["shutdown", "1"])).unwrap(), "OK");
+        assert_eq!(sys.do_domain(|domains| domains.get(1).map(|domain| domain.shutdown)),
+                   Some(true));
+        assert_eq!(sys.fired().len(), 1);
+        dispatch(&mut sys, &md, &args(&["shutdown", "1"])).unwrap();
+        assert_eq!(sys.fired().len(), 1);
+
+        match dispatch(&mut sys, &md, &args(&["shutdown", "2"])) {
+            Err(Error::ENOENT(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "shut down a domain that was not introduced"),
+        }
+    }
+
+    #[test]
+    fn unknown_command() {
+        let mut sys = system();
+
+        match dispatch(&mut sys, &metadata(store::DOM0_DOMAIN_ID), &args(&["frobnicate"])) {
+            Err(Error::EINVAL(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "ran an unknown command"),
+        }
+    }
+
+    #[test]
+    fn guests_may_not_control() {
+        let mut sys = system();
+
+        match dispatch(&mut sys, &metadata(1), &args(&["log", "on"])) {
+            Err(Error::EACCES(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "a guest used a control command"),
+        }
+        assert!(!sys.trace());
+    }
+}
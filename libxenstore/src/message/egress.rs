@@ -20,13 +20,35 @@ use std::error::Error;
 use super::*;
 use super::super::{error, path, store, watch, wire};
 
+/// Build a body out of `fields`, each followed by the NUL the protocol
+/// ends its strings with
+fn nul_terminated<I, T>(fields: I) -> wire::Body
+    where I: IntoIterator<Item = T>,
+          T: AsRef<[u8]>
+{
+    let fields = fields.into_iter()
+        .map(|field| {
+                 let mut bytes = field.as_ref().to_vec();
+                 bytes.push(b'\0');
+                 bytes
+             })
+        .collect::<Vec<_>>();
+
+    wire::Body::from(fields)
+}
+
 pub trait Egress {
     fn msg_type(&self) -> u32;
     fn md(&self) -> &Metadata;
 
+    /// Acknowledge the request the way C xenstored does, with "OK"
     fn encode(&self) -> (wire::Header, wire::Body) {
-        let body: Vec<Vec<u8>> = Vec::with_capacity(0);
+        self.reply(nul_terminated(vec!["OK"]))
+    }
 
+    /// Put the header for this message in front of `body`, taking its
+    /// length from the bytes that will actually be sent
+    fn reply(&self, body: wire::Body) -> (wire::Header, wire::Body) {
         let header = wire::Header {
             msg_type: self.msg_type(),
             req_id: self.md().req_id,
@@ -34,7 +56,7 @@ pub trait Egress {
             len: body.len() as u32,
         };
 
-        (header, wire::Body(body))
+        (header, body)
     }
 }
 
@@ -82,27 +104,59 @@ impl Egress for Directory {
     }
 
     fn encode(&self) -> (wire::Header, wire::Body) {
-        // a build a vector of vectors of u8
-        let body = self.paths
-            .iter()
-            .map(|p| {
-                     let mut p = p.as_bytes().to_owned();
-                     p.push(b'\0');
-                     p
-                 })
-            .collect();
-
-        // covert to wire::Body
-        let body = wire::Body(body);
+        self.reply(nul_terminated(self.paths.iter().map(|p| p.as_bytes())))
+    }
+}
 
-        let header = wire::Header {
-            msg_type: self.msg_type(),
-            req_id: self.md().req_id,
-            tx_id: self.md().tx_id,
-            len: body.len() as u32,
-        };
+pub struct DirectoryPart {
+    pub md: Metadata,
+    pub generation: u64,
+    pub paths: Vec<store::Basename>,
+    pub offset: usize,
+}
 
-        (header, body)
+impl Egress for DirectoryPart {
+    fn msg_type(&self) -> u32 {
+        wire::XS_DIRECTORY_PART
+    }
+
+    fn md(&self) -> &Metadata {
+        &self.md
+    }
+
+    fn encode(&self) -> (wire::Header, wire::Body) {
+        // the generation lets the client notice the list changing between parts
+        let mut body = format!("{}", self.generation).into_bytes();
+        body.push(b'\0');
+
+        // the offset counts bytes into the NUL separated list of children
+        let mut children = Vec::new();
+        for p in &self.paths {
+            children.extend_from_slice(p.as_bytes());
+            children.push(b'\0');
+        }
+
+        // send as many whole names as fit, keeping a byte for the end marker
+        let start = if self.offset < children.len() { self.offset } else { children.len() };
+        let max = wire::XENSTORE_PAYLOAD_MAX - body.len() - 1;
+        let mut end = start;
+        for name in children[start..].split(|b| *b == b'\0').filter(|name| !name.is_empty()) {
+            if end - start + name.len() >= max {
+                break;
+            }
+            end += name.len() + 1;
+        }
+        body.extend_from_slice(&children[start..end]);
+
+        // an empty name marks the end of the list
+        if end == children.len() {
+            body.push(b'\0');
+        }
+
+        // convert to wire::Body
+        let body = wire::Body::from(vec![body]);
+
+        self.reply(body)
     }
 }
 
@@ -121,20 +175,11 @@ impl Egress for Read {
     }
 
     fn encode(&self) -> (wire::Header, wire::Body) {
-        // a build a vector of u8s
-        let value = self.value.as_bytes().to_owned();
-
-        // convert to wire::Body
-        let body = wire::Body(vec![value]);
-
-        let header = wire::Header {
-            msg_type: self.msg_type(),
-            req_id: self.md().req_id,
-            tx_id: self.md().tx_id,
-            len: body.len() as u32,
-        };
+        // values are bytes rather than strings, so like C xenstored they go
+        // out exactly as stored, without a NUL after them
+        let body = wire::Body::from(vec![self.value.as_bytes().to_owned()]);
 
-        (header, body)
+        self.reply(body)
     }
 }
 
@@ -153,33 +198,7 @@ impl Egress for GetPerms {
     }
 
     fn encode(&self) -> (wire::Header, wire::Body) {
-        let perms = self.perms
-            .iter()
-            .map(|p| {
-                let pstr = match p.perm {
-                    store::Perm::Read => "r",
-                    store::Perm::Write => "w",
-                    store::Perm::Both => "b",
-                    _ => "n",
-                };
-                let string = format!("{}{}", pstr, p.id);
-                let mut bytes = string.as_bytes().to_owned();
-                bytes.push(b'\0');
-                bytes
-            })
-            .collect();
-
-        // convert to wire::Body
-        let body = wire::Body(perms);
-
-        let header = wire::Header {
-            msg_type: self.msg_type(),
-            req_id: self.md().req_id,
-            tx_id: self.md().tx_id,
-            len: body.len() as u32,
-        };
-
-        (header, body)
+        self.reply(nul_terminated(self.perms.iter().map(|p| p.to_string())))
     }
 }
 
@@ -198,19 +217,7 @@ impl Egress for TransactionStart {
     }
 
     fn encode(&self) -> (wire::Header, wire::Body) {
-        let value = format!("{}", self.tx_id).as_bytes().to_owned();
-
-        // convert to wire::Body
-        let body = wire::Body(vec![value]);
-
-        let header = wire::Header {
-            msg_type: self.msg_type(),
-            req_id: self.md().req_id,
-            tx_id: self.md().tx_id,
-            len: body.len() as u32,
-        };
-
-        (header, body)
+        self.reply(nul_terminated(vec![format!("{}", self.tx_id)]))
     }
 }
 
@@ -227,6 +234,29 @@ impl Egress for GetDomainPath {
     fn md(&self) -> &Metadata {
         &self.md
     }
+
+    fn encode(&self) -> (wire::Header, wire::Body) {
+        self.reply(nul_terminated(vec![self.path.as_bytes()]))
+    }
+}
+
+pub struct GetQuota {
+    pub md: Metadata,
+    pub value: String,
+}
+
+impl Egress for GetQuota {
+    fn msg_type(&self) -> u32 {
+        wire::XS_GET_QUOTA
+    }
+
+    fn md(&self) -> &Metadata {
+        &self.md
+    }
+
+    fn encode(&self) -> (wire::Header, wire::Body) {
+        self.reply(nul_terminated(vec![&self.value]))
+    }
 }
 
 pub struct IsDomainIntroduced {
@@ -242,6 +272,31 @@ impl Egress for IsDomainIntroduced {
     fn md(&self) -> &Metadata {
         &self.md
     }
+
+    fn encode(&self) -> (wire::Header, wire::Body) {
+        // C xenstored answers with a NUL terminated "T" or "F"
+        self.reply(nul_terminated(vec![if self.introduced { "T" } else { "F" }]))
+    }
+}
+
+pub struct Control {
+    pub md: Metadata,
+    pub value: String,
+}
+
+impl Egress for Control {
+    fn msg_type(&self) -> u32 {
+        wire::XS_CONTROL
+    }
+
+    fn md(&self) -> &Metadata {
+        &self.md
+    }
+
+    fn encode(&self) -> (wire::Header, wire::Body) {
+        // xenstore-control prints the NUL terminated reply as is
+        self.reply(nul_terminated(vec![&self.value]))
+    }
 }
 
 pub struct ErrorMsg {
@@ -266,12 +321,19 @@ impl Egress for ErrorMsg {
     fn md(&self) -> &Metadata {
         &self.md
     }
+
+    fn encode(&self) -> (wire::Header, wire::Body) {
+        // clients expect the NUL terminated name of the error
+        self.reply(nul_terminated(vec![&self.err]))
+    }
 }
 
 pub struct WatchEvent {
     pub md: Metadata,
     pub node: watch::WPath,
-    pub token: watch::WPath,
+    pub token: watch::WToken,
+    pub relative: bool,
+    pub domain: Option<wire::DomainId>,
 }
 
 impl WatchEvent {
@@ -284,6 +346,8 @@ impl WatchEvent {
             },
             node: watch.node,
             token: watch.token,
+            relative: watch.relative,
+            domain: watch.domain,
         }
     }
 }
@@ -298,24 +362,378 @@ impl Egress for WatchEvent {
     }
 
     fn encode(&self) -> (wire::Header, wire::Body) {
+        // the path goes back the way the client gave it and the token
+        // goes back exactly as the client sent it
+        let node = if self.relative {
+            self.node.as_relative_bytes(self.md.conn.dom_id)
+        } else {
+            self.node.as_bytes()
+        };
+        let mut fields = vec![node.to_vec(), self.token.as_bytes().to_vec()];
 
-        // convert to wire::Body
-        let body = wire::Body(vec![&self.node, &self.token]
-                                  .iter()
-                                  .map(|p| {
-                                           let mut p = p.as_bytes().to_owned();
-                                           p.push(b'\0');
-                                           p
-                                       })
-                                  .collect());
+        // domain events can name their domain after the token, where
+        // clients that don't know about it won't look
+        if let Some(dom_id) = self.domain {
+            fields.push(format!("{}", dom_id).into_bytes());
+        }
 
-        let header = wire::Header {
-            msg_type: self.msg_type(),
-            req_id: self.md().req_id,
-            tx_id: self.md().tx_id,
-            len: body.len() as u32,
+        self.reply(nul_terminated(fields))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate mio;
+    extern crate quickcheck;
+
+    use bytes::BytesMut;
+    use self::mio::Token;
+    use self::quickcheck::quickcheck;
+    use super::*;
+    use super::super::super::connection::ConnId;
+    use std::error::Error;
+    use super::super::super::{error, path, store, watch, wire};
+    use tokio_io::codec::{Decoder, Encoder};
+
+    fn metadata() -> Metadata {
+        Metadata {
+            conn: ConnId::new(Token(0), 0),
+            req_id: 7,
+            tx_id: 3,
+        }
+    }
+
+    /// Send `msg` through the codec, checking its header is honest about
+    /// the body, and hand back the fields that arrive
+    fn round_trip<E: Egress>(msg: &E) -> Option<Vec<Vec<u8>>> {
+        let (header, body) = msg.encode();
+        if header.len() != body.to_vec().len() {
+            return None;
+        }
+
+        let mut buf = BytesMut::new();
+        wire::XenStoreCodec.encode((header.clone(), body), &mut buf).unwrap();
+        match wire::XenStoreCodec.decode(&mut buf) {
+            Ok(Some((ref decoded, ref body))) if *decoded == header && buf.is_empty() => {
+                Some(body.fields().into_iter().map(|field| field.to_vec()).collect())
+            }
+            _ => None,
+        }
+    }
+
+    /// Keep the strings that can be sent as a field of their own
+    fn fields(strs: Vec<String>) -> Vec<String> {
+        strs.into_iter().filter(|s| !s.is_empty() && !s.contains('\0')).collect()
+    }
+
+    fn bytes(strs: &[String]) -> Vec<Vec<u8>> {
+        strs.iter().map(|s| s.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn no_arg() {
+        // acknowledged with "OK" like C xenstored's send_ack
+        assert_eq!(round_trip(&Write { md: metadata() }), Some(vec![b"OK".to_vec()]));
+        assert_eq!(on_the_wire(&Remove { md: metadata() }),
+                   vec![13, 0, 0, 0, 7, 0, 0, 0, 3, 0, 0, 0, 3, 0, 0, 0, b'O', b'K', 0]);
+    }
+
+    #[test]
+    fn golden_bytes() {
+        fn body<E: Egress>(msg: &E) -> Vec<u8> {
+            on_the_wire(msg)[wire::HEADER_SIZE..].to_vec()
+        }
+
+        // strings are each followed by a NUL, values go out exactly as stored
+        assert_eq!(body(&Read {
+                            md: metadata(),
+                            value: store::Value::from(b"va\0lue".to_vec()),
+                        }),
+                   b"va\0lue".to_vec());
+        assert_eq!(body(&TransactionStart {
+                            md: metadata(),
+                            tx_id: 42,
+                        }),
+                   b"42\0".to_vec());
+        assert_eq!(body(&Directory {
+                            md: metadata(),
+                            paths: vec![store::Basename::from(String::from("a")),
+                                        store::Basename::from(String::from("bc"))],
+                        }),
+                   b"a\0bc\0".to_vec());
+        assert_eq!(body(&Directory {
+                            md: metadata(),
+                            paths: vec![],
+                        }),
+                   vec![]);
+        assert_eq!(body(&DirectoryPart {
+                            md: metadata(),
+                            generation: 9,
+                            paths: vec![store::Basename::from(String::from("a"))],
+                            offset: 0,
+                        }),
+                   b"9\0a\0\0".to_vec());
+        assert_eq!(body(&GetPerms {
+                            md: metadata(),
+                            perms: vec![store::Permission {
+                                            id: 0,
+                                            perm: store::Perm::Write,
+                                        },
+                                        store::Permission {
+                                            id: 5,
+                                            perm: store::Perm::Read,
+                                        }],
+                        }),
+                   b"w0\0r5\0".to_vec());
+        assert_eq!(body(&GetQuota {
+                            md: metadata(),
+                            value: String::from("128"),
+                        }),
+                   b"128\0".to_vec());
+        assert_eq!(body(&Control {
+                            md: metadata(),
+                            value: String::from("done"),
+                        }),
+                   b"done\0".to_vec());
+        assert_eq!(body(&ErrorMsg {
+                            md: metadata(),
+                            err: String::from("ENOENT"),
+                        }),
+                   b"ENOENT\0".to_vec());
+        assert_eq!(body(&WatchEvent {
+                            md: metadata(),
+                            node: watch::WPath::try_from(0, "/a").unwrap(),
+                            token: watch::WToken::from("tok"),
+                            relative: false,
+                            domain: Some(4),
+                        }),
+                   b"/a\0tok\04\0".to_vec());
+    }
+
+    #[test]
+    fn error_names() {
+        // the errno names C xenstored's xsd_errors table sends, each NUL
+        // terminated
+        let table = vec![(error::Error::EINVAL(String::new()), &b"EINVAL\0"[..]),
+                         (error::Error::EACCES(String::new()), b"EACCES\0"),
+                         (error::Error::EEXIST(String::new()), b"EEXIST\0"),
+                         (error::Error::EISDIR(String::new()), b"EISDIR\0"),
+                         (error::Error::ENOENT(String::new()), b"ENOENT\0"),
+                         (error::Error::ENOMEM(String::new()), b"ENOMEM\0"),
+                         (error::Error::ENOSPC(String::new()), b"ENOSPC\0"),
+                         (error::Error::EIO(String::new()), b"EIO\0"),
+                         (error::Error::ENOTEMPTY(String::new()), b"ENOTEMPTY\0"),
+                         (error::Error::ENOSYS(String::new()), b"ENOSYS\0"),
+                         (error::Error::EROFS(String::new()), b"EROFS\0"),
+                         (error::Error::EBUSY(String::new()), b"EBUSY\0"),
+                         (error::Error::EAGAIN(String::new()), b"EAGAIN\0"),
+                         (error::Error::EISCONN(String::new()), b"EISCONN\0"),
+                         (error::Error::E2BIG(String::new()), b"E2BIG\0")];
+
+        for (err, expected) in table {
+            let (header, body) = ErrorMsg::from(metadata(), &err).encode();
+            assert_eq!(header.msg_type, wire::XS_ERROR);
+            assert_eq!(header.req_id, 7);
+            assert_eq!(header.tx_id, 3);
+            assert_eq!(body.to_vec(), expected.to_vec());
+
+            // and clients turn the name back into the same error
+            let name = String::from_utf8(expected[..expected.len() - 1].to_vec()).unwrap();
+            assert_eq!(error::Error::from_wire(&name).description(), err.description());
+        }
+    }
+
+    #[test]
+    fn directory() {
+        fn prop(names: Vec<String>) -> bool {
+            let names = fields(names);
+            let msg = Directory {
+                md: metadata(),
+                paths: names.iter().cloned().map(store::Basename::from).collect(),
+            };
+
+            // longer listings are what DIRECTORY_PART is for
+            if msg.encode().0.len() > wire::XENSTORE_PAYLOAD_MAX {
+                return true;
+            }
+
+            round_trip(&msg) == Some(bytes(&names))
+        }
+
+        quickcheck(prop as fn(Vec<String>) -> bool);
+    }
+
+    #[test]
+    fn directory_part() {
+        fn prop(names: Vec<String>, generation: u64) -> bool {
+            let names = fields(names);
+            let msg = DirectoryPart {
+                md: metadata(),
+                generation: generation,
+                paths: names.iter().cloned().map(store::Basename::from).collect(),
+                offset: 0,
+            };
+
+            // the generation comes first, then as many names as fit
+            msg.encode().0.len() <= wire::XENSTORE_PAYLOAD_MAX &&
+            round_trip(&msg)
+                .map(|fields| {
+                         fields[0] == format!("{}", generation).into_bytes() &&
+                         bytes(&names).starts_with(&fields[1..])
+                     })
+                .unwrap_or(false)
+        }
+
+        quickcheck(prop as fn(Vec<String>, u64) -> bool);
+    }
+
+    #[test]
+    fn read() {
+        fn prop(value: Vec<u8>) -> bool {
+            let msg = Read {
+                md: metadata(),
+                value: store::Value::from(value.clone()),
+            };
+
+            // values go out as they are, NULs and all
+            round_trip(&msg).is_some() && msg.encode().1.to_vec() == value
+        }
+
+        quickcheck(prop as fn(Vec<u8>) -> bool);
+    }
+
+    #[test]
+    fn get_perms() {
+        fn prop(perms: Vec<(u32, u8)>) -> bool {
+            let perms = perms.into_iter()
+                .map(|(id, perm)| {
+                    store::Permission {
+                        id: id,
+                        perm: match perm % 4 {
+                            0 => store::Perm::None,
+                            1 => store::Perm::Read,
+                            2 => store::Perm::Write,
+                            _ => store::Perm::Both,
+                        },
+                    }
+                })
+                .collect::<Vec<_>>();
+            let expected = perms.iter()
+                .map(|p| {
+                    let perm = match p.perm {
+                        store::Perm::Read => "r",
+                        store::Perm::Write => "w",
+                        store::Perm::Both => "b",
+                        _ => "n",
+                    };
+                    format!("{}{}", perm, p.id).into_bytes()
+                })
+                .collect::<Vec<_>>();
+            let msg = GetPerms {
+                md: metadata(),
+                perms: perms,
+            };
+
+            round_trip(&msg) == Some(expected)
+        }
+
+        quickcheck(prop as fn(Vec<(u32, u8)>) -> bool);
+    }
+
+    /// The bytes `msg` goes out as, header and all
+    fn on_the_wire<E: Egress>(msg: &E) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        wire::XenStoreCodec.encode(msg.encode(), &mut buf).unwrap();
+        buf.to_vec()
+    }
+
+    #[test]
+    fn domain_replies() {
+        // as C xenstored sends them: the path with its NUL, and "T" or "F"
+        // with theirs
+        let path = GetDomainPath {
+            md: metadata(),
+            path: path::get_domain_path(5),
         };
+        let mut expected = vec![10, 0, 0, 0, 7, 0, 0, 0, 3, 0, 0, 0, 16, 0, 0, 0];
+        expected.extend_from_slice(b"/local/domain/5\0");
+        assert_eq!(on_the_wire(&path), expected);
 
-        (header, body)
+        let introduced = IsDomainIntroduced {
+            md: metadata(),
+            introduced: true,
+        };
+        assert_eq!(on_the_wire(&introduced),
+                   vec![17, 0, 0, 0, 7, 0, 0, 0, 3, 0, 0, 0, 2, 0, 0, 0, b'T', 0]);
+
+        let introduced = IsDomainIntroduced {
+            md: metadata(),
+            introduced: false,
+        };
+        assert_eq!(on_the_wire(&introduced),
+                   vec![17, 0, 0, 0, 7, 0, 0, 0, 3, 0, 0, 0, 2, 0, 0, 0, b'F', 0]);
+    }
+
+    #[test]
+    fn single_values() {
+        fn prop(text: String, tx_id: u32, introduced: bool) -> bool {
+            let text = fields(vec![text]);
+            let text_ok = text.is_empty() ||
+                          round_trip(&ErrorMsg {
+                                         md: metadata(),
+                                         err: text[0].clone(),
+                                     }) == Some(bytes(&text)) &&
+                          round_trip(&Control {
+                                         md: metadata(),
+                                         value: text[0].clone(),
+                                     }) == Some(bytes(&text)) &&
+                          round_trip(&GetQuota {
+                                         md: metadata(),
+                                         value: text[0].clone(),
+                                     }) == Some(bytes(&text));
+
+            let start = TransactionStart {
+                md: metadata(),
+                tx_id: tx_id,
+            };
+            let introduced = IsDomainIntroduced {
+                md: metadata(),
+                introduced: introduced,
+            };
+            let answer = if introduced.introduced { b"T" } else { b"F" };
+
+            text_ok && round_trip(&start) == Some(vec![format!("{}", tx_id).into_bytes()]) &&
+            round_trip(&introduced) == Some(vec![answer.to_vec()])
+        }
+
+        quickcheck(prop as fn(String, u32, bool) -> bool);
+    }
+
+    #[test]
+    fn watch_event() {
+        fn prop(token: String, domain: Option<u32>) -> bool {
+            let token = fields(vec![token]);
+            if token.is_empty() {
+                return true;
+            }
+
+            let node = "/local/domain/0/device";
+            let msg = WatchEvent {
+                md: metadata(),
+                node: watch::WPath::try_from(0, node).unwrap(),
+                token: watch::WToken::from(&token[0][..]),
+                relative: false,
+                domain: domain,
+            };
+
+            let mut expected = vec![node.as_bytes().to_vec(), token[0].as_bytes().to_vec()];
+            if let Some(dom_id) = domain {
+                expected.push(format!("{}", dom_id).into_bytes());
+            }
+
+            round_trip(&msg) == Some(expected)
+        }
+
+        quickcheck(prop as fn(String, Option<u32>) -> bool);
     }
 }
@@ -18,7 +18,7 @@
 
 use std::str;
 use super::*;
-use super::super::{connection, path, watch, wire};
+use super::super::{connection, path, store, watch, wire};
 use super::super::error::{Error, Result};
 
 pub trait IngressPath {
@@ -26,7 +26,7 @@ pub trait IngressPath {
 }
 
 pub trait IngressWPath {
-    fn new(Metadata, watch::WPath, watch::WPath) -> Self;
+    fn new(Metadata, watch::WPath, watch::WToken, bool) -> Self;
 }
 
 pub trait IngressPathRest {
@@ -37,6 +37,10 @@ pub trait IngressBool {
     fn new(Metadata, bool) -> Self;
 }
 
+pub trait IngressDomId {
+    fn new(Metadata, wire::DomainId) -> Self;
+}
+
 pub trait IngressNoArg {
     fn new(Metadata) -> Self;
 }
@@ -64,15 +68,21 @@ macro_rules! ingress_wpath {
         pub struct $id {
             pub md: Metadata,
             pub node: watch::WPath,
-            pub token: watch::WPath,
+            pub token: watch::WToken,
+            pub relative: bool,
         }
 
         impl IngressWPath for $id {
-            fn new(md: Metadata, node: watch::WPath, token: watch::WPath) -> $id {
+            fn new(md: Metadata,
+                   node: watch::WPath,
+                   token: watch::WToken,
+                   relative: bool)
+                   -> $id {
                 $id {
                     md: md,
                     node: node,
                     token: token,
+                    relative: relative,
                 }
             }
         }
@@ -117,6 +127,24 @@ macro_rules! ingress_bool {
     }
 }
 
+macro_rules! ingress_domid {
+    ($id:ident) => {
+        pub struct $id {
+            pub md: Metadata,
+            pub dom_id: wire::DomainId,
+        }
+
+        impl IngressDomId for $id {
+            fn new(md: Metadata, dom_id: wire::DomainId) -> $id {
+                $id {
+                    md: md,
+                    dom_id: dom_id,
+                }
+            }
+        }
+    }
+}
+
 macro_rules! ingress_no_arg {
     ($id:ident) => {
         pub struct $id {
@@ -139,7 +167,6 @@ ingress_path!(GetPerms);
 ingress_path!(Mkdir);
 ingress_path!(Remove);
 
-ingress_path_rest!(Write);
 ingress_path_rest!(SetPerms);
 
 ingress_bool!(TransactionEnd);
@@ -147,33 +174,65 @@ ingress_bool!(TransactionEnd);
 ingress_wpath!(Watch);
 ingress_wpath!(Unwatch);
 
+ingress_domid!(Release);
+ingress_domid!(IsDomainIntroduced);
+ingress_domid!(GetDomainPath);
+ingress_domid!(Resume);
+ingress_domid!(Restrict);
+
 ingress_no_arg!(TransactionStart);
-ingress_no_arg!(Release);
-ingress_no_arg!(GetDomainPath);
-ingress_no_arg!(Resume);
-ingress_no_arg!(Restrict);
+
+pub struct Write {
+    pub md: Metadata,
+    pub path: path::Path,
+    pub value: store::Value,
+}
+
+pub struct Introduce {
+    pub md: Metadata,
+    pub dom_id: wire::DomainId,
+    pub mfn: Mfn,
+    pub port: EvtChnPort,
+}
+
+pub struct SetTarget {
+    pub md: Metadata,
+    pub dom_id: wire::DomainId,
+    pub target: wire::DomainId,
+}
+
+pub struct DirectoryPart {
+    pub md: Metadata,
+    pub path: path::Path,
+    pub offset: usize,
+}
+
+pub struct Control {
+    pub md: Metadata,
+    pub args: Vec<String>,
+}
+
+pub struct GetQuota {
+    pub md: Metadata,
+    // the domain the quota is wanted for, rather than the global one
+    pub dom_id: Option<wire::DomainId>,
+    // listing the quota names when missing
+    pub quota: Option<String>,
+}
 
 pub struct ErrorMsg {
     pub md: Metadata,
     pub err: Error,
 }
 
-//    Debug(Metadata, Vec<String>)
-//    Introduce(Metadata, Mfn, EvtChnPort)
-//    IsDomainIntroduced(Metadata)
-//    SetTarget(Metadata, wire::DomainId)
-//    Restrict(Metadata)
 //    ResetWatches(Metadata)
 
-fn to_strs<'a>(body: &'a wire::Body) -> Result<Vec<&'a str>> {
-    // parse out the Vec<Vec<u8>>
-    let wire::Body(ref body) = *body;
+fn to_str(bytes: &[u8]) -> Result<&str> {
+    str::from_utf8(bytes).map_err(|_| Error::EINVAL(format!("bad supplied string")))
+}
 
-    body.iter()
-        .map(|bytes| {
-                 str::from_utf8(bytes).map_err(|_| Error::EINVAL(format!("bad supplied string")))
-             })
-        .collect()
+fn to_strs<'a>(body: &'a wire::Body) -> Result<Vec<&'a str>> {
+    body.fields().into_iter().map(to_str).collect()
 }
 
 fn to_path_str<'a>(body: &'a wire::Body) -> Result<&'a str> {
@@ -205,13 +264,23 @@ fn parse_wpaths<T: 'static + IngressWPath + ProcessMessage>(md: Metadata,
                                                             body: wire::Body)
                                                             -> Result<Box<ProcessMessage>> {
     let dom_id = md.conn.dom_id;
-    let (node, token) = try!(to_strs(&body).and_then(|strs| {
-        watch::WPath::try_from(dom_id, strs[0]).and_then(|node| {
-            watch::WPath::try_from(dom_id, strs[1]).and_then(|token| Ok((node, token)))
-        })
-    }));
+    // the token is opaque so it's kept as bytes, and may be empty
+    let fields = body.positional_fields();
+
+    // this request must contain a path and a token
+    if fields.len() != 2 {
+        let thanks_cargo_fmt = format!("Invalid number of fields received. Expected 2. \
+                                        Got: {}",
+                                       fields.len());
+        return Err(Error::EINVAL(thanks_cargo_fmt));
+    }
+
+    let node = try!(to_str(&fields[0]));
+    let relative = !node.starts_with('/');
+    let node = try!(watch::WPath::try_from(dom_id, node));
+    let token = watch::WToken::from(&fields[1][..]);
 
-    Ok(Box::new(T::new(md, node, token)))
+    Ok(Box::new(T::new(md, node, token, relative)))
 }
 
 fn parse_path_rest<T: 'static + IngressPathRest + ProcessMessage>
@@ -237,6 +306,26 @@ fn parse_path_rest<T: 'static + IngressPathRest + ProcessMessage>
     Ok(Box::new(T::new(md, path, rest)))
 }
 
+fn parse_write(md: Metadata, body: wire::Body) -> Result<Box<ProcessMessage>> {
+    let dom_id = md.conn.dom_id;
+
+    // the path is NULL terminated and the value is every byte after it,
+    // so the value may be empty or contain NULL characters of its own. A
+    // body that stops at the path is writing an empty value too.
+    let (name, value) = body.split_first();
+
+    let path = try!(str::from_utf8(&name)
+                        .map_err(|_| Error::EINVAL(format!("bad supplied string")))
+                        .and_then(|p| path::Path::try_from(dom_id, p)));
+    let value = value.map_or_else(store::Value::new, |value| store::Value::from(value.to_vec()));
+
+    Ok(Box::new(Write {
+                    md: md,
+                    path: path,
+                    value: value,
+                }))
+}
+
 fn parse_path_bool<T: 'static + IngressBool + ProcessMessage>(md: Metadata,
                                                               body: wire::Body)
                                                               -> Result<Box<ProcessMessage>> {
@@ -251,17 +340,183 @@ fn parse_path_bool<T: 'static + IngressBool + ProcessMessage>(md: Metadata,
         return Err(Error::EINVAL(thanks_cargo_fmt));
     }
 
-    let value = strs[0] == "T";
+    let value = match strs[0] {
+        "T" => true,
+        "F" => false,
+        value => return Err(Error::EINVAL(format!("expected T or F, got {:?}", value))),
+    };
 
     Ok(Box::new(T::new(md, value)))
 }
 
+fn parse_domid<T: 'static + IngressDomId + ProcessMessage>(md: Metadata,
+                                                           body: wire::Body)
+                                                           -> Result<Box<ProcessMessage>> {
+    // parse out the Vec<&str>
+    let strs = try!(to_strs(&body));
+
+    // this request must contain exactly one domain id
+    if strs.len() != 1 {
+        let thanks_cargo_fmt = format!("Invalid number of strs received. Expected 1. \
+                                        Got: {}",
+                                       strs.len());
+        return Err(Error::EINVAL(thanks_cargo_fmt));
+    }
+
+    let dom_id = try!(strs[0]
+                          .parse::<wire::DomainId>()
+                          .map_err(|_| Error::EINVAL(format!("bad domain id: {}", strs[0]))));
+
+    Ok(Box::new(T::new(md, dom_id)))
+}
+
+fn parse_introduce(md: Metadata, body: wire::Body) -> Result<Box<ProcessMessage>> {
+    // parse out the Vec<&str>
+    let strs = try!(to_strs(&body));
+
+    // this request must contain a domain id, an mfn and an event channel port
+    if strs.len() != 3 {
+        let thanks_cargo_fmt = format!("Invalid number of strs received. Expected 3. \
+                                        Got: {}",
+                                       strs.len());
+        return Err(Error::EINVAL(thanks_cargo_fmt));
+    }
+
+    let dom_id = try!(strs[0]
+                          .parse::<wire::DomainId>()
+                          .map_err(|_| Error::EINVAL(format!("bad domain id: {}", strs[0]))));
+    let mfn = try!(strs[1]
+                       .parse::<Mfn>()
+                       .map_err(|_| Error::EINVAL(format!("bad mfn: {}", strs[1]))));
+    let port = try!(strs[2]
+                        .parse::<EvtChnPort>()
+                        .map_err(|_| Error::EINVAL(format!("bad event channel: {}", strs[2]))));
+
+    Ok(Box::new(Introduce {
+                    md: md,
+                    dom_id: dom_id,
+                    mfn: mfn,
+                    port: port,
+                }))
+}
+
+fn parse_set_target(md: Metadata, body: wire::Body) -> Result<Box<ProcessMessage>> {
+    // parse out the Vec<&str>
+    let strs = try!(to_strs(&body));
+
+    // this request must contain a domain id and its target domain id
+    if strs.len() != 2 {
+        let thanks_cargo_fmt = format!("Invalid number of strs received. Expected 2. \
+                                        Got: {}",
+                                       strs.len());
+        return Err(Error::EINVAL(thanks_cargo_fmt));
+    }
+
+    let dom_id = try!(strs[0]
+                          .parse::<wire::DomainId>()
+                          .map_err(|_| Error::EINVAL(format!("bad domain id: {}", strs[0]))));
+    let target = try!(strs[1]
+                          .parse::<wire::DomainId>()
+                          .map_err(|_| Error::EINVAL(format!("bad target id: {}", strs[1]))));
+
+    Ok(Box::new(SetTarget {
+                    md: md,
+                    dom_id: dom_id,
+                    target: target,
+                }))
+}
+
+fn parse_directory_part(md: Metadata, body: wire::Body) -> Result<Box<ProcessMessage>> {
+    let dom_id = md.conn.dom_id;
+
+    // parse out the Vec<&str>
+    let strs = try!(to_strs(&body));
+
+    // this request must contain a path and an offset into its children
+    if strs.len() != 2 {
+        let thanks_cargo_fmt = format!("Invalid number of strs received. Expected 2. \
+                                        Got: {}",
+                                       strs.len());
+        return Err(Error::EINVAL(thanks_cargo_fmt));
+    }
+
+    let path = try!(path::Path::try_from(dom_id, strs[0]));
+    let offset = try!(strs[1]
+                          .parse::<usize>()
+                          .map_err(|_| Error::EINVAL(format!("bad offset: {}", strs[1]))));
+
+    Ok(Box::new(DirectoryPart {
+                    md: md,
+                    path: path,
+                    offset: offset,
+                }))
+}
+
+fn parse_control(md: Metadata, body: wire::Body) -> Result<Box<ProcessMessage>> {
+    // parse out the Vec<&str>
+    let strs = try!(to_strs(&body));
+
+    // this request must contain at least a subcommand
+    if strs.is_empty() {
+        return Err(Error::EINVAL(format!("Invalid number of strs received. Expected at least 1. \
+                                          Got: 0")));
+    }
+
+    Ok(Box::new(Control {
+                    md: md,
+                    args: strs.iter().map(|s| s.to_string()).collect(),
+                }))
+}
+
+fn parse_get_quota(md: Metadata, body: wire::Body) -> Result<Box<ProcessMessage>> {
+    // parse out the Vec<&str>
+    let strs = try!(to_strs(&body));
+
+    let (dom_id, quota) = match strs.len() {
+        0 => (None, None),
+        1 => (None, Some(strs[0].to_string())),
+        2 => {
+            let dom_id = try!(strs[0].parse::<wire::DomainId>().map_err(|_| {
+                Error::EINVAL(format!("bad domain id: {}", strs[0]))
+            }));
+            (Some(dom_id), Some(strs[1].to_string()))
+        }
+        n => {
+            return Err(Error::EINVAL(format!("Invalid number of strs received. Expected at \
+                                              most 2. Got: {}",
+                                             n)))
+        }
+    };
+
+    Ok(Box::new(GetQuota {
+                    md: md,
+                    dom_id: dom_id,
+                    quota: quota,
+                }))
+}
+
 fn parse_metadata_only<T: 'static + IngressNoArg + ProcessMessage>
     (md: Metadata)
      -> Result<Box<ProcessMessage>> {
     Ok(Box::new(T::new(md)))
 }
 
+/// Check if `msg_type` manages domains or the daemon, which only a
+/// privileged domain may do
+fn is_privileged(msg_type: u32) -> bool {
+    match msg_type {
+        wire::XS_INTRODUCE |
+        wire::XS_RELEASE |
+        wire::XS_IS_DOMAIN_INTRODUCED |
+        wire::XS_SET_TARGET |
+        wire::XS_RESUME |
+        wire::XS_RESTRICT |
+        wire::XS_CONTROL |
+        wire::XS_GET_QUOTA => true,
+        _ => false,
+    }
+}
+
 pub fn parse(conn: connection::ConnId,
              header: &wire::Header,
              body: wire::Body)
@@ -273,23 +528,35 @@ pub fn parse(conn: connection::ConnId,
         tx_id: header.tx_id,
     };
 
-    let msg = match header.msg_type {
-        wire::XS_DIRECTORY => parse_path_only::<Directory>(md, body),
-        wire::XS_READ => parse_path_only::<Read>(md, body),
-        wire::XS_WRITE => parse_path_rest::<Write>(md, body),
-        wire::XS_GET_PERMS => parse_path_only::<GetPerms>(md, body),
-        wire::XS_SET_PERMS => parse_path_rest::<SetPerms>(md, body),
-        wire::XS_MKDIR => parse_path_only::<Mkdir>(md, body),
-        wire::XS_RM => parse_path_only::<Remove>(md, body),
-        wire::XS_WATCH => parse_wpaths::<Watch>(md, body),
-        wire::XS_UNWATCH => parse_wpaths::<Unwatch>(md, body),
-        wire::XS_TRANSACTION_START => parse_metadata_only::<TransactionStart>(md),
-        wire::XS_TRANSACTION_END => parse_path_bool::<TransactionEnd>(md, body),
-        wire::XS_RELEASE => parse_metadata_only::<Release>(md),
-        wire::XS_GET_DOMAIN_PATH => parse_metadata_only::<GetDomainPath>(md),
-        wire::XS_RESUME => parse_metadata_only::<Resume>(md),
-        wire::XS_RESTRICT => parse_metadata_only::<Restrict>(md),
-        _ => Err(Error::EINVAL(format!("bad msg id: {}", header.msg_type))),
+    let msg = if is_privileged(header.msg_type) && conn.dom_id != store::DOM0_DOMAIN_ID {
+        Err(Error::EACCES(format!("domain {} may not send message type {}",
+                                  conn.dom_id,
+                                  header.msg_type)))
+    } else {
+        match header.msg_type {
+            wire::XS_DIRECTORY => parse_path_only::<Directory>(md, body),
+            wire::XS_READ => parse_path_only::<Read>(md, body),
+            wire::XS_WRITE => parse_write(md, body),
+            wire::XS_GET_PERMS => parse_path_only::<GetPerms>(md, body),
+            wire::XS_SET_PERMS => parse_path_rest::<SetPerms>(md, body),
+            wire::XS_MKDIR => parse_path_only::<Mkdir>(md, body),
+            wire::XS_RM => parse_path_only::<Remove>(md, body),
+            wire::XS_WATCH => parse_wpaths::<Watch>(md, body),
+            wire::XS_UNWATCH => parse_wpaths::<Unwatch>(md, body),
+            wire::XS_TRANSACTION_START => parse_metadata_only::<TransactionStart>(md),
+            wire::XS_TRANSACTION_END => parse_path_bool::<TransactionEnd>(md, body),
+            wire::XS_INTRODUCE => parse_introduce(md, body),
+            wire::XS_RELEASE => parse_domid::<Release>(md, body),
+            wire::XS_IS_DOMAIN_INTRODUCED => parse_domid::<IsDomainIntroduced>(md, body),
+            wire::XS_GET_DOMAIN_PATH => parse_domid::<GetDomainPath>(md, body),
+            wire::XS_SET_TARGET => parse_set_target(md, body),
+            wire::XS_RESUME => parse_domid::<Resume>(md, body),
+            wire::XS_RESTRICT => parse_domid::<Restrict>(md, body),
+            wire::XS_CONTROL => parse_control(md, body),
+            wire::XS_DIRECTORY_PART => parse_directory_part(md, body),
+            wire::XS_GET_QUOTA => parse_get_quota(md, body),
+            _ => Err(Error::ENOSYS(format!("bad msg id: {}", header.msg_type))),
+        }
     };
 
     msg.unwrap_or_else(|e| {
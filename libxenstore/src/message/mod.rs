@@ -17,13 +17,14 @@
 **/
 
 use connection;
-use std::collections::HashSet;
-use std::sync::MutexGuard;
+use error::{Error, Result};
 use super::path;
+use quota;
 use store;
 use system;
+use tracelog;
 use transaction;
-use watch::Watch;
+use watch::{self, Watch};
 use wire;
 
 pub type Mfn = u64;
@@ -36,37 +37,117 @@ pub struct Metadata {
     pub tx_id: wire::TxId,
 }
 
+pub mod control;
 pub mod egress;
 pub mod ingress;
 
 pub struct Response {
     pub msg: Box<egress::Egress>,
-    pub watch_events: Option<HashSet<Watch>>,
 }
 
 impl Response {
     fn new(msg: Box<egress::Egress>) -> Response {
-        Response {
-            msg: msg,
-            watch_events: None,
-        }
+        Response { msg: msg }
     }
+}
 
-    fn new_with_events(msg: Box<egress::Egress>, events: HashSet<Watch>) -> Response {
-        Response {
-            msg: msg,
-            watch_events: Some(events),
-        }
+/// Carries out a parsed request against the `System`.
+///
+/// Whoever owns the `System` decides how access to it is serialized; the
+/// `Handler` locks it for the duration of each request.
+pub trait ProcessMessage {
+    fn process(&self, &mut system::System) -> Response;
+
+    /// Answer from `view` alone, without the `System`. Only requests that
+    /// the view `answers` are handed to it.
+    fn process_read(&self, _: &system::ReadView) -> Option<Response> {
+        None
     }
 }
 
-pub trait ProcessMessage {
-    fn process(&self, &mut MutexGuard<system::System>) -> Response;
+/// Parse and process a single request from `conn`, returning the encoded
+/// reply. Any watch events it fired are held by the `System` until
+/// `dispatch_fired` is called, once the reply is on its way. Both are logged
+/// when tracing has been turned on with the `log` control command, and the
+/// request goes in the access log when that's on.
+pub fn handle(sys: &mut system::System,
+              conn: connection::ConnId,
+              header: &wire::Header,
+              body: wire::Body)
+              -> (wire::Header, wire::Body) {
+    let conn = sys.effective_conn(conn);
+
+    if sys.trace() {
+        info!("{:?} request {:?} {:?}", conn, header, body);
+    }
+    let msg_type = header.msg_type;
+    let access = if sys.tracelog_enabled() {
+        Some(tracelog::Request::new(conn, header, &body))
+    } else {
+        None
+    };
+
+    let rsp = ingress::parse(conn, header, body).process(sys);
+    let reply = rsp.msg.encode();
+
+    if sys.trace() {
+        info!("{:?} reply {:?} {:?}", conn, reply.0, reply.1);
+    }
+    if let Some(access) = access {
+        sys.record_access(&access, &reply);
+    }
+    sys.record_request(msg_type, &reply);
+
+    reply
+}
+
+/// Answer a request that `view` `answers` from it alone, so that it need
+/// not wait for the `System`. It is counted in the metrics like any other.
+pub fn handle_read(view: &system::ReadView,
+                   conn: connection::ConnId,
+                   header: &wire::Header,
+                   body: wire::Body)
+                   -> (wire::Header, wire::Body) {
+    let conn = view.effective_conn(conn);
+    let md = Metadata {
+        conn: conn,
+        req_id: header.req_id,
+        tx_id: header.tx_id,
+    };
+
+    let rsp = ingress::parse(conn, header, body).process_read(view).unwrap_or_else(|| {
+        let err = Error::EINVAL(format!("message type {} needs the system", header.msg_type));
+        Response::new(Box::new(egress::ErrorMsg::from(md, &err)))
+    });
+    let reply = rsp.msg.encode();
+    view.record_request(header.msg_type, &reply);
+
+    reply
+}
+
+/// Whether the request described by `header` only changes the store outside
+/// of a transaction, so that a run of them from one connection can be
+/// handled with `System::do_store_batch`.
+pub fn batches(header: &wire::Header) -> bool {
+    let writes = match header.msg_type {
+        wire::XS_WRITE | wire::XS_MKDIR | wire::XS_RM | wire::XS_SET_PERMS => true,
+        _ => false,
+    };
+    writes && header.tx_id == transaction::ROOT_TRANSACTION
+}
+
+/// Check that the request's connection is allowed to change things
+fn writable(md: &Metadata) -> Result<()> {
+    if md.conn.read_only {
+        Err(Error::EACCES(format!("{:?} is read-only", md.conn)))
+    } else {
+        Ok(())
+    }
 }
 
 /// process an incoming directory request
 impl ProcessMessage for ingress::Directory {
-    fn process(&self, sys: &mut MutexGuard<system::System>) -> Response {
+    fn process(&self, sys: &mut system::System) -> Response {
         sys.do_store(self.md.conn,
                       self.md.tx_id,
                       |store, changes| store.directory(changes, self.md.conn.dom_id, &self.path))
@@ -78,11 +159,41 @@ impl ProcessMessage for ingress::Directory {
                  })
             .unwrap_or_else(|e| Response::new(Box::new(egress::ErrorMsg::from(self.md, &e))))
     }
+
+    fn process_read(&self, view: &system::ReadView) -> Option<Response> {
+        Some(view.reader
+                 .directory(self.md.conn.dom_id, view.target(self.md.conn), &self.path)
+                 .map(|entries| {
+                          Response::new(Box::new(egress::Directory {
+                                                     md: self.md,
+                                                     paths: entries,
+                                                 }))
+                      })
+                 .unwrap_or_else(|e| Response::new(Box::new(egress::ErrorMsg::from(self.md, &e)))))
+    }
+}
+
+/// process an incoming directory part request
+impl ProcessMessage for ingress::DirectoryPart {
+    fn process(&self, sys: &mut system::System) -> Response {
+        sys.do_store(self.md.conn, self.md.tx_id, |store, changes| {
+                store.directory_part(changes, self.md.conn.dom_id, &self.path)
+            })
+            .map(|(generation, entries)| {
+                     Response::new(Box::new(egress::DirectoryPart {
+                                                md: self.md,
+                                                generation: generation,
+                                                paths: entries,
+                                                offset: self.offset,
+                                            }))
+                 })
+            .unwrap_or_else(|e| Response::new(Box::new(egress::ErrorMsg::from(self.md, &e))))
+    }
 }
 
 /// process an incoming read request
 impl ProcessMessage for ingress::Read {
-    fn process(&self, sys: &mut MutexGuard<system::System>) -> Response {
+    fn process(&self, sys: &mut system::System) -> Response {
         sys.do_store(self.md.conn,
                       self.md.tx_id,
                       |store, changes| store.read(changes, self.md.conn.dom_id, &self.path))
@@ -94,11 +205,23 @@ impl ProcessMessage for ingress::Read {
                  })
             .unwrap_or_else(|e| Response::new(Box::new(egress::ErrorMsg::from(self.md, &e))))
     }
+
+    fn process_read(&self, view: &system::ReadView) -> Option<Response> {
+        Some(view.reader
+                 .read(self.md.conn.dom_id, view.target(self.md.conn), &self.path)
+                 .map(|value| {
+                          Response::new(Box::new(egress::Read {
+                                                     md: self.md,
+                                                     value: value,
+                                                 }))
+                      })
+                 .unwrap_or_else(|e| Response::new(Box::new(egress::ErrorMsg::from(self.md, &e)))))
+    }
 }
 
 /// process an incoming get permissions request
 impl ProcessMessage for ingress::GetPerms {
-    fn process(&self, sys: &mut MutexGuard<system::System>) -> Response {
+    fn process(&self, sys: &mut system::System) -> Response {
         sys.do_store(self.md.conn,
                       self.md.tx_id,
                       |store, changes| store.get_perms(changes, self.md.conn.dom_id, &self.path))
@@ -110,57 +233,97 @@ impl ProcessMessage for ingress::GetPerms {
                  })
             .unwrap_or_else(|e| Response::new(Box::new(egress::ErrorMsg::from(self.md, &e))))
     }
+
+    fn process_read(&self, view: &system::ReadView) -> Option<Response> {
+        Some(view.reader
+                 .get_perms(self.md.conn.dom_id, view.target(self.md.conn), &self.path)
+                 .map(|perms| {
+                          Response::new(Box::new(egress::GetPerms {
+                                                     md: self.md,
+                                                     perms: perms,
+                                                 }))
+                      })
+                 .unwrap_or_else(|e| Response::new(Box::new(egress::ErrorMsg::from(self.md, &e)))))
+    }
 }
 
 /// process an incoming make directory request
 impl ProcessMessage for ingress::Mkdir {
-    fn process(&self, sys: &mut MutexGuard<system::System>) -> Response {
+    fn process(&self, sys: &mut system::System) -> Response {
         let mut sys = sys;
-        sys.do_store_mut(self.md.conn, self.md.tx_id, |store, changes| {
-                store.mkdir(changes, self.md.conn.dom_id, self.path.clone())
+        writable(&self.md)
+            .and_then(|_| {
+                sys.do_store_mut(self.md.conn, self.md.tx_id, |store, changes| {
+                    store.mkdir(changes, self.md.conn.dom_id, self.path.clone())
+                })
             })
-            .map(|watch_events| {
-                     Response::new_with_events(Box::new(egress::Mkdir { md: self.md }),
-                                               watch_events)
-                 })
+            .map(|_| Response::new(Box::new(egress::Mkdir { md: self.md })))
             .unwrap_or_else(|e| Response::new(Box::new(egress::ErrorMsg::from(self.md, &e))))
     }
 }
 
 /// process an incoming remove request
 impl ProcessMessage for ingress::Remove {
-    fn process(&self, sys: &mut MutexGuard<system::System>) -> Response {
+    fn process(&self, sys: &mut system::System) -> Response {
         let mut sys = sys;
-        sys.do_store_mut(self.md.conn,
-                          self.md.tx_id,
-                          |store, changes| store.rm(changes, self.md.conn.dom_id, &self.path))
-            .map(|watch_events| {
-                     Response::new_with_events(Box::new(egress::Remove { md: self.md }),
-                                               watch_events)
-                 })
+        writable(&self.md)
+            .and_then(|_| {
+                sys.do_store_mut(self.md.conn, self.md.tx_id, |store, changes| {
+                    store.rm(changes, self.md.conn.dom_id, &self.path)
+                })
+            })
+            .map(|_| Response::new(Box::new(egress::Remove { md: self.md })))
             .unwrap_or_else(|e| Response::new(Box::new(egress::ErrorMsg::from(self.md, &e))))
     }
 }
 
+/// Check that a watch request names no transaction, or one of the
+/// connection's own. Watches are not part of a transaction: like C
+/// xenstored, the transaction is only looked up and the watch takes effect
+/// straight away, firing for changes applied to the store rather than for
+/// those made in the transaction.
+fn outside_transaction(sys: &system::System, md: &Metadata) -> Result<()> {
+    sys.do_store(md.conn, md.tx_id, |_, _| Ok(()))
+}
+
 /// process an incoming watch request
 impl ProcessMessage for ingress::Watch {
-    fn process(&self, sys: &mut MutexGuard<system::System>) -> Response {
+    fn process(&self, sys: &mut system::System) -> Response {
         let mut sys = sys;
-        sys.do_watch_mut(|watches| {
-                              watches.watch(self.md.conn, self.node.clone(), self.token.clone())
-                          })
-            .map(|_| Response::new(Box::new(egress::Watch { md: self.md })))
+        outside_transaction(sys, &self.md)
+            .and_then(|_| {
+                sys.do_watch_mut(|watches| {
+                                      watches.add(Watch {
+                                                      relative: self.relative,
+                                                      ..Watch::new(self.md.conn,
+                                                                   self.node.clone(),
+                                                                   self.token.clone())
+                                                  })
+                                  })
+            })
+            .map(|watch| {
+                     // a new watch always fires once straight away
+                     let mut watch_events = watch::Events::new();
+                     watch_events.push(watch);
+                     sys.fire(watch_events);
+                     Response::new(Box::new(egress::Watch { md: self.md }))
+                 })
             .unwrap_or_else(|e| Response::new(Box::new(egress::ErrorMsg::from(self.md, &e))))
     }
 }
 
 /// process an incoming unwatch request
 impl ProcessMessage for ingress::Unwatch {
-    fn process(&self, sys: &mut MutexGuard<system::System>) -> Response {
+    fn process(&self, sys: &mut system::System) -> Response {
         let mut sys = sys;
-        sys.do_watch_mut(|watches| {
-                              watches.unwatch(self.md.conn, self.node.clone(), self.token.clone())
-                          })
+        outside_transaction(sys, &self.md)
+            .and_then(|_| {
+                sys.do_watch_mut(|watches| {
+                                      watches.unwatch(self.md.conn,
+                                                      self.node.clone(),
+                                                      self.token.clone())
+                                  })
+            })
             .map(|_| Response::new(Box::new(egress::Unwatch { md: self.md })))
             .unwrap_or_else(|e| Response::new(Box::new(egress::ErrorMsg::from(self.md, &e))))
     }
@@ -168,19 +331,22 @@ impl ProcessMessage for ingress::Unwatch {
 
 /// process an incoming transaction start request
 impl ProcessMessage for ingress::TransactionStart {
-    fn process(&self, sys: &mut MutexGuard<system::System>) -> Response {
+    fn process(&self, sys: &mut system::System) -> Response {
         let mut sys = sys;
-        let tx_id = sys.do_transaction_mut(|txns, store| txns.start(self.md.conn, &store));
-        Response::new(Box::new(egress::TransactionStart {
-                                   md: self.md,
-                                   tx_id: tx_id,
-                               }))
+        sys.do_transaction_mut(|txns, store| txns.start(self.md.conn, &store))
+            .map(|tx_id| {
+                     Response::new(Box::new(egress::TransactionStart {
+                                                md: self.md,
+                                                tx_id: tx_id,
+                                            }))
+                 })
+            .unwrap_or_else(|e| Response::new(Box::new(egress::ErrorMsg::from(self.md, &e))))
     }
 }
 
 /// process an incoming transaction end request
 impl ProcessMessage for ingress::TransactionEnd {
-    fn process(&self, sys: &mut MutexGuard<system::System>) -> Response {
+    fn process(&self, sys: &mut system::System) -> Response {
         let mut sys = sys;
         let complete = if self.value {
             transaction::TransactionStatus::Success
@@ -191,99 +357,302 @@ impl ProcessMessage for ingress::TransactionEnd {
         sys.do_transaction_mut(|txns, store| txns.end(store, self.md.conn, self.md.tx_id, complete))
             .map(|changes| {
                      let watch_events = sys.do_watch_mut(|watch_list| watch_list.fire(changes));
-                     Response::new_with_events(Box::new(egress::TransactionEnd { md: self.md }),
-                                               watch_events)
+                     sys.fire(watch_events);
+                     Response::new(Box::new(egress::TransactionEnd { md: self.md }))
                  })
             .unwrap_or_else(|e| Response::new(Box::new(egress::ErrorMsg::from(self.md, &e))))
     }
 }
 
+/// Create the domain path for a newly introduced domain, owned by the
+/// domain itself, unless the toolstack has already made it.
+fn create_domain_path(store: &store::Store,
+                      changes: &store::ChangeSet,
+                      dom_id: wire::DomainId,
+                      path: &path::Path)
+                      -> Result<store::ChangeSet> {
+    if store.read(changes, store::DOM0_DOMAIN_ID, path).is_ok() {
+        return Ok(changes.clone());
+    }
+
+    store.mkdir(changes, store::DOM0_DOMAIN_ID, path.clone()).and_then(|changes| {
+        store.set_perms(&changes,
+                        store::DOM0_DOMAIN_ID,
+                        path,
+                        vec![store::Permission {
+                                 id: dom_id,
+                                 perm: store::Perm::None,
+                             }])
+    })
+}
+
+/// Introduce a domain on behalf of `conn`, giving a newly introduced one its
+/// domain path and firing @introduceDomain for it. Returns whether the
+/// domain was newly introduced, like `DomainList::introduce`.
+pub fn introduce_domain(sys: &mut system::System,
+                        conn: connection::ConnId,
+                        dom_id: wire::DomainId,
+                        mfn: Mfn,
+                        port: EvtChnPort)
+                        -> Result<bool> {
+    let introduced = try!(sys.do_domain_mut(|domains, _| domains.introduce(dom_id, mfn, port)));
+
+    // only a newly introduced domain gets a domain path and fires
+    // @introduceDomain
+    if introduced {
+        let path = path::get_domain_path(dom_id);
+        let created = sys.do_store_mut(conn, transaction::ROOT_TRANSACTION, |store, changes| {
+            create_domain_path(store, changes, dom_id, &path)
+        });
+        if let Err(e) = created {
+            warn!("unable to create {:?}: {}", path, e);
+        }
+
+        let change = store::AppliedChange::IntroduceDomain(dom_id);
+        let watch_events = sys.do_watch_mut(|watch_list| watch_list.fire_single(&change));
+        sys.fire(watch_events);
+    }
+
+    Ok(introduced)
+}
+
+/// process an incoming introduce request
+impl ProcessMessage for ingress::Introduce {
+    fn process(&self, sys: &mut system::System) -> Response {
+        writable(&self.md)
+            .and_then(|_| introduce_domain(sys, self.md.conn, self.dom_id, self.mfn, self.port))
+            .map(|_| Response::new(Box::new(egress::Introduce { md: self.md })))
+            .unwrap_or_else(|e| Response::new(Box::new(egress::ErrorMsg::from(self.md, &e))))
+    }
+}
+
+/// process an incoming is domain introduced request
+impl ProcessMessage for ingress::IsDomainIntroduced {
+    fn process(&self, sys: &mut system::System) -> Response {
+        let introduced = sys.do_domain(|domains| domains.is_introduced(self.dom_id));
+        Response::new(Box::new(egress::IsDomainIntroduced {
+                                   md: self.md,
+                                   introduced: introduced,
+                               }))
+    }
+}
+
+/// process an incoming set target request
+impl ProcessMessage for ingress::SetTarget {
+    fn process(&self, sys: &mut system::System) -> Response {
+        let mut sys = sys;
+        writable(&self.md)
+            .and_then(|_| if sys.do_domain(|domains| domains.is_introduced(self.dom_id)) {
+                // it is the connection on the domain's ring that acts for
+                // the target, not every connection the domain might have
+                let conn = sys.domain_connection(self.dom_id);
+                sys.set_target(conn, Some(self.target));
+                Ok(())
+            } else {
+                Err(Error::ENOENT(format!("domain {} has not been introduced", self.dom_id)))
+            })
+            .map(|_| Response::new(Box::new(egress::SetTarget { md: self.md })))
+            .unwrap_or_else(|e| Response::new(Box::new(egress::ErrorMsg::from(self.md, &e))))
+    }
+}
+
 /// process an incoming release request
 impl ProcessMessage for ingress::Release {
-    fn process(&self, _: &mut MutexGuard<system::System>) -> Response {
-        Response::new(Box::new(egress::Release { md: self.md }))
+    fn process(&self, sys: &mut system::System) -> Response {
+        let mut sys = sys;
+        writable(&self.md)
+            .and_then(|_| sys.do_domain_mut(|domains, _| domains.release(self.dom_id)))
+            .map(|_| {
+                // drop everything the released domain was still holding on to
+                sys.release_connection(self.dom_id);
+                sys.do_transaction_mut(|txns, _| txns.reset_domain(self.dom_id));
+                let watch_events = sys.do_watch_mut(|watch_list| {
+                    let _ = watch_list.reset_domain(self.dom_id);
+                    watch_list.fire_single(&store::AppliedChange::ReleaseDomain(self.dom_id))
+                });
+                sys.fire(watch_events);
+
+                if sys.release_cleanup() {
+                    let path = path::get_domain_path(self.dom_id);
+                    let removed = sys.do_store_mut(self.md.conn,
+                                                   transaction::ROOT_TRANSACTION,
+                                                   |store, changes| {
+                                                       store.rm(changes,
+                                                                store::DOM0_DOMAIN_ID,
+                                                                &path)
+                                                   });
+                    match removed {
+                        Ok(()) |
+                        Err(Error::ENOENT(_)) => (),
+                        Err(e) => warn!("unable to remove {:?}: {}", path, e),
+                    }
+                }
+                Response::new(Box::new(egress::Release { md: self.md }))
+            })
+            .unwrap_or_else(|e| Response::new(Box::new(egress::ErrorMsg::from(self.md, &e))))
     }
 }
 
 /// process an incoming get domain path request
 impl ProcessMessage for ingress::GetDomainPath {
-    fn process(&self, _: &mut MutexGuard<system::System>) -> Response {
+    fn process(&self, _: &mut system::System) -> Response {
+        // like C xenstored, any domain's path can be asked for
         Response::new(Box::new(egress::GetDomainPath {
                                    md: self.md,
-                                   path: path::get_domain_path(self.md.conn.dom_id),
+                                   path: path::get_domain_path(self.dom_id),
                                }))
     }
 }
 
 /// process an incoming resume request
 impl ProcessMessage for ingress::Resume {
-    fn process(&self, _: &mut MutexGuard<system::System>) -> Response {
-        Response::new(Box::new(egress::Resume { md: self.md }))
+    fn process(&self, sys: &mut system::System) -> Response {
+        let mut sys = sys;
+        writable(&self.md)
+            .and_then(|_| sys.do_domain_mut(|domains, _| domains.resume(self.dom_id)))
+            .map(|_| Response::new(Box::new(egress::Resume { md: self.md })))
+            .unwrap_or_else(|e| Response::new(Box::new(egress::ErrorMsg::from(self.md, &e))))
     }
 }
 
 /// process an incoming restrict request
 impl ProcessMessage for ingress::Restrict {
-    fn process(&self, _: &mut MutexGuard<system::System>) -> Response {
-        Response::new(Box::new(egress::Restrict { md: self.md }))
+    fn process(&self, sys: &mut system::System) -> Response {
+        writable(&self.md)
+            .and_then(|_| sys.restrict(self.md.conn, self.dom_id))
+            .map(|_| Response::new(Box::new(egress::Restrict { md: self.md })))
+            .unwrap_or_else(|e| Response::new(Box::new(egress::ErrorMsg::from(self.md, &e))))
+    }
+}
+
+/// process an incoming control request
+impl ProcessMessage for ingress::Control {
+    fn process(&self, sys: &mut system::System) -> Response {
+        control::dispatch(sys, &self.md, &self.args)
+            .map(|value| {
+                     Response::new(Box::new(egress::Control {
+                                                md: self.md,
+                                                value: value,
+                                            }))
+                 })
+            .unwrap_or_else(|e| Response::new(Box::new(egress::ErrorMsg::from(self.md, &e))))
+    }
+}
+
+/// process an incoming get quota request
+impl ProcessMessage for ingress::GetQuota {
+    fn process(&self, sys: &mut system::System) -> Response {
+        let value = match self.quota {
+            Some(ref quota) => sys.quota(self.dom_id, quota).map(|value| value.to_string()),
+            None => Ok(quota::QUOTA_NAMES.join(" ")),
+        };
+
+        value.map(|value| {
+                      Response::new(Box::new(egress::GetQuota {
+                                                 md: self.md,
+                                                 value: value,
+                                             }))
+                  })
+            .unwrap_or_else(|e| Response::new(Box::new(egress::ErrorMsg::from(self.md, &e))))
     }
 }
 
 /// process an error that occurred while parsing
 impl ProcessMessage for ingress::ErrorMsg {
-    fn process(&self, _: &mut MutexGuard<system::System>) -> Response {
+    fn process(&self, _: &mut system::System) -> Response {
         Response::new(Box::new(egress::ErrorMsg::from(self.md, &self.err)))
     }
+
+    fn process_read(&self, _: &system::ReadView) -> Option<Response> {
+        Some(Response::new(Box::new(egress::ErrorMsg::from(self.md, &self.err))))
+    }
 }
 
 /// process an incoming write request
 impl ProcessMessage for ingress::Write {
-    fn process(&self, sys: &mut MutexGuard<system::System>) -> Response {
+    fn process(&self, sys: &mut system::System) -> Response {
         let mut sys = sys;
-        sys.do_store_mut(self.md.conn, self.md.tx_id, |store, changes| {
-                store.write(changes,
-                            self.md.conn.dom_id,
-                            self.path.clone(),
-                            self.rest[0].clone())
+        writable(&self.md)
+            .and_then(|_| {
+                sys.do_store_mut(self.md.conn, self.md.tx_id, |store, changes| {
+                    store.write(changes,
+                                self.md.conn.dom_id,
+                                self.path.clone(),
+                                self.value.clone())
+                })
             })
-            .map(|watch_events| {
-                     let msg = Box::new(egress::Write { md: self.md });
-                     Response::new_with_events(msg, watch_events)
-                 })
+            .map(|_| Response::new(Box::new(egress::Write { md: self.md })))
             .unwrap_or_else(|e| Response::new(Box::new(egress::ErrorMsg::from(self.md, &e))))
     }
 }
 
 /// process an incoming set_perms request
 impl ProcessMessage for ingress::SetPerms {
-    fn process(&self, sys: &mut MutexGuard<system::System>) -> Response {
+    fn process(&self, sys: &mut system::System) -> Response {
         let perms = self.rest
             .iter()
-            .map(|s| {
-                // FIXME: get rid of the unwraps here
-                let id = s[1..].parse::<wire::DomainId>().unwrap();
-                let perm = match s.chars().nth(0).unwrap() {
-                    'r' => store::Perm::Read,
-                    'w' => store::Perm::Write,
-                    'b' => store::Perm::Both,
-                    _ => store::Perm::None,
-                };
-
-                store::Permission {
-                    id: id,
-                    perm: perm,
-                }
-            })
-            .collect();
+            .map(|s| store::Permission::try_from(s))
+            .collect::<Result<Vec<_>>>();
 
         let mut sys = sys;
-        sys.do_store_mut(self.md.conn, self.md.tx_id, |store, changes| {
-                store.set_perms(changes, self.md.conn.dom_id, &self.path, perms)
+        writable(&self.md)
+            .and_then(|_| perms)
+            .and_then(|perms| {
+                sys.do_store_mut(self.md.conn, self.md.tx_id, |store, changes| {
+                    store.set_perms(changes, self.md.conn.dom_id, &self.path, perms)
+                })
             })
-            .map(|watch_events| {
-                     Response::new_with_events(Box::new(egress::SetPerms { md: self.md }),
-                                               watch_events)
-                 })
+            .map(|_| Response::new(Box::new(egress::SetPerms { md: self.md })))
             .unwrap_or_else(|e| Response::new(Box::new(egress::ErrorMsg::from(self.md, &e))))
     }
 }
+
+#[cfg(test)]
+mod test {
+    extern crate mio;
+
+    use self::mio::Token;
+    use super::super::connection::ConnId;
+    use super::super::domain::DomainList;
+    use super::super::path;
+    use super::super::store::{self, Store};
+    use super::super::system::System;
+    use super::super::transaction::{self, TransactionList};
+    use super::super::watch::{WatchList, WPath, WToken};
+    use super::*;
+
+    #[test]
+    fn introduce_dom0() {
+        let mut system = System::new(Store::new(),
+                                     WatchList::new(),
+                                     TransactionList::new(),
+                                     DomainList::new());
+        let conn = system.new_connection(store::DOM0_DOMAIN_ID);
+        let watcher = ConnId::new(Token(100), store::DOM0_DOMAIN_ID);
+        let special = WPath::try_from(store::DOM0_DOMAIN_ID, "@introduceDomain").unwrap();
+        system.do_watch_mut(|watch_list| watch_list.watch(watcher, special, WToken::from("t")))
+            .unwrap();
+
+        assert_eq!(introduce_domain(&mut system, conn, store::DOM0_DOMAIN_ID, 0, 3).unwrap(),
+                   true);
+        assert!(system.do_domain(|domains| domains.is_introduced(store::DOM0_DOMAIN_ID)));
+        assert_eq!(system.fired().len(), 1);
+
+        // dom0 owns its domain path and keeps it to itself
+        let path = path::get_domain_path(store::DOM0_DOMAIN_ID);
+        let perms = system.do_store(conn, transaction::ROOT_TRANSACTION, |store, changes| {
+                store.get_perms(changes, store::DOM0_DOMAIN_ID, &path)
+            })
+            .unwrap();
+        assert_eq!(perms,
+                   vec![store::Permission {
+                            id: store::DOM0_DOMAIN_ID,
+                            perm: store::Perm::None,
+                        }]);
+
+        // starting up again with the same ring changes nothing
+        assert_eq!(introduce_domain(&mut system, conn, store::DOM0_DOMAIN_ID, 0, 3).unwrap(),
+                   false);
+        assert_eq!(system.fired().len(), 1);
+    }
+}
@@ -0,0 +1,132 @@
+/**
+    xenstore-rs provides a Rust based xenstore implementation.
+    Copyright (C) 2016 Star Lab Corp.
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License along
+    with this program; if not, see <http://www.gnu.org/licenses/>.
+**/
+
+// Counters and gauges describing the daemon, written out in the Prometheus
+// text format so monitoring in dom0 can scrape them with xenstore-control.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use super::tracelog::msg_type_name;
+use super::wire;
+
+/// What every metric's name starts with
+const PREFIX: &'static str = "rxenstored";
+
+/// A measurement of how things are right now, taken when the metrics are
+/// asked for.
+pub struct Gauge {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub value: u64,
+}
+
+/// The `Metrics` type.
+///
+/// Counts the requests handled, by message type, and the errors returned
+/// for them, by error.
+pub struct Metrics {
+    requests: BTreeMap<&'static str, u64>,
+    errors: BTreeMap<String, u64>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            requests: BTreeMap::new(),
+            errors: BTreeMap::new(),
+        }
+    }
+
+    /// Count a request of `msg_type`, and the error in `reply` if it is one.
+    pub fn record(&mut self, msg_type: u32, reply: &(wire::Header, wire::Body)) {
+        *self.requests.entry(msg_type_name(msg_type)).or_insert(0) += 1;
+
+        if reply.0.msg_type == wire::XS_ERROR {
+            let error = reply.1
+                .fields()
+                .first()
+                .map(|name| String::from_utf8_lossy(name).into_owned())
+                .unwrap_or(String::new());
+            *self.errors.entry(error).or_insert(0) += 1;
+        }
+    }
+
+    /// Write out the counters followed by `gauges`.
+    pub fn render(&self, gauges: &[Gauge]) -> String {
+        let mut out = String::new();
+
+        let _ = writeln!(out, "# HELP {}_requests_total Requests handled, by message type", PREFIX);
+        let _ = writeln!(out, "# TYPE {}_requests_total counter", PREFIX);
+        for (msg_type, count) in &self.requests {
+            let _ = writeln!(out, "{}_requests_total{{type=\"{}\"}} {}", PREFIX, msg_type, count);
+        }
+
+        let _ = writeln!(out, "# HELP {}_errors_total Errors returned, by error", PREFIX);
+        let _ = writeln!(out, "# TYPE {}_errors_total counter", PREFIX);
+        for (error, count) in &self.errors {
+            let _ = writeln!(out, "{}_errors_total{{error=\"{}\"}} {}", PREFIX, error, count);
+        }
+
+        for gauge in gauges {
+            let _ = writeln!(out, "# HELP {}_{} {}", PREFIX, gauge.name, gauge.help);
+            let _ = writeln!(out, "# TYPE {}_{} gauge", PREFIX, gauge.name);
+            let _ = writeln!(out, "{}_{} {}", PREFIX, gauge.name, gauge.value);
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::wire;
+
+    fn reply(msg_type: u32, body: &[u8]) -> (wire::Header, wire::Body) {
+        (wire::Header {
+             msg_type: msg_type,
+             req_id: 0,
+             tx_id: 0,
+             len: body.len() as u32,
+         },
+         wire::Body::from(vec![body.to_owned()]))
+    }
+
+    #[test]
+    fn counts() {
+        let mut metrics = Metrics::new();
+        metrics.record(wire::XS_READ, &reply(wire::XS_READ, b"value"));
+        metrics.record(wire::XS_READ, &reply(wire::XS_ERROR, b"ENOENT\0"));
+        metrics.record(wire::XS_WRITE, &reply(wire::XS_ERROR, b"EACCES\0"));
+
+        let gauges = [Gauge {
+                          name: "nodes",
+                          help: "Nodes in the store",
+                          value: 7,
+                      }];
+        let rendered = metrics.render(&gauges);
+        let lines = rendered.lines().collect::<Vec<_>>();
+
+        assert!(lines.contains(&"rxenstored_requests_total{type=\"read\"} 2"));
+        assert!(lines.contains(&"rxenstored_requests_total{type=\"write\"} 1"));
+        assert!(lines.contains(&"rxenstored_errors_total{error=\"ENOENT\"} 1"));
+        assert!(lines.contains(&"rxenstored_errors_total{error=\"EACCES\"} 1"));
+        assert!(lines.contains(&"# TYPE rxenstored_nodes gauge"));
+        assert!(lines.contains(&"rxenstored_nodes 7"));
+    }
+}
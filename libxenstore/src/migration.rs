@@ -0,0 +1,655 @@
+/**
+    xenstore-rs provides a Rust based xenstore implementation.
+    Copyright (C) 2016 Star Lab Corp.
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License along
+    with this program; if not, see <http://www.gnu.org/licenses/>.
+**/
+
+// Save and restore the daemon's state across a live update.
+
+// The state is written in the record based stream described by Xen's
+// `docs/designs/xenstore-migration.md`: a header followed by connection,
+// watch, transaction and node records, each padded to 8 bytes. Socket
+// connections can't survive the update so their watches and transactions
+// are dropped on restore; shared ring connections keep their `ConnId`.
+
+extern crate mio;
+
+use bytes::{Buf, BufMut, LittleEndian};
+use self::mio::Token;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::Arc;
+use super::authz::Authorizer;
+use super::connection::ConnId;
+use super::domain::DomainList;
+use super::path::Path;
+use super::quota::Quota;
+use super::store::{Basename, Change, ChangeSet, Children, Node, Perm, Permission, Store, Value};
+use super::system::System;
+use super::transaction::TransactionList;
+use super::watch::{Watch, WatchList, WPath, WToken};
+use super::wire;
+
+const IDENT: &'static [u8] = b"xenstore";
+const VERSION: u32 = 1;
+const FLAGS_LITTLE_ENDIAN: u32 = 0;
+
+const REC_END: u32 = 0x00;
+const REC_GLOBAL_DATA: u32 = 0x01;
+const REC_CONNECTION_DATA: u32 = 0x02;
+const REC_WATCH_DATA: u32 = 0x03;
+const REC_TRANSACTION_DATA: u32 = 0x04;
+const REC_NODE_DATA: u32 = 0x05;
+/// Not part of the documented stream: the ring page of an introduced domain,
+/// which rxenstored maps by frame number rather than through the grant table
+const REC_RXENSTORED_DOMAIN: u32 = 0x80;
+
+const CONN_SHARED_RING: u16 = 0;
+const CONN_SOCKET: u16 = 1;
+
+const ACCESS_READ: u16 = 0;
+const ACCESS_WRITTEN: u16 = 1;
+const ACCESS_DELETED: u16 = 2;
+
+const NO_FD: u32 = 0xffffffff;
+const DOMID_INVALID: u16 = 0x7ff4;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Connection id 0 means "not in a transaction" in node records
+fn conn_id(conn: ConnId) -> u32 {
+    let Token(token) = conn.token;
+    token as u32 + 1
+}
+
+fn put_record(out: &mut Vec<u8>, ty: u32, body: &[u8]) {
+    out.put_u32::<LittleEndian>(ty);
+    out.put_u32::<LittleEndian>(body.len() as u32);
+    out.put_slice(body);
+    while out.len() % 8 != 0 {
+        out.put_u8(0);
+    }
+}
+
+/// Strings are stored with their NUL terminator, which counts toward their length
+fn string_len(bytes: &[u8]) -> io::Result<u16> {
+    if bytes.len() >= u16::max_value() as usize {
+        return Err(invalid("string too long for the migration stream"));
+    }
+    Ok(bytes.len() as u16 + 1)
+}
+
+fn put_string(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.put_slice(bytes);
+    out.put_u8(0);
+}
+
+fn perm_to_u8(perm: Perm) -> u8 {
+    match perm {
+        Perm::None => b'n',
+        Perm::Read => b'r',
+        Perm::Write => b'w',
+        Perm::Both => b'b',
+    }
+}
+
+fn perm_from_u8(perm: u8) -> io::Result<Perm> {
+    match perm {
+        b'n' => Ok(Perm::None),
+        b'r' => Ok(Perm::Read),
+        b'w' => Ok(Perm::Write),
+        b'b' => Ok(Perm::Both),
+        _ => Err(invalid("unknown permission in migration stream")),
+    }
+}
+
+fn node_record(conn_id: u32,
+               tx_id: wire::TxId,
+               access: u16,
+               path: &Path,
+               value: &[u8],
+               permissions: &[Permission])
+               -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    body.put_u32::<LittleEndian>(conn_id);
+    body.put_u32::<LittleEndian>(tx_id);
+    body.put_u16::<LittleEndian>(try!(string_len(path.as_bytes())));
+    body.put_u16::<LittleEndian>(value.len() as u16);
+    body.put_u16::<LittleEndian>(access);
+    body.put_u16::<LittleEndian>(permissions.len() as u16);
+    for permission in permissions {
+        body.put_u8(perm_to_u8(permission.perm));
+        body.put_u8(0);
+        body.put_u16::<LittleEndian>(permission.id as u16);
+    }
+    put_string(&mut body, path.as_bytes());
+    if value.len() > u16::max_value() as usize {
+        return Err(invalid("value too long for the migration stream"));
+    }
+    body.put_slice(value);
+    Ok(body)
+}
+
+/// Write out everything needed to carry on where `sys` left off.
+pub fn dump(sys: &System) -> io::Result<Vec<u8>> {
+    sys.do_all(|store, watches, txns, domains| {
+        let mut out = Vec::new();
+        out.put_slice(IDENT);
+        out.put_u32::<LittleEndian>(VERSION);
+        out.put_u32::<LittleEndian>(FLAGS_LITTLE_ENDIAN);
+
+        // no file descriptors are handed over
+        let mut body = Vec::new();
+        body.put_u32::<LittleEndian>(NO_FD);
+        body.put_u32::<LittleEndian>(NO_FD);
+        put_record(&mut out, REC_GLOBAL_DATA, &body);
+
+        for domain in domains.iter() {
+            let mut body = Vec::new();
+            body.put_u32::<LittleEndian>(domain.dom_id);
+            body.put_u32::<LittleEndian>(domain.port as u32);
+            body.put_u64::<LittleEndian>(domain.mfn);
+            put_record(&mut out, REC_RXENSTORED_DOMAIN, &body);
+        }
+
+        // every connection that has some state worth keeping
+        let txn_list = txns.list();
+        let mut conns = watches.iter()
+            .map(|watch| watch.conn)
+            .chain(txn_list.iter().map(|&(_, conn, _)| conn))
+            .collect::<HashSet<ConnId>>()
+            .into_iter()
+            .collect::<Vec<ConnId>>();
+        conns.sort_by_key(|conn| conn_id(*conn));
+
+        for conn in conns {
+            let mut body = Vec::new();
+            body.put_u32::<LittleEndian>(conn_id(conn));
+            match domains.get(conn.dom_id) {
+                Some(domain) => {
+                    body.put_u16::<LittleEndian>(CONN_SHARED_RING);
+                    body.put_u16::<LittleEndian>(0);
+                    body.put_u16::<LittleEndian>(conn.dom_id as u16);
+                    body.put_u16::<LittleEndian>(DOMID_INVALID);
+                    body.put_u32::<LittleEndian>(domain.port as u32);
+                }
+                None => {
+                    body.put_u16::<LittleEndian>(CONN_SOCKET);
+                    body.put_u16::<LittleEndian>(0);
+                    body.put_u32::<LittleEndian>(NO_FD);
+                    body.put_u32::<LittleEndian>(0);
+                }
+            }
+            // nothing is ever left half read or half written
+            body.put_u32::<LittleEndian>(0);
+            body.put_u32::<LittleEndian>(0);
+            body.put_u32::<LittleEndian>(0);
+            put_record(&mut out, REC_CONNECTION_DATA, &body);
+        }
+
+        for watch in watches.iter() {
+            // relative watches are written the way the guest gave them
+            let node = if watch.relative {
+                watch.node.as_relative_bytes(watch.conn.dom_id)
+            } else {
+                watch.node.as_bytes()
+            };
+            let token = watch.token.as_bytes();
+
+            let mut body = Vec::new();
+            body.put_u32::<LittleEndian>(conn_id(watch.conn));
+            body.put_u16::<LittleEndian>(try!(string_len(node)));
+            body.put_u16::<LittleEndian>(try!(string_len(token)));
+            put_string(&mut body, node);
+            put_string(&mut body, token);
+            put_record(&mut out, REC_WATCH_DATA, &body);
+        }
+
+        for &(tx_id, conn, changes) in &txn_list {
+            let mut body = Vec::new();
+            body.put_u32::<LittleEndian>(conn_id(conn));
+            body.put_u32::<LittleEndian>(tx_id);
+            put_record(&mut out, REC_TRANSACTION_DATA, &body);
+
+            for path in changes.reads() {
+                let body = try!(node_record(conn_id(conn), tx_id, ACCESS_READ, &path, b"", &[]));
+                put_record(&mut out, REC_NODE_DATA, &body);
+            }
+
+            for change in changes.changes() {
+                if let Change::Write(ref node) = *change {
+                    let body = try!(node_record(conn_id(conn),
+                                                tx_id,
+                                                ACCESS_WRITTEN,
+                                                &node.path,
+                                                node.value.as_bytes(),
+                                                &node.permissions));
+                    put_record(&mut out, REC_NODE_DATA, &body);
+                }
+            }
+
+            // removed subtrees are written out node by node
+            for path in changes.removals(store) {
+                let body = try!(node_record(conn_id(conn), tx_id, ACCESS_DELETED, &path, b"", &[]));
+                put_record(&mut out, REC_NODE_DATA, &body);
+            }
+        }
+
+        for node in store.nodes() {
+            let body = try!(node_record(0,
+                                        0,
+                                        ACCESS_WRITTEN,
+                                        &node.path,
+                                        node.value.as_bytes(),
+                                        &node.permissions));
+            put_record(&mut out, REC_NODE_DATA, &body);
+        }
+
+        put_record(&mut out, REC_END, &[]);
+        Ok(out)
+    })
+}
+
+/// A node record as read from the stream
+struct NodeData {
+    access: u16,
+    path: Path,
+    value: Value,
+    permissions: Vec<Permission>,
+}
+
+impl NodeData {
+    fn into_node(self, children: Children) -> Node {
+        Node {
+            path: self.path,
+            value: self.value,
+            children: children,
+            permissions: self.permissions,
+        }
+    }
+}
+
+fn get_u16(input: &mut io::Cursor<&[u8]>) -> io::Result<u16> {
+    if input.remaining() < 2 {
+        return Err(invalid("truncated migration record"));
+    }
+    Ok(input.get_u16::<LittleEndian>())
+}
+
+fn get_u32(input: &mut io::Cursor<&[u8]>) -> io::Result<u32> {
+    if input.remaining() < 4 {
+        return Err(invalid("truncated migration record"));
+    }
+    Ok(input.get_u32::<LittleEndian>())
+}
+
+fn get_bytes(input: &mut io::Cursor<&[u8]>, len: usize) -> io::Result<Vec<u8>> {
+    if input.remaining() < len {
+        return Err(invalid("truncated migration record"));
+    }
+
+    let mut bytes = vec![0; len];
+    input.copy_to_slice(&mut bytes);
+    Ok(bytes)
+}
+
+fn get_terminated(input: &mut io::Cursor<&[u8]>, len: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = try!(get_bytes(input, len));
+    // drop the NUL terminator, if there is one
+    if bytes.last() == Some(&0) {
+        bytes.pop();
+    }
+    Ok(bytes)
+}
+
+fn get_string(input: &mut io::Cursor<&[u8]>, len: usize) -> io::Result<String> {
+    let bytes = try!(get_terminated(input, len));
+    String::from_utf8(bytes).map_err(|_| invalid("migration stream is not UTF-8"))
+}
+
+fn get_path(input: &mut io::Cursor<&[u8]>, len: usize) -> io::Result<Path> {
+    let path = try!(get_string(input, len));
+    Path::try_from(0, &path).map_err(|_| invalid("invalid path in migration stream"))
+}
+
+fn get_node(input: &mut io::Cursor<&[u8]>) -> io::Result<(u32, wire::TxId, NodeData)> {
+    let conn_id = try!(get_u32(input));
+    let tx_id = try!(get_u32(input));
+    let path_len = try!(get_u16(input)) as usize;
+    let value_len = try!(get_u16(input)) as usize;
+    let access = try!(get_u16(input));
+    let perm_count = try!(get_u16(input));
+
+    let mut permissions = Vec::new();
+    for _ in 0..perm_count {
+        let perm = try!(get_u16(input));
+        let id = try!(get_u16(input));
+        permissions.push(Permission {
+                             id: id as wire::DomainId,
+                             perm: try!(perm_from_u8((perm & 0xff) as u8)),
+                         });
+    }
+
+    let path = try!(get_path(input, path_len));
+    // values aren't NUL terminated and may be binary
+    let value = try!(get_bytes(input, value_len));
+
+    Ok((conn_id,
+        tx_id,
+        NodeData {
+            access: access,
+            path: path,
+            value: Value::from(value),
+            permissions: permissions,
+        }))
+}
+
+/// Work out the children of every node from their paths
+fn children_of(paths: &[&Path]) -> HashMap<Path, Children> {
+    let mut children = HashMap::new();
+    for path in paths {
+        if let (Some(parent), Some(basename)) = (path.parent(), path.basename()) {
+            children.entry(parent)
+                .or_insert_with(Children::new)
+                .insert(Basename::from(basename), ());
+        }
+    }
+    children
+}
+
+/// Rebuild a `System` from the output of `dump`.
+///
+/// The store and watches are limited by `quota`, access to the store is
+/// decided by `authorizer` and transactions are put back into `txns`.
+///
+/// # Errors
+///
+/// * `io::ErrorKind::InvalidData` if the input is not a migration stream
+pub fn restore(bytes: &[u8],
+               quota: Quota,
+               authorizer: Arc<Authorizer>,
+               mut txns: TransactionList)
+               -> io::Result<System> {
+    if bytes.len() < IDENT.len() + 8 || !bytes.starts_with(IDENT) {
+        return Err(invalid("not a migration stream"));
+    }
+
+    let mut input = io::Cursor::new(&bytes[IDENT.len()..]);
+    if try!(get_u32(&mut input)) != VERSION {
+        return Err(invalid("unsupported migration stream version"));
+    }
+    if try!(get_u32(&mut input)) != FLAGS_LITTLE_ENDIAN {
+        return Err(invalid("unsupported migration stream byte order"));
+    }
+
+    let mut domains = DomainList::new();
+    let mut conns = HashMap::new();
+    let mut watches = Vec::new();
+    let mut transactions = Vec::new();
+    let mut committed = Vec::new();
+    let mut uncommitted = HashMap::new();
+
+    loop {
+        let ty = try!(get_u32(&mut input));
+        let len = try!(get_u32(&mut input)) as usize;
+        if input.remaining() < len {
+            return Err(invalid("truncated migration stream"));
+        }
+
+        let start = input.position() as usize;
+        let record = &input.get_ref()[start..start + len];
+        let mut body = io::Cursor::new(record);
+
+        match ty {
+            REC_END => break,
+            REC_GLOBAL_DATA => {}
+            REC_RXENSTORED_DOMAIN => {
+                let dom_id = try!(get_u32(&mut body));
+                let port = try!(get_u32(&mut body));
+                if body.remaining() < 8 {
+                    return Err(invalid("truncated migration record"));
+                }
+                let mfn = body.get_u64::<LittleEndian>();
+                try!(domains.introduce(dom_id, mfn, port as u16)
+                    .map_err(|_| invalid("domain introduced twice in migration stream")));
+            }
+            REC_CONNECTION_DATA => {
+                let id = try!(get_u32(&mut body));
+                let conn_type = try!(get_u16(&mut body));
+                let _flags = try!(get_u16(&mut body));
+                let dom_id = try!(get_u16(&mut body));
+
+                // sockets are gone once we restart so only rings are kept
+                if conn_type == CONN_SHARED_RING {
+                    let conn = ConnId::new(Token(id as usize - 1), dom_id as wire::DomainId);
+                    conns.insert(id, conn);
+                }
+            }
+            REC_WATCH_DATA => {
+                let id = try!(get_u32(&mut body));
+                let node_len = try!(get_u16(&mut body)) as usize;
+                let token_len = try!(get_u16(&mut body)) as usize;
+                let node = try!(get_string(&mut body, node_len));
+                let token = WToken::from(&try!(get_terminated(&mut body, token_len))[..]);
+                watches.push((id, node, token));
+            }
+            REC_TRANSACTION_DATA => {
+                let id = try!(get_u32(&mut body));
+                let tx_id = try!(get_u32(&mut body));
+                transactions.push((id, tx_id));
+            }
+            REC_NODE_DATA => {
+                let (id, tx_id, node) = try!(get_node(&mut body));
+                if id == 0 {
+                    committed.push(node);
+                } else {
+                    uncommitted.entry((id, tx_id)).or_insert_with(Vec::new).push(node);
+                }
+            }
+            _ => return Err(invalid("unknown record in migration stream")),
+        }
+
+        // skip over the record and its padding
+        let next = start + len + (8 - len % 8) % 8;
+        input.set_position(next as u64);
+    }
+
+    // the node tree
+    let children = {
+        let paths = committed.iter().map(|node| &node.path).collect::<Vec<&Path>>();
+        children_of(&paths)
+    };
+    let nodes = committed.into_iter()
+        .map(|node| {
+                 let kids = children.get(&node.path).cloned().unwrap_or(Children::new());
+                 node.into_node(kids)
+             })
+        .collect::<Vec<Node>>();
+    let store = Store::restore(0, nodes, quota, authorizer);
+
+    let mut watch_list = WatchList::with_quota(quota);
+    // wildcard watches were allowed when they were registered, whether or
+    // not new ones are allowed once the daemon is told its options
+    watch_list.set_wildcards(true);
+    for (id, node, token) in watches {
+        if let Some(conn) = conns.get(&id) {
+            let relative = !node.starts_with('/');
+            let node = try!(WPath::try_from(conn.dom_id, &node)
+                .map_err(|_| invalid("invalid watch in migration stream")));
+            try!(watch_list.add(Watch { relative: relative, ..Watch::new(*conn, node, token) })
+                .map_err(|_| invalid("duplicate watch in migration stream")));
+        }
+    }
+
+    for (id, tx_id) in transactions {
+        let conn = match conns.get(&id) {
+            Some(conn) => *conn,
+            None => continue,
+        };
+
+        let records = uncommitted.remove(&(id, tx_id)).unwrap_or(Vec::new());
+        let mut reads = Vec::new();
+        let mut written = Vec::new();
+        let mut deleted = HashSet::new();
+        for node in records {
+            match node.access {
+                ACCESS_READ => reads.push(node.path),
+                ACCESS_DELETED => {
+                    deleted.insert(node.path);
+                }
+                _ => written.push(node),
+            }
+        }
+
+        // children as the transaction sees them
+        let created = {
+            let paths = written.iter().map(|node| &node.path).collect::<Vec<&Path>>();
+            children_of(&paths)
+        };
+        let removed = {
+            let paths = deleted.iter().collect::<Vec<&Path>>();
+            children_of(&paths)
+        };
+
+        let mut changes = Vec::new();
+        for node in written {
+            let mut kids = store.nodes()
+                .find(|n| n.path == node.path)
+                .map(|n| n.children.clone())
+                .unwrap_or(Children::new());
+            if let Some(created) = created.get(&node.path) {
+                kids.extend(created.keys().map(|kid| (kid.clone(), ())));
+            }
+            if let Some(removed) = removed.get(&node.path) {
+                for kid in removed.keys() {
+                    kids.remove(kid);
+                }
+            }
+            changes.push(Change::Write(node.into_node(kids)));
+        }
+        for path in deleted {
+            changes.push(Change::Remove(Node {
+                                            path: path,
+                                            value: Value::new(),
+                                            children: Children::new(),
+                                            permissions: Vec::new(),
+                                        }));
+        }
+
+        let changes = ChangeSet::restore(&store, changes, reads);
+        try!(txns.restore(conn, tx_id, changes)
+            .map_err(|_| invalid("duplicate transaction in migration stream")));
+    }
+
+    let mut sys = System::new(store, watch_list, txns, domains);
+    for conn in conns.values() {
+        sys.adopt_connection(*conn);
+    }
+    Ok(sys)
+}
+
+#[cfg(test)]
+mod test {
+    extern crate mio;
+
+    use self::mio::Token;
+    use std::io;
+    use super::super::authz::PermissionAuthorizer;
+    use super::super::connection::ConnId;
+    use super::super::domain::DomainList;
+    use super::super::path::Path;
+    use super::super::quota::Quota;
+    use super::super::store::{self, Store, Value};
+    use super::super::system::System;
+    use super::super::transaction::TransactionList;
+    use super::super::watch::{WatchList, WPath};
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let ring = ConnId::new(Token(3), 5);
+        let socket = ConnId::new(Token(4), store::DOM0_DOMAIN_ID);
+        let committed = Path::try_from(store::DOM0_DOMAIN_ID, "/committed").unwrap();
+        let pending = Path::try_from(store::DOM0_DOMAIN_ID, "/committed/pending").unwrap();
+
+        let mut sys = System::new(Store::new(),
+                                  WatchList::new(),
+                                  TransactionList::new(),
+                                  DomainList::new());
+        sys.do_domain_mut(|domains, _| domains.introduce(5, 0x1234, 7)).unwrap();
+        sys.do_store_mut(socket, 0, |store, changes| {
+                store.write(changes,
+                            store::DOM0_DOMAIN_ID,
+                            committed.clone(),
+                            Value::from("value"))
+            })
+            .unwrap();
+        for conn in &[ring, socket] {
+            sys.do_watch_mut(|watches| {
+                    watches.watch(*conn,
+                                  WPath::Normal(committed.clone()),
+                                  WToken::from("token"))
+                })
+                .unwrap();
+        }
+        let tx_id = sys.do_transaction_mut(|txns, store| txns.start(ring, store)).unwrap();
+        sys.do_store_mut(ring, tx_id, |store, changes| {
+                store.write(changes,
+                            store::DOM0_DOMAIN_ID,
+                            pending.clone(),
+                            Value::from(b"pending\xff\0".to_vec()))
+            })
+            .unwrap();
+
+        let dump = dump(&sys).unwrap();
+        let mut restored = restore(&dump,
+                                   Quota::new(),
+                                   Arc::new(PermissionAuthorizer),
+                                   TransactionList::new())
+            .unwrap();
+
+        // the ring connection comes back with everything it had
+        assert_eq!(restored.domain_connection(5), ring);
+        assert_eq!(restored.do_domain(|domains| domains.get(5).map(|d| (d.mfn, d.port))),
+                   Some((0x1234, 7)));
+        assert_eq!(restored.do_store(ring, tx_id, |store, changes| {
+                           store.read(changes, store::DOM0_DOMAIN_ID, &pending)
+                       })
+                       .unwrap(),
+                   Value::from(b"pending\xff\0".to_vec()));
+
+        // the socket connection's watch is gone and the transaction is still pending
+        restored.do_all(|store, watches, _, _| {
+            assert_eq!(watches.iter().map(|watch| watch.conn).collect::<Vec<ConnId>>(),
+                       vec![ring]);
+            assert_eq!(store.nodes().filter(|node| node.path == committed).count(), 1);
+            assert_eq!(store.nodes().filter(|node| node.path == pending).count(), 0);
+        });
+    }
+
+    #[test]
+    fn restore_garbage() {
+        match restore(b"not a migration stream",
+                      Quota::new(),
+                      Arc::new(PermissionAuthorizer),
+                      TransactionList::new()) {
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "restored garbage"),
+        }
+    }
+}
@@ -16,14 +16,17 @@
     with this program; if not, see <http://www.gnu.org/licenses/>.
 **/
 
+use std::cmp::Ordering;
 use std::iter::{IntoIterator, Iterator};
 use std::os::unix::ffi::OsStrExt;
 use std::path;
+use std::sync::Arc;
 use super::error::{Error, Result};
 use super::wire;
 
+// copies of a path share its buffer
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
-pub struct Path(path::PathBuf);
+pub struct Path(Arc<path::PathBuf>);
 pub struct ParentIterator(Option<path::PathBuf>);
 
 const MAX_RELATIVE: usize = 2048;
@@ -40,16 +43,34 @@ impl Iterator for ParentIterator {
             }
         };
 
-        Some(Path(match current.parent() {
-                      Some(ref p) => {
-            self.0 = Some(p.to_path_buf());
-            current.clone()
-        }
-                      None => {
-            self.0 = None;
-            path::PathBuf::from("/")
-        }
-                  }))
+        let path = match current.parent() {
+            Some(ref p) => {
+                self.0 = Some(p.to_path_buf());
+                current.clone()
+            }
+            None => {
+                self.0 = None;
+                path::PathBuf::from("/")
+            }
+        };
+
+        Some(Path(Arc::new(path)))
+    }
+}
+
+// paths order component by component, so everything below a path sorts
+// directly after it. Comparing the bytes with the separator below every
+// other character gives the same order as splitting the paths up would.
+impl Ord for Path {
+    fn cmp(&self, other: &Path) -> Ordering {
+        let key = |b: &u8| if *b == b'/' { 0 } else { *b };
+        self.as_bytes().iter().map(&key).cmp(other.as_bytes().iter().map(&key))
+    }
+}
+
+impl PartialOrd for Path {
+    fn partial_cmp(&self, other: &Path) -> Option<Ordering> {
+        Some(self.cmp(other))
     }
 }
 
@@ -58,12 +79,20 @@ impl IntoIterator for Path {
     type IntoIter = ParentIterator;
 
     fn into_iter(self) -> Self::IntoIter {
-        ParentIterator(Some(self.0.clone()))
+        ParentIterator(Some((*self.0).clone()))
+    }
+}
+
+/// The characters C xenstored accepts in node names, plus `.`
+fn valid_char(c: char) -> bool {
+    match c {
+        'a'...'z' | 'A'...'Z' | '0'...'9' | '-' | '/' | '_' | '@' | '.' => true,
+        _ => false,
     }
 }
 
 pub fn get_domain_path(dom_id: wire::DomainId) -> Path {
-    Path(path::PathBuf::from(format!("/local/domain/{}/", dom_id)))
+    Path(Arc::new(path::PathBuf::from(format!("/local/domain/{}", dom_id))))
 }
 
 impl Path {
@@ -80,6 +109,22 @@ impl Path {
             return Err(Error::EINVAL("trailing / is not allowed".into()));
         }
 
+        if let Some(c) = s.chars().find(|c| !valid_char(*c)) {
+            return Err(Error::EINVAL(format!("{:?} is not allowed in a path", c)));
+        }
+
+        for component in s.split('/') {
+            if component == "." || component == ".." {
+                return Err(Error::EINVAL("relative components are not allowed".into()));
+            }
+
+            // only the special watch paths may start with @, and they aren't nodes
+            if component.starts_with('@') {
+                return Err(Error::EINVAL(format!("{} is reserved for special watch paths",
+                                                 component)));
+            }
+        }
+
         let input = path::PathBuf::from(s);
         let internal = {
             if input.is_absolute() {
@@ -97,13 +142,13 @@ impl Path {
                                                      MAX_RELATIVE)));
                 }
 
-                let mut real = get_domain_path(dom_id);
-                real.0.push(input);
-                real.0
+                let mut real = path::PathBuf::from(format!("/local/domain/{}/", dom_id));
+                real.push(input);
+                real
             }
         };
 
-        Ok(Path(internal))
+        Ok(Path(Arc::new(internal)))
     }
 
     pub fn as_bytes(&self) -> &[u8] {
@@ -122,17 +167,17 @@ impl Path {
         self.0
             .as_path()
             .parent()
-            .map(|parent| Path(parent.to_path_buf()))
+            .map(|parent| Path(Arc::new(parent.to_path_buf())))
     }
 
     pub fn push(&self, component: &str) -> Path {
-        let mut path = self.0.clone();
+        let mut path = (*self.0).clone();
         path.push(component);
-        Path(path)
+        Path(Arc::new(path))
     }
 
     pub fn is_child(&self, parent: &Path) -> bool {
-        self.0.starts_with(&parent.0)
+        self.0.starts_with(&*parent.0)
     }
 }
 
@@ -140,6 +185,28 @@ impl Path {
 mod test {
     use super::*;
 
+    #[test]
+    fn ordered_by_component() {
+        let mut paths = ["/a/b-c", "/a/b/c", "/a", "/a/b.c", "/a/b", "/a-b", "/a/b/c/d", "/"]
+            .iter()
+            .map(|p| Path::try_from(0, p).unwrap())
+            .collect::<Vec<Path>>();
+        paths.sort();
+
+        // everything below a path comes straight after it
+        let sorted = paths.iter().map(|p| p.as_bytes()).collect::<Vec<&[u8]>>();
+        assert_eq!(sorted,
+                   vec![&b"/"[..],
+                        &b"/a"[..],
+                        &b"/a/b"[..],
+                        &b"/a/b/c"[..],
+                        &b"/a/b/c/d"[..],
+                        &b"/a/b-c"[..],
+                        &b"/a/b.c"[..],
+                        &b"/a-b"[..]]);
+        assert!(paths.windows(2).all(|w| w[0].0.components().lt(w[1].0.components())));
+    }
+
     #[test]
     #[should_panic]
     fn empty_path() {
@@ -158,6 +225,41 @@ mod test {
         Path::try_from(0, "/root/").unwrap();
     }
 
+    #[test]
+    #[should_panic]
+    fn invalid_char() {
+        Path::try_from(0, "/root/foo bar").unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn control_char() {
+        Path::try_from(0, "/root/foo\nbar").unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn dot_component() {
+        Path::try_from(0, "/root/./bar").unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn dot_dot_component() {
+        Path::try_from(1, "../2/bar").unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn special_component() {
+        Path::try_from(0, "@introduceDomain").unwrap();
+    }
+
+    #[test]
+    fn valid_chars() {
+        Path::try_from(0, "/local/domain/1/device/vif-0/mac_addr.v4@eth0").unwrap();
+    }
+
     #[test]
     #[should_panic]
     fn long_relative() {
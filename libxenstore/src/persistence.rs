@@ -0,0 +1,554 @@
+/**
+    xenstore-rs provides a Rust based xenstore implementation.
+    Copyright (C) 2016 Star Lab Corp.
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License along
+    with this program; if not, see <http://www.gnu.org/licenses/>.
+**/
+
+use bytes::{Buf, BufMut, LittleEndian};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use super::authz::Authorizer;
+use super::config::NodeConfig;
+use super::path::Path;
+use super::quota::Quota;
+use super::store::{Basename, Children, Node, Perm, Permission, Store, StoreBuilder, Value,
+                   DOM0_DOMAIN_ID};
+
+/// Identifies a saved store, followed by the format version
+const MAGIC: &'static [u8] = b"RXSTORE\0";
+const VERSION: u32 = 1;
+
+/// The default number of store generations between saves.
+pub const DEFAULT_SAVE_INTERVAL: u64 = 100;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.put_u32::<LittleEndian>(bytes.len() as u32);
+    buf.put_slice(bytes);
+}
+
+fn get_u32(input: &mut io::Cursor<&[u8]>) -> io::Result<u32> {
+    if input.remaining() < 4 {
+        return Err(invalid("truncated store file"));
+    }
+    Ok(input.get_u32::<LittleEndian>())
+}
+
+fn get_bytes(input: &mut io::Cursor<&[u8]>) -> io::Result<Vec<u8>> {
+    let len = try!(get_u32(input)) as usize;
+    if input.remaining() < len {
+        return Err(invalid("truncated store file"));
+    }
+
+    let mut bytes = vec![0; len];
+    input.copy_to_slice(&mut bytes);
+    Ok(bytes)
+}
+
+fn get_string(input: &mut io::Cursor<&[u8]>) -> io::Result<String> {
+    String::from_utf8(try!(get_bytes(input))).map_err(|_| invalid("store file is not UTF-8"))
+}
+
+fn perm_to_u8(perm: Perm) -> u8 {
+    match perm {
+        Perm::None => 0,
+        Perm::Read => 1,
+        Perm::Write => 2,
+        Perm::Both => 3,
+    }
+}
+
+fn perm_from_u8(perm: u8) -> io::Result<Perm> {
+    match perm {
+        0 => Ok(Perm::None),
+        1 => Ok(Perm::Read),
+        2 => Ok(Perm::Write),
+        3 => Ok(Perm::Both),
+        _ => Err(invalid("unknown permission in store file")),
+    }
+}
+
+/// Serialize the contents of a `Store`.
+pub fn encode(store: &Store) -> Vec<u8> {
+    let mut buf = Vec::new();
+
+    buf.put_slice(MAGIC);
+    buf.put_u32::<LittleEndian>(VERSION);
+    buf.put_u64::<LittleEndian>(store.generation());
+    buf.put_u32::<LittleEndian>(store.nodes().len() as u32);
+
+    for node in store.nodes() {
+        put_bytes(&mut buf, node.path.as_bytes());
+        put_bytes(&mut buf, node.value.as_bytes());
+
+        buf.put_u32::<LittleEndian>(node.children.len() as u32);
+        for child in node.children.keys() {
+            put_bytes(&mut buf, child.as_bytes());
+        }
+
+        buf.put_u32::<LittleEndian>(node.permissions.len() as u32);
+        for permission in &node.permissions {
+            buf.put_u32::<LittleEndian>(permission.id);
+            buf.put_u8(perm_to_u8(permission.perm));
+        }
+    }
+
+    buf
+}
+
+/// Rebuild a `Store` from the output of `encode`, whose access is decided
+/// by `authorizer`.
+///
+/// # Errors
+///
+/// * `io::ErrorKind::InvalidData` if the input is not a saved store
+pub fn decode(bytes: &[u8], quota: Quota, authorizer: Arc<Authorizer>) -> io::Result<Store> {
+    if !bytes.starts_with(MAGIC) {
+        return Err(invalid("not a store file"));
+    }
+
+    let mut input = io::Cursor::new(&bytes[MAGIC.len()..]);
+    if try!(get_u32(&mut input)) != VERSION {
+        return Err(invalid("unsupported store file version"));
+    }
+
+    if input.remaining() < 8 {
+        return Err(invalid("truncated store file"));
+    }
+    let generation = input.get_u64::<LittleEndian>();
+
+    let count = try!(get_u32(&mut input));
+    let mut nodes = Vec::new();
+    for _ in 0..count {
+        let path = try!(get_string(&mut input));
+        let path = try!(Path::try_from(DOM0_DOMAIN_ID, &path)
+            .map_err(|_| invalid("invalid path in store file")));
+        let value = try!(get_bytes(&mut input));
+
+        let mut children = Children::new();
+        for _ in 0..try!(get_u32(&mut input)) {
+            children.insert(Basename::from(try!(get_string(&mut input))), ());
+        }
+
+        let mut permissions = Vec::new();
+        for _ in 0..try!(get_u32(&mut input)) {
+            let id = try!(get_u32(&mut input));
+            if input.remaining() < 1 {
+                return Err(invalid("truncated store file"));
+            }
+            let perm = try!(perm_from_u8(input.get_u8()));
+            permissions.push(Permission {
+                                 id: id,
+                                 perm: perm,
+                             });
+        }
+
+        if permissions.is_empty() {
+            return Err(invalid("node without permissions in store file"));
+        }
+
+        nodes.push(Node {
+                       path: path,
+                       value: Value::from(value),
+                       children: children,
+                       permissions: permissions,
+                   });
+    }
+
+    Ok(Store::restore(generation, nodes, quota, authorizer))
+}
+
+/// Save a `Store` to `file`.
+///
+/// The store is written to a temporary file first and then moved into
+/// place so a crash part way through never leaves a damaged file behind.
+pub fn save(store: &Store, file: &::std::path::Path) -> io::Result<()> {
+    let mut tmp = file.as_os_str().to_owned();
+    tmp.push(".tmp");
+
+    {
+        let mut out = try!(File::create(&tmp));
+        try!(out.write_all(&encode(store)));
+        try!(out.sync_all());
+    }
+
+    fs::rename(&tmp, file)
+}
+
+/// Load a `Store` that was written by `save`.
+pub fn load(file: &::std::path::Path,
+            quota: Quota,
+            authorizer: Arc<Authorizer>)
+            -> io::Result<Store> {
+    let mut bytes = Vec::new();
+    try!(File::open(file).and_then(|mut f| f.read_to_end(&mut bytes)));
+    decode(&bytes, quota, authorizer)
+}
+
+/// Write a value the way `xenstore-ls` does: backslashes doubled and
+/// anything unprintable as a three digit octal escape.
+fn escape_value(value: &[u8]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for &b in value {
+        match b {
+            b'\\' => escaped.push_str("\\\\"),
+            b' '...b'~' => escaped.push(b as char),
+            _ => escaped.push_str(&format!("\\{:03o}", b)),
+        }
+    }
+    escaped
+}
+
+/// Undo `escape_value`, also taking the escapes `xenstore-write` does.
+fn unescape_value(value: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut input = value.bytes();
+
+    while let Some(b) = input.next() {
+        if b != b'\\' {
+            bytes.push(b);
+            continue;
+        }
+
+        let unescaped = match input.next() {
+            Some(b'\\') => b'\\',
+            Some(b'"') => b'"',
+            Some(b'a') => 0x07,
+            Some(b'b') => 0x08,
+            Some(b'f') => 0x0c,
+            Some(b'n') => b'\n',
+            Some(b'r') => b'\r',
+            Some(b't') => b'\t',
+            Some(b'v') => 0x0b,
+            Some(first @ b'0'...b'7') => {
+                let mut octal = (first - b'0') as u32;
+                for _ in 0..2 {
+                    match input.next() {
+                        Some(digit @ b'0'...b'7') => octal = octal * 8 + (digit - b'0') as u32,
+                        _ => return None,
+                    }
+                }
+                if octal > 0xff {
+                    return None;
+                }
+                octal as u8
+            }
+            _ => return None,
+        };
+        bytes.push(unescaped);
+    }
+
+    Some(bytes)
+}
+
+/// Format a node as `xenstore-ls -fp` lists it.
+pub fn text_line(path: &str, value: &[u8], permissions: &[Permission]) -> String {
+    let perms = permissions.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+    format!("{} = \"{}\"  ({})", path, escape_value(value), perms.join(","))
+}
+
+/// List every node of a `Store` below `/`, one line each in the format of
+/// `xenstore-ls -fp`.
+pub fn dump_text(store: &Store) -> String {
+    let mut text = String::new();
+    for node in store.nodes().filter(|node| node.path.parent().is_some()) {
+        text.push_str(&text_line(&String::from_utf8_lossy(node.path.as_bytes()),
+                                 node.value.as_bytes(),
+                                 &node.permissions));
+        text.push('\n');
+    }
+    text
+}
+
+/// Read a line of `xenstore-ls -fp` output. The permissions may be left off,
+/// which is what `xenstore-ls -f` prints.
+fn parse_line(line: &str) -> Option<NodeConfig> {
+    let eq = match line.find(" = \"") {
+        Some(eq) => eq,
+        None => return None,
+    };
+    let path = &line[..eq];
+    let rest = line[eq + 4..].trim_right();
+
+    // values aren't quoted inside, so the value ends at the last quote
+    // before the permissions
+    let (value, perms) = match rest.rfind('(') {
+        Some(open) if rest.ends_with(')') && rest[..open].trim_right().ends_with('"') => {
+            (rest[..open].trim_right(), Some(&rest[open + 1..rest.len() - 1]))
+        }
+        _ => (rest, None),
+    };
+    if !value.ends_with('"') || !path.starts_with('/') {
+        return None;
+    }
+
+    let permissions = match perms {
+        Some(perms) => {
+            match perms.split(',').map(Permission::try_from).collect::<Result<Vec<_>, _>>() {
+                Ok(permissions) => permissions,
+                Err(_) => return None,
+            }
+        }
+        None => {
+            vec![Permission {
+                     id: DOM0_DOMAIN_ID,
+                     perm: Perm::None,
+                 }]
+        }
+    };
+
+    Some(NodeConfig {
+        path: match Path::try_from(DOM0_DOMAIN_ID, path) {
+            Ok(path) => path,
+            Err(_) => return None,
+        },
+        value: match unescape_value(&value[..value.len() - 1]) {
+            Some(value) => Value::from(value),
+            None => return None,
+        },
+        permissions: permissions,
+    })
+}
+
+/// Read the nodes listed in the output of `dump_text` or `xenstore-ls -fp`.
+///
+/// # Errors
+///
+/// * `io::ErrorKind::InvalidData` naming the first line that isn't a node
+pub fn parse_text(text: &str) -> io::Result<Vec<NodeConfig>> {
+    text.lines()
+        .enumerate()
+        .filter(|&(_, line)| !line.trim().is_empty())
+        .map(|(n, line)| {
+            parse_line(line).ok_or_else(|| invalid(&format!("line {}: not a node", n + 1)))
+        })
+        .collect()
+}
+
+/// Rebuild a `Store` from the output of `dump_text` or `xenstore-ls -fp`.
+///
+/// Parents that aren't listed are created for dom0 alone.
+pub fn load_text(text: &str, quota: Quota, authorizer: Arc<Authorizer>) -> io::Result<Store> {
+    let nodes = try!(parse_text(text));
+    let builder = StoreBuilder::bare().quota(quota).authorizer(authorizer);
+    let builder = nodes.into_iter().fold(builder, |builder, node| {
+        builder.node(node.path, node.value, node.permissions)
+    });
+    Ok(builder.build())
+}
+
+/// The `Persister` type.
+///
+/// Saves the store to disk every so many generations.
+pub struct Persister {
+    file: PathBuf,
+    every: u64,
+    saved: u64,
+}
+
+impl Persister {
+    /// Create a new `Persister` saving `store` to `file` every `every` generations.
+    pub fn new<P: Into<PathBuf>>(file: P, every: u64, store: &Store) -> Persister {
+        Persister {
+            file: file.into(),
+            every: every,
+            saved: store.generation(),
+        }
+    }
+
+    /// Save the store if enough generations have passed since the last save.
+    ///
+    /// Returns `true` if the store was saved.
+    pub fn checkpoint(&mut self, store: &Store) -> io::Result<bool> {
+        if store.generation().wrapping_sub(self.saved) < self.every {
+            return Ok(false);
+        }
+
+        try!(self.save(store));
+        Ok(true)
+    }
+
+    /// Save the store now.
+    pub fn save(&mut self, store: &Store) -> io::Result<()> {
+        try!(save(store, &self.file));
+        self.saved = store.generation();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand;
+    use std::env;
+    use std::fs;
+    use std::io;
+    use super::super::authz::PermissionAuthorizer;
+    use super::super::path::Path;
+    use super::super::quota::Quota;
+    use super::super::store::{ChangeSet, Perm, Permission, Store, Value, DOM0_DOMAIN_ID};
+    use super::*;
+
+    fn populated() -> Store {
+        let mut store = Store::new();
+        let path = Path::try_from(DOM0_DOMAIN_ID, "/local/domain/1/name").unwrap();
+
+        let changes = store.write(&ChangeSet::new(&store),
+                                  DOM0_DOMAIN_ID,
+                                  path.clone(),
+                                  Value::from(b"gu\0est\xff".to_vec()))
+            .unwrap();
+        let changes = store.set_perms(&changes,
+                                      DOM0_DOMAIN_ID,
+                                      &path,
+                                      vec![Permission {
+                                               id: 1,
+                                               perm: Perm::Read,
+                                           }])
+            .unwrap();
+        store.apply(changes).unwrap();
+        store
+    }
+
+    #[test]
+    fn round_trip() {
+        let store = populated();
+        let path = Path::try_from(DOM0_DOMAIN_ID, "/local/domain/1/name").unwrap();
+
+        let loaded = decode(&encode(&store), Quota::new(), Arc::new(PermissionAuthorizer)).unwrap();
+
+        assert_eq!(loaded.generation(), store.generation());
+        assert_eq!(loaded.nodes().len(), store.nodes().len());
+        assert_eq!(loaded.read(&ChangeSet::new(&loaded), 1, &path).unwrap(),
+                   Value::from(b"gu\0est\xff".to_vec()));
+        assert_eq!(loaded.get_perms(&ChangeSet::new(&loaded), 1, &path).unwrap(),
+                   vec![Permission {
+                            id: 1,
+                            perm: Perm::Read,
+                        }]);
+        assert_eq!(loaded.usage(1), store.usage(1));
+    }
+
+    #[test]
+    fn decode_garbage() {
+        let encoded = encode(&populated());
+
+        for bytes in vec![&b"nonsense"[..], &encoded[..encoded.len() - 1]] {
+            match decode(bytes, Quota::new(), Arc::new(PermissionAuthorizer)) {
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidData => assert!(true),
+                Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+                Ok(_) => assert!(false, "decoded a damaged store"),
+            }
+        }
+    }
+
+    #[test]
+    fn text_round_trip() {
+        let store = populated();
+        let path = Path::try_from(DOM0_DOMAIN_ID, "/local/domain/1/name").unwrap();
+
+        let text = store.dump_text();
+        assert!(text.contains("/local/domain/1/name = \"gu\\000est\\377\"  (r1)\n"));
+
+        let loaded = Store::load_text(&text, Quota::new(), Arc::new(PermissionAuthorizer)).unwrap();
+        assert_eq!(loaded.nodes().len(), store.nodes().len());
+        assert_eq!(loaded.read(&ChangeSet::new(&loaded), 1, &path).unwrap(),
+                   Value::from(b"gu\0est\xff".to_vec()));
+        assert_eq!(loaded.dump_text(), text);
+        assert_eq!(loaded.usage(1), store.usage(1));
+    }
+
+    #[test]
+    fn xenstore_ls_output() {
+        let nodes = parse_text(r#"
+/local = ""   (n0)
+/local/domain/3/name = "guest "one" (test)"                          (n0,r3)
+/local/domain/3/data = "a\\b\tc"
+"#)
+            .unwrap();
+
+        assert_eq!(nodes.len(), 3);
+        assert_eq!(nodes[1].path,
+                   Path::try_from(DOM0_DOMAIN_ID, "/local/domain/3/name").unwrap());
+        assert_eq!(nodes[1].value, "guest \"one\" (test)");
+        assert_eq!(nodes[1].permissions,
+                   vec![Permission {
+                            id: 0,
+                            perm: Perm::None,
+                        },
+                        Permission {
+                            id: 3,
+                            perm: Perm::Read,
+                        }]);
+        assert_eq!(nodes[2].value, "a\\b\tc");
+        assert_eq!(nodes[2].permissions,
+                   vec![Permission {
+                            id: DOM0_DOMAIN_ID,
+                            perm: Perm::None,
+                        }]);
+
+        // the parent of a listed node is filled in
+        let store = load_text("/a/b = \"c\"  (b1)", Quota::new(), Arc::new(PermissionAuthorizer))
+            .unwrap();
+        let a = Path::try_from(DOM0_DOMAIN_ID, "/a").unwrap();
+        assert_eq!(store.read(&ChangeSet::new(&store), DOM0_DOMAIN_ID, &a).unwrap(), "");
+    }
+
+    #[test]
+    fn parse_text_garbage() {
+        for text in &["nonsense",
+                      "/a = c",
+                      "/a = \"c",
+                      "a = \"c\"",
+                      "/a = \"c\"  (x1)",
+                      "/a = \"c\"  ()",
+                      "/a = \"c\\q\"",
+                      "/a = \"c\\77\""] {
+            match parse_text(text) {
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidData => assert!(true),
+                Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+                Ok(_) => assert!(false, "parsed {:?}", text),
+            }
+        }
+    }
+
+    #[test]
+    fn checkpoint() {
+        let file = env::temp_dir().join(format!("rxenstored-test-{}.db", rand::random::<u32>()));
+        let mut store = Store::new();
+        let mut persister = Persister::new(file.clone(), 2, &store);
+
+        let path = Path::try_from(DOM0_DOMAIN_ID, "/a").unwrap();
+        let changes = store.write(&ChangeSet::new(&store), DOM0_DOMAIN_ID, path, Value::from(""))
+            .unwrap();
+        store.apply(changes).unwrap();
+        assert_eq!(persister.checkpoint(&store).unwrap(), false);
+
+        let path = Path::try_from(DOM0_DOMAIN_ID, "/b").unwrap();
+        let changes = store.write(&ChangeSet::new(&store), DOM0_DOMAIN_ID, path, Value::from(""))
+            .unwrap();
+        store.apply(changes).unwrap();
+        assert_eq!(persister.checkpoint(&store).unwrap(), true);
+
+        let loaded = load(&file, Quota::new(), Arc::new(PermissionAuthorizer)).unwrap();
+        assert_eq!(loaded.generation(), 2);
+
+        fs::remove_file(&file).unwrap();
+    }
+}
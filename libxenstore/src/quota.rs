@@ -0,0 +1,183 @@
+/**
+    xenstore-rs provides a Rust based xenstore implementation.
+    Copyright (C) 2016 Star Lab Corp.
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License along
+    with this program; if not, see <http://www.gnu.org/licenses/>.
+**/
+
+use super::error::{Error, Result};
+use super::store::DOM0_DOMAIN_ID;
+use super::wire;
+
+/// The default number of nodes a domain may own.
+pub const DEFAULT_MAX_ENTRIES: usize = 1000;
+
+/// The default largest value a domain may store in a single node.
+pub const DEFAULT_MAX_ENTRY_SIZE: usize = 2048;
+
+/// The default total number of value bytes a domain may own.
+pub const DEFAULT_MAX_BYTES: usize = 1024 * 1024;
+
+/// The default number of watches a domain may register.
+pub const DEFAULT_MAX_WATCHES: usize = 128;
+
+/// The quotas that can be asked for with XS_GET_QUOTA, as Xen names them
+pub const QUOTA_NAMES: &'static [&'static str] = &["memory",
+                                                   "node-size",
+                                                   "nodes",
+                                                   "transactions",
+                                                   "watches"];
+
+/// The `Quota` type.
+///
+/// Limits on how much of the store an unprivileged domain may use. Dom0 is
+/// never limited.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quota {
+    pub max_entries: usize,
+    pub max_entry_size: usize,
+    pub max_bytes: usize,
+    pub max_watches: usize,
+}
+
+/// The `Usage` type.
+///
+/// How much of the store a domain currently owns.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Usage {
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+impl Quota {
+    /// Create a new `Quota` with the default limits.
+    pub fn new() -> Quota {
+        Quota {
+            max_entries: DEFAULT_MAX_ENTRIES,
+            max_entry_size: DEFAULT_MAX_ENTRY_SIZE,
+            max_bytes: DEFAULT_MAX_BYTES,
+            max_watches: DEFAULT_MAX_WATCHES,
+        }
+    }
+
+    /// Check that a single value may be stored by a domain.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::E2BIG` if the value is larger than `max_entry_size`
+    pub fn check_entry_size(&self, dom_id: wire::DomainId, size: usize) -> Result<()> {
+        if dom_id != DOM0_DOMAIN_ID && size > self.max_entry_size {
+            return Err(Error::E2BIG(format!("domain {} cannot store {} bytes in one node",
+                                            dom_id,
+                                            size)));
+        }
+        Ok(())
+    }
+
+    /// Check that a domain may own this much of the store.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::ENOSPC` if the domain would own too many nodes or bytes
+    pub fn check_usage(&self, dom_id: wire::DomainId, usage: &Usage) -> Result<()> {
+        if dom_id == DOM0_DOMAIN_ID {
+            return Ok(());
+        }
+
+        if usage.entries > self.max_entries {
+            return Err(Error::ENOSPC(format!("domain {} cannot own more than {} nodes",
+                                             dom_id,
+                                             self.max_entries)));
+        }
+
+        if usage.bytes > self.max_bytes {
+            return Err(Error::ENOSPC(format!("domain {} cannot own more than {} bytes",
+                                             dom_id,
+                                             self.max_bytes)));
+        }
+        Ok(())
+    }
+
+    /// Check that a domain may register another watch when it already has
+    /// `count` of them.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::E2BIG` if the domain has all the watches it is allowed
+    pub fn check_watches(&self, dom_id: wire::DomainId, count: usize) -> Result<()> {
+        if dom_id != DOM0_DOMAIN_ID && count >= self.max_watches {
+            return Err(Error::E2BIG(format!("domain {} cannot register more than {} watches",
+                                            dom_id,
+                                            self.max_watches)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::error::Error;
+    use super::super::store::DOM0_DOMAIN_ID;
+    use super::*;
+
+    #[test]
+    fn entry_size() {
+        let quota = Quota { max_entry_size: 4, ..Quota::new() };
+
+        quota.check_entry_size(1, 4).unwrap();
+        quota.check_entry_size(DOM0_DOMAIN_ID, 5).unwrap();
+
+        match quota.check_entry_size(1, 5) {
+            Err(Error::E2BIG(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "allowed an oversized entry"),
+        }
+    }
+
+    #[test]
+    fn usage() {
+        let quota = Quota {
+            max_entries: 2,
+            max_bytes: 10,
+            ..Quota::new()
+        };
+
+        quota.check_usage(1,
+                         &Usage {
+                              entries: 2,
+                              bytes: 10,
+                          })
+            .unwrap();
+
+        match quota.check_usage(1,
+                                &Usage {
+                                     entries: 3,
+                                     bytes: 0,
+                                 }) {
+            Err(Error::ENOSPC(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "allowed too many entries"),
+        }
+
+        match quota.check_usage(1,
+                                &Usage {
+                                     entries: 0,
+                                     bytes: 11,
+                                 }) {
+            Err(Error::ENOSPC(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "allowed too many bytes"),
+        }
+    }
+}
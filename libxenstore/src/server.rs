@@ -16,78 +16,211 @@
     with this program; if not, see <http://www.gnu.org/licenses/>.
 **/
 
-extern crate mio;
-
 use connection;
-use futures::{future, Future, BoxFuture};
-use message::ingress;
+use error::Error;
+use futures::{future, Async, Future, Poll, Sink, Stream};
+use futures::future::Either;
+use futures::sync::mpsc;
+use handler::Handler;
+use message::Metadata;
+use message::egress::{Egress, ErrorMsg, WatchEvent};
+use std::cell::Cell;
 use std::io;
+use std::rc::Rc;
 use std::sync::{Arc, Mutex};
 use store;
 use system::System;
 use tokio_io::{AsyncRead, AsyncWrite};
-use tokio_io::codec::Framed;
-use tokio_proto::pipeline::ServerProto;
-use tokio_service::Service;
 use wire;
 
-pub struct XenStoreProto;
+/// The replies a socket connection may have waiting to be written before
+/// the client is taken to be flooding us and is disconnected
+pub const MAX_PENDING_REPLIES: usize = 1024;
 
-impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for XenStoreProto {
-    /// For this protocol style, `Request` matches the `Item` type of the codec's `Encoder`
-    type Request = (wire::Header, wire::Body);
+/// Creates a `XenStoredService` with its own `ConnId` for every accepted connection
+#[derive(Clone)]
+pub struct XenStoredNewService {
+    // datastore system objects
+    pub system: Arc<Mutex<System>>,
+    // mark every connection as read-only
+    pub read_only: bool,
+    // the domain every connection acts as
+    pub dom_id: wire::DomainId,
+}
 
-    /// For this protocol style, `Response` matches the `Item` type of the codec's `Decoder`
-    type Response = (wire::Header, wire::Body);
+impl XenStoredNewService {
+    pub fn new(system: Arc<Mutex<System>>) -> XenStoredNewService {
+        XenStoredNewService::with_domain(system, store::DOM0_DOMAIN_ID)
+    }
 
-    /// A bit of boilerplate to hook in the codec:
-    type Transport = Framed<T, wire::XenStoreCodec>;
-    type BindTransport = Result<Self::Transport, io::Error>;
-    fn bind_transport(&self, io: T) -> Self::BindTransport {
-        Ok(io.framed(wire::XenStoreCodec))
+    /// Like `new`, but every connection acts as `dom_id` rather than dom0
+    pub fn with_domain(system: Arc<Mutex<System>>, dom_id: wire::DomainId) -> XenStoredNewService {
+        XenStoredNewService {
+            system: system,
+            read_only: false,
+            dom_id: dom_id,
+        }
     }
-}
 
-pub struct XenStoredService {
-    // datastore system objects
-    pub system: Arc<Mutex<System>>,
-}
+    /// Like `new`, but every connection can only look at the store, like
+    /// C xenstored's `socket_ro`
+    pub fn read_only(system: Arc<Mutex<System>>) -> XenStoredNewService {
+        XenStoredNewService {
+            system: system,
+            read_only: true,
+            dom_id: store::DOM0_DOMAIN_ID,
+        }
+    }
 
-impl Service for XenStoredService {
-    // These types must match the corresponding protocol types:
-    type Request = (wire::Header, wire::Body);
-    type Response = (wire::Header, wire::Body);
+    /// Create the service for a new connection, with a `ConnId` of its own.
+    pub fn new_service(&self) -> XenStoredService {
+        let conn = self.system.lock().unwrap().new_connection(self.dom_id);
+        let conn = if self.read_only { conn.read_only() } else { conn };
 
-    // For non-streaming protocols, service errors are always io::Error
-    type Error = io::Error;
+        XenStoredService {
+            handler: Handler::new(self.system.clone()),
+            conn: conn,
+        }
+    }
 
-    // The future for computing the response; box it for simplicity.
-    type Future = BoxFuture<Self::Response, Self::Error>;
+    /// Serve a socket connection until the client hangs up.
+    ///
+    /// Responses are written back as soon as they are ready, followed by any
+    /// watch events that have been queued in the connection's outbox. A
+    /// client that sends a message we can't make sense of, or keeps more
+    /// than `MAX_PENDING_REPLIES` replies waiting, is sent an error for the
+    /// request that was turned away and then disconnected.
+    pub fn serve<T>(&self, io: T) -> Box<Future<Item = (), Error = io::Error>>
+        where T: AsyncRead + AsyncWrite + 'static
+    {
+        let service = self.new_service();
+        let handler = service.handler.clone();
+        let conn = service.conn;
+
+        let (tx, rx) = mpsc::unbounded();
+        let pending = Rc::new(Cell::new(0));
+        handler.open(conn);
+        let outgoing = Outgoing {
+            system: handler.system().clone(),
+            conn: conn,
+            responses: rx,
+            pending: pending.clone(),
+        };
+
+        let (sink, stream) = io.framed(wire::XenStoreCodec).split();
+
+        let refusal = tx.clone();
+        let reader = stream.for_each(move |req| {
+                if pending.get() >= MAX_PENDING_REPLIES {
+                    let err = Error::EINVAL(format!("more than {} replies waiting to be sent",
+                                                    MAX_PENDING_REPLIES));
+                    return Err(wire::reject(req.0, err));
+                }
+
+                pending.set(pending.get() + 1);
+                service.process(req, |msg| {
+                    tx.unbounded_send(msg).map_err(|_| {
+                        io::Error::new(io::ErrorKind::BrokenPipe, "writer has gone away")
+                    })
+                })
+            })
+            .map(|_| None)
+            .or_else(move |e| {
+                // answer the request that was turned away, and let the
+                // replies already waiting go out ahead of it
+                let reply = match wire::Rejected::find(&e) {
+                    Some(rejected) => {
+                        let md = Metadata {
+                            conn: conn,
+                            req_id: rejected.header.req_id,
+                            tx_id: rejected.header.tx_id,
+                        };
+                        ErrorMsg::from(md, &rejected.err).encode()
+                    }
+                    None => return Err(e),
+                };
+                let _ = refusal.unbounded_send(reply);
+                Ok(Some(e))
+            });
+
+        let writer = sink.send_all(outgoing).map(|_| ());
+
+        let connection = reader.select2(writer).then(|res| -> Box<Future<Item = (), Error = _>> {
+            match res {
+                // the writer finishes once the last reply has been written
+                Ok(Either::A((Some(e), writer))) => Box::new(writer.then(move |_| Err(e))),
+                Ok(_) => Box::new(future::ok(())),
+                Err(Either::A((e, _))) => Box::new(future::err(e)),
+                Err(Either::B((e, _))) => Box::new(future::err(e)),
+            }
+        });
+
+        Box::new(connection.then(move |res| {
+            handler.close(conn);
+            res
+        }))
+    }
+}
 
-    // Produce a future for computing a response from a request.
-    fn call(&self, req: Self::Request) -> Self::Future {
-        // grab a lock to the System object, it won't fail since
-        // we are running single-threaded since that's how xenstored
-        // works
-        let mut sys = self.system.lock().unwrap();
+/// Everything waiting to be written to a socket connection
+struct Outgoing {
+    system: Arc<Mutex<System>>,
+    conn: connection::ConnId,
+    responses: mpsc::UnboundedReceiver<(wire::Header, wire::Body)>,
+    // the replies sent to `responses` that haven't been taken yet
+    pending: Rc<Cell<usize>>,
+}
 
-        // create the connection object that is currently required
-        // future refactors will have to change this to know which
-        // socket the data came from but right now we just have one
-        // socket. We also only currently support dom0 communication
-        // so hardcode dom0
-        let token = mio::Token(0);
-        let conn = connection::ConnId::new(token, store::DOM0_DOMAIN_ID);
+impl Stream for Outgoing {
+    type Item = (wire::Header, wire::Body);
+    type Error = io::Error;
 
-        // parse the incoming request (header, body) and process it
-        let msg = ingress::parse(conn, &req.0, req.1).process(&mut sys);
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        // responses always go out ahead of the events they fired
+        match self.responses.poll() {
+            Ok(Async::Ready(Some(msg))) => {
+                self.pending.set(self.pending.get().saturating_sub(1));
+                return Ok(Async::Ready(Some(msg)));
+            }
+            Ok(Async::Ready(None)) | Err(()) => return Ok(Async::Ready(None)),
+            Ok(Async::NotReady) => {}
+        }
+
+        // a closed outbox ends the stream once it has been emptied
+        let mut sys = self.system.lock().unwrap();
+        let event = sys.do_outbox_mut(self.conn, |outbox| if outbox.is_finished() {
+                None
+            } else {
+                Some(outbox.pop().map(|event| {
+                    if event.is_none() {
+                        outbox.park();
+                    }
+                    event
+                }))
+            })
+            .and_then(|event| event);
+
+        match event {
+            Some(Ok(Some(event))) => Ok(Async::Ready(Some(WatchEvent::new(event).encode()))),
+            Some(Ok(None)) => Ok(Async::NotReady),
+            Some(Err(e)) => Err(io::Error::new(io::ErrorKind::Other, e)),
+            None => Ok(Async::Ready(None)),
+        }
+    }
+}
 
-        // take the response and encode it to (header, body), this throws
-        // away any watches that may have fired so this will need to be
-        // fixed in the future
-        let (hdr, body) = msg.msg.encode();
+pub struct XenStoredService {
+    // processes the requests against the datastore system objects
+    pub handler: Handler,
+    // the connection this service is handling
+    pub conn: connection::ConnId,
+}
 
-        // return the completed future
-        future::ok((hdr, body)).boxed()
+impl XenStoredService {
+    /// Process a single request, passing the encoded response to `reply`.
+    pub fn process<F, R>(&self, req: (wire::Header, wire::Body), reply: F) -> R
+        where F: FnOnce((wire::Header, wire::Body)) -> R
+    {
+        self.handler.process(self.conn, req, reply)
     }
 }
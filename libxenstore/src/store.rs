@@ -16,17 +16,178 @@
     with this program; if not, see <http://www.gnu.org/licenses/>.
 **/
 
-use std::collections::{HashMap, HashSet, LinkedList};
+use std::borrow::Borrow;
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::{HashMap, HashSet, LinkedList, VecDeque};
+use std::fmt;
+use std::io;
 use std::num::Wrapping;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use super::authz::{Authorizer, PermissionAuthorizer};
 use super::error::{Result, Error};
+use super::persistence;
+use super::quota::{Quota, Usage};
 use super::wire;
 use super::path::Path;
+use super::tree::{Range, Tree, Values};
 
 /// The Dom0 Domain Id.
 pub const DOM0_DOMAIN_ID: wire::DomainId = 0;
 
-pub type Basename = String;
-pub type Value = String;
+/// Where the server advertises the features it supports.
+pub const FEATURES_PATH: &'static str = "/tool/xenstored/features";
+
+/// The features advertised below `FEATURES_PATH` in every new store.
+pub const FEATURES: &'static [&'static str] = &["live-update", "directory-part"];
+
+/// The nodes that only dom0 may write or create children of, whatever their
+/// permissions say, unless the store is given others with `set_protected`.
+pub const PROTECTED_PATHS: &'static [&'static str] = &["/", "/tool", "/libxl", "/vm", "/local"];
+
+/// The name of a node below its parent.
+///
+/// Copies of a name share it, and the store hands out a single copy of each
+/// name, so the many nodes called `state` or `backend` share one.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Basename(Arc<String>);
+
+impl Basename {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Deref for Basename {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Borrow<str> for Basename {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl<'a> From<&'a str> for Basename {
+    fn from(name: &'a str) -> Basename {
+        Basename(Arc::new(name.to_owned()))
+    }
+}
+
+impl From<String> for Basename {
+    fn from(name: String) -> Basename {
+        Basename(Arc::new(name))
+    }
+}
+
+impl PartialEq<String> for Basename {
+    fn eq(&self, other: &String) -> bool {
+        *self.0 == *other
+    }
+}
+
+/// The contents of a node.
+///
+/// Copies of a value share it, so reading a node or copying it into a
+/// transaction doesn't copy the value.
+// Arc<[u8]> can't be built from a Vec before Rust 1.21, hence the extra box
+#[derive(Clone, Eq, PartialEq)]
+pub struct Value(Arc<Vec<u8>>);
+
+impl Value {
+    pub fn new() -> Value {
+        Value(Arc::new(Vec::new()))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", String::from_utf8_lossy(&self.0))
+    }
+}
+
+impl<'a> From<&'a str> for Value {
+    fn from(value: &'a str) -> Value {
+        Value(Arc::new(value.as_bytes().to_owned()))
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Value {
+        Value(Arc::new(value.into_bytes()))
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(value: Vec<u8>) -> Value {
+        Value(Arc::new(value))
+    }
+}
+
+impl<'a> PartialEq<&'a str> for Value {
+    fn eq(&self, other: &&'a str) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+/// The smallest number of names kept before unused ones are dropped
+const MIN_NAMES: usize = 1024;
+
+/// Hands out a single shared copy of each name
+struct Names {
+    names: HashSet<Basename>,
+    // how many names to hold before dropping the ones no node uses
+    limit: usize,
+}
+
+impl Names {
+    fn new() -> Names {
+        Names {
+            names: HashSet::new(),
+            limit: MIN_NAMES,
+        }
+    }
+
+    fn intern(&mut self, name: &str) -> Basename {
+        if let Some(name) = self.names.get(name) {
+            return name.clone();
+        }
+
+        if self.names.len() >= self.limit {
+            self.names = self.names
+                .drain()
+                .filter(|name| Arc::strong_count(&name.0) > 1)
+                .collect();
+            self.limit = cmp::max(MIN_NAMES, self.names.len() * 2);
+        }
+
+        let name = Basename::from(name);
+        self.names.insert(name.clone());
+        name
+    }
+}
+
+/// The names of a node's children, kept in order and shared between copies of
+/// the node so that adding or removing one child doesn't copy all the others
+pub type Children = Tree<Basename, ()>;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Perm {
@@ -56,43 +217,133 @@ pub struct Permission {
     pub perm: Perm,
 }
 
+impl Permission {
+    /// Parse a permission written as its type followed by a domain id, like `r3`.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::EINVAL` if the string isn't a valid permission
+    pub fn try_from(s: &str) -> Result<Permission> {
+        let perm = match s.chars().nth(0) {
+            Some('n') => Perm::None,
+            Some('r') => Perm::Read,
+            Some('w') => Perm::Write,
+            Some('b') => Perm::Both,
+            _ => return Err(Error::EINVAL(format!("bad permission: {}", s))),
+        };
+        let id = try!(s[1..]
+                          .parse::<wire::DomainId>()
+                          .map_err(|_| Error::EINVAL(format!("bad permission: {}", s))));
+
+        Ok(Permission {
+               id: id,
+               perm: perm,
+           })
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let perm = match self.perm {
+            Perm::None => "n",
+            Perm::Read => "r",
+            Perm::Write => "w",
+            Perm::Both => "b",
+        };
+        write!(f, "{}{}", perm, self.id)
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Node {
     pub path: Path,
     pub value: Value,
-    pub children: HashSet<Basename>,
+    pub children: Children,
     pub permissions: Vec<Permission>,
 }
 
-fn perms_ok(dom_id: wire::DomainId, permissions: &[Permission], perm: Perm) -> bool {
-    let mask = Perm::Both;
-
-    if dom_id == DOM0_DOMAIN_ID || permissions[0].id == dom_id {
-        return mask.allowed(&perm);
+impl Node {
+    /// The domain that is charged for this node.
+    pub fn owner(&self) -> wire::DomainId {
+        self.permissions[0].id
     }
 
-    if let Some(p) = permissions.iter().find(|p| p.id == dom_id) {
-        return p.perm.allowed(&perm);
+    fn charge(&self, usage: &mut Usage) {
+        usage.entries += 1;
+        usage.bytes += self.value.len();
     }
 
-    permissions[0].perm.allowed(&perm)
-}
-
-impl Node {
-    pub fn perms_ok(&self, dom_id: wire::DomainId, perm: Perm) -> bool {
-        perms_ok(dom_id, &self.permissions, perm)
+    fn refund(&self, usage: &mut Usage) {
+        usage.entries -= 1;
+        usage.bytes -= self.value.len();
     }
 }
 
 pub struct Store {
     generation: Wrapping<u64>,
-    store: HashMap<Path, Node>,
+    store: Tree<Path, Node>,
+    // the generation that last wrote or removed each path
+    modified: Tree<Path, Wrapping<u64>>,
+    // the paths removed by each generation, oldest first, so that they can
+    // be dropped from `modified` once no changeset is made from before then
+    removals: VecDeque<(Wrapping<u64>, Path)>,
+    quota: Quota,
+    usage: HashMap<wire::DomainId, Usage>,
+    names: RefCell<Names>,
+    authorizer: Arc<Authorizer>,
+    protected: Vec<Path>,
+    // in-process consumers told about every batch of applied changes
+    subscribers: Subscribers,
+}
+
+/// How many batches of changes a subscriber may fall behind by before it is
+/// dropped
+pub const SUBSCRIPTION_BUFFER: usize = 1024;
+
+/// The `Subscribers` type.
+///
+/// The in-process consumers of the changes applied to a store. Clones share
+/// the same subscribers, so handing them to a store that takes over from
+/// another (e.g. one loaded from a file) keeps everyone subscribed.
+#[derive(Clone, Default)]
+pub struct Subscribers(Arc<Mutex<Vec<SyncSender<Vec<AppliedChange>>>>>);
+
+impl Subscribers {
+    fn subscribe(&self) -> Receiver<Vec<AppliedChange>> {
+        let (sender, receiver) = mpsc::sync_channel(SUBSCRIPTION_BUFFER);
+        self.0.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Send `applied` to every subscriber, dropping those that have gone
+    /// away or can't keep up rather than queueing changes for them forever.
+    fn send(&self, applied: &[AppliedChange]) {
+        let mut subscribers = self.0.lock().unwrap();
+        if subscribers.is_empty() {
+            return;
+        }
+
+        subscribers.retain(|subscriber| match subscriber.try_send(applied.to_vec()) {
+                               Ok(()) => true,
+                               Err(TrySendError::Full(_)) => {
+                                   warn!("dropped a store subscriber that fell behind");
+                                   false
+                               }
+                               Err(TrySendError::Disconnected(_)) => false,
+                           });
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().is_empty()
+    }
 }
 
 #[derive(Clone, Debug)]
 pub enum Change {
     Write(Node),
     Remove(Node),
+    /// Remove a node along with everything below it
+    RemoveSubtree(Path),
 }
 
 impl Change {
@@ -100,6 +351,32 @@ impl Change {
         match *self {
             Change::Write(ref node) => &node.path,
             Change::Remove(ref node) => &node.path,
+            Change::RemoveSubtree(ref path) => path,
+        }
+    }
+}
+
+/// An iterator over the entries of a `Tree` at or below a path
+struct Subtree<'a, V: 'a> {
+    range: Range<'a, Path, V>,
+    path: &'a Path,
+}
+
+fn subtree<'a, V>(tree: &'a Tree<Path, V>, path: &'a Path) -> Subtree<'a, V> {
+    Subtree {
+        range: tree.range_from(path),
+        path: path,
+    }
+}
+
+impl<'a, V> Iterator for Subtree<'a, V> {
+    type Item = (&'a Path, &'a V);
+
+    fn next(&mut self) -> Option<(&'a Path, &'a V)> {
+        // everything below a path sorts directly after it
+        match self.range.next() {
+            Some((path, value)) if path.is_child(self.path) => Some((path, value)),
+            _ => None,
         }
     }
 }
@@ -107,102 +384,859 @@ impl Change {
 #[derive(Clone)]
 pub struct ChangeSet {
     parent: Wrapping<u64>,
-    changes: HashMap<Path, Change>,
+    changes: Tree<Path, Change>,
+    // the roots of the subtrees removed through this changeset, what the
+    // store holds below them is gone even if some of the paths are written
+    // again afterwards
+    removed: Tree<Path, ()>,
+    // every path looked at through this changeset, used to detect conflicts
+    reads: RefCell<Tree<Path, ()>>,
+    // the domain the connection working through this changeset has been
+    // made to act for with XS_SET_TARGET
+    target: Cell<Option<wire::DomainId>>,
+    // what each domain gains and loses in the store once this is applied
+    charged: HashMap<wire::DomainId, Usage>,
+    refunded: HashMap<wire::DomainId, Usage>,
 }
 
 impl ChangeSet {
     pub fn new(from: &Store) -> ChangeSet {
         ChangeSet {
             parent: from.generation,
-            changes: HashMap::new(),
+            changes: Tree::new(),
+            removed: Tree::new(),
+            reads: RefCell::new(Tree::new()),
+            target: Cell::new(None),
+            charged: HashMap::new(),
+            refunded: HashMap::new(),
+        }
+    }
+
+    /// The generation of the store this was made from.
+    pub fn generation(&self) -> u64 {
+        self.parent.0
+    }
+
+    /// Let whoever works through this changeset access nodes as if they
+    /// were `target` as well, until it is set again.
+    pub fn act_for(&self, target: Option<wire::DomainId>) {
+        self.target.set(target);
+    }
+
+    /// Add `change`, replacing any earlier change to the same path, and keep
+    /// track of how it moves usage between domains compared to `store`.
+    fn insert(&mut self, store: &Store, change: Change) {
+        let path = change.path().clone();
+
+        match self.changes.get(&path) {
+            // only the latest write to a path is charged for
+            Some(&Change::Write(ref old)) => {
+                old.refund(self.charged.entry(old.owner()).or_insert_with(Usage::default))
+            }
+            Some(&Change::Remove(_)) |
+            Some(&Change::RemoveSubtree(_)) => {}
+            // the node in the store is refunded the first time it's changed,
+            // unless a subtree removal above it already refunded it
+            None => {
+                match store.store.get(&path) {
+                    Some(old) if !self.is_removed(&path) => {
+                        old.charge(self.refunded.entry(old.owner()).or_insert_with(Usage::default))
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        if let Change::Write(ref node) = change {
+            node.charge(self.charged.entry(node.owner()).or_insert_with(Usage::default));
+        }
+
+        self.changes.insert(path, change);
+    }
+
+    /// Remove `path` and everything below it, replacing the changes made
+    /// below it with a single `Change::RemoveSubtree` rather than a change
+    /// for every node.
+    fn remove_subtree(&mut self, store: &Store, path: Path) {
+        // refund the nodes in the store that weren't already
+        for (node_path, node) in subtree(&store.store, &path) {
+            if !self.changes.contains_key(node_path) && !self.is_removed(node_path) {
+                node.charge(self.refunded.entry(node.owner()).or_insert_with(Usage::default));
+            }
+        }
+
+        // and drop the changes made there
+        let superseded = subtree(&self.changes, &path)
+            .map(|(change_path, _)| change_path.clone())
+            .collect::<Vec<Path>>();
+        for change_path in superseded {
+            if let Some(&Change::Write(ref old)) = self.changes.get(&change_path) {
+                old.refund(self.charged.entry(old.owner()).or_insert_with(Usage::default));
+            }
+            self.changes.remove(&change_path);
+        }
+
+        let nested = subtree(&self.removed, &path)
+            .map(|(root, _)| root.clone())
+            .collect::<Vec<Path>>();
+        for root in nested {
+            self.removed.remove(&root);
+        }
+
+        self.removed.insert(path.clone(), ());
+        self.changes.insert(path.clone(), Change::RemoveSubtree(path));
+    }
+
+    /// Whether `path` is below a subtree removed through this changeset.
+    fn is_removed(&self, path: &Path) -> bool {
+        !self.removed.is_empty() &&
+        path.clone().into_iter().skip(1).any(|parent| self.removed.contains_key(&parent))
+    }
+
+    /// Whether the closest parent of `path` changed through this changeset
+    /// was removed, taking whatever the store holds below it along.
+    fn removes_parent(&self, path: &Path) -> bool {
+        if self.changes.is_empty() {
+            return false;
+        }
+
+        for parent in path.clone().into_iter().skip(1) {
+            match self.changes.get(&parent) {
+                Some(&Change::Write(_)) => return false,
+                Some(_) => return true,
+                None => {}
+            }
+        }
+        false
+    }
+
+    /// Rebuild a `ChangeSet` on top of `from` holding `changes` and having
+    /// read `reads`.
+    pub fn restore(from: &Store, changes: Vec<Change>, reads: Vec<Path>) -> ChangeSet {
+        let mut change_set = ChangeSet::new(from);
+        for change in changes {
+            change_set.insert(from, change);
         }
+        change_set.reads.borrow_mut().extend(reads.into_iter().map(|path| (path, ())));
+        change_set
+    }
+
+    /// Iterate over the changes made in this changeset.
+    pub fn changes(&self) -> Values<Path, Change> {
+        self.changes.values()
     }
 
-    fn insert(&mut self, change: Change) -> Option<Change> {
-        self.changes.insert(change.path().clone(), change)
+    /// Every path in `store` that applying this changeset removes, including
+    /// the nodes below removed subtrees.
+    pub fn removals(&self, store: &Store) -> Vec<Path> {
+        let mut removals = self.changes
+            .values()
+            .filter_map(|change| match *change {
+                            Change::Remove(ref node) => Some(node.path.clone()),
+                            _ => None,
+                        })
+            .collect::<Vec<Path>>();
+
+        for root in self.removed.keys() {
+            removals.extend(subtree(&store.store, root)
+                                .filter(|&(path, _)| match self.changes.get(path) {
+                                            Some(&Change::Write(_)) => false,
+                                            _ => true,
+                                        })
+                                .map(|(path, _)| path.clone()));
+        }
+
+        removals
+    }
+
+    /// The paths that have been read through this changeset.
+    pub fn reads(&self) -> Vec<Path> {
+        self.reads.borrow().keys().cloned().collect()
+    }
+
+    /// Carry over the paths read through `other`, which this changeset was
+    /// derived from.
+    pub fn merge_reads(&mut self, other: &ChangeSet) {
+        self.reads.borrow_mut().extend(other.reads.borrow().keys().map(|path| (path.clone(), ())));
+    }
+}
+
+/// What a write did to a node, for those that need to know more than which
+/// path changed
+#[derive(Clone, Debug, PartialEq)]
+pub enum Written {
+    /// The node was created holding this value
+    Created(Value),
+    /// The node's value went from the first to the second, its permissions
+    /// may have changed too, from these
+    Modified(Value, Value, Vec<Permission>),
+    /// Only the node's permissions changed, from these
+    PermsChanged(Vec<Permission>),
+    /// Only the node's list of children changed
+    ChildrenChanged,
+}
+
+impl Written {
+    fn between(old: Option<&Node>, new: &Node) -> Written {
+        match old {
+            None => Written::Created(new.value.clone()),
+            Some(old) if old.value != new.value => {
+                Written::Modified(old.value.clone(),
+                                  new.value.clone(),
+                                  old.permissions.clone())
+            }
+            Some(old) if old.permissions != new.permissions => {
+                Written::PermsChanged(old.permissions.clone())
+            }
+            Some(_) => Written::ChildrenChanged,
+        }
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum AppliedChange {
-    Write(Path, Vec<Permission>),
-    Remove(Path),
-    IntroduceDomain,
-    ReleaseDomain,
+    Write(Path, Vec<Permission>, Written),
+    Remove(Path, Vec<Permission>),
+    RemoveSubtree(Path, Vec<Permission>),
+    IntroduceDomain(wire::DomainId),
+    ReleaseDomain(wire::DomainId),
 }
 
 impl AppliedChange {
-    pub fn perms_ok(&self, dom_id: wire::DomainId, perm: Perm) -> bool {
+    /// The path that changed, if a node changed rather than a domain.
+    pub fn path(&self) -> Option<&Path> {
         match *self {
-            AppliedChange::Write(_, ref permissions) => perms_ok(dom_id, permissions, perm),
-            AppliedChange::Remove(_) => true,
-            AppliedChange::IntroduceDomain => true,
-            AppliedChange::ReleaseDomain => true,
+            AppliedChange::Write(ref path, _, _) |
+            AppliedChange::Remove(ref path, _) |
+            AppliedChange::RemoveSubtree(ref path, _) => Some(path),
+            AppliedChange::IntroduceDomain(_) |
+            AppliedChange::ReleaseDomain(_) => None,
+        }
+    }
+
+    /// Whether `authorizer` lets `dom_id`, acting on behalf of `target` if
+    /// it has one, `perm` what changed.
+    pub fn perms_ok(&self,
+                    authorizer: &Authorizer,
+                    dom_id: wire::DomainId,
+                    target: Option<wire::DomainId>,
+                    perm: Perm)
+                    -> bool {
+        match *self {
+            AppliedChange::Write(ref path, ref permissions, _) |
+            AppliedChange::Remove(ref path, ref permissions) |
+            AppliedChange::RemoveSubtree(ref path, ref permissions) => {
+                authorizer.check(dom_id, target, perm, path, permissions).is_ok()
+            }
+            AppliedChange::IntroduceDomain(_) => true,
+            AppliedChange::ReleaseDomain(_) => true,
         }
     }
 }
 
-/// Insert manual entries into a Store
-fn manual_entry(store: &mut HashMap<Path, Node>, name: Path, child_list: Vec<Basename>) {
-    let children = child_list.iter().cloned().collect::<HashSet<Basename>>();
+/// An immutable copy of the `Store` as it was at one generation.
+#[derive(Clone)]
+pub struct Snapshot {
+    generation: u64,
+    nodes: Tree<Path, Node>,
+}
+
+impl Snapshot {
+    /// The generation of the store this was taken at.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Look up the node at `path`, if there was one.
+    pub fn get(&self, path: &Path) -> Option<&Node> {
+        self.nodes.get(path)
+    }
+
+    /// Iterate over every node in the snapshot.
+    pub fn nodes(&self) -> Values<Path, Node> {
+        self.nodes.values()
+    }
+}
+
+/// The `Reader` type.
+///
+/// Answers reads made outside of a transaction from a snapshot of the
+/// store, applying the same checks as the store did when it was taken, so
+/// they can go ahead while the store itself is busy.
+#[derive(Clone)]
+pub struct Reader {
+    snapshot: Snapshot,
+    authorizer: Arc<Authorizer>,
+}
+
+impl Reader {
+    /// The generation of the store being read.
+    pub fn generation(&self) -> u64 {
+        self.snapshot.generation()
+    }
+
+    fn get_node(&self,
+                dom_id: wire::DomainId,
+                target: Option<wire::DomainId>,
+                path: &Path,
+                perm: Perm)
+                -> Result<&Node> {
+        let node = try!(self.snapshot
+                            .get(path)
+                            .ok_or(Error::ENOENT(format!("failed to lookup {:?}", path))));
+        self.authorizer.check(dom_id, target, perm, &node.path, &node.permissions).map(|_| node)
+    }
 
+    /// Read the `Value` at `path`, like `Store::read`, for `dom_id` acting
+    /// for `target` if it has one.
+    pub fn read(&self,
+                dom_id: wire::DomainId,
+                target: Option<wire::DomainId>,
+                path: &Path)
+                -> Result<Value> {
+        self.get_node(dom_id, target, path, Perm::Read).map(|node| node.value.clone())
+    }
+
+    /// List the children of `path`, like `Store::directory`.
+    pub fn directory(&self,
+                     dom_id: wire::DomainId,
+                     target: Option<wire::DomainId>,
+                     path: &Path)
+                     -> Result<Vec<Basename>> {
+        self.get_node(dom_id, target, path, Perm::Read)
+            .map(|node| node.children.keys().cloned().collect::<Vec<Basename>>())
+    }
+
+    /// Get the permissions of `path`, like `Store::get_perms`.
+    pub fn get_perms(&self,
+                     dom_id: wire::DomainId,
+                     target: Option<wire::DomainId>,
+                     path: &Path)
+                     -> Result<Vec<Permission>> {
+        self.get_node(dom_id, target, path, Perm::Read).map(|node| node.permissions.clone())
+    }
+}
+
+/// How a path differs between two snapshots
+#[derive(Clone, Debug, PartialEq)]
+pub enum Difference {
+    Added(Path),
+    Removed(Path),
+    // the value or the permissions of the node changed
+    Changed(Path),
+}
+
+impl Difference {
+    pub fn path(&self) -> &Path {
+        match *self {
+            Difference::Added(ref path) => path,
+            Difference::Removed(ref path) => path,
+            Difference::Changed(ref path) => path,
+        }
+    }
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match *self {
+            Difference::Added(_) => "+",
+            Difference::Removed(_) => "-",
+            Difference::Changed(_) => "~",
+        };
+        write!(f, "{} {}", kind, String::from_utf8_lossy(self.path().as_bytes()))
+    }
+}
+
+/// The nodes every new store starts out with, besides the features, as
+/// oxenstored sets them up: owned by dom0 and closed to everyone else.
+pub const BOOTSTRAP_PATHS: &'static [&'static str] = &["/",
+                                                       "/tool",
+                                                       "/tool/xenstored",
+                                                       "/local",
+                                                       "/local/domain",
+                                                       "/vm",
+                                                       "/libxl"];
+
+/// Permissions that leave a node to dom0 alone
+fn dom0_only() -> Vec<Permission> {
+    vec![Permission {
+             id: DOM0_DOMAIN_ID,
+             perm: Perm::None,
+         }]
+}
+
+/// Insert a manual entry into a Store, listing it among its parent's
+/// children and creating any parents that are missing for dom0 alone. An
+/// entry that is already there keeps its children.
+fn manual_entry(store: &mut Tree<Path, Node>,
+                name: Path,
+                value: Value,
+                permissions: Vec<Permission>) {
+    if let (Some(parent), Some(basename)) = (name.parent(), name.basename()) {
+        if !store.contains_key(&parent) {
+            manual_entry(store, parent.clone(), Value::new(), dom0_only());
+        }
+        let mut parent_node = store.get(&parent).unwrap().clone();
+        parent_node.children.insert(Basename::from(basename), ());
+        store.insert(parent, parent_node);
+    }
+
+    let children = store.get(&name).map(|node| node.children.clone()).unwrap_or_else(Children::new);
     store.insert(name.clone(),
                  Node {
                      path: name,
-                     value: Value::from(""),
+                     value: value,
                      children: children,
-                     permissions: vec![Permission {
-                                           id: DOM0_DOMAIN_ID,
-                                           perm: Perm::None,
-                                       }],
+                     permissions: permissions,
                  });
 }
 
+/// The `StoreBuilder` type.
+///
+/// Sets up the nodes a new `Store` starts out with, along with its quota.
+pub struct StoreBuilder {
+    nodes: Tree<Path, Node>,
+    quota: Quota,
+    authorizer: Arc<Authorizer>,
+}
+
+impl StoreBuilder {
+    /// Start from the nodes in `BOOTSTRAP_PATHS` and the advertised
+    /// features, which is what `Store::new` gives.
+    pub fn new() -> StoreBuilder {
+        let mut builder = StoreBuilder::bare();
+        for path in BOOTSTRAP_PATHS {
+            builder = builder.node(Path::try_from(DOM0_DOMAIN_ID, path).unwrap(),
+                                   Value::new(),
+                                   dom0_only());
+        }
+
+        // guests may look up what we support, but only dom0 may change it
+        let features = Path::try_from(DOM0_DOMAIN_ID, FEATURES_PATH).unwrap();
+        let readable = vec![Permission {
+                                id: DOM0_DOMAIN_ID,
+                                perm: Perm::Read,
+                            }];
+        builder = builder.node(features.clone(), Value::new(), readable.clone());
+        for feature in FEATURES {
+            builder = builder.node(features.push(feature), Value::from("1"), readable.clone());
+        }
+
+        builder
+    }
+
+    /// Start from nothing but `/`.
+    pub fn bare() -> StoreBuilder {
+        let mut nodes = Tree::new();
+        manual_entry(&mut nodes,
+                     Path::try_from(DOM0_DOMAIN_ID, "/").unwrap(),
+                     Value::new(),
+                     dom0_only());
+        StoreBuilder {
+            nodes: nodes,
+            quota: Quota::new(),
+            authorizer: Arc::new(PermissionAuthorizer),
+        }
+    }
+
+    /// Limit unprivileged domains to `quota`.
+    pub fn quota(mut self, quota: Quota) -> StoreBuilder {
+        self.quota = quota;
+        self
+    }
+
+    /// Decide who may access which nodes with `authorizer`.
+    pub fn authorizer(mut self, authorizer: Arc<Authorizer>) -> StoreBuilder {
+        self.authorizer = authorizer;
+        self
+    }
+
+    /// Start out with a node at `path`, replacing the value and permissions
+    /// of any that is there already. Missing parents are created for dom0
+    /// alone.
+    pub fn node(mut self,
+                path: Path,
+                value: Value,
+                permissions: Vec<Permission>)
+                -> StoreBuilder {
+        manual_entry(&mut self.nodes, path, value, permissions);
+        self
+    }
+
+    /// Create the `Store`.
+    pub fn build(self) -> Store {
+        Store::restore(0,
+                       self.nodes.values().cloned().collect(),
+                       self.quota,
+                       self.authorizer)
+    }
+}
+
 impl Store {
     pub fn new() -> Store {
-        let mut store = HashMap::new();
+        StoreBuilder::new().build()
+    }
+
+    /// Create a new `Store` limiting unprivileged domains to `quota`.
+    pub fn with_quota(quota: Quota) -> Store {
+        StoreBuilder::new().quota(quota).build()
+    }
+
+    /// Rebuild a `Store` at `generation` holding `nodes`, whose access is
+    /// decided by `authorizer`.
+    pub fn restore(generation: u64,
+                   nodes: Vec<Node>,
+                   quota: Quota,
+                   authorizer: Arc<Authorizer>)
+                   -> Store {
+        let mut store = Tree::new();
+        let mut usage = HashMap::new();
+        let mut names = Names::new();
+        for mut node in nodes {
+            node.charge(usage.entry(node.owner()).or_insert_with(Usage::default));
+            node.children = node.children
+                .keys()
+                .map(|name| (names.intern(name), ()))
+                .collect();
+            store.insert(node.path.clone(), node);
+        }
 
-        manual_entry(&mut store,
-                     Path::try_from(DOM0_DOMAIN_ID, "/").unwrap(),
-                     vec![Basename::from("tool")]);
-        manual_entry(&mut store,
-                     Path::try_from(DOM0_DOMAIN_ID, "/tool").unwrap(),
-                     vec![Basename::from("xenstored")]);
-        manual_entry(&mut store,
-                     Path::try_from(DOM0_DOMAIN_ID, "/tool/xenstored").unwrap(),
-                     vec![]);
         Store {
-            generation: Wrapping(0),
+            generation: Wrapping(generation),
             store: store,
+            modified: Tree::new(),
+            removals: VecDeque::new(),
+            quota: quota,
+            usage: usage,
+            names: RefCell::new(names),
+            authorizer: authorizer,
+            protected: PROTECTED_PATHS.iter()
+                .map(|path| Path::try_from(DOM0_DOMAIN_ID, path).unwrap())
+                .collect(),
+            subscribers: Subscribers::default(),
         }
     }
 
-    pub fn apply(&mut self, change_set: ChangeSet) -> Option<Vec<AppliedChange>> {
+    /// Subscribe to the changes applied to the store, without going through
+    /// watches.
+    ///
+    /// Every `apply` that changes something sends its `AppliedChange`s, in
+    /// the order they were applied. Dropping the receiver ends the
+    /// subscription, as does falling `SUBSCRIPTION_BUFFER` batches behind.
+    pub fn subscribe(&mut self) -> Receiver<Vec<AppliedChange>> {
+        self.subscribers.subscribe()
+    }
+
+    /// Everyone subscribed to the store, to hand to `set_subscribers` of a
+    /// store that replaces it.
+    pub fn subscribers(&self) -> Subscribers {
+        self.subscribers.clone()
+    }
+
+    /// Tell `subscribers` about the changes applied to this store from now
+    /// on, in place of whoever subscribed to it so far.
+    pub fn set_subscribers(&mut self, subscribers: Subscribers) {
+        self.subscribers = subscribers;
+    }
+
+    /// Load a `Store` previously saved with `save`.
+    pub fn load<P: AsRef<::std::path::Path>>(file: P,
+                                             quota: Quota,
+                                             authorizer: Arc<Authorizer>)
+                                             -> io::Result<Store> {
+        persistence::load(file.as_ref(), quota, authorizer)
+    }
+
+    /// Save the `Store` to a file.
+    pub fn save<P: AsRef<::std::path::Path>>(&self, file: P) -> io::Result<()> {
+        persistence::save(self, file.as_ref())
+    }
+
+    /// List the store the way `xenstore-ls -fp` does, one node per line.
+    pub fn dump_text(&self) -> String {
+        persistence::dump_text(self)
+    }
+
+    /// Rebuild a `Store` from the output of `dump_text` or `xenstore-ls -fp`.
+    pub fn load_text(text: &str,
+                     quota: Quota,
+                     authorizer: Arc<Authorizer>)
+                     -> io::Result<Store> {
+        persistence::load_text(text, quota, authorizer)
+    }
+
+    /// The number of changes that have been applied to the store.
+    pub fn generation(&self) -> u64 {
+        self.generation.0
+    }
+
+    /// Iterate over every node in the store.
+    pub fn nodes(&self) -> Values<Path, Node> {
+        self.store.values()
+    }
+
+    /// Check that the nodes hang together: each is kept under its own path
+    /// and has an owner, every child it lists is a node and every node but
+    /// `/` is listed by its parent.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::EIO` describing the first node found that doesn't
+    pub fn check_invariants(&self) -> Result<()> {
+        for (path, node) in self.store.iter() {
+            if &node.path != path {
+                return Err(Error::EIO(format!("{:?} is kept under {:?}", node.path, path)));
+            }
+
+            if node.permissions.is_empty() {
+                return Err(Error::EIO(format!("{:?} has no permissions", path)));
+            }
+
+            for child in node.children.keys() {
+                if !self.store.contains_key(&path.push(child)) {
+                    return Err(Error::EIO(format!("{:?} lists {} but it isn't there",
+                                                  path,
+                                                  child.as_str())));
+                }
+            }
+
+            if let Some(parent) = path.parent() {
+                let listed = match (self.store.get(&parent), path.basename()) {
+                    (Some(parent), Some(name)) => parent.children.contains_key(name.as_str()),
+                    _ => false,
+                };
+                if !listed {
+                    return Err(Error::EIO(format!("{:?} isn't listed by its parent", path)));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Take an immutable copy of the store as it is now.
+    ///
+    /// The copy shares its nodes with the store, so this is cheap.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            generation: self.generation.0,
+            nodes: self.store.clone(),
+        }
+    }
+
+    /// Take a `Reader` that answers reads as the store would right now.
+    pub fn reader(&self) -> Reader {
+        Reader {
+            snapshot: self.snapshot(),
+            authorizer: self.authorizer.clone(),
+        }
+    }
+
+    /// List the paths that were added, removed or changed going from `a` to
+    /// `b`, in path order.
+    pub fn diff(a: &Snapshot, b: &Snapshot) -> Vec<Difference> {
+        let mut differences = a.nodes()
+            .filter_map(|old| match b.get(&old.path) {
+                            None => Some(Difference::Removed(old.path.clone())),
+                            Some(new) if new.value != old.value ||
+                                         new.permissions != old.permissions => {
+                                Some(Difference::Changed(old.path.clone()))
+                            }
+                            Some(_) => None,
+                        })
+            .chain(b.nodes()
+                       .filter(|new| a.get(&new.path).is_none())
+                       .map(|new| Difference::Added(new.path.clone())))
+            .collect::<Vec<_>>();
+
+        differences.sort_by(|x, y| x.path().as_bytes().cmp(y.path().as_bytes()));
+        differences
+    }
+
+    pub fn quota(&self) -> &Quota {
+        &self.quota
+    }
+
+    /// How much of the store `dom_id` currently owns.
+    pub fn usage(&self, dom_id: wire::DomainId) -> Usage {
+        self.usage.get(&dom_id).cloned().unwrap_or(Usage::default())
+    }
+
+    /// How much of the store each domain that owns part of it is using.
+    pub fn usage_by_domain(&self) -> Vec<(wire::DomainId, Usage)> {
+        self.usage.iter().map(|(dom_id, usage)| (*dom_id, *usage)).collect()
+    }
+
+    /// Check that applying `change_set` would not take any domain over its quota.
+    ///
+    /// Only domains whose usage would grow are checked, so a domain that is
+    /// already over its quota can still tidy up.
+    fn check_quota(&self, change_set: &ChangeSet) -> Result<()> {
+        for (&dom_id, charged) in &change_set.charged {
+            let refunded = change_set.refunded.get(&dom_id).cloned().unwrap_or(Usage::default());
+            if charged.entries > refunded.entries || charged.bytes > refunded.bytes {
+                let current = self.usage(dom_id);
+                // the store may have moved on since the refunds were counted
+                let projected = Usage {
+                    entries: (current.entries + charged.entries).saturating_sub(refunded.entries),
+                    bytes: (current.bytes + charged.bytes).saturating_sub(refunded.bytes),
+                };
+                try!(self.quota.check_usage(dom_id, &projected));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Decide who may access which nodes with `authorizer` instead of the
+    /// nodes' permissions alone.
+    pub fn set_authorizer(&mut self, authorizer: Arc<Authorizer>) {
+        self.authorizer = authorizer;
+    }
+
+    /// What decides who may access which nodes.
+    pub fn authorizer(&self) -> Arc<Authorizer> {
+        self.authorizer.clone()
+    }
+
+    /// Only let dom0 write `protected` or create the nodes directly below
+    /// them, instead of `PROTECTED_PATHS`.
+    pub fn set_protected(&mut self, protected: Vec<Path>) {
+        self.protected = protected;
+    }
+
+    /// The nodes that only dom0 may write or create children of.
+    pub fn protected(&self) -> &[Path] {
+        &self.protected
+    }
+
+    /// Check that `dom_id` may write `path`, or add children to it, if it is
+    /// protected.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::EACCES` if `path` is protected and `dom_id` isn't dom0
+    fn check_protected(&self, dom_id: wire::DomainId, path: &Path) -> Result<()> {
+        if dom_id != DOM0_DOMAIN_ID && self.protected.contains(path) {
+            return Err(Error::EACCES(format!("only dom0 may write {:?}", path)));
+        }
+        Ok(())
+    }
+
+    /// Forget the paths removed at or before the generation `oldest`, that
+    /// the oldest changeset still to be applied was made from. Changesets
+    /// only conflict with what changed after they were made, so nothing
+    /// can conflict with those removals any more.
+    ///
+    /// Paths still in the store are remembered for `directory_part`.
+    pub fn forget_removed(&mut self, oldest: u64) {
+        while self.removals.front().map_or(false, |&(generation, _)| generation.0 <= oldest) {
+            let (generation, path) = self.removals.pop_front().unwrap();
+            if !self.store.contains_key(&path) && self.modified.get(&path) == Some(&generation) {
+                self.modified.remove(&path);
+            }
+        }
+    }
+
+    /// Apply a `ChangeSet` to the store.
+    ///
+    /// Returns the changes that were made so that watches can be fired. A
+    /// changeset that changes nothing leaves the generation where it was.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::EAGAIN` if anything the changeset read or wrote has been
+    ///   modified since it was created
+    pub fn apply(&mut self, change_set: ChangeSet) -> Result<Vec<AppliedChange>> {
         if self.generation != change_set.parent {
-            return None;
+            let reads = change_set.reads.borrow();
+            let newer = |generation: &Wrapping<u64>| generation.0 > change_set.parent.0;
+            let conflict = change_set.changes
+                .keys()
+                .chain(reads.keys())
+                .any(|path| self.modified.get(path).map_or(false, |generation| newer(generation)));
+            // as does anything changed below a removed subtree
+            let conflict = conflict ||
+                           change_set.removed.keys().any(|root| {
+                subtree(&self.modified, root).any(|(_, generation)| newer(generation))
+            });
+
+            if conflict {
+                return Err(Error::EAGAIN("conflicting changes were made to the store".into()));
+            }
         }
 
         let changes = &change_set.changes;
+        if changes.is_empty() {
+            return Ok(Vec::new());
+        }
+        let generation = self.generation + Wrapping(1);
+        let mut applied = Vec::new();
+
+        // clear out the removed subtrees first, the changes below them may
+        // write some of their paths again
+        for root in change_set.removed.keys() {
+            // a subtree that was only ever in the changeset leaves nothing to
+            // report
+            let permissions = self.store.get(root).map(|node| node.permissions.clone());
+            let gone = subtree(&self.store, root)
+                .map(|(path, _)| path.clone())
+                .collect::<Vec<Path>>();
+            for path in gone {
+                if let Some(old) = self.store.get(&path) {
+                    old.refund(self.usage.entry(old.owner()).or_insert_with(Usage::default));
+                }
+                self.store.remove(&path);
+                self.modified.insert(path.clone(), generation);
+                self.removals.push_back((generation, path));
+            }
+            if let Some(permissions) = permissions {
+                applied.push(AppliedChange::RemoveSubtree(root.clone(), permissions));
+            }
+        }
 
         for (path, change) in changes {
+            let old = self.store.get(path).cloned();
+            if let Some(ref old) = old {
+                old.refund(self.usage.entry(old.owner()).or_insert_with(Usage::default));
+            }
+
             match *change {
-                Change::Write(ref node) => self.store.insert(path.clone(), node.clone()),
-                Change::Remove(_) => self.store.remove(path),
+                Change::Write(ref node) => {
+                    node.charge(self.usage.entry(node.owner()).or_insert_with(Usage::default));
+                    self.store.insert(path.clone(), node.clone());
+                    applied.push(AppliedChange::Write(path.clone(),
+                                                      node.permissions.clone(),
+                                                      Written::between(old.as_ref(), node)));
+                }
+                Change::Remove(_) => {
+                    self.store.remove(path);
+                    self.removals.push_back((generation, path.clone()));
+                    // with the permissions it had, a restored removal doesn't
+                    // carry them
+                    if let Some(ref old) = old {
+                        applied.push(AppliedChange::Remove(path.clone(), old.permissions.clone()));
+                    }
+                }
+                // already reported along with the rest of the subtree
+                Change::RemoveSubtree(_) => {
+                    self.store.remove(path);
+                    self.removals.push_back((generation, path.clone()));
+                }
             };
+            self.modified.insert(path.clone(), generation);
         }
 
-        let applied = changes.iter()
-            .map(|(path, change)| match *change {
-                     Change::Write(ref node) => {
-                         AppliedChange::Write(path.clone(), node.permissions.clone())
-                     }
-                     Change::Remove(_) => AppliedChange::Remove(path.clone()),
-                 })
-            .collect::<Vec<AppliedChange>>();
+        self.generation = generation;
 
+        if cfg!(debug_assertions) {
+            if let Err(e) = self.check_invariants() {
+                panic!("store is inconsistent after applying changes: {}", e);
+            }
+        }
 
-        self.generation += Wrapping(1);
-        Some(applied)
+        self.subscribers.send(&applied);
+        Ok(applied)
     }
 
     fn get_node<'a>(&'a self,
@@ -211,26 +1245,39 @@ impl Store {
                     path: &Path,
                     perm: Perm)
                     -> Result<&'a Node> {
+        change_set.reads.borrow_mut().insert(path.clone(), ());
+
         let node = {
-            if change_set.changes.contains_key(path) {
-                match *change_set.changes.get(path).unwrap() {
-                    Change::Write(ref node) => Ok(node),
-                    Change::Remove(_) => Err(Error::ENOENT(format!("failed to lookup {:?}", path))),
-                }
-            } else {
-                self.store.get(path).ok_or(Error::ENOENT(format!("failed to lookup {:?}", path)))
+            match change_set.changes.get(path) {
+                Some(&Change::Write(ref node)) => Some(node),
+                Some(_) => None,
+                None if change_set.is_removed(path) => None,
+                None if change_set.removes_parent(path) => None,
+                None => self.store.get(path),
             }
         };
+        let node = node.ok_or(Error::ENOENT(format!("failed to lookup {:?}", path)));
 
-        node.and_then(|node| if !node.perms_ok(dom_id, perm) {
-                          Err(Error::EACCES(format!("failed to verify permissions for {:?}",
-                                                    node.path)))
-                      } else {
-                          Ok(node)
-                      })
+        let target = change_set.target.get();
+
+        node.and_then(|node| {
+            self.authorizer
+                .check(dom_id, target, perm, &node.path, &node.permissions)
+                .map(|_| node)
+        })
     }
 
-    /// Construct a new node
+    /// Construct a new node along with any missing parents, which are owned
+    /// by whoever creates them and otherwise inherit their parent's
+    /// permissions.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::EEXIST` when there is already a node at `path`.
+    /// * `Error::EACCES` when the closest existing parent isn't writable, even
+    ///   if parents further down are missing, as C xenstored doesn't let a
+    ///   domain find out what lies below a node it can't write. The same goes
+    ///   for a protected parent and domains other than dom0.
     #[doc(hidden)]
     fn construct_node(&self,
                       change_set: &ChangeSet,
@@ -239,7 +1286,8 @@ impl Store {
                       value: Value)
                       -> Result<LinkedList<Node>> {
 
-        // Get a list of paths that need to be created
+        // Get a list of paths that need to be created, stopping at the first
+        // one that exists whether or not it may be written
         let paths_to_create = path.clone()
             .into_iter()
             .take_while(|ref path| match self.get_node(change_set, dom_id, path, Perm::Write) {
@@ -248,16 +1296,18 @@ impl Store {
                         })
             .collect::<LinkedList<Path>>();
 
-        // If we are trying to construct a node and cannot, it is due to access restritions
         if paths_to_create.is_empty() {
-            return Err(Error::EACCES(format!("could not create {:?}", path)));
+            return Err(Error::EEXIST(format!("{:?} already exists", path)));
         }
 
-        // Get a copy of the highest parent that does not need to be created
+        // Get a copy of the highest parent that does not need to be created,
+        // which has to be writable to add the new nodes below it. The root
+        // always exists, so there is one.
         let parent_path = paths_to_create.back()
             .unwrap()
             .parent()
             .unwrap();
+        try!(self.check_protected(dom_id, &parent_path));
         let mut list = match self.get_node(change_set, dom_id, &parent_path, Perm::Write) {
             Ok(parent) => {
                 let mut lst = LinkedList::new();
@@ -274,7 +1324,7 @@ impl Store {
             let node = {
                 let mut parent = list.front_mut().unwrap();
                 if let Some(basename) = path.basename() {
-                    parent.children.insert(basename);
+                    parent.children.insert(self.names.borrow_mut().intern(&basename), ());
                 }
 
                 // Clone the immediate parent node's permissions
@@ -289,7 +1339,7 @@ impl Store {
                 Node {
                     path: path.clone(),
                     value: Value::from(""),
-                    children: HashSet::new(),
+                    children: Children::new(),
                     permissions: permissions,
                 }
             };
@@ -305,32 +1355,60 @@ impl Store {
         Ok(list)
     }
 
-    /// Write a `Value` at `Path` inside of the current transaction.
+    /// Write a `Value` at `Path` inside of the current transaction, creating
+    /// the node and any missing parents if need be.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::EACCES` when the node, or the closest existing parent of a
+    ///   new node, isn't writable or is protected from `dom_id`. This is
+    ///   checked before the quota is.
+    /// * `Error::E2BIG` when the value is too large for the domain to store.
+    /// * `Error::ENOSPC` when the domain would own too much of the store.
     pub fn write(&self,
                  change_set: &ChangeSet,
                  dom_id: wire::DomainId,
                  path: Path,
                  value: Value)
                  -> Result<ChangeSet> {
+        // no domain can store more than fits in a single message
+        if value.len() > wire::BODY_SIZE {
+            return Err(Error::E2BIG(format!("value of {} bytes is larger than {}",
+                                            value.len(),
+                                            wire::BODY_SIZE)));
+        }
+
         let node = {
             self.get_node(change_set, dom_id, &path, Perm::Write).map(|n| n.clone())
         };
 
         let mut changes = change_set.clone();
+        let size = value.len();
 
-        match node {
-            Ok(mut node) => {
-                node.value = value;
-                changes.insert(Change::Write(node));
-            }
-            _ => {
-                let nodes = try!(self.construct_node(change_set, dom_id, path, value));
+        if node.is_ok() {
+            try!(self.check_protected(dom_id, &path));
+        }
 
-                for node in nodes.iter() {
-                    changes.insert(Change::Write(node.clone()));
-                }
+        let nodes = match node {
+            // writing the value a node already has changes nothing
+            Ok(ref node) if node.value == value => return Ok(changes),
+            Ok(node) => {
+                let mut nodes = LinkedList::new();
+                nodes.push_back(Node { value: value, ..node });
+                nodes
             }
+            Err(Error::ENOENT(_)) => try!(self.construct_node(change_set, dom_id, path, value)),
+            Err(e) => return Err(e),
+        };
+
+        // the domain's quota only matters once it's known that it may write
+        try!(self.quota.check_entry_size(dom_id, size));
+
+        for node in nodes {
+            changes.insert(self, Change::Write(node));
         }
+
+        try!(self.check_quota(&changes));
         Ok(changes)
     }
 
@@ -348,6 +1426,8 @@ impl Store {
     }
 
     /// Make a new directory `Path` inside of the current transaction.
+    ///
+    /// Making a directory that already exists changes nothing.
     pub fn mkdir(&self,
                  change_set: &ChangeSet,
                  dom_id: wire::DomainId,
@@ -360,9 +1440,10 @@ impl Store {
                 let nodes = try!(self.construct_node(change_set, dom_id, path, Value::from("")));
 
                 for node in nodes.iter() {
-                    changes.insert(Change::Write(node.clone()));
+                    changes.insert(self, Change::Write(node.clone()));
                 }
 
+                try!(self.check_quota(&changes));
                 Ok(changes)
             }
             Ok(_) => Ok(changes),
@@ -372,6 +1453,10 @@ impl Store {
 
     /// Get a list of directories at `Path` inside the current transaction.
     ///
+    /// The children written or removed in the transaction are taken into
+    /// account even if the node's own list of them hasn't caught up, as
+    /// happens with transactions carried over by a live update.
+    ///
     /// # Errors
     ///
     /// * `Error::ENOENT` when the path does not exist in the transaction.
@@ -380,21 +1465,68 @@ impl Store {
                      dom_id: wire::DomainId,
                      path: &Path)
                      -> Result<Vec<Basename>> {
-        self.get_node(change_set, dom_id, path, Perm::Read).map(|node| {
-            let mut subdirs = node.children
-                .iter()
-                .map(|s| s.to_owned())
-                .collect::<Vec<Basename>>();
-            subdirs.sort();
-            subdirs
-        })
+        let mut children = {
+            try!(self.get_node(change_set, dom_id, path, Perm::Read)).children.clone()
+        };
+
+        // children below a subtree removed in the transaction are gone,
+        // unless they were written again
+        let stale = children.keys()
+            .filter(|name| change_set.is_removed(&path.push(name.as_str())))
+            .cloned()
+            .collect::<Vec<Basename>>();
+        for name in stale {
+            children.remove(name.as_str());
+        }
+
+        for (child, change) in subtree(&change_set.changes, path) {
+            if child.parent().as_ref() != Some(path) {
+                continue;
+            }
+            let name = match child.basename() {
+                Some(name) => name,
+                None => continue,
+            };
+            match *change {
+                Change::Write(_) => {
+                    children.insert(self.names.borrow_mut().intern(&name), ());
+                }
+                Change::Remove(_) |
+                Change::RemoveSubtree(_) => {
+                    children.remove(name.as_str());
+                }
+            }
+        }
+
+        Ok(children.keys().cloned().collect::<Vec<Basename>>())
     }
 
-    /// Remove an entry and its children from `Path` inside the current transaction.
+    /// Get a list of subdirectories at `Path` along with the generation that
+    /// last changed them, so that clients listing them in parts can tell if
+    /// the list changed underneath them.
     ///
     /// # Errors
     ///
     /// * `Error::ENOENT` when the path does not exist in the transaction.
+    pub fn directory_part(&self,
+                          change_set: &ChangeSet,
+                          dom_id: wire::DomainId,
+                          path: &Path)
+                          -> Result<(u64, Vec<Basename>)> {
+        let generation = self.modified.get(path).map(|generation| generation.0).unwrap_or(0);
+        self.directory(change_set, dom_id, path).map(|subdirs| (generation, subdirs))
+    }
+
+    /// Remove an entry and its children from `Path` inside the current transaction.
+    ///
+    /// Only the entry itself has to be writable, what lies below it is
+    /// removed without looking at each node. Removing an entry that isn't
+    /// there changes nothing, as long as its parent is.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::ENOENT` when the parent of the path does not exist in the
+    ///   transaction.
     pub fn rm(&self,
               change_set: &ChangeSet,
               dom_id: wire::DomainId,
@@ -413,30 +1545,20 @@ impl Store {
         let parent_node = try!(self.get_node(&changes, dom_id, &parent, Perm::Write)
                                    .map(|node| {
                                             let mut children = node.children.clone();
-                                            children.remove(&basename);
+                                            children.remove(basename.as_str());
                                             Node { children: children, ..node.clone() }
                                         }));
-        changes.insert(Change::Write(parent_node));
 
-        let mut remove = LinkedList::new();
-        remove.push_back(path.clone());
-
-        while let Some(path) = remove.pop_front() {
-            // Grab a list of all of the children
-            let node = {
-                try!(self.get_node(change_set, dom_id, &path, Perm::Write))
-            };
-
-            // And recursively remove all of its children
-            for child in &node.children {
-                let path = path.push(&child);
-                remove.push_back(path);
-            }
-
-            // Then remove the child node
-            changes.insert(Change::Remove(node.clone()));
+        match self.get_node(change_set, dom_id, path, Perm::Write) {
+            // it's already gone, which is all that was asked for
+            Err(Error::ENOENT(_)) => return Ok(changes),
+            Err(e) => return Err(e),
+            Ok(_) => {}
         }
 
+        changes.insert(self, Change::Write(parent_node));
+        changes.remove_subtree(self, path.clone());
+
         Ok(changes)
     }
 
@@ -453,34 +1575,142 @@ impl Store {
         self.get_node(change_set, dom_id, path, Perm::Read).map(|node| node.permissions.clone())
     }
 
-    /// Set the permissions for a node.
+    /// Set the permissions for a node, the first of which names its owner.
+    ///
+    /// The store's `Authorizer` decides who may change them, which with
+    /// the usual policy is the node's owner (or a domain targeting it) and
+    /// dom0, and only dom0 may hand it to a new owner.
     ///
     /// # Errors
     ///
     /// * `Error::ENOENT` when the path does not exist in the transaction.
+    /// * `Error::EINVAL` when there are no permissions to name an owner.
+    /// * `Error::EACCES` when `dom_id` doesn't own the node, or tries to give
+    ///   it away.
     pub fn set_perms(&self,
                      change_set: &ChangeSet,
                      dom_id: wire::DomainId,
                      path: &Path,
                      permissions: Vec<Permission>)
                      -> Result<ChangeSet> {
+        if permissions.is_empty() {
+            return Err(Error::EINVAL(format!("no owner given for {:?}", path)));
+        }
+
         let node = {
             try!(self.get_node(change_set, dom_id, path, Perm::Write).map(|node| node.clone()))
         };
 
+        let target = change_set.target.get();
+        try!(self.authorizer
+                 .check_set_perms(dom_id, target, path, &node.permissions, &permissions));
+
         let mut changes = change_set.clone();
-        changes.insert(Change::Write(Node { permissions: permissions, ..node }));
+        changes.insert(self, Change::Write(Node { permissions: permissions, ..node }));
+
+        try!(self.check_quota(&changes));
         Ok(changes)
     }
 }
 
 #[cfg(test)]
 mod test {
+    extern crate quickcheck;
+
+    use self::quickcheck::quickcheck;
     use std::num::Wrapping;
     use super::super::error::Error;
     use super::super::path::Path;
+    use super::super::quota::{Quota, Usage};
     use super::*;
 
+    #[test]
+    fn permission_strings() {
+        for s in &["n0", "r1", "w2", "b3"] {
+            assert_eq!(&Permission::try_from(s).unwrap().to_string(), s);
+        }
+
+        for s in &["", "x1", "r", "rx", "é1"] {
+            match Permission::try_from(s) {
+                Err(Error::EINVAL(_)) => assert!(true),
+                Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+                Ok(_) => assert!(false, format!("parsed {:?} as a permission", s)),
+            }
+        }
+    }
+
+    #[test]
+    fn permission_parse() {
+        fn prop(s: String) -> bool {
+            // anything a client sends is either a permission or EINVAL, and
+            // whatever parses reads back the same once written out again
+            match Permission::try_from(&s) {
+                Ok(perm) => Permission::try_from(&perm.to_string()).ok() == Some(perm),
+                Err(Error::EINVAL(_)) => true,
+                Err(_) => false,
+            }
+        }
+
+        quickcheck(prop as fn(String) -> bool);
+    }
+
+    #[test]
+    fn permission_round_trip() {
+        fn prop(id: wire::DomainId, perm: u8) -> bool {
+            let perm = Permission {
+                id: id,
+                perm: match perm % 4 {
+                    0 => Perm::None,
+                    1 => Perm::Read,
+                    2 => Perm::Write,
+                    _ => Perm::Both,
+                },
+            };
+
+            Permission::try_from(&perm.to_string()).ok() == Some(perm)
+        }
+
+        quickcheck(prop as fn(wire::DomainId, u8) -> bool);
+    }
+
+    #[test]
+    fn snapshot_diff() {
+        let mut store = Store::new();
+        let before = store.snapshot();
+
+        let basic = Path::try_from(DOM0_DOMAIN_ID, "/basic").unwrap();
+        let tool = Path::try_from(DOM0_DOMAIN_ID, "/tool").unwrap();
+        let xenstored = Path::try_from(DOM0_DOMAIN_ID, "/tool/xenstored").unwrap();
+
+        let changes = store.write(&ChangeSet::new(&store),
+                         DOM0_DOMAIN_ID,
+                         basic.clone(),
+                         Value::from("value"))
+            .unwrap();
+        let changes = store.write(&changes, DOM0_DOMAIN_ID, tool.clone(), Value::from("value"))
+            .unwrap();
+        let changes = store.rm(&changes, DOM0_DOMAIN_ID, &xenstored).unwrap();
+        store.apply(changes).unwrap();
+
+        // the snapshot doesn't change along with the store
+        let after = store.snapshot();
+        assert_eq!(before.generation(), 0);
+        assert_eq!(after.generation(), 1);
+        assert!(before.get(&basic).is_none());
+
+        // the parents of added and removed nodes only change their children,
+        // which isn't counted as a change
+        let features = Path::try_from(DOM0_DOMAIN_ID, FEATURES_PATH).unwrap();
+        assert_eq!(Store::diff(&before, &after),
+                   vec![Difference::Added(basic),
+                        Difference::Changed(tool),
+                        Difference::Removed(xenstored),
+                        Difference::Removed(features.clone()),
+                        Difference::Removed(features.push("directory-part")),
+                        Difference::Removed(features.push("live-update"))]);
+        assert_eq!(Store::diff(&after, &after), vec![]);
+    }
+
     #[test]
     fn basic_write() {
         let store = Store::new();
@@ -518,6 +1748,90 @@ mod test {
         assert_eq!(read, value);
     }
 
+    #[test]
+    fn advertised_features() {
+        let store = Store::new();
+        let changes = ChangeSet::new(&store);
+        let features = Path::try_from(DOM0_DOMAIN_ID, FEATURES_PATH).unwrap();
+
+        let mut listed = store.directory(&changes, 1, &features).unwrap();
+        listed.sort();
+        let mut expected =
+            FEATURES.iter().map(|feature| Basename::from(*feature)).collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(listed, expected);
+
+        for feature in FEATURES {
+            let path = features.push(feature);
+            assert_eq!(store.read(&changes, 1, &path).unwrap(), Value::from("1"));
+
+            match store.write(&changes, 1, path, Value::from("0")) {
+                Err(Error::EACCES(_)) => assert!(true),
+                Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+                Ok(_) => assert!(false, "a guest changed an advertised feature"),
+            }
+        }
+    }
+
+    #[test]
+    fn bootstrap_tree() {
+        let store = Store::new();
+        let changes = ChangeSet::new(&store);
+        let root = Path::try_from(DOM0_DOMAIN_ID, "/").unwrap();
+
+        let mut listed = store.directory(&changes, DOM0_DOMAIN_ID, &root).unwrap();
+        listed.sort();
+        assert_eq!(listed,
+                   vec![Basename::from("libxl"),
+                        Basename::from("local"),
+                        Basename::from("tool"),
+                        Basename::from("vm")]);
+
+        // the conventional nodes are there for dom0 alone
+        for path in BOOTSTRAP_PATHS {
+            let path = Path::try_from(DOM0_DOMAIN_ID, path).unwrap();
+            assert_eq!(store.read(&changes, DOM0_DOMAIN_ID, &path).unwrap(), "");
+            assert_eq!(store.get_perms(&changes, DOM0_DOMAIN_ID, &path).unwrap(),
+                       dom0_only());
+            match store.read(&changes, 1, &path) {
+                Err(Error::EACCES(_)) => assert!(true),
+                Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+                Ok(_) => assert!(false, "a guest read {:?}", path),
+            }
+        }
+    }
+
+    #[test]
+    fn bootstrap_builder() {
+        let name = Path::try_from(DOM0_DOMAIN_ID, "/local/domain/0/name").unwrap();
+        let tool = Path::try_from(DOM0_DOMAIN_ID, "/tool").unwrap();
+        let readable = vec![Permission {
+                                id: DOM0_DOMAIN_ID,
+                                perm: Perm::Read,
+                            }];
+        let store = StoreBuilder::bare()
+            .quota(Quota { max_entries: 2, ..Quota::new() })
+            .node(name.clone(), Value::from("Domain-0"), readable.clone())
+            .build();
+        let changes = ChangeSet::new(&store);
+
+        // the parents are made along the way, without anything else
+        assert_eq!(store.read(&changes, 1, &name).unwrap(), "Domain-0");
+        assert_eq!(store.get_perms(&changes, 1, &name).unwrap(), readable);
+        let domain = name.parent().unwrap();
+        assert_eq!(store.get_perms(&changes, DOM0_DOMAIN_ID, &domain).unwrap(),
+                   dom0_only());
+        assert_eq!(store.directory(&changes, DOM0_DOMAIN_ID, &domain).unwrap(),
+                   vec![Basename::from("name")]);
+        assert!(store.read(&changes, DOM0_DOMAIN_ID, &tool).is_err());
+        assert_eq!(store.quota().max_entries, 2);
+
+        // and seeding a node that's there already keeps its children
+        let store = StoreBuilder::new().node(tool.clone(), Value::from("tools"), readable).build();
+        assert_eq!(store.directory(&ChangeSet::new(&store), 1, &tool).unwrap(),
+                   vec![Basename::from("xenstored")]);
+    }
+
     #[test]
     fn basic_applied_write_and_read() {
         let mut store = Store::new();
@@ -538,6 +1852,115 @@ mod test {
         assert_eq!(read, value);
     }
 
+    #[test]
+    fn applied_changes_say_what_was_written() {
+        let mut store = Store::new();
+        let root = Path::try_from(DOM0_DOMAIN_ID, "/").unwrap();
+        let path = Path::try_from(DOM0_DOMAIN_ID, "/basic").unwrap();
+        let written = |applied: &[AppliedChange], path: &Path| {
+            applied.iter()
+                .filter_map(|change| match *change {
+                    AppliedChange::Write(ref p, _, ref w) if p == path => Some(w.clone()),
+                    _ => None,
+                })
+                .next()
+                .unwrap()
+        };
+
+        let changes = store.write(&ChangeSet::new(&store),
+                                  DOM0_DOMAIN_ID,
+                                  path.clone(),
+                                  Value::from("one"))
+            .unwrap();
+        let applied = store.apply(changes).unwrap();
+        assert_eq!(written(&applied, &path), Written::Created(Value::from("one")));
+        assert_eq!(written(&applied, &root), Written::ChildrenChanged);
+
+        let changes = store.write(&ChangeSet::new(&store),
+                                  DOM0_DOMAIN_ID,
+                                  path.clone(),
+                                  Value::from("two"))
+            .unwrap();
+        let perms = store.get_perms(&ChangeSet::new(&store), DOM0_DOMAIN_ID, &path).unwrap();
+        let applied = store.apply(changes).unwrap();
+        assert_eq!(written(&applied, &path),
+                   Written::Modified(Value::from("one"), Value::from("two"), perms.clone()));
+
+        let changes = store.set_perms(&ChangeSet::new(&store),
+                                      DOM0_DOMAIN_ID,
+                                      &path,
+                                      vec![Permission {
+                                               id: 1,
+                                               perm: Perm::Read,
+                                           }])
+            .unwrap();
+        let applied = store.apply(changes).unwrap();
+        assert_eq!(written(&applied, &path), Written::PermsChanged(perms));
+    }
+
+    #[test]
+    fn subscribe() {
+        let mut store = Store::new();
+        let path = Path::try_from(DOM0_DOMAIN_ID, "/basic").unwrap();
+        let subscription = store.subscribe();
+
+        let changes = store.write(&ChangeSet::new(&store),
+                                  DOM0_DOMAIN_ID,
+                                  path.clone(),
+                                  Value::from("value"))
+            .unwrap();
+        store.apply(changes).unwrap();
+
+        let paths = subscription.try_recv()
+            .unwrap()
+            .iter()
+            .map(|change| change.path().unwrap().clone())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, vec![Path::try_from(DOM0_DOMAIN_ID, "/").unwrap(), path.clone()]);
+
+        // nothing is sent when nothing changed
+        store.apply(ChangeSet::new(&store)).unwrap();
+        assert!(subscription.try_recv().is_err());
+
+        // a store that takes over keeps the subscription going
+        let mut loaded = Store::load_text(&store.dump_text(),
+                                          Quota::new(),
+                                          Arc::new(PermissionAuthorizer))
+            .unwrap();
+        loaded.set_subscribers(store.subscribers());
+        let changes = loaded.rm(&ChangeSet::new(&loaded), DOM0_DOMAIN_ID, &path).unwrap();
+        loaded.apply(changes).unwrap();
+        assert!(subscription.try_recv().is_ok());
+
+        drop(subscription);
+        let changes = loaded.write(&ChangeSet::new(&loaded),
+                                   DOM0_DOMAIN_ID,
+                                   path.clone(),
+                                   Value::from("value"))
+            .unwrap();
+        loaded.apply(changes).unwrap();
+        assert!(loaded.subscribers.is_empty());
+    }
+
+    #[test]
+    fn slow_subscriber() {
+        let mut store = Store::new();
+        let path = Path::try_from(DOM0_DOMAIN_ID, "/basic").unwrap();
+        let subscription = store.subscribe();
+
+        // one batch more than fits leaves the subscriber behind for good
+        for n in 0..SUBSCRIPTION_BUFFER + 1 {
+            let changes = store.write(&ChangeSet::new(&store),
+                                      DOM0_DOMAIN_ID,
+                                      path.clone(),
+                                      Value::from(n.to_string()))
+                .unwrap();
+            store.apply(changes).unwrap();
+        }
+        assert!(store.subscribers.is_empty());
+        assert_eq!(subscription.iter().count(), SUBSCRIPTION_BUFFER);
+    }
+
     #[test]
     fn recursive_write() {
         let store = Store::new();
@@ -589,6 +2012,45 @@ mod test {
         assert_eq!(read, "");
     }
 
+    #[test]
+    fn unchanged_write_and_mkdir() {
+        let mut store = Store::new();
+        let path = Path::try_from(DOM0_DOMAIN_ID, "/basic/path").unwrap();
+
+        let changes = store.write(&ChangeSet::new(&store),
+                         DOM0_DOMAIN_ID,
+                         path.clone(),
+                         Value::from("value"))
+            .unwrap();
+        store.apply(changes).unwrap();
+        let generation = store.generation();
+
+        // writing the same value again, or making a directory that's there
+        // already, applies nothing and leaves the generation alone
+        let changes = store.write(&ChangeSet::new(&store),
+                         DOM0_DOMAIN_ID,
+                         path.clone(),
+                         Value::from("value"))
+            .unwrap();
+        assert_eq!(changes.changes().count(), 0);
+        assert!(store.apply(changes).unwrap().is_empty());
+
+        for dir in vec![path.clone(), path.parent().unwrap()] {
+            let changes = store.mkdir(&ChangeSet::new(&store), DOM0_DOMAIN_ID, dir).unwrap();
+            assert!(store.apply(changes).unwrap().is_empty());
+        }
+        assert_eq!(store.generation(), generation);
+
+        // while a different value is still a change
+        let changes = store.write(&ChangeSet::new(&store),
+                         DOM0_DOMAIN_ID,
+                         path.clone(),
+                         Value::from("other"))
+            .unwrap();
+        assert_eq!(store.apply(changes).unwrap().len(), 1);
+        assert_eq!(store.generation(), generation + 1);
+    }
+
     #[test]
     fn basic_directory() {
         let store = Store::new();
@@ -607,90 +2069,432 @@ mod test {
     }
 
     #[test]
-    fn rm_deletes_all_directories() {
-        let store = Store::new();
+    fn directory_follows_changes_to_children() {
+        let mut store = Store::new();
+        let basic = Path::try_from(DOM0_DOMAIN_ID, "/basic").unwrap();
+        let path1 = Path::try_from(DOM0_DOMAIN_ID, "/basic/path1").unwrap();
+        let path2 = Path::try_from(DOM0_DOMAIN_ID, "/basic/path2").unwrap();
+        let path3 = Path::try_from(DOM0_DOMAIN_ID, "/basic/path3").unwrap();
+        let path4 = Path::try_from(DOM0_DOMAIN_ID, "/basic/path4").unwrap();
+
+        let mut changes = ChangeSet::new(&store);
+        for path in &[&path1, &path2, &path3] {
+            changes = store.mkdir(&changes, DOM0_DOMAIN_ID, (*path).clone()).unwrap();
+        }
+        store.apply(changes).unwrap();
+
+        // a transaction whose parent node never heard of the changes made
+        // to its children, like one carried over by a live update
+        let node = |path: &Path| {
+            Node {
+                path: path.clone(),
+                value: Value::new(),
+                children: Children::new(),
+                permissions: vec![Permission {
+                                      id: DOM0_DOMAIN_ID,
+                                      perm: Perm::None,
+                                  }],
+            }
+        };
+        let changes = ChangeSet::restore(&store,
+                                         vec![Change::Remove(node(&path1)),
+                                              Change::RemoveSubtree(path2.clone()),
+                                              Change::Write(node(&path4))],
+                                         Vec::new());
+
+        assert_eq!(store.directory(&changes, DOM0_DOMAIN_ID, &basic).unwrap(),
+                   vec![String::from("path3"), String::from("path4")]);
+    }
+
+    #[test]
+    fn directory_part_generation() {
+        let mut store = Store::new();
+        let path1 = Path::try_from(DOM0_DOMAIN_ID, "/basic/path1").unwrap();
+        let path2 = Path::try_from(DOM0_DOMAIN_ID, "/basic/path2").unwrap();
+        let parent = path1.parent().unwrap();
+
+        let changes = store.mkdir(&ChangeSet::new(&store), DOM0_DOMAIN_ID, path1.clone())
+            .unwrap();
+        store.apply(changes).unwrap();
+        let (generation, subdirs) =
+            store.directory_part(&ChangeSet::new(&store), DOM0_DOMAIN_ID, &parent).unwrap();
+        assert_eq!(subdirs, vec![Basename::from("path1")]);
+
+        // adding a child moves the generation on
+        let changes = store.mkdir(&ChangeSet::new(&store), DOM0_DOMAIN_ID, path2.clone())
+            .unwrap();
+        store.apply(changes).unwrap();
+        let (next, subdirs) =
+            store.directory_part(&ChangeSet::new(&store), DOM0_DOMAIN_ID, &parent).unwrap();
+        assert_eq!(subdirs,
+                   vec![Basename::from("path1"), Basename::from("path2")]);
+        assert!(next > generation);
+    }
+
+    #[test]
+    fn rm_deletes_all_directories() {
+        let store = Store::new();
+
+        let path1 = Path::try_from(DOM0_DOMAIN_ID, "/basic/path1").unwrap();
+        let path2 = Path::try_from(DOM0_DOMAIN_ID, "/basic/path2").unwrap();
+        let basic = path1.parent().unwrap();
+        let mut changes = store.mkdir(&ChangeSet::new(&store), DOM0_DOMAIN_ID, path1.clone())
+            .unwrap();
+        changes = store.mkdir(&changes, DOM0_DOMAIN_ID, path2.clone()).unwrap();
+
+        changes = store.rm(&changes, DOM0_DOMAIN_ID, &basic).unwrap();
+
+        // verify the parent directory was removed
+        match store.read(&changes, DOM0_DOMAIN_ID, &basic) {
+            Err(Error::ENOENT(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, format!("failed to remove {:?}", basic)),
+        }
+
+        // verify the path1 directory was removed
+        match store.read(&changes, DOM0_DOMAIN_ID, &path1) {
+            Err(Error::ENOENT(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, format!("failed to remove {:?}", basic)),
+        }
+
+        // verify the path2 directory was removed
+        match store.read(&changes, DOM0_DOMAIN_ID, &path2) {
+            Err(Error::ENOENT(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, format!("failed to remove {:?}", basic)),
+        }
+
+        // verify the root still exists
+        let read = store.read(&changes,
+                              DOM0_DOMAIN_ID,
+                              &Path::try_from(DOM0_DOMAIN_ID, "/").unwrap())
+            .unwrap();
+        assert_eq!(read, "");
+    }
+
+    #[test]
+    fn rm_cannot_delete_root() {
+        let store = Store::new();
+
+        let path1 = Path::try_from(DOM0_DOMAIN_ID, "/basic/path1").unwrap();
+
+        let changes = store.mkdir(&ChangeSet::new(&store), DOM0_DOMAIN_ID, path1.clone()).unwrap();
+
+        let rslt = store.rm(&changes,
+                            DOM0_DOMAIN_ID,
+                            &Path::try_from(DOM0_DOMAIN_ID, "/").unwrap());
+
+        match rslt {
+            Ok(_) => assert!(false, "removed the root directory"),
+            Err(Error::EINVAL(_)) => assert!(true, "it is invalid to try and remove /"),
+            Err(_) => assert!(false, "unknown error"),
+        }
+    }
+
+    #[test]
+    fn rm_missing_entry() {
+        let mut store = Store::new();
+        let device = Path::try_from(DOM0_DOMAIN_ID, "/local/domain/1/device").unwrap();
+
+        let changes = store.mkdir(&ChangeSet::new(&store), DOM0_DOMAIN_ID, device.clone())
+            .unwrap();
+        store.apply(changes).unwrap();
+        let generation = store.generation();
+
+        // an entry that isn't there below one that is is already removed
+        let changes = store.rm(&ChangeSet::new(&store), DOM0_DOMAIN_ID, &device.push("vbd"))
+            .unwrap();
+        assert_eq!(changes.changes().count(), 0);
+        assert!(store.apply(changes).unwrap().is_empty());
+        assert_eq!(store.generation(), generation);
+
+        // the same goes inside a transaction that removed it already
+        let changes = store.rm(&ChangeSet::new(&store), DOM0_DOMAIN_ID, &device).unwrap();
+        store.rm(&changes, DOM0_DOMAIN_ID, &device).unwrap();
+
+        // but without a parent there is nothing to remove it from
+        let disk = device.push("vbd").push("51712");
+        match store.rm(&ChangeSet::new(&store), DOM0_DOMAIN_ID, &disk) {
+            Err(Error::ENOENT(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "removed an entry below a missing parent"),
+        }
+    }
+
+    #[test]
+    fn rm_removes_from_parent() {
+        let store = Store::new();
+
+        // Create the global state
+        let path1 = Path::try_from(DOM0_DOMAIN_ID, "/basic/path1").unwrap();
+        let path2 = Path::try_from(DOM0_DOMAIN_ID, "/basic/path2").unwrap();
+        let basic = path1.parent().unwrap();
+
+        let mut changes = store.mkdir(&ChangeSet::new(&store), DOM0_DOMAIN_ID, path1.clone())
+            .unwrap();
+        changes = store.mkdir(&changes, DOM0_DOMAIN_ID, path2.clone()).unwrap();
+
+        changes = store.rm(&changes, DOM0_DOMAIN_ID, &path1).unwrap();
+
+        // verify the path1 directory was removed
+        match store.read(&changes, DOM0_DOMAIN_ID, &path1) {
+            Err(Error::ENOENT(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, format!("failed to remove {:?}", basic)),
+        }
+
+        let subdirs = store.directory(&changes, DOM0_DOMAIN_ID, &basic).unwrap();
+        assert_eq!(subdirs, vec![String::from("path2")]);
+    }
+
+    #[test]
+    fn rm_then_write_below() {
+        let mut store = Store::new();
+
+        let basic = Path::try_from(DOM0_DOMAIN_ID, "/basic").unwrap();
+        let path1 = Path::try_from(DOM0_DOMAIN_ID, "/basic/path1").unwrap();
+        let path2 = Path::try_from(DOM0_DOMAIN_ID, "/basic/path2").unwrap();
+        let old = Path::try_from(DOM0_DOMAIN_ID, "/basic/path1/old").unwrap();
+        let new = Path::try_from(DOM0_DOMAIN_ID, "/basic/path1/new").unwrap();
+
+        let changes = store.write(&ChangeSet::new(&store),
+                         DOM0_DOMAIN_ID,
+                         old.clone(),
+                         Value::from("old"))
+            .unwrap();
+        let changes = store.mkdir(&changes, DOM0_DOMAIN_ID, path2.clone()).unwrap();
+        store.apply(changes).unwrap();
+        let before = store.usage(DOM0_DOMAIN_ID);
+
+        // writing below a removed tree only brings back what was written
+        let changes = store.rm(&ChangeSet::new(&store), DOM0_DOMAIN_ID, &basic).unwrap();
+        let changes = store.write(&changes, DOM0_DOMAIN_ID, new.clone(), Value::from("new"))
+            .unwrap();
+
+        for path in &[&old, &path2] {
+            match store.read(&changes, DOM0_DOMAIN_ID, path) {
+                Err(Error::ENOENT(_)) => assert!(true),
+                Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+                Ok(_) => assert!(false, format!("failed to remove {:?}", path)),
+            }
+        }
+        assert_eq!(store.directory(&changes, DOM0_DOMAIN_ID, &basic).unwrap(),
+                   vec![String::from("path1")]);
+        assert_eq!(store.directory(&changes, DOM0_DOMAIN_ID, &path1).unwrap(),
+                   vec![String::from("new")]);
+
+        store.apply(changes).unwrap();
+        let left = store.nodes()
+            .map(|node| node.path.clone())
+            .filter(|path| path.is_child(&basic))
+            .collect::<Vec<Path>>();
+        assert_eq!(left, vec![basic, path1, new]);
+
+        // four nodes went away and three came back
+        assert_eq!(store.usage(DOM0_DOMAIN_ID).entries, before.entries - 1);
+    }
+
+    #[test]
+    fn rm_then_read_below() {
+        let mut store = Store::new();
+
+        let a = Path::try_from(DOM0_DOMAIN_ID, "/a").unwrap();
+        let b = Path::try_from(DOM0_DOMAIN_ID, "/a/b").unwrap();
+        let c = Path::try_from(DOM0_DOMAIN_ID, "/a/b/c").unwrap();
+
+        let changes = store.write(&ChangeSet::new(&store),
+                         DOM0_DOMAIN_ID,
+                         c.clone(),
+                         Value::from("c"))
+            .unwrap();
+        store.apply(changes).unwrap();
+
+        let gone = |changes: &ChangeSet, path: &Path| {
+            match store.read(changes, DOM0_DOMAIN_ID, path) {
+                Err(Error::ENOENT(_)) => assert!(true),
+                Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+                Ok(_) => assert!(false, format!("found {:?} below a removed node", path)),
+            }
+            match store.get_perms(changes, DOM0_DOMAIN_ID, path) {
+                Err(Error::ENOENT(_)) => assert!(true),
+                Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+                Ok(_) => assert!(false, format!("found {:?} below a removed node", path)),
+            }
+        };
+
+        // nothing below a removed node can be found
+        let removed = store.rm(&ChangeSet::new(&store), DOM0_DOMAIN_ID, &a).unwrap();
+        gone(&removed, &b);
+        gone(&removed, &c);
+
+        // even when only the node itself was recorded as removed, as it is
+        // for transactions carried over by a live update
+        let node = Node {
+            path: a.clone(),
+            value: Value::new(),
+            children: Children::new(),
+            permissions: Vec::new(),
+        };
+        let restored = ChangeSet::restore(&store, vec![Change::Remove(node)], Vec::new());
+        gone(&restored, &b);
+        gone(&restored, &c);
+
+        // writing below it again starts afresh
+        for changes in &[removed, restored] {
+            let changes = store.write(changes, DOM0_DOMAIN_ID, c.clone(), Value::from("new"))
+                .unwrap();
+            assert_eq!(store.read(&changes, DOM0_DOMAIN_ID, &b).unwrap(), Value::from(""));
+            assert_eq!(store.read(&changes, DOM0_DOMAIN_ID, &c).unwrap(), Value::from("new"));
+        }
+    }
+
+    #[test]
+    fn invariants() {
+        let mut store = Store::new();
+        store.check_invariants().unwrap();
+
+        let a = Path::try_from(DOM0_DOMAIN_ID, "/a").unwrap();
+        let c = Path::try_from(DOM0_DOMAIN_ID, "/a/b/c").unwrap();
+
+        let changes = store.write(&ChangeSet::new(&store),
+                         DOM0_DOMAIN_ID,
+                         c.clone(),
+                         Value::from("c"))
+            .unwrap();
+        store.apply(changes).unwrap();
+        store.check_invariants().unwrap();
+
+        let changes = store.rm(&ChangeSet::new(&store), DOM0_DOMAIN_ID, &a).unwrap();
+        store.apply(changes).unwrap();
+        store.check_invariants().unwrap();
+
+        let node = |path: &str, children: &[&str]| {
+            Node {
+                path: Path::try_from(DOM0_DOMAIN_ID, path).unwrap(),
+                value: Value::new(),
+                children: children.iter().map(|child| (Basename::from(*child), ())).collect(),
+                permissions: vec![Permission {
+                                      id: DOM0_DOMAIN_ID,
+                                      perm: Perm::None,
+                                  }],
+            }
+        };
+
+        let broken = vec![// a child that isn't there
+                          vec![node("/", &["a"])],
+                          // a node its parent doesn't list
+                          vec![node("/", &[]), node("/a", &[])],
+                          // a node below one that isn't there
+                          vec![node("/", &[]), node("/a/b", &[])]];
+        for nodes in broken {
+            let store = Store::restore(0, nodes, Quota::new(), Arc::new(PermissionAuthorizer));
+            match store.check_invariants() {
+                Err(Error::EIO(_)) => assert!(true),
+                Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+                Ok(_) => assert!(false, "missed an inconsistent store"),
+            }
+        }
+    }
+
+    #[test]
+    fn rm_conflicts_with_changes_below() {
+        let mut store = Store::new();
 
-        let path1 = Path::try_from(DOM0_DOMAIN_ID, "/basic/path1").unwrap();
-        let path2 = Path::try_from(DOM0_DOMAIN_ID, "/basic/path2").unwrap();
-        let basic = path1.parent().unwrap();
-        let mut changes = store.mkdir(&ChangeSet::new(&store), DOM0_DOMAIN_ID, path1.clone())
+        let basic = Path::try_from(DOM0_DOMAIN_ID, "/basic").unwrap();
+        let path = Path::try_from(DOM0_DOMAIN_ID, "/basic/path1/path2").unwrap();
+
+        let changes = store.write(&ChangeSet::new(&store),
+                         DOM0_DOMAIN_ID,
+                         path.clone(),
+                         Value::from("value"))
             .unwrap();
-        changes = store.mkdir(&changes, DOM0_DOMAIN_ID, path2.clone()).unwrap();
+        store.apply(changes).unwrap();
 
-        changes = store.rm(&changes, DOM0_DOMAIN_ID, &basic).unwrap();
+        // the removal never looks at path2, but still conflicts with it
+        let removal = store.rm(&ChangeSet::new(&store), DOM0_DOMAIN_ID, &basic).unwrap();
+        let changes = store.write(&ChangeSet::new(&store),
+                         DOM0_DOMAIN_ID,
+                         path.clone(),
+                         Value::from("new"))
+            .unwrap();
+        store.apply(changes).unwrap();
 
-        // verify the parent directory was removed
-        match store.read(&changes, DOM0_DOMAIN_ID, &basic) {
-            Err(Error::ENOENT(_)) => assert!(true),
+        match store.apply(removal) {
+            Err(Error::EAGAIN(_)) => assert!(true),
             Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
-            Ok(_) => assert!(false, format!("failed to remove {:?}", basic)),
+            Ok(_) => assert!(false, "removed a tree that changed underneath it"),
         }
+    }
 
-        // verify the path1 directory was removed
-        match store.read(&changes, DOM0_DOMAIN_ID, &path1) {
-            Err(Error::ENOENT(_)) => assert!(true),
-            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
-            Ok(_) => assert!(false, format!("failed to remove {:?}", basic)),
-        }
+    #[test]
+    fn forget_removed() {
+        let mut store = Store::new();
+        let path = Path::try_from(DOM0_DOMAIN_ID, "/basic").unwrap();
 
-        // verify the path2 directory was removed
-        match store.read(&changes, DOM0_DOMAIN_ID, &path2) {
-            Err(Error::ENOENT(_)) => assert!(true),
+        let changes = store.write(&ChangeSet::new(&store),
+                                  DOM0_DOMAIN_ID,
+                                  path.clone(),
+                                  Value::from("value"))
+            .unwrap();
+        store.apply(changes).unwrap();
+        let older = ChangeSet::new(&store);
+        let changes = store.rm(&ChangeSet::new(&store), DOM0_DOMAIN_ID, &path).unwrap();
+        store.apply(changes).unwrap();
+
+        // a changeset made before the removal still conflicts with it
+        let modified = store.modified.len();
+        store.forget_removed(older.generation());
+        assert_eq!(store.modified.len(), modified);
+        let changes = store.write(&older, DOM0_DOMAIN_ID, path.clone(), Value::from("new"))
+            .unwrap();
+        match store.apply(changes) {
+            Err(Error::EAGAIN(_)) => assert!(true),
             Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
-            Ok(_) => assert!(false, format!("failed to remove {:?}", basic)),
+            Ok(_) => assert!(false, "wrote over a removal made underneath it"),
         }
 
-        // verify the root still exists
-        let read = store.read(&changes,
-                              DOM0_DOMAIN_ID,
-                              &Path::try_from(DOM0_DOMAIN_ID, "/").unwrap())
-            .unwrap();
-        assert_eq!(read, "");
+        // but once there are none the removal is forgotten
+        let generation = store.generation();
+        store.forget_removed(generation);
+        assert_eq!(store.modified.len(), modified - 1);
+        assert!(store.removals.is_empty());
     }
 
     #[test]
-    fn rm_cannot_delete_root() {
-        let store = Store::new();
-
-        let path1 = Path::try_from(DOM0_DOMAIN_ID, "/basic/path1").unwrap();
+    fn names_are_shared() {
+        let mut store = Store::new();
 
-        let changes = store.mkdir(&ChangeSet::new(&store), DOM0_DOMAIN_ID, path1.clone()).unwrap();
+        let front = Path::try_from(DOM0_DOMAIN_ID, "/front").unwrap();
+        let back = Path::try_from(DOM0_DOMAIN_ID, "/back").unwrap();
 
-        let rslt = store.rm(&changes,
-                            DOM0_DOMAIN_ID,
-                            &Path::try_from(DOM0_DOMAIN_ID, "/").unwrap());
+        let changes = store.write(&ChangeSet::new(&store),
+                         DOM0_DOMAIN_ID,
+                         front.push("state"),
+                         Value::from("1"))
+            .unwrap();
+        let changes = store.write(&changes, DOM0_DOMAIN_ID, back.push("state"), Value::from("1"))
+            .unwrap();
+        store.apply(changes).unwrap();
 
-        match rslt {
-            Ok(_) => assert!(false, "removed the root directory"),
-            Err(Error::EINVAL(_)) => assert!(true, "it is invalid to try and remove /"),
-            Err(_) => assert!(false, "unknown error"),
-        }
+        let changes = ChangeSet::new(&store);
+        let front = store.directory(&changes, DOM0_DOMAIN_ID, &front).unwrap();
+        let back = store.directory(&changes, DOM0_DOMAIN_ID, &back).unwrap();
+        assert_eq!(front[0].as_ptr(), back[0].as_ptr());
     }
 
     #[test]
-    fn rm_removes_from_parent() {
-        let store = Store::new();
-
-        // Create the global state
-        let path1 = Path::try_from(DOM0_DOMAIN_ID, "/basic/path1").unwrap();
-        let path2 = Path::try_from(DOM0_DOMAIN_ID, "/basic/path2").unwrap();
-        let basic = path1.parent().unwrap();
-
-        let mut changes = store.mkdir(&ChangeSet::new(&store), DOM0_DOMAIN_ID, path1.clone())
-            .unwrap();
-        changes = store.mkdir(&changes, DOM0_DOMAIN_ID, path2.clone()).unwrap();
-
-        changes = store.rm(&changes, DOM0_DOMAIN_ID, &path1).unwrap();
+    fn unused_names_are_dropped() {
+        let mut names = Names::new();
+        let kept = names.intern("kept");
 
-        // verify the path1 directory was removed
-        match store.read(&changes, DOM0_DOMAIN_ID, &path1) {
-            Err(Error::ENOENT(_)) => assert!(true),
-            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
-            Ok(_) => assert!(false, format!("failed to remove {:?}", basic)),
+        for i in 0..MIN_NAMES {
+            names.intern(&format!("{}", i));
         }
 
-        let subdirs = store.directory(&changes, DOM0_DOMAIN_ID, &basic).unwrap();
-        assert_eq!(subdirs, vec![String::from("path2")]);
+        // filling up the table drops everything but the name still in use
+        assert_eq!(names.names.len(), 2);
+        assert_eq!(names.intern("kept").as_ptr(), kept.as_ptr());
     }
 
     #[test]
@@ -885,6 +2689,128 @@ mod test {
         assert_eq!(read, Value::from("new value"));
     }
 
+    #[test]
+    fn write_creates_parents() {
+        let store = Store::with_quota(Quota { max_entry_size: 4, ..Quota::new() });
+        let home = Path::try_from(DOM0_DOMAIN_ID, "/local/domain/1").unwrap();
+        let other = Path::try_from(DOM0_DOMAIN_ID, "/local/domain/2").unwrap();
+
+        let mut changes = ChangeSet::new(&store);
+        for (dom_path, dom_id) in vec![(&home, 1), (&other, 2)] {
+            changes = store.mkdir(&changes, DOM0_DOMAIN_ID, dom_path.clone()).unwrap();
+            changes = store.set_perms(&changes,
+                                      DOM0_DOMAIN_ID,
+                                      dom_path,
+                                      vec![Permission {
+                                               id: dom_id,
+                                               perm: Perm::None,
+                                           },
+                                           Permission {
+                                               id: 3,
+                                               perm: Perm::Read,
+                                           }])
+                .unwrap();
+        }
+
+        // a domain may create a whole branch below a node it can write, and
+        // owns every node it creates while the rest of the permissions are
+        // inherited
+        let path = home.push("device").push("vif").push("0");
+        changes = store.write(&changes, 1, path.clone(), Value::from("4")).unwrap();
+        let vif = path.parent().unwrap();
+        for created in vec![vif.parent().unwrap(), vif, path] {
+            assert_eq!(store.get_perms(&changes, DOM0_DOMAIN_ID, &created).unwrap(),
+                       vec![Permission {
+                                id: 1,
+                                perm: Perm::None,
+                            },
+                            Permission {
+                                id: 3,
+                                perm: Perm::Read,
+                            }]);
+        }
+
+        // but below a node it can't write, it doesn't get to find out
+        // whether anything is missing, and the size of what it writes
+        // doesn't matter
+        for path in vec![other.clone(),
+                         other.push("missing"),
+                         other.push("missing").push("deeper")] {
+            for value in vec!["ok", "too long"] {
+                match store.write(&changes, 1, path.clone(), Value::from(value)) {
+                    Err(Error::EACCES(_)) => assert!(true),
+                    Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+                    Ok(_) => assert!(false, "wrote below another domain's node"),
+                }
+            }
+        }
+
+        // where it may write, the size is what gets in the way
+        match store.write(&changes, 1, home.push("name"), Value::from("too long")) {
+            Err(Error::E2BIG(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "wrote an oversized value"),
+        }
+    }
+
+    #[test]
+    fn protected_paths() {
+        let mut store = Store::new();
+        let root = Path::try_from(DOM0_DOMAIN_ID, "/").unwrap();
+        let local = Path::try_from(DOM0_DOMAIN_ID, "/local").unwrap();
+        let home = Path::try_from(DOM0_DOMAIN_ID, "/local/domain/1").unwrap();
+        let everyone = |id| {
+            vec![Permission {
+                     id: id,
+                     perm: Perm::Both,
+                 }]
+        };
+
+        // even with the permissions of the top level nodes opened up
+        let mut changes = store.mkdir(&ChangeSet::new(&store), DOM0_DOMAIN_ID, home.clone())
+            .unwrap();
+        for path in vec![&root, &local, &home] {
+            changes = store.set_perms(&changes, DOM0_DOMAIN_ID, path, everyone(DOM0_DOMAIN_ID))
+                .unwrap();
+        }
+        store.apply(changes).unwrap();
+
+        // guests can't write them or create anything directly below them
+        for path in vec![root.push("foo"),
+                         root.push("foo").push("bar"),
+                         local.clone(),
+                         local.push("foo"),
+                         Path::try_from(DOM0_DOMAIN_ID, "/vm/foo").unwrap()] {
+            match store.write(&ChangeSet::new(&store), 1, path.clone(), Value::from("v")) {
+                Err(Error::EACCES(_)) => assert!(true),
+                Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+                Ok(_) => assert!(false, "a guest wrote {:?}", path),
+            }
+            match store.mkdir(&ChangeSet::new(&store), 1, path.clone()) {
+                Err(Error::EACCES(_)) => assert!(true),
+                Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+                Ok(_) if path == local => assert!(true, "it's already there"),
+                Ok(_) => assert!(false, "a guest created {:?}", path),
+            }
+        }
+
+        // while further down the permissions decide
+        store.write(&ChangeSet::new(&store), 1, home.push("foo"), Value::from("v")).unwrap();
+
+        // and dom0 may write wherever it likes
+        store.write(&ChangeSet::new(&store), DOM0_DOMAIN_ID, root.push("foo"), Value::from("v"))
+            .unwrap();
+
+        // unless told otherwise
+        store.set_protected(vec![local.clone()]);
+        store.write(&ChangeSet::new(&store), 1, root.push("foo"), Value::from("v")).unwrap();
+        match store.write(&ChangeSet::new(&store), 1, local.push("foo"), Value::from("v")) {
+            Err(Error::EACCES(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "a guest wrote below a protected node"),
+        }
+    }
+
     #[test]
     fn block_cross_domain_rm() {
         let store = Store::new();
@@ -919,6 +2845,207 @@ mod test {
         store.rm(&changes, DOM0_DOMAIN_ID, &path).unwrap();
     }
 
+    #[test]
+    fn target_domain_access() {
+        let mut store = Store::new();
+
+        let mut changes = store.mkdir(&ChangeSet::new(&store),
+                                      DOM0_DOMAIN_ID,
+                                      Path::try_from(DOM0_DOMAIN_ID, "/local/domain/1").unwrap())
+            .unwrap();
+
+        changes = store.set_perms(&changes,
+                                  DOM0_DOMAIN_ID,
+                                  &Path::try_from(DOM0_DOMAIN_ID, "/local/domain/1").unwrap(),
+                                  vec![Permission {
+                                           id: 1,
+                                           perm: Perm::None,
+                                       }])
+            .unwrap();
+
+        let path = Path::try_from(1, "foo").unwrap();
+        let value = Value::from("value");
+        changes = store.write(&changes, 1, path.clone(), value.clone()).unwrap();
+
+        // Check the domain 2 is blocked
+        match store.read(&changes, 2, &path) {
+            Ok(_) => assert!(false, "allowed cross-domain read"),
+            Err(Error::EACCES(..)) => assert!(true, "blocked cross-domain read"),
+            Err(_) => assert!(false, "unknown error"),
+        }
+
+        // Once domain 2 targets domain 1 it can act on its behalf
+        changes.act_for(Some(1));
+        let read = store.read(&changes, 2, &path).unwrap();
+        assert_eq!(read, value);
+        store.write(&changes, 2, path.clone(), Value::from("new value")).unwrap();
+
+        // But not anymore once the target is cleared
+        changes.act_for(None);
+        match store.read(&changes, 2, &path) {
+            Ok(_) => assert!(false, "allowed cross-domain read"),
+            Err(Error::EACCES(..)) => assert!(true, "blocked cross-domain read"),
+            Err(_) => assert!(false, "unknown error"),
+        }
+    }
+
+    #[test]
+    fn custom_authorizer() {
+        // keeps everyone, dom0 included, away from anything below /secret
+        struct NoSecrets;
+
+        impl Authorizer for NoSecrets {
+            fn check(&self,
+                     _: wire::DomainId,
+                     _: Option<wire::DomainId>,
+                     _: Perm,
+                     path: &Path,
+                     _: &[Permission])
+                     -> Result<()> {
+                if path.as_bytes().starts_with(b"/secret") {
+                    Err(Error::EACCES(format!("{:?} is secret", path)))
+                } else {
+                    Ok(())
+                }
+            }
+
+            fn check_set_perms(&self,
+                               _: wire::DomainId,
+                               _: Option<wire::DomainId>,
+                               _: &Path,
+                               _: &[Permission],
+                               _: &[Permission])
+                               -> Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut store = Store::new();
+        let secret = Path::try_from(DOM0_DOMAIN_ID, "/secret").unwrap();
+        let public = Path::try_from(DOM0_DOMAIN_ID, "/public").unwrap();
+        let changes = store.write(&ChangeSet::new(&store),
+                                  DOM0_DOMAIN_ID,
+                                  secret.clone(),
+                                  Value::from("value"))
+            .unwrap();
+        store.apply(changes).unwrap();
+
+        store.set_authorizer(Arc::new(NoSecrets));
+        store.set_protected(vec![]);
+
+        match store.read(&ChangeSet::new(&store), DOM0_DOMAIN_ID, &secret) {
+            Err(Error::EACCES(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "read a node the authorizer denied"),
+        }
+
+        // the node permissions are no longer consulted, so with nothing
+        // protected domain 1 may write anywhere else
+        store.write(&ChangeSet::new(&store), 1, public, Value::from("value")).unwrap();
+
+        // and hand over nodes it doesn't own
+        let root = Path::try_from(DOM0_DOMAIN_ID, "/").unwrap();
+        let owned_by_2 = vec![Permission {
+                                  id: 2,
+                                  perm: Perm::None,
+                              }];
+        store.set_perms(&ChangeSet::new(&store), 1, &root, owned_by_2).unwrap();
+
+        // and readers taken from the store ask the same authorizer
+        match store.reader().read(DOM0_DOMAIN_ID, None, &secret) {
+            Err(Error::EACCES(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "read a node the authorizer denied"),
+        }
+    }
+
+    #[test]
+    fn reader() {
+        let mut store = Store::new();
+        let path = Path::try_from(DOM0_DOMAIN_ID, "/local/domain/1/foo").unwrap();
+        let mut changes = store.write(&ChangeSet::new(&store),
+                                      DOM0_DOMAIN_ID,
+                                      path.clone(),
+                                      Value::from("old"))
+            .unwrap();
+        changes = store.set_perms(&changes,
+                                  DOM0_DOMAIN_ID,
+                                  &path,
+                                  vec![Permission {
+                                           id: 1,
+                                           perm: Perm::None,
+                                       }])
+            .unwrap();
+        store.apply(changes).unwrap();
+
+        let reader = store.reader();
+        let changes = store.write(&ChangeSet::new(&store), 1, path.clone(), Value::from("new"))
+            .unwrap();
+        store.apply(changes).unwrap();
+
+        // a reader keeps answering from the store as it was when taken
+        assert_eq!(reader.generation(), store.generation() - 1);
+        assert_eq!(reader.read(1, None, &path).unwrap(), Value::from("old"));
+        assert_eq!(store.reader().read(1, None, &path).unwrap(), Value::from("new"));
+        assert_eq!(reader.get_perms(1, None, &path).unwrap(),
+                   store.get_perms(&ChangeSet::new(&store), 1, &path).unwrap());
+
+        let parent = Path::try_from(DOM0_DOMAIN_ID, "/local/domain/1").unwrap();
+        assert_eq!(reader.directory(DOM0_DOMAIN_ID, None, &parent).unwrap(),
+                   vec![Basename::from("foo")]);
+
+        // and checks permissions the way the store does
+        match reader.read(2, None, &path) {
+            Err(Error::EACCES(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "read another domain's node"),
+        }
+    }
+
+    #[test]
+    fn set_perms_owner_rules() {
+        let store = Store::new();
+        let domain = Path::try_from(DOM0_DOMAIN_ID, "/local/domain/1").unwrap();
+        let shared = Path::try_from(DOM0_DOMAIN_ID, "/local/domain/1/shared").unwrap();
+        let owned = |id: wire::DomainId, others: Perm| {
+            vec![Permission {
+                     id: id,
+                     perm: others,
+                 }]
+        };
+
+        // domain 1 owns its directory, and may write to a node owned by domain 2
+        let mut changes = store.mkdir(&ChangeSet::new(&store), DOM0_DOMAIN_ID, domain.clone())
+            .unwrap();
+        changes = store.set_perms(&changes, DOM0_DOMAIN_ID, &domain, owned(1, Perm::None))
+            .unwrap();
+        changes = store.mkdir(&changes, DOM0_DOMAIN_ID, shared.clone()).unwrap();
+        changes = store.set_perms(&changes, DOM0_DOMAIN_ID, &shared, owned(2, Perm::Both))
+            .unwrap();
+
+        // the owner may change who else has access, but not hand the node over
+        let changes = store.set_perms(&changes, 1, &domain, owned(1, Perm::Read)).unwrap();
+        match store.set_perms(&changes, 1, &domain, owned(2, Perm::Read)) {
+            Err(Error::EACCES(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "a guest gave its node away"),
+        }
+
+        // write access isn't enough to take over a node
+        match store.set_perms(&changes, 1, &shared, owned(1, Perm::None)) {
+            Err(Error::EACCES(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "a guest took over a node it doesn't own"),
+        }
+
+        // and there must always be an owner
+        match store.set_perms(&changes, DOM0_DOMAIN_ID, &domain, vec![]) {
+            Err(Error::EINVAL(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "removed the owner of a node"),
+        }
+    }
+
     #[test]
     fn block_cross_domain_directory() {
         let store = Store::new();
@@ -951,4 +3078,91 @@ mod test {
         // Check the Dom0 is still allowed
         store.directory(&changes, DOM0_DOMAIN_ID, &domain).unwrap();
     }
+
+    #[test]
+    fn write_body_size() {
+        let store = Store::new();
+        let path = Path::try_from(DOM0_DOMAIN_ID, "/basic").unwrap();
+
+        // even dom0 can't store a value it could never read back
+        let value = Value::from(String::from_utf8(vec![b'a'; wire::BODY_SIZE + 1]).unwrap());
+        match store.write(&ChangeSet::new(&store), DOM0_DOMAIN_ID, path.clone(), value) {
+            Err(Error::E2BIG(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "wrote an oversized value"),
+        }
+
+        let value = Value::from(String::from_utf8(vec![b'a'; wire::BODY_SIZE]).unwrap());
+        store.write(&ChangeSet::new(&store), DOM0_DOMAIN_ID, path, value).unwrap();
+    }
+
+    #[test]
+    fn quota_entry_size() {
+        let mut store = Store::with_quota(Quota { max_entry_size: 4, ..Quota::new() });
+        let path = Path::try_from(DOM0_DOMAIN_ID, "/basic").unwrap();
+        store.set_protected(vec![]);
+
+        let changes = store.set_perms(&ChangeSet::new(&store),
+                                      DOM0_DOMAIN_ID,
+                                      &Path::try_from(DOM0_DOMAIN_ID, "/").unwrap(),
+                                      vec![Permission {
+                                               id: DOM0_DOMAIN_ID,
+                                               perm: Perm::Write,
+                                           }])
+            .unwrap();
+
+        match store.write(&changes, 1, path.clone(), Value::from("value")) {
+            Err(Error::E2BIG(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "wrote an oversized value"),
+        }
+
+        store.write(&changes, 1, path, Value::from("four")).unwrap();
+    }
+
+    #[test]
+    fn quota_entries() {
+        let mut store = Store::with_quota(Quota { max_entries: 2, ..Quota::new() });
+        let path = Path::try_from(DOM0_DOMAIN_ID, "/local/domain/1").unwrap();
+
+        // give domain 1 its home directory like the toolstack would
+        let changes = store.mkdir(&ChangeSet::new(&store), DOM0_DOMAIN_ID, path.clone())
+            .unwrap();
+        let changes = store.set_perms(&changes,
+                                      DOM0_DOMAIN_ID,
+                                      &path,
+                                      vec![Permission {
+                                               id: 1,
+                                               perm: Perm::None,
+                                           }])
+            .unwrap();
+        store.apply(changes).unwrap();
+        assert_eq!(store.usage(1),
+                   Usage {
+                       entries: 1,
+                       bytes: 0,
+                   });
+
+        let changes = store.write(&ChangeSet::new(&store), 1, path.push("a"), Value::from("v"))
+            .unwrap();
+        store.apply(changes).unwrap();
+        assert_eq!(store.usage(1),
+                   Usage {
+                       entries: 2,
+                       bytes: 1,
+                   });
+
+        match store.mkdir(&ChangeSet::new(&store), 1, path.push("b")) {
+            Err(Error::ENOSPC(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "created more nodes than the quota"),
+        }
+
+        // removing a node frees up room again
+        let changes = store.rm(&ChangeSet::new(&store), 1, &path.push("a")).unwrap();
+        store.apply(changes).unwrap();
+        assert_eq!(store.usage(1).entries, 1);
+
+        store.mkdir(&ChangeSet::new(&store), 1, path.push("b")).unwrap();
+    }
 }
@@ -16,83 +16,782 @@
     with this program; if not, see <http://www.gnu.org/licenses/>.
 **/
 
-use std::collections::HashSet;
-use super::connection::ConnId;
-use super::error::Result;
+extern crate mio;
+
+use self::mio::Token;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io;
+use std::mem;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::mpsc::Receiver;
+use super::connection::{ConnId, Outbox, MAX_QUEUED_EVENTS};
+use super::domain::*;
+use super::error::{Error, Result};
+use super::logger::LogHandle;
+use super::metrics::{Gauge, Metrics};
+use super::path::Path;
+use super::persistence::Persister;
+use super::quota::{Usage, QUOTA_NAMES};
+use super::tracelog::{self, TraceLog};
 use super::transaction::*;
 use super::watch::*;
 use super::wire;
 use super::store::*;
 
+/// The most snapshots of the store kept around for debugging at once
+pub const MAX_SNAPSHOTS: usize = 8;
+
+/// Present when @introduceDomain and @releaseDomain events carry a domain id
+pub const DOMAIN_IDS_FEATURE: &'static str = "/tool/xenstored/features/domain-ids";
+
+/// Present when dom0 may register wildcard watches
+pub const WILDCARD_WATCHES_FEATURE: &'static str = "/tool/xenstored/features/wildcard-watches";
+
+/// The `DomainUsage` type.
+///
+/// How much of the daemon a domain is taking up, to find the guest that is
+/// hogging it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DomainUsage {
+    /// The nodes it owns and their size
+    pub store: Usage,
+    pub watches: usize,
+    pub transactions: usize,
+    /// Watch events waiting to be delivered to its connections
+    pub queued_events: usize,
+}
+
+/// The identity `conn` has once the XS_RESTRICT it may have sent is taken
+/// into account
+fn effective_conn(restricted: &HashMap<ConnId, wire::DomainId>, conn: ConnId) -> ConnId {
+    match restricted.get(&conn) {
+        Some(dom_id) => ConnId { dom_id: *dom_id, ..conn },
+        None => conn,
+    }
+}
+
+/// The domain `conn` acts for with XS_SET_TARGET, if any.
+fn target(targets: &HashMap<ConnId, wire::DomainId>, conn: ConnId) -> Option<wire::DomainId> {
+    targets.get(&conn).cloned()
+}
+
+/// The `ReadView` type.
+///
+/// Everything needed to answer requests that only read the store, published
+/// by the `System` after it changes so those requests can be answered
+/// without waiting for it.
+#[derive(Clone)]
+pub struct ReadView {
+    pub reader: Reader,
+    restricted: HashMap<ConnId, wire::DomainId>,
+    targets: HashMap<ConnId, wire::DomainId>,
+    // requests must go through the System while they are being logged
+    traced: bool,
+    metrics: Arc<Mutex<Metrics>>,
+}
+
+impl ReadView {
+    /// Whether the request described by `header` can be answered from the
+    /// view: a read, directory listing or permissions lookup made outside
+    /// of a transaction while nothing is being logged.
+    pub fn answers(&self, header: &wire::Header) -> bool {
+        let read_only = match header.msg_type {
+            wire::XS_READ | wire::XS_DIRECTORY | wire::XS_GET_PERMS => true,
+            _ => false,
+        };
+        read_only && header.tx_id == ROOT_TRANSACTION && !self.traced
+    }
+
+    /// The identity `conn` has, taking any XS_RESTRICT it sent into account.
+    pub fn effective_conn(&self, conn: ConnId) -> ConnId {
+        effective_conn(&self.restricted, conn)
+    }
+
+    /// The domain `conn` acts for with XS_SET_TARGET, if any.
+    pub fn target(&self, conn: ConnId) -> Option<wire::DomainId> {
+        target(&self.targets, conn)
+    }
+
+    /// Count a handled request of `msg_type` and the `reply` sent for it.
+    pub fn record_request(&self, msg_type: u32, reply: &(wire::Header, wire::Body)) {
+        self.metrics.lock().unwrap().record(msg_type, reply);
+    }
+}
+
 pub struct System {
     store: Store,
     watches: WatchList,
     txns: TransactionList,
+    domains: DomainList,
+    next_token: usize,
+    outboxes: HashMap<ConnId, Outbox>,
+    persister: Option<Persister>,
+    // the connection each domain's ring is served on, from when it is first
+    // needed, or carried over by a live update, until it is closed
+    rings: HashMap<wire::DomainId, ConnId>,
+    live_update: bool,
+    // copies of the store taken for debugging, oldest first
+    snapshots: VecDeque<Snapshot>,
+    // remove a domain's path from the store when it is released
+    release_cleanup: bool,
+    // log every request and reply, toggled with the `log` control command
+    trace: bool,
+    // adjusts the daemon's logging from control commands, when it has one
+    log_handle: Option<LogHandle>,
+    // records every request while the access log is on
+    tracelog: Option<TraceLog>,
+    // where the access log goes the next time it is turned on
+    tracelog_path: PathBuf,
+    // counts of the requests handled and errors returned
+    metrics: Arc<Mutex<Metrics>>,
+    // the domain that connections which sent XS_RESTRICT now act as
+    restricted: HashMap<ConnId, wire::DomainId>,
+    // the domain that connections sent XS_SET_TARGET for may act for
+    targets: HashMap<ConnId, wire::DomainId>,
+    // what requests that only read are answered from
+    view: Arc<RwLock<ReadView>>,
+    // watch events fired by the request being handled, queued on the
+    // outboxes once its reply has gone out
+    fired: Events,
+    // changes made outside a transaction by a `do_store_batch` that is
+    // under way, applied together once it is done
+    batch: Option<ChangeSet>,
 }
 
 impl System {
-    pub fn new(store: Store, watches: WatchList, txns: TransactionList) -> System {
+    pub fn new(store: Store,
+               mut watches: WatchList,
+               txns: TransactionList,
+               domains: DomainList)
+               -> System {
+        watches.set_authorizer(store.authorizer());
+        let metrics = Arc::new(Mutex::new(Metrics::new()));
+        let view = ReadView {
+            reader: store.reader(),
+            restricted: HashMap::new(),
+            targets: HashMap::new(),
+            traced: false,
+            metrics: metrics.clone(),
+        };
+
         System {
             store: store,
             watches: watches,
             txns: txns,
+            domains: domains,
+            next_token: 0,
+            outboxes: HashMap::new(),
+            persister: None,
+            rings: HashMap::new(),
+            live_update: false,
+            snapshots: VecDeque::new(),
+            release_cleanup: false,
+            trace: false,
+            log_handle: None,
+            tracelog: None,
+            tracelog_path: PathBuf::from(tracelog::DEFAULT_TRACELOG_PATH),
+            metrics: metrics,
+            restricted: HashMap::new(),
+            targets: HashMap::new(),
+            view: Arc::new(RwLock::new(view)),
+            fired: Events::new(),
+            batch: None,
+        }
+    }
+
+    /// Periodically save the store with `persister` as it changes.
+    pub fn set_persister(&mut self, persister: Persister) {
+        self.persister = Some(persister);
+    }
+
+    /// Choose whether releasing a domain also removes its domain path and
+    /// everything below it. Off by default, leaving it to the toolstack.
+    pub fn set_release_cleanup(&mut self, cleanup: bool) {
+        self.release_cleanup = cleanup;
+    }
+
+    pub fn release_cleanup(&self) -> bool {
+        self.release_cleanup
+    }
+
+    /// Choose whether every request and its reply are logged.
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+        self.publish();
+    }
+
+    pub fn trace(&self) -> bool {
+        self.trace
+    }
+
+    /// Let control commands change the level and destination of the log
+    /// with `handle`.
+    pub fn set_log_handle(&mut self, handle: LogHandle) {
+        self.log_handle = Some(handle);
+    }
+
+    pub fn log_handle(&self) -> Option<&LogHandle> {
+        self.log_handle.as_ref()
+    }
+
+    /// Count a handled request of `msg_type` and the `reply` sent for it.
+    pub fn record_request(&mut self, msg_type: u32, reply: &(wire::Header, wire::Body)) {
+        self.metrics.lock().unwrap().record(msg_type, reply);
+    }
+
+    /// Look up the quota `name`, as it applies to `dom_id` if one is given.
+    ///
+    /// Dom0 isn't limited, which is reported as a quota of 0.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::EINVAL` if there is no such quota
+    pub fn quota(&self, dom_id: Option<wire::DomainId>, name: &str) -> Result<usize> {
+        if !QUOTA_NAMES.contains(&name) {
+            return Err(Error::EINVAL(format!("unknown quota: {}", name)));
+        }
+        if dom_id == Some(DOM0_DOMAIN_ID) {
+            return Ok(0);
+        }
+
+        Ok(match name {
+            "memory" => self.store.quota().max_bytes,
+            "node-size" => self.store.quota().max_entry_size,
+            "nodes" => self.store.quota().max_entries,
+            "transactions" => self.txns.quota(),
+            _ => self.watches.quota().max_watches,
+        })
+    }
+
+    /// Find out how much each domain is using, by domain.
+    pub fn domain_usage(&self) -> BTreeMap<wire::DomainId, DomainUsage> {
+        let mut domains = BTreeMap::new();
+
+        for (dom_id, usage) in self.store.usage_by_domain() {
+            domains.entry(dom_id).or_insert(DomainUsage::default()).store = usage;
+        }
+        for (dom_id, count) in self.watches.count_by_domain() {
+            domains.entry(dom_id).or_insert(DomainUsage::default()).watches = count;
+        }
+        for (_, conn, _) in self.txns.list() {
+            domains.entry(conn.dom_id).or_insert(DomainUsage::default()).transactions += 1;
+        }
+        for (conn, outbox) in &self.outboxes {
+            domains.entry(conn.dom_id).or_insert(DomainUsage::default()).queued_events +=
+                outbox.len();
+        }
+
+        domains
+    }
+
+    /// Describe how busy the daemon is in the Prometheus text format.
+    pub fn metrics(&self) -> String {
+        let gauges = [Gauge {
+                          name: "connections",
+                          help: "Open client connections",
+                          value: self.outboxes.len() as u64,
+                      },
+                      Gauge {
+                          name: "domains",
+                          help: "Introduced domains",
+                          value: self.domains.iter().count() as u64,
+                      },
+                      Gauge {
+                          name: "transactions",
+                          help: "Open transactions",
+                          value: self.txns.list().len() as u64,
+                      },
+                      Gauge {
+                          name: "watches",
+                          help: "Registered watches",
+                          value: self.watches.iter().count() as u64,
+                      },
+                      Gauge {
+                          name: "nodes",
+                          help: "Nodes in the store",
+                          value: self.store.nodes().count() as u64,
+                      },
+                      Gauge {
+                          name: "generation",
+                          help: "Changes applied to the store",
+                          value: self.store.generation(),
+                      }];
+
+        self.metrics.lock().unwrap().render(&gauges)
+    }
+
+    /// Start recording every request in the access log at `path`, or where
+    /// it was last kept if there is no `path`.
+    pub fn start_tracelog(&mut self, path: Option<PathBuf>) -> io::Result<()> {
+        if let Some(path) = path {
+            self.tracelog_path = path;
         }
+
+        let log = try!(TraceLog::open(&self.tracelog_path,
+                                      tracelog::DEFAULT_MAX_LINES,
+                                      tracelog::DEFAULT_MAX_FILES));
+        self.tracelog = Some(log);
+        self.publish();
+        Ok(())
+    }
+
+    pub fn stop_tracelog(&mut self) {
+        self.tracelog = None;
+        self.publish();
+    }
+
+    pub fn tracelog_enabled(&self) -> bool {
+        self.tracelog.is_some()
+    }
+
+    /// Add `request` and its `reply` to the access log, if it's on. The log
+    /// is turned off if it can't be written to.
+    pub fn record_access(&mut self,
+                         request: &tracelog::Request,
+                         reply: &(wire::Header, wire::Body)) {
+        let failed = match self.tracelog {
+            Some(ref mut log) => {
+                log.record(request, reply)
+                    .map_err(|e| {
+                                 warn!("turning off the access log {}: {}",
+                                       log.path().display(),
+                                       e)
+                             })
+                    .is_err()
+            }
+            None => false,
+        };
+
+        if failed {
+            self.stop_tracelog();
+        }
+    }
+
+    /// Choose whether @introduceDomain and @releaseDomain events name the
+    /// domain they are about, advertising it to clients by creating
+    /// `DOMAIN_IDS_FEATURE` when they do.
+    pub fn set_domain_ids(&mut self, domain_ids: bool) -> Result<()> {
+        self.watches.set_domain_ids(domain_ids);
+        self.advertise(DOMAIN_IDS_FEATURE, domain_ids)
+    }
+
+    /// Choose whether dom0 may register wildcard watches, advertising it to
+    /// clients by creating `WILDCARD_WATCHES_FEATURE` when it may.
+    pub fn set_wildcard_watches(&mut self, wildcards: bool) -> Result<()> {
+        self.watches.set_wildcards(wildcards);
+        self.advertise(WILDCARD_WATCHES_FEATURE, wildcards)
+    }
+
+    /// Create the feature node at `feature` for every domain to read, or
+    /// remove it.
+    fn advertise(&mut self, feature: &str, on: bool) -> Result<()> {
+        let path = Path::try_from(DOM0_DOMAIN_ID, feature).unwrap();
+        let changes = ChangeSet::new(&self.store);
+        let changes = if on {
+            // every domain may look for it
+            let perms = vec![Permission {
+                                 id: DOM0_DOMAIN_ID,
+                                 perm: Perm::Read,
+                             }];
+            self.store
+                .write(&changes, DOM0_DOMAIN_ID, path.clone(), Value::from("1"))
+                .and_then(|changes| self.store.set_perms(&changes, DOM0_DOMAIN_ID, &path, perms))
+        } else {
+            match self.store.rm(&changes, DOM0_DOMAIN_ID, &path) {
+                Err(Error::ENOENT(_)) => return Ok(()),
+                changes => changes,
+            }
+        };
+
+        changes.and_then(|changes| self.store.apply(changes)).map(|_| ())
+    }
+
+    /// Save the store right away, if it is being persisted.
+    pub fn save(&mut self) -> io::Result<()> {
+        match self.persister {
+            Some(ref mut persister) => persister.save(&self.store),
+            None => Ok(()),
+        }
+    }
+
+    /// Let the store forget the paths it removed before every changeset
+    /// still to be applied was made, as they can't conflict with any of them.
+    fn forget_removed(&mut self) {
+        let oldest = self.txns
+            .list()
+            .iter()
+            .map(|&(_, _, changes)| changes.generation())
+            .chain(self.batch.iter().map(|batch| batch.generation()))
+            .min()
+            .unwrap_or(self.store.generation());
+        self.store.forget_removed(oldest);
+    }
+
+    /// Save the store if it has changed enough since it was last saved.
+    fn checkpoint(&mut self) {
+        if let Some(ref mut persister) = self.persister {
+            if let Err(e) = persister.checkpoint(&self.store) {
+                error!("failed to save the store: {}", e);
+            }
+        }
+    }
+
+    /// Have `conn` act as `dom_id` from now on, for good.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::ENOENT` if `dom_id` has not been introduced
+    /// * `Error::EBUSY` if `conn` still has watches or transactions, which
+    ///   were set up with the rights it is giving up
+    pub fn restrict(&mut self, conn: ConnId, dom_id: wire::DomainId) -> Result<()> {
+        if !self.domains.is_introduced(dom_id) {
+            return Err(Error::ENOENT(format!("domain {} has not been introduced", dom_id)));
+        }
+
+        let watching = self.watches.iter().any(|watch| watch.conn == conn);
+        let in_transaction = self.txns.list().iter().any(|&(_, owner, _)| owner == conn);
+        if watching || in_transaction {
+            return Err(Error::EBUSY(format!("{:?} has watches or transactions open", conn)));
+        }
+
+        self.restricted.insert(conn, dom_id);
+        // nor does it keep acting for a target
+        self.set_target(conn, None);
+        Ok(())
+    }
+
+    /// The identity `conn` has, taking any XS_RESTRICT it sent into account.
+    pub fn effective_conn(&self, conn: ConnId) -> ConnId {
+        effective_conn(&self.restricted, conn)
+    }
+
+    /// Let `conn` access nodes and see watch events as if it were `target`
+    /// as well, or stop it with `None`.
+    pub fn set_target(&mut self, conn: ConnId, target: Option<wire::DomainId>) {
+        match target {
+            Some(target) => self.targets.insert(conn, target),
+            None => self.targets.remove(&conn),
+        };
+        self.watches.set_targets(self.targets.clone());
+        self.publish();
+    }
+
+    /// The domain `conn` acts for with XS_SET_TARGET, if any.
+    pub fn target(&self, conn: ConnId) -> Option<wire::DomainId> {
+        target(&self.targets, conn)
+    }
+
+    /// The view that requests which only read are answered from, kept up to
+    /// date by `publish`.
+    pub fn view(&self) -> Arc<RwLock<ReadView>> {
+        self.view.clone()
+    }
+
+    /// Bring the `view` up to date, after anything that changes the store or
+    /// how requests are answered.
+    pub fn publish(&self) {
+        let view = ReadView {
+            reader: self.store.reader(),
+            restricted: self.restricted.clone(),
+            targets: self.targets.clone(),
+            traced: self.trace || self.tracelog.is_some(),
+            metrics: self.metrics.clone(),
+        };
+        *self.view.write().unwrap() = view;
+    }
+
+    /// Allocate a unique `ConnId` for a new connection from `dom_id`.
+    pub fn new_connection(&mut self, dom_id: wire::DomainId) -> ConnId {
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        ConnId::new(token, dom_id)
+    }
+
+    /// Take over a ring connection that existed before a live update.
+    ///
+    /// The next call to `domain_connection` for its domain hands it back.
+    pub fn adopt_connection(&mut self, conn: ConnId) {
+        let Token(token) = conn.token;
+        if token >= self.next_token {
+            self.next_token = token + 1;
+        }
+        self.rings.insert(conn.dom_id, conn);
+    }
+
+    /// Get the `ConnId` for a domain's ring, the same one until it is
+    /// closed, reusing the one it had before a live update if there was one.
+    pub fn domain_connection(&mut self, dom_id: wire::DomainId) -> ConnId {
+        if let Some(conn) = self.rings.get(&dom_id) {
+            return *conn;
+        }
+
+        let conn = self.new_connection(dom_id);
+        self.rings.insert(dom_id, conn);
+        conn
+    }
+
+    /// Forget the `ConnId` for a released domain's ring, and the target it
+    /// acted for, even if the ring was never attached.
+    pub fn release_connection(&mut self, dom_id: wire::DomainId) {
+        if let Some(conn) = self.rings.remove(&dom_id) {
+            self.set_target(conn, None);
+        }
+    }
+
+    /// Give a domain's ring a new `ConnId` when its guest starts over on
+    /// it, acting for the same target as `conn` did.
+    pub fn reconnect(&mut self, conn: ConnId) -> ConnId {
+        let new = self.new_connection(conn.dom_id);
+        if self.rings.get(&conn.dom_id) == Some(&conn) {
+            self.rings.insert(conn.dom_id, new);
+        }
+        let target = self.target(conn);
+        self.set_target(conn, None);
+        self.set_target(new, target);
+        new
+    }
+
+    /// Ask for the daemon to be replaced with a new instance.
+    pub fn request_live_update(&mut self) {
+        self.live_update = true;
+    }
+
+    /// Check if a live update has been asked for.
+    pub fn live_update_requested(&self) -> bool {
+        self.live_update
+    }
+
+    /// Give up on a live update that couldn't be carried out, so that it
+    /// isn't tried again until it is asked for.
+    pub fn cancel_live_update(&mut self) {
+        self.live_update = false;
+    }
+
+    /// The number of changes that have been applied to the store.
+    pub fn generation(&self) -> u64 {
+        self.store.generation()
+    }
+
+    /// Keep a snapshot of the store to compare against later, returning the
+    /// generation it was taken at.
+    ///
+    /// Only the latest `MAX_SNAPSHOTS` snapshots are kept.
+    pub fn snapshot(&mut self) -> u64 {
+        let snapshot = self.store.snapshot();
+        let generation = snapshot.generation();
+
+        if self.snapshots.back().map(|last| last.generation()) != Some(generation) {
+            if self.snapshots.len() == MAX_SNAPSHOTS {
+                self.snapshots.pop_front();
+            }
+            self.snapshots.push_back(snapshot);
+        }
+
+        generation
+    }
+
+    /// Compare the snapshot taken at generation `from` with the one taken at
+    /// `to`, or with the store as it is now.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::ENOENT` if there is no snapshot for either generation
+    pub fn diff(&self, from: u64, to: Option<u64>) -> Result<Vec<Difference>> {
+        let find = |generation: u64| {
+            self.snapshots
+                .iter()
+                .find(|snapshot| snapshot.generation() == generation)
+                .ok_or(Error::ENOENT(format!("no snapshot of generation {}", generation)))
+        };
+
+        let from = try!(find(from));
+        match to {
+            Some(to) => Ok(Store::diff(from, try!(find(to)))),
+            None => Ok(Store::diff(from, &self.store.snapshot())),
+        }
+    }
+
+    /// Start queueing the watch events fired for `conn`.
+    pub fn open_outbox(&mut self, conn: ConnId) {
+        self.outboxes.insert(conn, Outbox::new(MAX_QUEUED_EVENTS));
+    }
+
+    /// Close every connection's outbox, so that they hang up once they have
+    /// delivered what is already queued.
+    pub fn close_outboxes(&mut self) {
+        for outbox in self.outboxes.values_mut() {
+            outbox.close();
+        }
+    }
+
+    /// Stop queueing watch events for `conn`, dropping any still waiting.
+    pub fn close_outbox(&mut self, conn: ConnId) {
+        self.outboxes.remove(&conn);
+    }
+
+    /// Forget everything `conn` left behind once it has gone away: its
+    /// outbox, its watches and any transactions it never finished.
+    pub fn connection_closed(&mut self, conn: ConnId) {
+        if self.rings.get(&conn.dom_id) == Some(&conn) {
+            self.rings.remove(&conn.dom_id);
+        }
+        if self.targets.contains_key(&conn) {
+            self.set_target(conn, None);
+        }
+        self.restricted.remove(&conn);
+        self.close_outbox(conn);
+        let _ = self.watches.reset(conn);
+        self.txns.reset(conn);
+        self.forget_removed();
+    }
+
+    /// Queue fired watch events for the connections that own the watches.
+    ///
+    /// Events for connections without an outbox are dropped.
+    pub fn dispatch_events(&mut self, events: Events) {
+        for event in events {
+            let conn = event.conn;
+            if let Some(outbox) = self.outboxes.get_mut(&conn) {
+                if let Err(e) = outbox.push(event) {
+                    warn!("dropping connection {:?}: {}", conn, e);
+                }
+            }
+        }
+    }
+
+    pub fn do_outbox_mut<F, R>(&mut self, conn: ConnId, thunk: F) -> Option<R>
+        where F: FnOnce(&mut Outbox) -> R
+    {
+        // Do the outbox operation, if the connection has one
+        self.outboxes.get_mut(&conn).map(thunk)
+    }
+
+    /// Hold on to watch events fired by the request being handled until
+    /// `dispatch_fired` is called, once its reply has been sent.
+    pub fn fire(&mut self, events: Events) {
+        self.fired.extend(events);
+    }
+
+    /// The watch events fired since they were last dispatched.
+    pub fn fired(&self) -> &Events {
+        &self.fired
+    }
+
+    /// Queue the watch events held back by `fire` for the connections that
+    /// own the watches.
+    pub fn dispatch_fired(&mut self) {
+        let events = mem::replace(&mut self.fired, Events::new());
+        self.dispatch_events(events);
     }
 
+    /// Run `thunk` against the store within `tx_id`. Changes made outside a
+    /// transaction are applied straight away, or when the `do_store_batch`
+    /// under way is done, and the watches they fire are held back until
+    /// `dispatch_fired`, so that the requester's reply goes out before anyone
+    /// sees the events.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::ENOENT` if `tx_id` was never started or has already ended
+    /// * `Error::EACCES` if `tx_id` belongs to another connection
     pub fn do_store_mut<F>(&mut self,
                            conn: ConnId,
                            tx_id: wire::TxId,
                            thunk: F)
-                           -> Result<HashSet<Watch>>
+                           -> Result<()>
         where F: FnOnce(&mut Store, &ChangeSet) -> Result<ChangeSet>
     {
         let changes = {
             let root_changeset = ChangeSet::new(&self.store);
             // If the transaction ID is the root transaction
             let changeset = match tx_id {
-                // return a root changeset
-                ROOT_TRANSACTION => &root_changeset,
+                // return the batch under way or a root changeset
+                ROOT_TRANSACTION => self.batch.as_ref().unwrap_or(&root_changeset),
                 // otherwise, look up the transaction ID and return that instead
                 _ => try!(self.txns.get(conn, tx_id)),
             };
 
             // Once we have a changeset, apply the thunk to the data store and
-            // the changeset, returning a new ChangeSet
-            try!(thunk(&mut self.store, changeset))
+            // the changeset, acting for the connection's target, returning a
+            // new ChangeSet that remembers everything that was read along the
+            // way
+            changeset.act_for(self.target(conn));
+            let mut changes = try!(thunk(&mut self.store, changeset));
+            changes.merge_reads(changeset);
+            changes
         };
 
-        Ok(match tx_id {
-               // If the transaction ID is the root transaction
-               ROOT_TRANSACTION => {
-            // Apply the changes to the data store
-            let applied = self.store.apply(changes);
-            // and fire any watches associated with the changes
-            self.watches.fire(applied)
+        match tx_id {
+            // If a batch is under way, the changes wait for the rest of it
+            ROOT_TRANSACTION if self.batch.is_some() => self.batch = Some(changes),
+            // If the transaction ID is the root transaction
+            ROOT_TRANSACTION => try!(self.apply(changes)),
+            // otherwise just store the changes back with the transaction id
+            _ => try!(self.txns.put(conn, tx_id, changes)),
         }
-               // otherwise
-               _ => {
-            // just store the changes back with the transaction id
-            try!(self.txns.put(conn, tx_id, changes));
-            // and return no watches
-            HashSet::new()
+
+        Ok(())
+    }
+
+    /// Apply `changes` made outside a transaction to the store, saving it if
+    /// it is time to and firing the watches they concern.
+    fn apply(&mut self, changes: ChangeSet) -> Result<()> {
+        let applied = try!(self.store.apply(changes));
+        self.forget_removed();
+        self.checkpoint();
+        let events = self.watches.fire(Some(applied));
+        self.fire(events);
+        Ok(())
+    }
+
+    /// Run each of `ops` in turn, collecting what they return. The changes
+    /// they make outside a transaction build on one another and are applied
+    /// to the store together once the last is done, with a single bump of
+    /// its generation and a single pass over the watches.
+    ///
+    /// Transactions started or ended by `ops` don't see the batch.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::EAGAIN` if a transaction ended by `ops` conflicts with it
+    pub fn do_store_batch<I, F, R>(&mut self, ops: I) -> Result<Vec<R>>
+        where I: IntoIterator<Item = F>,
+              F: FnOnce(&mut System) -> R
+    {
+        // a batch within a batch is just part of it
+        if self.batch.is_some() {
+            return Ok(ops.into_iter().map(|op| op(self)).collect());
         }
-           })
+
+        self.batch = Some(ChangeSet::new(&self.store));
+        let results = ops.into_iter().map(|op| op(self)).collect();
+        let changes = self.batch.take().expect("the batch went missing");
+        try!(self.apply(changes));
+        Ok(results)
     }
 
+    /// Run `thunk` against the store within `tx_id`.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::ENOENT` if `tx_id` was never started or has already ended
+    /// * `Error::EACCES` if `tx_id` belongs to another connection
     pub fn do_store<F, R>(&self, conn: ConnId, tx_id: wire::TxId, thunk: F) -> Result<R>
         where F: FnOnce(&Store, &ChangeSet) -> Result<R>
     {
         let root_changeset = ChangeSet::new(&self.store);
         // If the transaction ID is the root transaction
         let changeset = match tx_id {
-            // return a root changeset
-            ROOT_TRANSACTION => &root_changeset,
+            // return the batch under way or a root changeset
+            ROOT_TRANSACTION => self.batch.as_ref().unwrap_or(&root_changeset),
             // otherwise, look up the transaction ID and return that instead
             _ => try!(self.txns.get(conn, tx_id)),
         };
 
         // Once we have a changeset, apply the thunk to the data store and
-        // the changeset, return the result
+        // the changeset, acting for the connection's target, return the result
+        changeset.act_for(self.target(conn));
         thunk(&self.store, changeset)
     }
 
@@ -107,7 +806,39 @@ impl System {
         where F: FnOnce(&mut TransactionList, &mut Store) -> R
     {
         // Do the transaction operation
-        thunk(&mut self.txns, &mut self.store)
+        let result = thunk(&mut self.txns, &mut self.store);
+        // ending a transaction may have changed the store, and ending the
+        // oldest lets it forget more of what it removed
+        self.forget_removed();
+        self.checkpoint();
+        result
+    }
+
+    pub fn do_all<F, R>(&self, thunk: F) -> R
+        where F: FnOnce(&Store, &WatchList, &TransactionList, &DomainList) -> R
+    {
+        // Look at everything at once
+        thunk(&self.store, &self.watches, &self.txns, &self.domains)
+    }
+
+    pub fn do_domain<F, R>(&self, thunk: F) -> R
+        where F: FnOnce(&DomainList) -> R
+    {
+        // Do the domain query
+        thunk(&self.domains)
+    }
+
+    /// Subscribe to the changes applied to the store, as `Store::subscribe`
+    /// does.
+    pub fn subscribe(&mut self) -> Receiver<Vec<AppliedChange>> {
+        self.store.subscribe()
+    }
+
+    pub fn do_domain_mut<F, R>(&mut self, thunk: F) -> R
+        where F: FnOnce(&mut DomainList, &mut Store) -> R
+    {
+        // Do the domain operation
+        thunk(&mut self.domains, &mut self.store)
     }
 }
 
@@ -117,6 +848,7 @@ mod test {
 
     use self::mio::Token;
     use super::super::connection::ConnId;
+    use super::super::domain;
     use super::super::path;
     use super::super::store;
     use super::super::transaction;
@@ -130,13 +862,14 @@ mod test {
 
         let mut system = System::new(store::Store::new(),
                                      watch::WatchList::new(),
-                                     transaction::TransactionList::new());
+                                     transaction::TransactionList::new(),
+                                     domain::DomainList::new());
 
         // set up a watch
         system.do_watch_mut(|watch_list| {
                                 watch_list.watch(ConnId::new(Token(0), store::DOM0_DOMAIN_ID),
                                                  watch::WPath::Normal(path.clone()),
-                                                 watch::WPath::Normal(path.clone()))
+                                                 watch::WToken::from("token"))
                             })
             .unwrap();
 
@@ -145,19 +878,20 @@ mod test {
                                                   txlst.start(ConnId::new(Token(0),
                                                                           store::DOM0_DOMAIN_ID),
                                                               store)
-                                              });
+                                              })
+            .unwrap();
 
         // add the value in the transaction
-        let fired_watches = system.do_store_mut(ConnId::new(Token(0), store::DOM0_DOMAIN_ID),
-                                                tx_id,
-                                                |store, changes| {
-                                                    store.write(changes,
-                                                                store::DOM0_DOMAIN_ID,
-                                                                path.clone(),
-                                                                value.clone())
-                                                })
+        system.do_store_mut(ConnId::new(Token(0), store::DOM0_DOMAIN_ID),
+                            tx_id,
+                            |store, changes| {
+                                store.write(changes,
+                                            store::DOM0_DOMAIN_ID,
+                                            path.clone(),
+                                            value.clone())
+                            })
             .unwrap();
-        assert_eq!(fired_watches.len(), 0);
+        assert_eq!(system.fired().len(), 0);
 
         // end the transaction
         let changes = system.do_transaction_mut(|txlst, store| {
@@ -173,4 +907,410 @@ mod test {
 
         assert_eq!(fired_watches.len(), 1);
     }
+
+    #[test]
+    fn test_new_connection_unique() {
+        let mut system = System::new(store::Store::new(),
+                                     watch::WatchList::new(),
+                                     transaction::TransactionList::new(),
+                                     domain::DomainList::new());
+
+        let conn1 = system.new_connection(store::DOM0_DOMAIN_ID);
+        let conn2 = system.new_connection(store::DOM0_DOMAIN_ID);
+
+        assert!(conn1 != conn2);
+        assert_eq!(conn1.dom_id, store::DOM0_DOMAIN_ID);
+        assert_eq!(conn2.dom_id, store::DOM0_DOMAIN_ID);
+    }
+
+    #[test]
+    fn test_dispatch_events() {
+        let path = path::Path::try_from(store::DOM0_DOMAIN_ID, "/root/file/path").unwrap();
+
+        let mut system = System::new(store::Store::new(),
+                                     watch::WatchList::new(),
+                                     transaction::TransactionList::new(),
+                                     domain::DomainList::new());
+
+        let conn1 = system.new_connection(store::DOM0_DOMAIN_ID);
+        let conn2 = system.new_connection(store::DOM0_DOMAIN_ID);
+
+        system.open_outbox(conn1);
+
+        let mut events = watch::Events::new();
+        events.push(watch::Watch::new(conn1,
+                                      watch::WPath::Normal(path.clone()),
+                                      watch::WToken::from("token")));
+        // there is no outbox for this one so it is dropped
+        events.push(watch::Watch::new(conn2,
+                                      watch::WPath::Normal(path.clone()),
+                                      watch::WToken::from("token")));
+        system.dispatch_events(events);
+
+        let event = system.do_outbox_mut(conn1, |outbox| outbox.pop().unwrap()).unwrap();
+        assert_eq!(event.map(|watch| watch.conn), Some(conn1));
+        assert!(system.do_outbox_mut(conn2, |outbox| outbox.pop().unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_connection_closed() {
+        let path = path::Path::try_from(store::DOM0_DOMAIN_ID, "/root/file/path").unwrap();
+
+        let mut system = System::new(store::Store::new(),
+                                     watch::WatchList::new(),
+                                     transaction::TransactionList::new(),
+                                     domain::DomainList::new());
+
+        let closed = system.new_connection(store::DOM0_DOMAIN_ID);
+        let open = system.new_connection(store::DOM0_DOMAIN_ID);
+        for conn in &[closed, open] {
+            system.open_outbox(*conn);
+            system.do_watch_mut(|watch_list| {
+                                    watch_list.watch(*conn,
+                                                     watch::WPath::Normal(path.clone()),
+                                                     watch::WToken::from("token"))
+                                })
+                .unwrap();
+            system.do_transaction_mut(|txlst, store| txlst.start(*conn, store)).unwrap();
+        }
+
+        system.connection_closed(closed);
+
+        // only what the other connection set up is left
+        system.do_all(|_, watch_list, txlst, _| {
+            assert_eq!(watch_list.iter().map(|watch| watch.conn).collect::<Vec<ConnId>>(),
+                       vec![open]);
+            assert_eq!(txlst.list().iter().map(|&(_, conn, _)| conn).collect::<Vec<ConnId>>(),
+                       vec![open]);
+        });
+        assert!(system.do_outbox_mut(closed, |_| ()).is_none());
+        assert!(system.do_outbox_mut(open, |_| ()).is_some());
+    }
+
+    #[test]
+    fn test_domain_usage() {
+        let path = path::Path::try_from(store::DOM0_DOMAIN_ID, "/root/file/path").unwrap();
+
+        let mut system = System::new(store::Store::new(),
+                                     watch::WatchList::new(),
+                                     transaction::TransactionList::new(),
+                                     domain::DomainList::new());
+
+        let conn = system.new_connection(1);
+        system.open_outbox(conn);
+        system.do_watch_mut(|watch_list| {
+                                watch_list.watch(conn,
+                                                 watch::WPath::Normal(path.clone()),
+                                                 watch::WToken::from("token"))
+                            })
+            .unwrap();
+        system.do_transaction_mut(|txlst, store| txlst.start(conn, store)).unwrap();
+        system.do_outbox_mut(conn, |outbox| {
+                outbox.push(watch::Watch::new(conn,
+                                              watch::WPath::Normal(path.clone()),
+                                              watch::WToken::from("token")))
+            })
+            .unwrap()
+            .unwrap();
+
+        let usage = system.domain_usage();
+        assert_eq!(usage[&1],
+                   DomainUsage {
+                       store: Default::default(),
+                       watches: 1,
+                       transactions: 1,
+                       queued_events: 1,
+                   });
+        assert!(usage[&store::DOM0_DOMAIN_ID].store.entries > 0);
+    }
+
+    #[test]
+    fn test_domain_ids_feature() {
+        let path = path::Path::try_from(store::DOM0_DOMAIN_ID, DOMAIN_IDS_FEATURE).unwrap();
+        let conn = ConnId::new(Token(0), 1);
+
+        let mut system = System::new(store::Store::new(),
+                                     watch::WatchList::new(),
+                                     transaction::TransactionList::new(),
+                                     domain::DomainList::new());
+
+        // guests can see that it is on
+        system.set_domain_ids(true).unwrap();
+        assert_eq!(system.do_store(conn, transaction::ROOT_TRANSACTION, |store, changes| {
+                           store.read(changes, 1, &path)
+                       })
+                       .unwrap(),
+                   store::Value::from("1"));
+
+        // and that it is off again
+        system.set_domain_ids(false).unwrap();
+        system.set_domain_ids(false).unwrap();
+        assert!(system.do_store(conn, transaction::ROOT_TRANSACTION, |store, changes| {
+                          store.read(changes, 1, &path)
+                      })
+                    .is_err());
+    }
+
+    #[test]
+    fn test_restrict_busy() {
+        let path = path::Path::try_from(store::DOM0_DOMAIN_ID, "/a").unwrap();
+        let mut domains = domain::DomainList::new();
+        domains.introduce(1, 0x1000, 5).unwrap();
+        let mut system = System::new(store::Store::new(),
+                                     watch::WatchList::new(),
+                                     transaction::TransactionList::new(),
+                                     domains);
+        let conn = system.new_connection(store::DOM0_DOMAIN_ID);
+
+        // a watch registered as dom0 would go on firing as dom0
+        system.do_watch_mut(|watch_list| {
+                                watch_list.watch(conn,
+                                                 watch::WPath::Normal(path.clone()),
+                                                 watch::WToken::from("token"))
+                            })
+            .unwrap();
+        match system.restrict(conn, 1) {
+            Err(Error::EBUSY(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "restricted a connection with a watch"),
+        }
+        system.do_watch_mut(|watch_list| watch_list.reset(conn)).unwrap();
+
+        // and so would a transaction read as dom0
+        system.do_transaction_mut(|txns, store| txns.start(conn, store)).unwrap();
+        match system.restrict(conn, 1) {
+            Err(Error::EBUSY(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "restricted a connection with a transaction"),
+        }
+        system.do_transaction_mut(|txns, _| txns.reset(conn));
+
+        system.restrict(conn, 1).unwrap();
+        assert_eq!(system.effective_conn(conn).dom_id, 1);
+    }
+
+    #[test]
+    fn test_targets() {
+        let path = path::Path::try_from(store::DOM0_DOMAIN_ID, "/local/domain/1/foo").unwrap();
+        let mut domains = domain::DomainList::new();
+        domains.introduce(1, 0x1000, 5).unwrap();
+        domains.introduce(2, 0x2000, 6).unwrap();
+        let mut system = System::new(store::Store::new(),
+                                     watch::WatchList::new(),
+                                     transaction::TransactionList::new(),
+                                     domains);
+        let dom0 = system.new_connection(store::DOM0_DOMAIN_ID);
+        system.do_store_mut(dom0, ROOT_TRANSACTION, |store, changes| {
+                let changes = try!(store.write(changes,
+                                               store::DOM0_DOMAIN_ID,
+                                               path.clone(),
+                                               store::Value::from("value")));
+                store.set_perms(&changes,
+                                store::DOM0_DOMAIN_ID,
+                                &path,
+                                vec![store::Permission {
+                                         id: 1,
+                                         perm: store::Perm::None,
+                                     }])
+            })
+            .unwrap();
+
+        // only the connection on domain 2's ring acts for domain 1
+        let ring = system.domain_connection(2);
+        let other = system.new_connection(2);
+        system.set_target(ring, Some(1));
+        assert_eq!(system.domain_connection(2), ring);
+
+        let read = |system: &System, conn: ConnId| {
+            system.do_store(conn,
+                            ROOT_TRANSACTION,
+                            |store, changes| store.read(changes, 2, &path))
+        };
+        assert_eq!(read(&system, ring).unwrap(), store::Value::from("value"));
+        match read(&system, other) {
+            Err(Error::EACCES(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "another connection acted for the target"),
+        }
+
+        // and so do reads answered from the view
+        {
+            let view = system.view();
+            let view = view.read().unwrap();
+            assert_eq!(view.reader.read(2, view.target(ring), &path).unwrap(),
+                       store::Value::from("value"));
+            assert_eq!(view.target(other), None);
+        }
+
+        // the ring keeps its target when the guest starts over on it
+        let ring = system.reconnect(ring);
+        assert_eq!(system.domain_connection(2), ring);
+        assert_eq!(read(&system, ring).unwrap(), store::Value::from("value"));
+
+        // but gives it up when it restricts itself
+        system.restrict(ring, 2).unwrap();
+        match read(&system, ring) {
+            Err(Error::EACCES(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "a restricted connection acted for the target"),
+        }
+    }
+
+    #[test]
+    fn test_wildcard_watches_feature() {
+        let path = path::Path::try_from(store::DOM0_DOMAIN_ID, WILDCARD_WATCHES_FEATURE).unwrap();
+        let node = watch::WPath::try_from(store::DOM0_DOMAIN_ID, "/local/domain/*").unwrap();
+        let conn = ConnId::new(Token(0), store::DOM0_DOMAIN_ID);
+
+        let mut system = System::new(store::Store::new(),
+                                     watch::WatchList::new(),
+                                     transaction::TransactionList::new(),
+                                     domain::DomainList::new());
+
+        // advertised once dom0 may use them
+        system.set_wildcard_watches(true).unwrap();
+        assert_eq!(system.do_store(conn, transaction::ROOT_TRANSACTION, |store, changes| {
+                           store.read(changes, store::DOM0_DOMAIN_ID, &path)
+                       })
+                       .unwrap(),
+                   store::Value::from("1"));
+        system.do_watch_mut(|watch_list| {
+                                watch_list.watch(conn, node.clone(), watch::WToken::from("a"))
+                            })
+            .unwrap();
+
+        // and no longer once it may not
+        system.set_wildcard_watches(false).unwrap();
+        assert!(system.do_store(conn, transaction::ROOT_TRANSACTION, |store, changes| {
+                          store.read(changes, store::DOM0_DOMAIN_ID, &path)
+                      })
+                    .is_err());
+        assert!(system.do_watch_mut(|watch_list| {
+                                        watch_list.watch(conn, node, watch::WToken::from("b"))
+                                    })
+                    .is_err());
+    }
+
+    #[test]
+    fn test_fired_held_until_dispatched() {
+        let path = path::Path::try_from(store::DOM0_DOMAIN_ID, "/basic").unwrap();
+
+        let mut system = System::new(store::Store::new(),
+                                     watch::WatchList::new(),
+                                     transaction::TransactionList::new(),
+                                     domain::DomainList::new());
+
+        let conn = system.new_connection(store::DOM0_DOMAIN_ID);
+        system.open_outbox(conn);
+        system.do_watch_mut(|watch_list| {
+                                watch_list.watch(conn,
+                                                 watch::WPath::Normal(path.clone()),
+                                                 watch::WToken::from("token"))
+                            })
+            .unwrap();
+
+        system.do_store_mut(conn, transaction::ROOT_TRANSACTION, |store, changes| {
+                store.write(changes, store::DOM0_DOMAIN_ID, path.clone(), store::Value::from("1"))
+            })
+            .unwrap();
+
+        // nothing reaches the outbox until the reply has gone
+        assert_eq!(system.fired().len(), 1);
+        assert_eq!(system.do_outbox_mut(conn, |outbox| outbox.len()), Some(0));
+
+        system.dispatch_fired();
+        assert!(system.fired().is_empty());
+        assert_eq!(system.do_outbox_mut(conn, |outbox| outbox.len()), Some(1));
+    }
+
+    #[test]
+    fn test_store_batch() {
+        let a = path::Path::try_from(store::DOM0_DOMAIN_ID, "/a").unwrap();
+        let b = path::Path::try_from(store::DOM0_DOMAIN_ID, "/a/b").unwrap();
+
+        let mut system = System::new(store::Store::new(),
+                                     watch::WatchList::new(),
+                                     transaction::TransactionList::new(),
+                                     domain::DomainList::new());
+
+        let conn = system.new_connection(store::DOM0_DOMAIN_ID);
+        system.do_watch_mut(|watch_list| {
+                                watch_list.watch(conn,
+                                                 watch::WPath::Normal(a.clone()),
+                                                 watch::WToken::from("token"))
+                            })
+            .unwrap();
+
+        let generation = system.generation();
+        let write = |dom_id: wire::DomainId, path: &path::Path, value: &str| {
+            let path = path.clone();
+            let value = store::Value::from(value);
+            move |sys: &mut System| {
+                sys.do_store_mut(conn, transaction::ROOT_TRANSACTION, |store, changes| {
+                    store.write(changes, dom_id, path, value)
+                })
+            }
+        };
+        let read = |system: &System, path: &path::Path| {
+            system.do_store(conn, transaction::ROOT_TRANSACTION, |store, changes| {
+                store.read(changes, store::DOM0_DOMAIN_ID, path)
+            })
+        };
+
+        let results = system.do_store_batch(vec![write(0, &a, "1"),
+                                                 write(0, &b, "2"),
+                                                 write(0, &a, "3")])
+            .unwrap();
+        assert!(results.iter().all(|result| result.is_ok()));
+
+        // one generation and one event for the lot
+        assert_eq!(system.generation(), generation + 1);
+        assert_eq!(system.fired().len(), 1);
+        assert_eq!(read(&system, &a).unwrap(), store::Value::from("3"));
+        assert_eq!(read(&system, &b).unwrap(), store::Value::from("2"));
+
+        // an operation that fails leaves the rest of the batch be
+        let c = path::Path::try_from(store::DOM0_DOMAIN_ID, "/c").unwrap();
+        let results = system.do_store_batch(vec![write(0, &c, "1"),
+                                                 write(5, &a, "4"),
+                                                 write(0, &b, "5")])
+            .unwrap();
+        match results[1] {
+            Err(Error::EACCES(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "a write without permission was allowed"),
+        }
+        assert_eq!(system.generation(), generation + 2);
+        assert_eq!(read(&system, &a).unwrap(), store::Value::from("3"));
+        assert_eq!(read(&system, &b).unwrap(), store::Value::from("5"));
+        assert_eq!(read(&system, &c).unwrap(), store::Value::from("1"));
+    }
+
+    #[test]
+    fn test_snapshot_diff() {
+        let path = path::Path::try_from(store::DOM0_DOMAIN_ID, "/basic").unwrap();
+        let conn = ConnId::new(Token(0), store::DOM0_DOMAIN_ID);
+
+        let mut system = System::new(store::Store::new(),
+                                     watch::WatchList::new(),
+                                     transaction::TransactionList::new(),
+                                     domain::DomainList::new());
+
+        let before = system.snapshot();
+        system.do_store_mut(conn, transaction::ROOT_TRANSACTION, |store, changes| {
+                store.write(changes, store::DOM0_DOMAIN_ID, path.clone(), store::Value::from("1"))
+            })
+            .unwrap();
+
+        let added = vec![store::Difference::Added(path.clone())];
+        assert_eq!(system.diff(before, None).unwrap(), added);
+        let after = system.snapshot();
+        assert_eq!(system.diff(before, Some(after)).unwrap(), added);
+
+        match system.diff(after + 1, None) {
+            Err(Error::ENOENT(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "compared against a snapshot that was never taken"),
+        }
+    }
 }
@@ -0,0 +1,308 @@
+/**
+    xenstore-rs provides a Rust based xenstore implementation.
+    Copyright (C) 2016 Star Lab Corp.
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License along
+    with this program; if not, see <http://www.gnu.org/licenses/>.
+**/
+
+// Record every request and how it went, laid out like oxenstored's
+// xenstored-access.log so the usual tools and habits carry over.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use super::connection::ConnId;
+use super::wire;
+
+/// Where the access log goes unless told otherwise
+pub const DEFAULT_TRACELOG_PATH: &'static str = "/var/log/xen/xenstored-access.log";
+/// The lines written to the access log before it is rotated
+pub const DEFAULT_MAX_LINES: usize = 13215;
+/// The rotated access logs kept around, named FILE.1 (the newest) to FILE.N
+pub const DEFAULT_MAX_FILES: usize = 20;
+
+/// What the access log calls each type of message
+pub fn msg_type_name(msg_type: u32) -> &'static str {
+    match msg_type {
+        wire::XS_CONTROL => "control",
+        wire::XS_DIRECTORY => "directory",
+        wire::XS_READ => "read",
+        wire::XS_GET_PERMS => "getperms",
+        wire::XS_WATCH => "watch",
+        wire::XS_UNWATCH => "unwatch",
+        wire::XS_TRANSACTION_START => "t start",
+        wire::XS_TRANSACTION_END => "t end",
+        wire::XS_INTRODUCE => "introduce",
+        wire::XS_RELEASE => "release",
+        wire::XS_GET_DOMAIN_PATH => "getdomain",
+        wire::XS_WRITE => "write",
+        wire::XS_MKDIR => "mkdir",
+        wire::XS_RM => "rm",
+        wire::XS_SET_PERMS => "setperms",
+        wire::XS_WATCH_EVENT => "w event",
+        wire::XS_ERROR => "error",
+        wire::XS_IS_DOMAIN_INTRODUCED => "is introduced",
+        wire::XS_RESUME => "resume",
+        wire::XS_SET_TARGET => "settarget",
+        wire::XS_RESTRICT => "restrict",
+        wire::XS_RESET_WATCHES => "reset watches",
+        wire::XS_DIRECTORY_PART => "directory part",
+        wire::XS_GET_QUOTA => "getquota",
+        _ => "invalid",
+    }
+}
+
+/// Make bytes from a message readable, keeping each entry on one line
+fn printable(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes)
+        .chars()
+        .flat_map(|c| if c.is_control() {
+                      c.escape_default().collect::<Vec<_>>()
+                  } else {
+                      vec![c]
+                  })
+        .collect()
+}
+
+/// Format a point in time as UTC, e.g. 20161016T09:30:45.123Z
+fn timestamp(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::new(0, 0));
+    let secs = since.as_secs();
+    let millis = since.subsec_nanos() / 1_000_000;
+
+    // turn the days since the epoch into a civil date
+    let days = secs / 86400 + 719468;
+    let era = days / 146097;
+    let doe = days - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let secs = secs % 86400;
+    format!("{:04}{:02}{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year,
+            month,
+            day,
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60,
+            millis)
+}
+
+/// A request as it appears in the access log, taken down before it is
+/// processed since processing consumes it.
+pub struct Request {
+    // the connection and transaction it was made on
+    prefix: String,
+    msg_type: u32,
+    data: String,
+}
+
+impl Request {
+    pub fn new(conn: ConnId, header: &wire::Header, body: &wire::Body) -> Request {
+        let data = if header.msg_type == wire::XS_WRITE {
+            // the value follows the path and may itself contain NULs
+            let bytes = body.to_vec();
+            match bytes.iter().position(|b| *b == 0) {
+                Some(end) => {
+                    format!("{} = {}", printable(&bytes[..end]), printable(&bytes[end + 1..]))
+                }
+                None => printable(&bytes),
+            }
+        } else {
+            body.fields().into_iter().map(printable).collect::<Vec<_>>().join(" ")
+        };
+
+        Request {
+            prefix: format!("D{}:{}.{}", conn.dom_id, conn.token.0, header.tx_id),
+            msg_type: header.msg_type,
+            data: data,
+        }
+    }
+}
+
+/// The `TraceLog` type.
+///
+/// Appends a line for every request to a file, and one more for each that
+/// failed, moving the file aside once it grows too long.
+pub struct TraceLog {
+    path: PathBuf,
+    file: File,
+    lines: usize,
+    max_lines: usize,
+    max_files: usize,
+}
+
+impl TraceLog {
+    /// Start appending to the access log at `path`, rotating it every
+    /// `max_lines` lines and keeping `max_files` of the old ones.
+    pub fn open(path: &Path, max_lines: usize, max_files: usize) -> io::Result<TraceLog> {
+        let file = try!(OpenOptions::new().create(true).append(true).open(path));
+
+        Ok(TraceLog {
+               path: path.to_path_buf(),
+               file: file,
+               lines: 0,
+               max_lines: max_lines,
+               max_files: max_files,
+           })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Log `request` along with the error in `reply`, if it is one.
+    pub fn record(&mut self,
+                  request: &Request,
+                  reply: &(wire::Header, wire::Body))
+                  -> io::Result<()> {
+        let now = timestamp(SystemTime::now());
+        try!(self.write_line(&format!("[{}] {:<14} {:<9} {}",
+                                      now,
+                                      request.prefix,
+                                      msg_type_name(request.msg_type),
+                                      request.data)));
+
+        if reply.0.msg_type == wire::XS_ERROR {
+            let error = reply.1.fields().first().map(|name| printable(name));
+            let error = error.unwrap_or(String::new());
+            try!(self.write_line(&format!("[{}] {:<14} {:<9} {}",
+                                          now,
+                                          request.prefix,
+                                          msg_type_name(wire::XS_ERROR),
+                                          error)));
+        }
+
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.lines >= self.max_lines {
+            try!(self.rotate());
+        }
+
+        try!(writeln!(self.file, "{}", line));
+        self.lines += 1;
+        Ok(())
+    }
+
+    /// Shift FILE.N-1 to FILE.N and so on, down to FILE becoming FILE.1,
+    /// then start again on an empty FILE
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files > 0 {
+            let rotated = |n: usize| PathBuf::from(format!("{}.{}", self.path.display(), n));
+
+            for n in (1..self.max_files).rev() {
+                if rotated(n).exists() {
+                    try!(fs::rename(rotated(n), rotated(n + 1)));
+                }
+            }
+            try!(fs::rename(&self.path, rotated(1)));
+        }
+
+        self.file = try!(OpenOptions::new()
+                             .create(true)
+                             .write(true)
+                             .truncate(true)
+                             .open(&self.path));
+        self.lines = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate mio;
+
+    use self::mio::Token;
+    use std::env;
+    use std::fs;
+    use std::io::Read;
+    use super::*;
+    use super::super::connection::ConnId;
+    use super::super::wire;
+
+    fn header(msg_type: u32) -> wire::Header {
+        wire::Header {
+            msg_type: msg_type,
+            req_id: 0,
+            tx_id: 3,
+            len: 0,
+        }
+    }
+
+    fn contents(path: &Path) -> String {
+        let mut contents = String::new();
+        fs::File::open(path).unwrap().read_to_string(&mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(timestamp(UNIX_EPOCH), "19700101T00:00:00.000Z");
+        assert_eq!(timestamp(UNIX_EPOCH + Duration::from_millis(951_827_696_789)),
+                   "20000229T12:34:56.789Z");
+    }
+
+    #[test]
+    fn request_data() {
+        let conn = ConnId::new(Token(2), 5);
+
+        let write = Request::new(conn,
+                                 &header(wire::XS_WRITE),
+                                 &wire::Body::from(vec![b"/a\0b\nc"[..].to_owned()]));
+        assert_eq!(write.prefix, "D5:2.3");
+        assert_eq!(write.data, "/a = b\\nc");
+
+        let watch = Request::new(conn,
+                                 &header(wire::XS_WATCH),
+                                 &wire::Body::from(vec![b"/a\0token\0"[..].to_owned()]));
+        assert_eq!(watch.data, "/a token");
+    }
+
+    #[test]
+    fn record_and_rotate() {
+        let path = env::temp_dir()
+            .join(format!("rxenstored-test-{}.log", ::rand::random::<u32>()));
+        let rotated = PathBuf::from(format!("{}.1", path.display()));
+        let mut log = TraceLog::open(&path, 2, 1).unwrap();
+
+        let conn = ConnId::new(Token(0), 0);
+        let request = Request::new(conn,
+                                   &header(wire::XS_READ),
+                                   &wire::Body::from(vec![b"/a\0"[..].to_owned()]));
+        let error = (header(wire::XS_ERROR), wire::Body::from(vec![b"ENOENT\0"[..].to_owned()]));
+        let reply = (header(wire::XS_READ), wire::Body::from(vec![b"value"[..].to_owned()]));
+
+        // a failed request takes two lines, filling the first file
+        log.record(&request, &error).unwrap();
+        let lines = contents(&path);
+        let lines = lines.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with("] D0:0.3         read      /a"));
+        assert!(lines[1].ends_with("] D0:0.3         error     ENOENT"));
+
+        log.record(&request, &reply).unwrap();
+        assert_eq!(contents(&path).lines().count(), 1);
+        assert_eq!(contents(&rotated).lines().count(), 2);
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&rotated).unwrap();
+    }
+}
@@ -20,16 +20,24 @@ use error::{Error, Result};
 use rand::{Rng, thread_rng};
 use std::boxed::Box;
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
 use super::connection::ConnId;
 use super::wire;
-use super::store::{ChangeSet, Store, AppliedChange};
+use super::store::{ChangeSet, Store, AppliedChange, DOM0_DOMAIN_ID};
 
 /// The Root Transaction Id.
 pub const ROOT_TRANSACTION: wire::TxId = 0;
 
+/// The default number of transactions a domain may have open at once.
+pub const DEFAULT_TRANSACTION_QUOTA: usize = 10;
+
+/// The default number of seconds a transaction may stay open before it is aborted.
+pub const DEFAULT_TRANSACTION_TIMEOUT: u64 = 60;
+
 struct Transaction {
     conn: ConnId,
     changes: ChangeSet,
+    started: Instant,
 }
 
 /// The `TransactionList` type.
@@ -37,6 +45,7 @@ struct Transaction {
 /// Used to access transactions by TxId as well as start and end transactions.
 pub struct TransactionList {
     list: HashMap<wire::TxId, Transaction>,
+    quota: usize,
 }
 
 /// The `TransactionStatus` type.
@@ -66,13 +75,39 @@ fn generate_txid<R: Rng + Sized, V>(rng: &mut Box<R>, list: &HashMap<wire::TxId,
 impl TransactionList {
     /// Create a new instance of the `TransactionList`.
     pub fn new() -> TransactionList {
-        TransactionList { list: HashMap::new() }
+        TransactionList::with_quota(DEFAULT_TRANSACTION_QUOTA)
+    }
+
+    /// Create a new instance of the `TransactionList` allowing each
+    /// unprivileged domain to have at most `quota` transactions open.
+    pub fn with_quota(quota: usize) -> TransactionList {
+        TransactionList {
+            list: HashMap::new(),
+            quota: quota,
+        }
+    }
+
+    /// The most transactions an unprivileged domain may have open.
+    pub fn quota(&self) -> usize {
+        self.quota
     }
 
     /// Start a new transaction.
     ///
     /// Returns the `TxId` associated with the new transaction.
-    pub fn start(&mut self, conn: ConnId, store: &Store) -> wire::TxId {
+    ///
+    /// # Errors
+    ///
+    /// * `Error::ENOSPC` if the domain already has its quota of transactions open
+    pub fn start(&mut self, conn: ConnId, store: &Store) -> Result<wire::TxId> {
+        if conn.dom_id != DOM0_DOMAIN_ID {
+            let open = self.list.values().filter(|txn| txn.conn.dom_id == conn.dom_id).count();
+            if open >= self.quota {
+                return Err(Error::ENOSPC(format!("domain {} has too many transactions open",
+                                                 conn.dom_id)));
+            }
+        }
+
         let next_id = generate_txid(&mut Box::new(thread_rng()), &self.list);
         let changes = ChangeSet::new(store);
 
@@ -80,8 +115,27 @@ impl TransactionList {
                          Transaction {
                              changes: changes,
                              conn: conn,
+                             started: Instant::now(),
                          });
-        next_id
+        Ok(next_id)
+    }
+
+    /// Find the transaction `tx_id`, making sure it belongs to `conn`.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::ENOENT` if the transaction id cannot be found in the list
+    /// * `Error::EACCES` if the transaction belongs to another connection
+    fn lookup(&self, conn: ConnId, tx_id: wire::TxId) -> Result<&Transaction> {
+        match self.list.get(&tx_id) {
+            None => Err(Error::ENOENT(format!("failed to find transaction {}", tx_id))),
+            Some(transaction) if transaction.conn != conn => {
+                Err(Error::EACCES(format!("transaction {} does not belong to domain {}",
+                                          tx_id,
+                                          conn.dom_id)))
+            }
+            Some(transaction) => Ok(transaction),
+        }
     }
 
     /// Get a reference to a `ChangeSet`.
@@ -89,17 +143,9 @@ impl TransactionList {
     /// # Errors
     ///
     /// * `Error::ENOENT` if the transaction id cannot be found in the list
+    /// * `Error::EACCES` if the transaction belongs to another connection
     pub fn get(&self, conn: ConnId, tx_id: wire::TxId) -> Result<&ChangeSet> {
-        self.list
-            .get(&tx_id)
-            .ok_or(Error::ENOENT(format!("failed to find transaction {}", tx_id)))
-            .and_then(|transaction| if transaction.conn != conn {
-                          Err(Error::ENOENT(format!("failed to find transaction {} for domain {}",
-                                                    tx_id,
-                                                    conn.dom_id)))
-                      } else {
-                          Ok(&transaction.changes)
-                      })
+        self.lookup(conn, tx_id).map(|transaction| &transaction.changes)
     }
 
     /// Put a reference to a `ChangeSet`.
@@ -107,18 +153,13 @@ impl TransactionList {
     /// # Errors
     ///
     /// * `Error::ENOENT` if the transaction id cannot be found in the list
+    /// * `Error::EACCES` if the transaction belongs to another connection
     pub fn put(&mut self, conn: ConnId, tx_id: wire::TxId, changes: ChangeSet) -> Result<()> {
-        self.list
-            .get_mut(&tx_id)
-            .ok_or(Error::ENOENT(format!("failed to find transaction {}", tx_id)))
-            .and_then(|transaction| if transaction.conn != conn {
-                          Err(Error::ENOENT(format!("failed to find transaction {} for domain {}",
-                                                    tx_id,
-                                                    conn.dom_id)))
-                      } else {
-                          transaction.changes = changes;
-                          Ok(())
-                      })
+        try!(self.lookup(conn, tx_id));
+        if let Some(transaction) = self.list.get_mut(&tx_id) {
+            transaction.changes = changes;
+        }
+        Ok(())
     }
 
     /// End a transaction.
@@ -131,41 +172,27 @@ impl TransactionList {
     ///
     /// * `Error::EINVAL` if the root transaction is being ended
     /// * `Error::ENOENT` if the transaction id cannot be found in the list
+    /// * `Error::EACCES` if the transaction belongs to another connection
+    /// * `Error::EAGAIN` if the store was changed underneath the transaction
     pub fn end(&mut self,
                store: &mut Store,
                conn: ConnId,
                tx_id: wire::TxId,
                success: TransactionStatus)
                -> Result<Option<Vec<AppliedChange>>> {
+        if tx_id == ROOT_TRANSACTION {
+            return Err(Error::EINVAL(format!("the root transaction cannot be ended")));
+        }
 
-        try!(self.list
-            .get(&tx_id)
-            .ok_or(Error::ENOENT(format!("failed to find transaction {}", tx_id)))
-            .and_then(|transaction| {
-                if transaction.conn != conn {
-                    Err(Error::ENOENT(format!("failed to find transaction {} for domain {}",
-                                              tx_id,
-                                              conn.dom_id)))
-                } else {
-                    Ok(())
-                }
-            }));
-
-        let changes = try!(self.list
-            .remove(&tx_id)
-            .ok_or(Error::ENOENT(format!("failed to find transaction {}", tx_id)))
-            .and_then(|transaction| {
-                if transaction.conn != conn {
-                    Err(Error::ENOENT(format!("failed to find transaction {} for domain {}",
-                                              tx_id,
-                                              conn.dom_id)))
-                } else {
-                    Ok(transaction.changes)
-                }
-            }));
+        // only the owner gets to take the transaction out of the list
+        try!(self.lookup(conn, tx_id));
+        let changes = match self.list.remove(&tx_id) {
+            Some(transaction) => transaction.changes,
+            None => return Err(Error::ENOENT(format!("failed to find transaction {}", tx_id))),
+        };
 
         Ok(match success {
-               TransactionStatus::Success => store.apply(changes),
+               TransactionStatus::Success => Some(try!(store.apply(changes))),
                TransactionStatus::Failure => None,
            })
     }
@@ -182,6 +209,71 @@ impl TransactionList {
             let _ = self.list.remove(&tx_id);
         }
     }
+
+    /// List every open transaction along with its connection and changes.
+    pub fn list(&self) -> Vec<(wire::TxId, ConnId, &ChangeSet)> {
+        self.list
+            .iter()
+            .map(|(tx_id, txn)| (*tx_id, txn.conn, &txn.changes))
+            .collect()
+    }
+
+    /// Put back a transaction that was saved with `list`.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::EEXIST` if the transaction id is already in use
+    pub fn restore(&mut self, conn: ConnId, tx_id: wire::TxId, changes: ChangeSet) -> Result<()> {
+        if tx_id == ROOT_TRANSACTION || self.list.contains_key(&tx_id) {
+            return Err(Error::EEXIST(format!("transaction {} already exists", tx_id)));
+        }
+
+        self.list.insert(tx_id,
+                         Transaction {
+                             changes: changes,
+                             conn: conn,
+                             started: Instant::now(),
+                         });
+        Ok(())
+    }
+
+    /// Abort every transaction that has been open for at least `older_than`.
+    ///
+    /// Nothing is applied to the store, so no watches fire. Returns the
+    /// transactions that were aborted.
+    pub fn expire(&mut self, older_than: Duration) -> Vec<(ConnId, wire::TxId)> {
+        let expired = self.list
+            .iter()
+            .filter_map(|(tx_id, txn)| if txn.started.elapsed() >= older_than {
+                            Some((txn.conn, *tx_id))
+                        } else {
+                            None
+                        })
+            .collect::<Vec<(ConnId, wire::TxId)>>();
+
+        for &(_, tx_id) in &expired {
+            let _ = self.list.remove(&tx_id);
+        }
+
+        expired
+    }
+
+    /// Reset the transactions for every connection of a domain.
+    pub fn reset_domain(&mut self, dom_id: wire::DomainId) {
+        let tx_ids = self.list
+            .iter()
+            .filter_map(|(tx_id, txn)| if txn.conn.dom_id == dom_id {
+                            Some(tx_id)
+                        } else {
+                            None
+                        })
+            .cloned()
+            .collect::<Vec<wire::TxId>>();
+
+        for tx_id in tx_ids {
+            let _ = self.list.remove(&tx_id);
+        }
+    }
 }
 
 #[cfg(test)]
@@ -193,6 +285,7 @@ mod test {
     use std::boxed::Box;
     use std::collections::HashMap;
     use std::num::Wrapping;
+    use std::time::Duration;
     use super::super::connection::ConnId;
     use super::super::error::Error;
     use super::super::path::Path;
@@ -235,7 +328,7 @@ mod test {
         let mut txns = TransactionList::new();
 
         // Create a new transaction
-        let tx_id = txns.start(ConnId::new(Token(0), DOM0_DOMAIN_ID), &store);
+        let tx_id = txns.start(ConnId::new(Token(0), DOM0_DOMAIN_ID), &store).unwrap();
 
         // And verify that it can be retrieved
         txns.get(ConnId::new(Token(0), DOM0_DOMAIN_ID), tx_id).unwrap();
@@ -250,7 +343,7 @@ mod test {
         let mut txns = TransactionList::new();
 
         // Create a new transaction
-        let tx_id = txns.start(ConnId::new(Token(0), DOM0_DOMAIN_ID), &store);
+        let tx_id = txns.start(ConnId::new(Token(0), DOM0_DOMAIN_ID), &store).unwrap();
 
         // And verify that it can be retrieved
         let changes = {
@@ -281,7 +374,7 @@ mod test {
         let mut txns = TransactionList::new();
 
         // Create a new transaction
-        let tx_id = txns.start(ConnId::new(Token(0), DOM0_DOMAIN_ID), &store);
+        let tx_id = txns.start(ConnId::new(Token(0), DOM0_DOMAIN_ID), &store).unwrap();
 
         // And verify that it can be retrieved
         let changes = {
@@ -316,7 +409,7 @@ mod test {
         let mut txns = TransactionList::new();
 
         // Create a new transaction
-        let tx_id = txns.start(ConnId::new(Token(0), DOM0_DOMAIN_ID), &store);
+        let tx_id = txns.start(ConnId::new(Token(0), DOM0_DOMAIN_ID), &store).unwrap();
 
         // And verify that it can be retrieved
         let changes = {
@@ -345,6 +438,21 @@ mod test {
         }
     }
 
+    #[test]
+    fn transaction_end_root() {
+        let mut store = Store::new();
+        let mut txns = TransactionList::new();
+        let conn = ConnId::new(Token(0), DOM0_DOMAIN_ID);
+        txns.start(conn, &store).unwrap();
+
+        match txns.end(&mut store, conn, ROOT_TRANSACTION, TransactionStatus::Success) {
+            Err(Error::EINVAL(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "ended the root transaction"),
+        }
+        assert_eq!(txns.list().len(), 1);
+    }
+
     #[test]
     fn transaction_ends_with_success_colliding() {
         let path = Path::try_from(DOM0_DOMAIN_ID, "/basic/path").unwrap();
@@ -355,7 +463,7 @@ mod test {
         let mut txns = TransactionList::new();
 
         // Create a new transaction
-        let tx_id = txns.start(ConnId::new(Token(0), DOM0_DOMAIN_ID), &store);
+        let tx_id = txns.start(ConnId::new(Token(0), DOM0_DOMAIN_ID), &store).unwrap();
 
         // Write to the store
         let changes = store.write(&ChangeSet::new(&store),
@@ -385,12 +493,15 @@ mod test {
         // Store it back in the transaction store
         txns.put(ConnId::new(Token(0), DOM0_DOMAIN_ID), tx_id, changes).unwrap();
 
-        // End the transaction with success
-        txns.end(&mut store,
-                 ConnId::new(Token(0), DOM0_DOMAIN_ID),
-                 tx_id,
-                 TransactionStatus::Success)
-            .unwrap();
+        // End the transaction with success, which must be retried
+        match txns.end(&mut store,
+                       ConnId::new(Token(0), DOM0_DOMAIN_ID),
+                       tx_id,
+                       TransactionStatus::Success) {
+            Err(Error::EAGAIN(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "a conflicting transaction was applied"),
+        }
 
         // And we cannot read the values that we stored in it because they were
         // not applied to the store
@@ -400,6 +511,94 @@ mod test {
         assert_eq!(v, value_external);
     }
 
+    #[test]
+    fn transaction_ends_with_success_read_conflict() {
+        let path = Path::try_from(DOM0_DOMAIN_ID, "/basic/path").unwrap();
+        let other = Path::try_from(DOM0_DOMAIN_ID, "/basic/other").unwrap();
+
+        let mut store = Store::new();
+        let mut txns = TransactionList::new();
+
+        let changes = store.write(&ChangeSet::new(&store),
+                                  DOM0_DOMAIN_ID,
+                                  path.clone(),
+                                  Value::from("value"))
+            .unwrap();
+        store.apply(changes).unwrap();
+
+        let conn = ConnId::new(Token(0), DOM0_DOMAIN_ID);
+        let tx_id = txns.start(conn, &store).unwrap();
+
+        // the transaction only reads the path
+        store.read(txns.get(conn, tx_id).unwrap(), DOM0_DOMAIN_ID, &path).unwrap();
+
+        // which then changes underneath it
+        let changes = store.write(&ChangeSet::new(&store),
+                                  DOM0_DOMAIN_ID,
+                                  path.clone(),
+                                  Value::from("value 2"))
+            .unwrap();
+        store.apply(changes).unwrap();
+
+        let changes = {
+            let changes = txns.get(conn, tx_id).unwrap();
+            let mut new = store.write(&changes, DOM0_DOMAIN_ID, other.clone(), Value::from("v"))
+                .unwrap();
+            new.merge_reads(&changes);
+            new
+        };
+        txns.put(conn, tx_id, changes).unwrap();
+
+        match txns.end(&mut store, conn, tx_id, TransactionStatus::Success) {
+            Err(Error::EAGAIN(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "a transaction with a stale read was applied"),
+        }
+    }
+
+    #[test]
+    fn transaction_ends_with_success_unrelated_change() {
+        let path = Path::try_from(DOM0_DOMAIN_ID, "/basic/path").unwrap();
+        let other = Path::try_from(DOM0_DOMAIN_ID, "/other/path").unwrap();
+        let value = Value::from("value");
+
+        let mut store = Store::new();
+        let mut txns = TransactionList::new();
+
+        // create both parents up front so the writes below only touch leaves
+        for p in vec![&path, &other] {
+            let changes = store.write(&ChangeSet::new(&store),
+                                      DOM0_DOMAIN_ID,
+                                      p.clone(),
+                                      Value::from(""))
+                .unwrap();
+            store.apply(changes).unwrap();
+        }
+
+        let conn = ConnId::new(Token(0), DOM0_DOMAIN_ID);
+        let tx_id = txns.start(conn, &store).unwrap();
+
+        // somebody else changes a path the transaction never looks at
+        let changes = store.write(&ChangeSet::new(&store),
+                                  DOM0_DOMAIN_ID,
+                                  other.clone(),
+                                  Value::from("external"))
+            .unwrap();
+        store.apply(changes).unwrap();
+
+        let changes = {
+            let changes = txns.get(conn, tx_id).unwrap();
+            store.write(&changes, DOM0_DOMAIN_ID, path.clone(), value.clone()).unwrap()
+        };
+        txns.put(conn, tx_id, changes).unwrap();
+
+        // so the transaction still goes through
+        txns.end(&mut store, conn, tx_id, TransactionStatus::Success).unwrap();
+
+        let v = store.read(&ChangeSet::new(&store), DOM0_DOMAIN_ID, &path).unwrap();
+        assert_eq!(v, value);
+    }
+
     #[test]
     fn transaction_must_match_dom_id() {
         let path = Path::try_from(DOM0_DOMAIN_ID, "/basic/path").unwrap();
@@ -409,7 +608,7 @@ mod test {
         let mut txns = TransactionList::new();
 
         // Create a new transaction
-        let tx_id = txns.start(ConnId::new(Token(0), DOM0_DOMAIN_ID), &store);
+        let tx_id = txns.start(ConnId::new(Token(0), DOM0_DOMAIN_ID), &store).unwrap();
 
         // And verify that it can be retrieved
         let changes = {
@@ -455,16 +654,56 @@ mod test {
         assert_eq!(v, value);
     }
 
+    #[test]
+    fn transaction_of_another_connection() {
+        let mut store = Store::new();
+        let mut txns = TransactionList::new();
+
+        let owner = ConnId::new(Token(0), 1);
+        let tx_id = txns.start(owner, &store).unwrap();
+        let changes = txns.get(owner, tx_id).unwrap().clone();
+
+        // another connection of the same domain is still somebody else
+        for other in vec![ConnId::new(Token(1), 1), ConnId::new(Token(2), 2)] {
+            match txns.get(other, tx_id) {
+                Err(Error::EACCES(_)) => assert!(true),
+                Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+                Ok(_) => assert!(false, "got another connection's transaction"),
+            }
+            match txns.put(other, tx_id, changes.clone()) {
+                Err(Error::EACCES(_)) => assert!(true),
+                Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+                Ok(_) => assert!(false, "put into another connection's transaction"),
+            }
+            match txns.end(&mut store, other, tx_id, TransactionStatus::Failure) {
+                Err(Error::EACCES(_)) => assert!(true),
+                Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+                Ok(_) => assert!(false, "ended another connection's transaction"),
+            }
+        }
+
+        // while a transaction nobody has is not found at all
+        match txns.get(owner, tx_id.wrapping_add(1)) {
+            Err(Error::ENOENT(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "got a transaction that was never started"),
+        }
+
+        // and the owner's transaction is left alone
+        txns.get(owner, tx_id).unwrap();
+        txns.end(&mut store, owner, tx_id, TransactionStatus::Success).unwrap();
+    }
+
     #[test]
     fn transaction_reset_transactions() {
         let store = Store::new();
         let mut txns = TransactionList::new();
 
         // Create new transactions
-        let tx_id_dom0_1 = txns.start(ConnId::new(Token(0), DOM0_DOMAIN_ID), &store);
-        let tx_id_dom0_2 = txns.start(ConnId::new(Token(0), DOM0_DOMAIN_ID), &store);
-        let tx_id_dom1_1 = txns.start(ConnId::new(Token(1), 1), &store);
-        let tx_id_dom1_2 = txns.start(ConnId::new(Token(1), 1), &store);
+        let tx_id_dom0_1 = txns.start(ConnId::new(Token(0), DOM0_DOMAIN_ID), &store).unwrap();
+        let tx_id_dom0_2 = txns.start(ConnId::new(Token(0), DOM0_DOMAIN_ID), &store).unwrap();
+        let tx_id_dom1_1 = txns.start(ConnId::new(Token(1), 1), &store).unwrap();
+        let tx_id_dom1_2 = txns.start(ConnId::new(Token(1), 1), &store).unwrap();
 
         txns.reset(ConnId::new(Token(0), DOM0_DOMAIN_ID));
 
@@ -480,4 +719,78 @@ mod test {
         txns.get(ConnId::new(Token(1), 1), tx_id_dom1_1).unwrap();
         txns.get(ConnId::new(Token(1), 1), tx_id_dom1_2).unwrap();
     }
+
+    #[test]
+    fn transaction_reset_domain_transactions() {
+        let store = Store::new();
+        let mut txns = TransactionList::new();
+
+        // Create new transactions
+        let tx_id_dom0 = txns.start(ConnId::new(Token(0), DOM0_DOMAIN_ID), &store).unwrap();
+        let tx_id_dom1_1 = txns.start(ConnId::new(Token(1), 1), &store).unwrap();
+        let tx_id_dom1_2 = txns.start(ConnId::new(Token(2), 1), &store).unwrap();
+
+        txns.reset_domain(1);
+
+        match txns.get(ConnId::new(Token(1), 1), tx_id_dom1_1) {
+            Ok(_) => assert!(false),
+            Err(_) => assert!(true),
+        }
+        match txns.get(ConnId::new(Token(2), 1), tx_id_dom1_2) {
+            Ok(_) => assert!(false),
+            Err(_) => assert!(true),
+        }
+
+        txns.get(ConnId::new(Token(0), DOM0_DOMAIN_ID), tx_id_dom0).unwrap();
+    }
+
+    #[test]
+    fn transaction_quota() {
+        let store = Store::new();
+        let mut txns = TransactionList::with_quota(2);
+
+        txns.start(ConnId::new(Token(1), 1), &store).unwrap();
+        txns.start(ConnId::new(Token(2), 1), &store).unwrap();
+
+        // the quota covers every connection of the domain
+        match txns.start(ConnId::new(Token(1), 1), &store) {
+            Err(Error::ENOSPC(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "started more transactions than the quota"),
+        }
+
+        // but other domains are unaffected
+        txns.start(ConnId::new(Token(3), 2), &store).unwrap();
+
+        // and dom0 is not limited at all
+        for _ in 0..3 {
+            txns.start(ConnId::new(Token(0), DOM0_DOMAIN_ID), &store).unwrap();
+        }
+
+        // finishing one makes room for another
+        txns.reset(ConnId::new(Token(2), 1));
+        txns.start(ConnId::new(Token(1), 1), &store).unwrap();
+    }
+
+    #[test]
+    fn transaction_expire() {
+        let store = Store::new();
+        let mut txns = TransactionList::new();
+
+        let conn = ConnId::new(Token(0), DOM0_DOMAIN_ID);
+        let tx_id = txns.start(conn, &store).unwrap();
+
+        // nothing has been open that long
+        assert_eq!(txns.expire(Duration::from_secs(3600)).len(), 0);
+        txns.get(conn, tx_id).unwrap();
+
+        // but everything has been open for no time at all
+        assert_eq!(txns.expire(Duration::from_secs(0)), vec![(conn, tx_id)]);
+
+        match txns.get(conn, tx_id) {
+            Err(Error::ENOENT(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "an expired transaction was still open"),
+        }
+    }
 }
@@ -0,0 +1,153 @@
+/**
+    xenstore-rs provides a Rust based xenstore implementation.
+    Copyright (C) 2016 Star Lab Corp.
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License along
+    with this program; if not, see <http://www.gnu.org/licenses/>.
+**/
+
+// Event channels, which the ring transport notifies guests over and waits on
+// for them to notify it.
+
+use libc;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use super::super::message::EvtChnPort;
+use super::super::wire;
+use super::ioc;
+
+const EVTCHN_PATH: &'static str = "/dev/xen/evtchn";
+
+/// The most pending ports taken from the device at once
+pub const MAX_PENDING: usize = 64;
+
+#[repr(C)]
+struct EvtchnBindInterdomain {
+    remote_domain: libc::c_uint,
+    remote_port: libc::c_uint,
+}
+
+#[repr(C)]
+struct EvtchnPort {
+    port: libc::c_uint,
+}
+
+/// The event channel operations the ring transport needs
+pub trait EventChannel: Send {
+    /// Bind to a remote domain's port, returning the local port
+    fn bind_interdomain(&mut self,
+                        dom_id: wire::DomainId,
+                        port: EvtChnPort)
+                        -> io::Result<EvtChnPort>;
+
+    fn unbind(&mut self, port: EvtChnPort) -> io::Result<()>;
+
+    /// Notify the remote end of a local port
+    fn notify(&mut self, port: EvtChnPort) -> io::Result<()>;
+
+    /// Wait up to `timeout` milliseconds for local ports to be notified,
+    /// returning the ones that were. They are unmasked again, so the next
+    /// notification on them is seen too.
+    fn pending(&mut self, timeout: libc::c_int) -> io::Result<Vec<EvtChnPort>>;
+}
+
+/// The event channel device, `/dev/xen/evtchn`
+pub struct XenEventChannel {
+    file: File,
+}
+
+impl XenEventChannel {
+    pub fn open() -> io::Result<XenEventChannel> {
+        let file = try!(OpenOptions::new().read(true).write(true).open(EVTCHN_PATH));
+        Ok(XenEventChannel { file: file })
+    }
+
+    fn port_ioctl(&self, nr: u8, port: EvtChnPort) -> io::Result<()> {
+        let mut arg = EvtchnPort { port: port as libc::c_uint };
+
+        let request = ioc(b'E', nr, mem::size_of::<EvtchnPort>());
+        let ret = unsafe { libc::ioctl(self.file.as_raw_fd(), request as _, &mut arg) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    /// Wait up to `timeout` milliseconds for the device to become readable
+    fn wait(&self, timeout: libc::c_int) -> io::Result<bool> {
+        let mut fds = libc::pollfd {
+            fd: self.file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+
+        let ret = unsafe { libc::poll(&mut fds, 1, timeout) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(ret > 0)
+    }
+}
+
+impl EventChannel for XenEventChannel {
+    fn bind_interdomain(&mut self,
+                        dom_id: wire::DomainId,
+                        port: EvtChnPort)
+                        -> io::Result<EvtChnPort> {
+        let mut bind = EvtchnBindInterdomain {
+            remote_domain: dom_id,
+            remote_port: port as libc::c_uint,
+        };
+
+        let request = ioc(b'E', 1, mem::size_of::<EvtchnBindInterdomain>());
+        let ret = unsafe { libc::ioctl(self.file.as_raw_fd(), request as _, &mut bind) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(ret as EvtChnPort)
+    }
+
+    fn unbind(&mut self, port: EvtChnPort) -> io::Result<()> {
+        self.port_ioctl(3, port)
+    }
+
+    fn notify(&mut self, port: EvtChnPort) -> io::Result<()> {
+        self.port_ioctl(4, port)
+    }
+
+    fn pending(&mut self, timeout: libc::c_int) -> io::Result<Vec<EvtChnPort>> {
+        if !try!(self.wait(timeout)) {
+            return Ok(Vec::new());
+        }
+
+        // each pending port is read as a u32, and writing it back unmasks it
+        let mut buf = [0u8; MAX_PENDING * 4];
+        let len = try!(self.file.read(&mut buf));
+        let len = len - len % 4;
+        try!(self.file.write_all(&buf[..len]));
+
+        Ok(buf[..len]
+               .chunks(4)
+               .map(|b| {
+                        let port = (b[0] as u32) | (b[1] as u32) << 8 | (b[2] as u32) << 16 |
+                                   (b[3] as u32) << 24;
+                        port as EvtChnPort
+                    })
+               .collect())
+    }
+}
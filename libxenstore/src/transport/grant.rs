@@ -0,0 +1,339 @@
+/**
+    xenstore-rs provides a Rust based xenstore implementation.
+    Copyright (C) 2016 Star Lab Corp.
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License along
+    with this program; if not, see <http://www.gnu.org/licenses/>.
+**/
+
+// Mapping the page a guest shares its xenstore ring on into our address
+// space.
+
+use libc;
+use std::cell::UnsafeCell;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use super::super::message::Mfn;
+use super::super::store::DOM0_DOMAIN_ID;
+use super::super::wire;
+use super::ioc;
+use super::ring::{Interface, SharedPage};
+use super::xenbus::{self, BackendPage};
+
+/// The grant reference every guest's xenstore page is granted under
+pub const GNTTAB_RESERVED_XENSTORE: u32 = 1;
+
+const PAGE_SIZE: usize = 4096;
+const PRIVCMD_PATH: &'static str = "/dev/xen/privcmd";
+const GNTDEV_PATH: &'static str = "/dev/xen/gntdev";
+
+/// Maps the xenstore page of a guest
+pub trait Mapper: Send {
+    /// Map the page `dom_id` was introduced with, which is at `mfn`
+    fn map(&mut self, dom_id: wire::DomainId, mfn: Mfn) -> io::Result<Box<SharedPage>>;
+}
+
+#[repr(C)]
+struct PrivcmdMmapBatchV2 {
+    num: libc::c_uint,
+    dom: u16,
+    addr: u64,
+    arr: *const u64,
+    err: *mut libc::c_int,
+}
+
+#[repr(C)]
+struct GntdevGrantRef {
+    domid: u32,
+    gref: u32,
+}
+
+#[repr(C)]
+struct GntdevMapGrantRef {
+    count: u32,
+    pad: u32,
+    index: u64,
+    refs: [GntdevGrantRef; 1],
+}
+
+#[repr(C)]
+struct GntdevUnmapGrantRef {
+    index: u64,
+    count: u32,
+    pad: u32,
+}
+
+/// A guest's xenstore page mapped into our address space via privcmd
+pub struct ForeignPage {
+    _privcmd: File,
+    addr: *mut libc::c_void,
+}
+
+impl ForeignPage {
+    pub fn map(dom_id: wire::DomainId, mfn: Mfn) -> io::Result<ForeignPage> {
+        let privcmd = try!(OpenOptions::new().read(true).write(true).open(PRIVCMD_PATH));
+
+        let addr = unsafe {
+            libc::mmap(ptr::null_mut(),
+                       PAGE_SIZE,
+                       libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_SHARED,
+                       privcmd.as_raw_fd(),
+                       0)
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        let page = ForeignPage {
+            _privcmd: privcmd,
+            addr: addr,
+        };
+
+        let arr = [mfn];
+        let mut err: libc::c_int = 0;
+        let mut batch = PrivcmdMmapBatchV2 {
+            num: 1,
+            dom: dom_id as u16,
+            addr: addr as u64,
+            arr: arr.as_ptr(),
+            err: &mut err,
+        };
+
+        let request = ioc(b'P', 4, mem::size_of::<PrivcmdMmapBatchV2>());
+        let ret = unsafe { libc::ioctl(page._privcmd.as_raw_fd(), request as _, &mut batch) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if err != 0 {
+            return Err(io::Error::from_raw_os_error(-err));
+        }
+
+        Ok(page)
+    }
+}
+
+impl SharedPage for ForeignPage {
+    fn interface(&self) -> *mut Interface {
+        self.addr as *mut Interface
+    }
+}
+
+// The mapping is owned by a single connection and only ever touched by
+// the thread currently servicing it.
+unsafe impl Send for ForeignPage {}
+
+impl Drop for ForeignPage {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.addr, PAGE_SIZE);
+        }
+    }
+}
+
+/// A guest's xenstore page mapped into our address space by its grant
+/// reference via gntdev
+pub struct GrantPage {
+    gntdev: Arc<File>,
+    index: u64,
+    addr: *mut libc::c_void,
+}
+
+impl GrantPage {
+    pub fn map(gntdev: Arc<File>, dom_id: wire::DomainId) -> io::Result<GrantPage> {
+        let mut map = GntdevMapGrantRef {
+            count: 1,
+            pad: 0,
+            index: 0,
+            refs: [GntdevGrantRef {
+                       domid: dom_id,
+                       gref: GNTTAB_RESERVED_XENSTORE,
+                   }],
+        };
+
+        let request = ioc(b'G', 0, mem::size_of::<GntdevMapGrantRef>());
+        let ret = unsafe { libc::ioctl(gntdev.as_raw_fd(), request as _, &mut map) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let addr = unsafe {
+            libc::mmap(ptr::null_mut(),
+                       PAGE_SIZE,
+                       libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_SHARED,
+                       gntdev.as_raw_fd(),
+                       map.index as libc::off_t)
+        };
+        let page = GrantPage {
+            gntdev: gntdev,
+            index: map.index,
+            addr: addr,
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(page)
+    }
+}
+
+impl SharedPage for GrantPage {
+    fn interface(&self) -> *mut Interface {
+        self.addr as *mut Interface
+    }
+}
+
+// The mapping is owned by a single connection and only ever touched by
+// the thread currently servicing it.
+unsafe impl Send for GrantPage {}
+
+impl Drop for GrantPage {
+    fn drop(&mut self) {
+        if self.addr != libc::MAP_FAILED {
+            unsafe {
+                libc::munmap(self.addr, PAGE_SIZE);
+            }
+        }
+
+        // the grant stays mapped until it is released too
+        let mut unmap = GntdevUnmapGrantRef {
+            index: self.index,
+            count: 1,
+            pad: 0,
+        };
+        let request = ioc(b'G', 1, mem::size_of::<GntdevUnmapGrantRef>());
+        unsafe {
+            libc::ioctl(self.gntdev.as_raw_fd(), request as _, &mut unmap);
+        }
+    }
+}
+
+/// Maps guest pages the way C xenstored does: by the grant reference set
+/// aside for xenstore where gntdev is there, falling back to the page's mfn
+/// for guests that didn't grant it. Our own kernel's page is reached through
+/// the xenbus backend device instead.
+pub struct XenMapper {
+    gntdev: Option<Arc<File>>,
+}
+
+impl XenMapper {
+    pub fn open() -> io::Result<XenMapper> {
+        let gntdev = if Path::new(GNTDEV_PATH).exists() {
+            let file = try!(OpenOptions::new().read(true).write(true).open(GNTDEV_PATH));
+            Some(Arc::new(file))
+        } else {
+            None
+        };
+
+        Ok(XenMapper { gntdev: gntdev })
+    }
+}
+
+impl Mapper for XenMapper {
+    fn map(&mut self, dom_id: wire::DomainId, mfn: Mfn) -> io::Result<Box<SharedPage>> {
+        if dom_id == DOM0_DOMAIN_ID && xenbus::available() {
+            return BackendPage::open().map(|(page, _)| Box::new(page) as Box<SharedPage>);
+        }
+
+        if let Some(ref gntdev) = self.gntdev {
+            match GrantPage::map(gntdev.clone(), dom_id) {
+                Ok(page) => return Ok(Box::new(page)),
+                Err(e) => debug!("no xenstore grant from domain {}: {}", dom_id, e),
+            }
+        }
+
+        ForeignPage::map(dom_id, mfn).map(|page| Box::new(page) as Box<SharedPage>)
+    }
+}
+
+// a page of memory that stands in for one a guest shares
+struct Frame(UnsafeCell<Interface>);
+
+// both ends of the ring only touch it through volatile accesses
+unsafe impl Send for Frame {}
+unsafe impl Sync for Frame {}
+
+/// A xenstore page held in our own memory, so the ring transport can be
+/// driven without Xen. Copies share the same page, one of them playing the
+/// guest's end of the ring.
+#[derive(Clone)]
+pub struct MemoryPage(Arc<Frame>);
+
+impl MemoryPage {
+    pub fn new() -> MemoryPage {
+        MemoryPage(Arc::new(Frame(UnsafeCell::new(unsafe { mem::zeroed() }))))
+    }
+}
+
+impl SharedPage for MemoryPage {
+    fn interface(&self) -> *mut Interface {
+        (self.0).0.get()
+    }
+}
+
+/// Hands out a `MemoryPage` for each domain in place of mapping its real
+/// one. Copies share the same pages.
+#[derive(Clone, Default)]
+pub struct MemoryMapper {
+    pages: Arc<Mutex<HashMap<wire::DomainId, MemoryPage>>>,
+}
+
+impl MemoryMapper {
+    pub fn new() -> MemoryMapper {
+        MemoryMapper::default()
+    }
+
+    /// The page `dom_id` shares, made the first time it's asked for.
+    pub fn page(&self, dom_id: wire::DomainId) -> MemoryPage {
+        self.pages
+            .lock()
+            .unwrap()
+            .entry(dom_id)
+            .or_insert_with(MemoryPage::new)
+            .clone()
+    }
+}
+
+impl Mapper for MemoryMapper {
+    fn map(&mut self, dom_id: wire::DomainId, _mfn: Mfn) -> io::Result<Box<SharedPage>> {
+        Ok(Box::new(self.page(dom_id)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::ring::SharedPage;
+    use super::*;
+
+    #[test]
+    fn memory_pages_shared() {
+        let mut mapper = MemoryMapper::new();
+        let guest = mapper.page(1);
+        let mapped = mapper.map(1, 0).unwrap();
+        let other = mapper.map(2, 0).unwrap();
+
+        // what the guest writes we see, in its page alone
+        unsafe {
+            (*guest.interface()).req_prod = 4;
+        }
+        assert_eq!(unsafe { (*mapped.interface()).req_prod }, 4);
+        assert_eq!(unsafe { (*other.interface()).req_prod }, 0);
+    }
+}
@@ -0,0 +1,47 @@
+/**
+    xenstore-rs provides a Rust based xenstore implementation.
+    Copyright (C) 2016 Star Lab Corp.
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License along
+    with this program; if not, see <http://www.gnu.org/licenses/>.
+**/
+
+// The ways messages reach the server other than its sockets.
+
+use libc;
+use std::io;
+use super::wire;
+
+pub mod evtchn;
+pub mod grant;
+pub mod ring;
+#[cfg(feature = "stubdom")]
+pub mod stubdom;
+pub mod xenbus;
+
+/// Build an ioctl request number with no direction bits (`_IOC(_IOC_NONE, ...)`)
+fn ioc(ty: u8, nr: u8, size: usize) -> libc::c_ulong {
+    ((size as libc::c_ulong) << 16) | ((ty as libc::c_ulong) << 8) | (nr as libc::c_ulong)
+}
+
+/// Carries messages between one connection and the `Handler`.
+pub trait Transport {
+    /// The next request that has arrived in full, if there is one yet.
+    fn recv(&mut self) -> io::Result<Option<(wire::Header, wire::Body)>>;
+
+    /// Send a response or watch event, which may be held back until `flush`.
+    fn send(&mut self, msg: (wire::Header, wire::Body)) -> io::Result<()>;
+
+    /// Push out everything sent so far.
+    fn flush(&mut self) -> io::Result<()>;
+}
@@ -0,0 +1,665 @@
+/**
+    xenstore-rs provides a Rust based xenstore implementation.
+    Copyright (C) 2016 Star Lab Corp.
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License along
+    with this program; if not, see <http://www.gnu.org/licenses/>.
+**/
+
+use bytes::BytesMut;
+use libc;
+use std::collections::HashMap;
+use std::io;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{fence, Ordering};
+use std::thread;
+use tokio_io::codec::{Decoder, Encoder};
+use super::super::connection::ConnId;
+use super::super::domain::Domain;
+use super::super::handler::Handler;
+use super::super::message::EvtChnPort;
+use super::super::system::System;
+use super::super::wire;
+use super::evtchn::{EventChannel, XenEventChannel};
+use super::grant::{Mapper, XenMapper};
+use super::Transport;
+
+/// Size of each of the request and response rings
+pub const XENSTORE_RING_SIZE: usize = 1024;
+
+/// Set in `server_features` when we follow a guest's request to reconnect
+pub const XENSTORE_SERVER_FEATURE_RECONNECTION: u32 = 1;
+
+/// The guest and we are using the rings
+pub const XENSTORE_CONNECTED: u32 = 0;
+/// The guest wants the rings emptied and everything we hold for it dropped
+pub const XENSTORE_RECONNECT: u32 = 1;
+
+/// How long to wait for an event channel notification before checking
+/// for newly introduced or released domains
+const POLL_TIMEOUT_MS: libc::c_int = 100;
+
+/// The layout of the shared xenstore page (`struct xenstore_domain_interface`)
+#[repr(C)]
+pub struct Interface {
+    pub req: [u8; XENSTORE_RING_SIZE],
+    pub rsp: [u8; XENSTORE_RING_SIZE],
+    pub req_cons: u32,
+    pub req_prod: u32,
+    pub rsp_cons: u32,
+    pub rsp_prod: u32,
+    pub server_features: u32,
+    pub connection: u32,
+}
+
+fn mask(idx: u32) -> usize {
+    (idx as usize) & (XENSTORE_RING_SIZE - 1)
+}
+
+/// Consume all of the request bytes the guest has produced, appending them to `buf`.
+unsafe fn read_requests(intf: *mut Interface, buf: &mut BytesMut) -> io::Result<usize> {
+    let cons = ptr::read_volatile(&(*intf).req_cons);
+    let prod = ptr::read_volatile(&(*intf).req_prod);
+    fence(Ordering::SeqCst);
+
+    let avail = prod.wrapping_sub(cons) as usize;
+    if avail > XENSTORE_RING_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "corrupted request ring"));
+    }
+
+    buf.reserve(avail);
+    for i in 0..avail {
+        let idx = mask(cons.wrapping_add(i as u32));
+        buf.extend_from_slice(&[ptr::read_volatile(&(*intf).req[idx])]);
+    }
+
+    fence(Ordering::SeqCst);
+    ptr::write_volatile(&mut (*intf).req_cons, cons.wrapping_add(avail as u32));
+    Ok(avail)
+}
+
+/// Produce as much of `data` as fits in the response ring, returning how much was written.
+unsafe fn write_responses(intf: *mut Interface, data: &[u8]) -> io::Result<usize> {
+    let cons = ptr::read_volatile(&(*intf).rsp_cons);
+    let prod = ptr::read_volatile(&(*intf).rsp_prod);
+    fence(Ordering::SeqCst);
+
+    let used = prod.wrapping_sub(cons) as usize;
+    if used > XENSTORE_RING_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "corrupted response ring"));
+    }
+
+    let len = ::std::cmp::min(XENSTORE_RING_SIZE - used, data.len());
+    for (i, byte) in data[..len].iter().enumerate() {
+        let idx = mask(prod.wrapping_add(i as u32));
+        ptr::write_volatile(&mut (*intf).rsp[idx], *byte);
+    }
+
+    fence(Ordering::SeqCst);
+    ptr::write_volatile(&mut (*intf).rsp_prod, prod.wrapping_add(len as u32));
+    Ok(len)
+}
+
+/// A page holding a `struct xenstore_domain_interface`
+pub trait SharedPage: Send {
+    fn interface(&self) -> *mut Interface;
+}
+
+/// Our end of the rings in a shared xenstore page: we consume the requests
+/// the guest produces and produce the responses it consumes.
+///
+/// A guest that wants to start over sets `connection` to
+/// `XENSTORE_RECONNECT`. Neither ring is touched until `reconnect` empties
+/// them both and sets it back to `XENSTORE_CONNECTED`.
+pub struct RingBuffer {
+    page: Box<SharedPage>,
+}
+
+impl RingBuffer {
+    /// Serve the rings in `page`, telling the guest we follow its requests
+    /// to reconnect.
+    pub fn new(page: Box<SharedPage>) -> RingBuffer {
+        let intf = page.interface();
+        unsafe {
+            let features = ptr::read_volatile(&(*intf).server_features);
+            ptr::write_volatile(&mut (*intf).server_features,
+                                features | XENSTORE_SERVER_FEATURE_RECONNECTION);
+        }
+        RingBuffer { page: page }
+    }
+
+    /// Check if the guest has asked to reconnect.
+    pub fn wants_reconnect(&self) -> bool {
+        let intf = self.page.interface();
+        unsafe { ptr::read_volatile(&(*intf).connection) == XENSTORE_RECONNECT }
+    }
+
+    /// Empty both rings and tell the guest it is connected again.
+    pub fn reconnect(&mut self) {
+        let intf = self.page.interface();
+        unsafe {
+            ptr::write_volatile(&mut (*intf).req_cons, 0);
+            ptr::write_volatile(&mut (*intf).req_prod, 0);
+            ptr::write_volatile(&mut (*intf).rsp_cons, 0);
+            ptr::write_volatile(&mut (*intf).rsp_prod, 0);
+            fence(Ordering::SeqCst);
+            ptr::write_volatile(&mut (*intf).connection, XENSTORE_CONNECTED);
+        }
+    }
+
+    /// Consume the request bytes the guest has produced, appending them to
+    /// `buf` and returning how many there were.
+    pub fn read(&mut self, buf: &mut BytesMut) -> io::Result<usize> {
+        if self.wants_reconnect() {
+            return Ok(0);
+        }
+        unsafe { read_requests(self.page.interface(), buf) }
+    }
+
+    /// Produce as much of `data` as fits in the response ring, returning how
+    /// much that was.
+    pub fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.wants_reconnect() {
+            return Ok(0);
+        }
+        unsafe { write_responses(self.page.interface(), data) }
+    }
+}
+
+/// A connection to a guest over its shared xenstore ring
+struct RingConnection {
+    conn: ConnId,
+    // only set for domains that were introduced to us
+    domain: Option<Domain>,
+    ring: RingBuffer,
+    local_port: EvtChnPort,
+    input: BytesMut,
+    output: Vec<u8>,
+    // whether anything moved on the ring since the guest was last notified
+    moved: bool,
+    // the ring may hold requests we weren't notified of, as it does when we
+    // first connect
+    unread: bool,
+}
+
+impl Transport for RingConnection {
+    fn recv(&mut self) -> io::Result<Option<(wire::Header, wire::Body)>> {
+        if let Some(msg) = try!(wire::XenStoreCodec.decode(&mut self.input)) {
+            return Ok(Some(msg));
+        }
+
+        let consumed = try!(self.ring.read(&mut self.input));
+        if consumed == 0 {
+            return Ok(None);
+        }
+
+        self.moved = true;
+        wire::XenStoreCodec.decode(&mut self.input)
+    }
+
+    fn send(&mut self, msg: (wire::Header, wire::Body)) -> io::Result<()> {
+        let mut buf = BytesMut::with_capacity(wire::HEADER_SIZE + msg.0.len());
+        try!(wire::XenStoreCodec.encode(msg, &mut buf));
+        self.output.extend_from_slice(&buf);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let produced = try!(self.ring.write(&self.output));
+        self.output.drain(..produced);
+        if produced > 0 {
+            self.moved = true;
+        }
+        Ok(())
+    }
+}
+
+/// Serves every introduced domain over its shared ring
+pub struct RingServer {
+    handler: Handler,
+    evtchn: Box<EventChannel>,
+    mapper: Box<Mapper>,
+    conns: HashMap<wire::DomainId, RingConnection>,
+}
+
+impl RingServer {
+    pub fn new(system: Arc<Mutex<System>>) -> io::Result<RingServer> {
+        let evtchn = try!(XenEventChannel::open());
+        let mapper = try!(XenMapper::open());
+        Ok(RingServer::with_backends(system, Box::new(evtchn), Box::new(mapper)))
+    }
+
+    /// Create a `RingServer` that waits on and notifies guests through
+    /// `evtchn` and maps the pages of introduced domains with `mapper`.
+    pub fn with_backends(system: Arc<Mutex<System>>,
+                         evtchn: Box<EventChannel>,
+                         mapper: Box<Mapper>)
+                         -> RingServer {
+        RingServer {
+            handler: Handler::new(system),
+            evtchn: evtchn,
+            mapper: mapper,
+            conns: HashMap::new(),
+        }
+    }
+
+    /// Run the ring server forever
+    pub fn run(&mut self) -> io::Result<()> {
+        loop {
+            try!(self.turn());
+        }
+    }
+
+    /// Wait for guests to notify us, then serve the rings that have
+    /// something to do
+    fn turn(&mut self) -> io::Result<()> {
+        self.reconcile();
+
+        let notified = try!(self.evtchn.pending(POLL_TIMEOUT_MS));
+
+        // besides the guests that notified us, serve those with responses
+        // the ring had no room for and those with watch events waiting
+        let dom_ids = {
+            let mut sys = self.handler.system().lock().unwrap();
+            self.conns
+                .iter()
+                .filter(|&(_, conn)| {
+                    conn.unread || notified.contains(&conn.local_port) ||
+                    !conn.output.is_empty() ||
+                    sys.do_outbox_mut(conn.conn, |outbox| !outbox.is_empty()).unwrap_or(false)
+                })
+                .map(|(dom_id, _)| *dom_id)
+                .collect::<Vec<wire::DomainId>>()
+        };
+
+        for dom_id in dom_ids {
+            if let Err(e) = self.service(dom_id) {
+                warn!("dropping ring connection to domain {}: {}", dom_id, e);
+                self.disconnect(dom_id);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Connect to newly introduced domains and drop released ones
+    fn reconcile(&mut self) {
+        let domains = {
+            let sys = self.handler.system().lock().unwrap();
+            sys.do_domain(|domains| domains.iter().cloned().collect::<Vec<Domain>>())
+        };
+
+        let released = self.conns
+            .iter()
+            .filter(|&(_, conn)| match conn.domain {
+                        Some(ref domain) => !domains.iter().any(|d| d.same_ring(domain)),
+                        None => false,
+                    })
+            .map(|(dom_id, _)| *dom_id)
+            .collect::<Vec<wire::DomainId>>();
+        for dom_id in released {
+            self.disconnect(dom_id);
+        }
+
+        for domain in domains {
+            if self.conns.contains_key(&domain.dom_id) {
+                continue;
+            }
+
+            let page = self.mapper.map(domain.dom_id, domain.mfn);
+            match page.and_then(|page| self.connect(domain.dom_id, page, domain.port)) {
+                Ok(mut conn) => {
+                    info!("connected to domain {} over its ring", domain.dom_id);
+                    conn.domain = Some(domain.clone());
+                    self.conns.insert(domain.dom_id, conn);
+                }
+                Err(e) => warn!("unable to connect to domain {}: {}", domain.dom_id, e),
+            }
+        }
+    }
+
+    /// Serve a ring that was not set up by an introduction, such as the
+    /// kernel's own xenbus page
+    pub fn attach(&mut self,
+                  dom_id: wire::DomainId,
+                  page: Box<SharedPage>,
+                  port: EvtChnPort)
+                  -> io::Result<()> {
+        let conn = try!(self.connect(dom_id, page, port));
+        self.conns.insert(dom_id, conn);
+        Ok(())
+    }
+
+    fn connect(&mut self,
+               dom_id: wire::DomainId,
+               page: Box<SharedPage>,
+               port: EvtChnPort)
+               -> io::Result<RingConnection> {
+        let local_port = try!(self.evtchn.bind_interdomain(dom_id, port));
+        let conn = self.handler.system().lock().unwrap().domain_connection(dom_id);
+        self.handler.open(conn);
+
+        Ok(RingConnection {
+               conn: conn,
+               domain: None,
+               ring: RingBuffer::new(page),
+               local_port: local_port,
+               input: BytesMut::with_capacity(wire::HEADER_SIZE + wire::BODY_SIZE),
+               output: Vec::new(),
+               moved: false,
+               unread: true,
+           })
+    }
+
+    fn disconnect(&mut self, dom_id: wire::DomainId) {
+        if let Some(conn) = self.conns.remove(&dom_id) {
+            self.handler.close(conn.conn);
+            let _ = self.evtchn.unbind(conn.local_port);
+        }
+    }
+
+    /// Process any requests waiting on a domain's ring and flush its responses
+    fn service(&mut self, dom_id: wire::DomainId) -> io::Result<()> {
+        let conn = self.conns.get_mut(&dom_id).unwrap();
+        if conn.ring.wants_reconnect() {
+            // the guest starts over on a new connection, without the
+            // watches, transactions and part messages of the old one
+            info!("domain {} reconnected over its ring", dom_id);
+            let old = conn.conn;
+            conn.conn = self.handler.system().lock().unwrap().reconnect(old);
+            self.handler.close(old);
+            self.handler.open(conn.conn);
+            conn.input.clear();
+            conn.output.clear();
+            conn.ring.reconnect();
+            conn.moved = true;
+        }
+
+        let id = conn.conn;
+        conn.unread = false;
+        try!(self.handler.service(id, conn));
+
+        if conn.moved {
+            conn.moved = false;
+            try!(self.evtchn.notify(conn.local_port));
+        }
+
+        Ok(())
+    }
+
+    /// Start serving the rings on a new thread
+    pub fn spawn(mut self) -> thread::JoinHandle<()> {
+        thread::spawn(move || if let Err(e) = self.run() {
+                          error!("ring transport failed: {}", e);
+                      })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::BytesMut;
+    use libc;
+    use std::io;
+    use std::mem;
+    use std::sync::{Arc, Mutex};
+    use super::super::super::domain::DomainList;
+    use super::super::super::message::EvtChnPort;
+    use super::super::super::store::Store;
+    use super::super::super::system::System;
+    use super::super::super::transaction::TransactionList;
+    use super::super::super::watch::WatchList;
+    use super::super::super::wire;
+    use super::super::evtchn::EventChannel;
+    use super::super::grant::{MemoryMapper, MemoryPage};
+    use super::*;
+
+    #[derive(Default)]
+    struct Ports {
+        bound: Vec<(wire::DomainId, EvtChnPort)>,
+        notified: Vec<EvtChnPort>,
+        pending: Vec<EvtChnPort>,
+    }
+
+    /// Stands in for the event channel device, letting the test see what
+    /// was done with it
+    struct MockChannel(Arc<Mutex<Ports>>);
+
+    impl EventChannel for MockChannel {
+        fn bind_interdomain(&mut self,
+                            dom_id: wire::DomainId,
+                            port: EvtChnPort)
+                            -> io::Result<EvtChnPort> {
+            let mut ports = self.0.lock().unwrap();
+            ports.bound.push((dom_id, port));
+            Ok(ports.bound.len() as EvtChnPort)
+        }
+
+        fn unbind(&mut self, _port: EvtChnPort) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn notify(&mut self, port: EvtChnPort) -> io::Result<()> {
+            self.0.lock().unwrap().notified.push(port);
+            Ok(())
+        }
+
+        fn pending(&mut self, _timeout: libc::c_int) -> io::Result<Vec<EvtChnPort>> {
+            Ok(mem::replace(&mut self.0.lock().unwrap().pending, Vec::new()))
+        }
+    }
+
+    /// Place bytes in the request ring the way a guest would
+    fn produce(page: &MemoryPage, bytes: &[u8]) {
+        let intf = unsafe { &mut *page.interface() };
+        for byte in bytes {
+            intf.req[mask(intf.req_prod)] = *byte;
+            intf.req_prod = intf.req_prod.wrapping_add(1);
+        }
+    }
+
+    fn encode(msg_type: u32, body: &[u8]) -> Vec<u8> {
+        let header = wire::Header {
+            msg_type: msg_type,
+            req_id: 0,
+            tx_id: 0,
+            len: body.len() as u32,
+        };
+        let mut bytes = header.to_vec();
+        bytes.extend_from_slice(body);
+        bytes
+    }
+
+    /// Place a request in the ring the way a guest would
+    fn request(page: &MemoryPage, msg_type: u32, body: &[u8]) {
+        produce(page, &encode(msg_type, body));
+    }
+
+    #[test]
+    fn ring_round_trip() {
+        let mut intf: Box<Interface> = Box::new(unsafe { mem::zeroed() });
+
+        // the guest places a request in the ring
+        intf.req[0] = b'a';
+        intf.req[1] = b'b';
+        intf.req_prod = 2;
+
+        let mut input = BytesMut::with_capacity(16);
+        let consumed = unsafe { read_requests(&mut *intf, &mut input).unwrap() };
+        assert_eq!(consumed, 2);
+        assert_eq!(&input[..], b"ab");
+        assert_eq!(intf.req_cons, 2);
+
+        // and we answer in the response ring
+        let produced = unsafe { write_responses(&mut *intf, b"cd").unwrap() };
+        assert_eq!(produced, 2);
+        assert_eq!(&intf.rsp[..2], b"cd");
+        assert_eq!(intf.rsp_prod, 2);
+    }
+
+    #[test]
+    fn ring_wraps_around() {
+        let page = MemoryPage::new();
+        let mut ring = RingBuffer::new(Box::new(page.clone()));
+        let intf = || unsafe { &mut *page.interface() };
+
+        // the indexes are about to wrap, and so are the rings
+        let start = u32::max_value() - 2;
+        intf().req_cons = start;
+        intf().req_prod = start;
+        intf().rsp_cons = start;
+        intf().rsp_prod = start;
+
+        produce(&page, b"abcdef");
+        let mut input = BytesMut::with_capacity(16);
+        assert_eq!(ring.read(&mut input).unwrap(), 6);
+        assert_eq!(&input[..], b"abcdef");
+        assert_eq!(intf().req_cons, 3);
+
+        assert_eq!(ring.write(b"ghijkl").unwrap(), 6);
+        assert_eq!(intf().rsp_prod, 3);
+        let written = (0..6)
+            .map(|i| intf().rsp[mask(start.wrapping_add(i))])
+            .collect::<Vec<u8>>();
+        assert_eq!(&written[..], b"ghijkl");
+    }
+
+    #[test]
+    fn ring_partial_messages() {
+        let page = MemoryPage::new();
+        let mut ring = RingBuffer::new(Box::new(page.clone()));
+        let intf = || unsafe { &mut *page.interface() };
+
+        // a request that arrives a few bytes at a time is only decoded once
+        // it is all there
+        let bytes = encode(wire::XS_READ, b"/a\0");
+        let mut input = BytesMut::with_capacity(wire::HEADER_SIZE + wire::BODY_SIZE);
+        produce(&page, &bytes[..5]);
+        assert_eq!(ring.read(&mut input).unwrap(), 5);
+        assert!(wire::XenStoreCodec.decode(&mut input).unwrap().is_none());
+        produce(&page, &bytes[5..]);
+        assert_eq!(ring.read(&mut input).unwrap(), bytes.len() - 5);
+        let (header, _) = wire::XenStoreCodec.decode(&mut input).unwrap().unwrap();
+        assert_eq!(header.msg_type, wire::XS_READ);
+
+        // responses only go in as far as there is room for them
+        let big = vec![b'x'; XENSTORE_RING_SIZE + 10];
+        assert_eq!(ring.write(&big).unwrap(), XENSTORE_RING_SIZE);
+        assert_eq!(ring.write(&big).unwrap(), 0);
+        intf().rsp_cons = 10;
+        assert_eq!(ring.write(&big).unwrap(), 10);
+
+        // and indexes further apart than the ring is long are refused
+        intf().req_prod = intf().req_cons.wrapping_add(XENSTORE_RING_SIZE as u32 + 1);
+        assert!(ring.read(&mut input).is_err());
+    }
+
+    #[test]
+    fn ring_reconnect() {
+        let page = MemoryPage::new();
+        let mut ring = RingBuffer::new(Box::new(page.clone()));
+        let intf = || unsafe { &mut *page.interface() };
+        assert!(intf().server_features & XENSTORE_SERVER_FEATURE_RECONNECTION != 0);
+
+        produce(&page, b"abc");
+        intf().connection = XENSTORE_RECONNECT;
+
+        // neither ring is touched until we reconnect
+        assert!(ring.wants_reconnect());
+        let mut input = BytesMut::with_capacity(16);
+        assert_eq!(ring.read(&mut input).unwrap(), 0);
+        assert_eq!(ring.write(b"def").unwrap(), 0);
+
+        ring.reconnect();
+        assert!(!ring.wants_reconnect());
+        assert_eq!(intf().connection, XENSTORE_CONNECTED);
+        assert_eq!((intf().req_cons, intf().req_prod, intf().rsp_cons, intf().rsp_prod),
+                   (0, 0, 0, 0));
+    }
+
+    #[test]
+    fn serves_notified_rings() {
+        let mut domains = DomainList::new();
+        domains.introduce(1, 0, 7).unwrap();
+        let system = System::new(Store::new(),
+                                 WatchList::new(),
+                                 TransactionList::new(),
+                                 domains);
+        let ports = Arc::new(Mutex::new(Ports::default()));
+        let mapper = MemoryMapper::new();
+        let mut server = RingServer::with_backends(Arc::new(Mutex::new(system)),
+                                                   Box::new(MockChannel(ports.clone())),
+                                                   Box::new(mapper.clone()));
+
+        let page = mapper.page(1);
+        let intf = || unsafe { &*page.interface() };
+        request(&page, wire::XS_GET_DOMAIN_PATH, b"1\0");
+
+        // the introduced domain's ring is mapped and read as soon as we
+        // connect to it, without being notified
+        server.turn().unwrap();
+        assert_eq!(ports.lock().unwrap().bound, vec![(1, 7)]);
+        let local = 1;
+        assert!(intf().rsp_prod > 0);
+        assert_eq!(ports.lock().unwrap().notified, vec![local]);
+
+        // and after that only when the guest notifies us
+        request(&page, wire::XS_GET_DOMAIN_PATH, b"1\0");
+        server.turn().unwrap();
+        assert!(intf().req_cons != intf().req_prod);
+        assert_eq!(ports.lock().unwrap().notified, vec![local]);
+
+        ports.lock().unwrap().pending.push(local);
+        server.turn().unwrap();
+        assert_eq!(intf().req_cons, intf().req_prod);
+        assert_eq!(ports.lock().unwrap().notified, vec![local, local]);
+    }
+
+    #[test]
+    fn reconnect_drops_connection() {
+        let mut domains = DomainList::new();
+        domains.introduce(1, 0, 7).unwrap();
+        let system = Arc::new(Mutex::new(System::new(Store::new(),
+                                                     WatchList::new(),
+                                                     TransactionList::new(),
+                                                     domains)));
+        let ports = Arc::new(Mutex::new(Ports::default()));
+        let mapper = MemoryMapper::new();
+        let mut server = RingServer::with_backends(system.clone(),
+                                                   Box::new(MockChannel(ports.clone())),
+                                                   Box::new(mapper.clone()));
+        let watches = || system.lock().unwrap().do_all(|_, watches, _, _| watches.count(1));
+
+        let page = mapper.page(1);
+        let intf = || unsafe { &mut *page.interface() };
+        request(&page, wire::XS_WATCH, b"device\0token\0");
+        server.turn().unwrap();
+        assert_eq!(watches(), 1);
+        assert!(intf().rsp_prod > 0);
+
+        // the guest starts over, leaving its watch behind
+        intf().connection = XENSTORE_RECONNECT;
+        ports.lock().unwrap().pending.push(1);
+        server.turn().unwrap();
+        assert_eq!(watches(), 0);
+        assert_eq!(intf().connection, XENSTORE_CONNECTED);
+        assert_eq!((intf().req_cons, intf().req_prod, intf().rsp_cons, intf().rsp_prod),
+                   (0, 0, 0, 0));
+
+        // and is served as before
+        request(&page, wire::XS_GET_DOMAIN_PATH, b"1\0");
+        ports.lock().unwrap().pending.push(1);
+        server.turn().unwrap();
+        assert!(intf().rsp_prod > 0);
+    }
+}
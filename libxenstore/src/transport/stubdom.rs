@@ -0,0 +1,223 @@
+/**
+    xenstore-rs provides a Rust based xenstore implementation.
+    Copyright (C) 2016 Star Lab Corp.
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License along
+    with this program; if not, see <http://www.gnu.org/licenses/>.
+**/
+
+// Event channels and page mapping for running in a xenstore stub domain,
+// where there is no /dev/xen to open. We go through libxenevtchn and
+// libxengnttab instead, which the stub domain's environment implements with
+// hypercalls, as it does for C xenstored.
+
+use libc;
+use std::io;
+use std::ptr;
+use std::sync::Arc;
+use super::super::message::{EvtChnPort, Mfn};
+use super::super::wire;
+use super::evtchn::{EventChannel, MAX_PENDING};
+use super::grant::{GNTTAB_RESERVED_XENSTORE, Mapper};
+use super::ring::{Interface, SharedPage};
+
+enum XenEvtchnHandle {}
+enum XenGnttabHandle {}
+
+#[link(name = "xenevtchn")]
+extern "C" {
+    fn xenevtchn_open(logger: *mut libc::c_void, flags: libc::c_uint) -> *mut XenEvtchnHandle;
+    fn xenevtchn_close(xce: *mut XenEvtchnHandle) -> libc::c_int;
+    fn xenevtchn_fd(xce: *mut XenEvtchnHandle) -> libc::c_int;
+    fn xenevtchn_bind_interdomain(xce: *mut XenEvtchnHandle,
+                                  domid: u32,
+                                  remote_port: u32)
+                                  -> libc::c_int;
+    fn xenevtchn_unbind(xce: *mut XenEvtchnHandle, port: u32) -> libc::c_int;
+    fn xenevtchn_notify(xce: *mut XenEvtchnHandle, port: u32) -> libc::c_int;
+    fn xenevtchn_pending(xce: *mut XenEvtchnHandle) -> libc::c_int;
+    fn xenevtchn_unmask(xce: *mut XenEvtchnHandle, port: u32) -> libc::c_int;
+}
+
+#[link(name = "xengnttab")]
+extern "C" {
+    fn xengnttab_open(logger: *mut libc::c_void, flags: libc::c_uint) -> *mut XenGnttabHandle;
+    fn xengnttab_close(xgt: *mut XenGnttabHandle) -> libc::c_int;
+    fn xengnttab_map_grant_ref(xgt: *mut XenGnttabHandle,
+                               domid: u32,
+                               gref: u32,
+                               prot: libc::c_int)
+                               -> *mut libc::c_void;
+    fn xengnttab_unmap(xgt: *mut XenGnttabHandle,
+                       start: *mut libc::c_void,
+                       count: u32)
+                       -> libc::c_int;
+}
+
+/// Turn the -1 the libraries fail with into the error they left in errno
+fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Event channels as libxenevtchn provides them
+pub struct StubEventChannel {
+    handle: *mut XenEvtchnHandle,
+}
+
+// The handle is only used by the ring server thread that owns it.
+unsafe impl Send for StubEventChannel {}
+
+impl StubEventChannel {
+    pub fn open() -> io::Result<StubEventChannel> {
+        let handle = unsafe { xenevtchn_open(ptr::null_mut(), 0) };
+        if handle.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(StubEventChannel { handle: handle })
+    }
+
+    /// Wait up to `timeout` milliseconds for a port to be pending
+    fn wait(&self, timeout: libc::c_int) -> io::Result<bool> {
+        let mut fds = libc::pollfd {
+            fd: unsafe { xenevtchn_fd(self.handle) },
+            events: libc::POLLIN,
+            revents: 0,
+        };
+
+        let ret = try!(check(unsafe { libc::poll(&mut fds, 1, timeout) }));
+        Ok(ret > 0)
+    }
+}
+
+impl EventChannel for StubEventChannel {
+    fn bind_interdomain(&mut self,
+                        dom_id: wire::DomainId,
+                        port: EvtChnPort)
+                        -> io::Result<EvtChnPort> {
+        let local = unsafe { xenevtchn_bind_interdomain(self.handle, dom_id, port as u32) };
+        check(local).map(|local| local as EvtChnPort)
+    }
+
+    fn unbind(&mut self, port: EvtChnPort) -> io::Result<()> {
+        check(unsafe { xenevtchn_unbind(self.handle, port as u32) }).map(|_| ())
+    }
+
+    fn notify(&mut self, port: EvtChnPort) -> io::Result<()> {
+        check(unsafe { xenevtchn_notify(self.handle, port as u32) }).map(|_| ())
+    }
+
+    fn pending(&mut self, timeout: libc::c_int) -> io::Result<Vec<EvtChnPort>> {
+        // the library hands out one pending port at a time and blocks when
+        // there are none, so only ask while it says there are more
+        let mut ports = Vec::new();
+        let mut timeout = timeout;
+        while ports.len() < MAX_PENDING && try!(self.wait(timeout)) {
+            let port = try!(check(unsafe { xenevtchn_pending(self.handle) }));
+            try!(check(unsafe { xenevtchn_unmask(self.handle, port as u32) }));
+            ports.push(port as EvtChnPort);
+            timeout = 0;
+        }
+
+        Ok(ports)
+    }
+}
+
+impl Drop for StubEventChannel {
+    fn drop(&mut self) {
+        unsafe {
+            xenevtchn_close(self.handle);
+        }
+    }
+}
+
+// an open libxengnttab handle, closed once the last page mapped through it
+// is gone
+struct Gnttab(*mut XenGnttabHandle);
+
+unsafe impl Send for Gnttab {}
+unsafe impl Sync for Gnttab {}
+
+impl Drop for Gnttab {
+    fn drop(&mut self) {
+        unsafe {
+            xengnttab_close(self.0);
+        }
+    }
+}
+
+/// A guest's xenstore page mapped by its grant reference through
+/// libxengnttab
+pub struct StubPage {
+    gnttab: Arc<Gnttab>,
+    addr: *mut libc::c_void,
+}
+
+impl SharedPage for StubPage {
+    fn interface(&self) -> *mut Interface {
+        self.addr as *mut Interface
+    }
+}
+
+// The mapping is owned by a single connection and only ever touched by
+// the thread currently servicing it.
+unsafe impl Send for StubPage {}
+
+impl Drop for StubPage {
+    fn drop(&mut self) {
+        unsafe {
+            xengnttab_unmap(self.gnttab.0, self.addr, 1);
+        }
+    }
+}
+
+/// Maps guest pages by the grant reference set aside for xenstore, which
+/// is the only way a stub domain can get at them. The toolstack grants it
+/// for dom0 as well as for every guest.
+pub struct StubMapper {
+    gnttab: Arc<Gnttab>,
+}
+
+impl StubMapper {
+    pub fn open() -> io::Result<StubMapper> {
+        let handle = unsafe { xengnttab_open(ptr::null_mut(), 0) };
+        if handle.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(StubMapper { gnttab: Arc::new(Gnttab(handle)) })
+    }
+}
+
+impl Mapper for StubMapper {
+    fn map(&mut self, dom_id: wire::DomainId, _mfn: Mfn) -> io::Result<Box<SharedPage>> {
+        let addr = unsafe {
+            xengnttab_map_grant_ref(self.gnttab.0,
+                                    dom_id,
+                                    GNTTAB_RESERVED_XENSTORE,
+                                    libc::PROT_READ | libc::PROT_WRITE)
+        };
+        if addr.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Box::new(StubPage {
+                        gnttab: self.gnttab.clone(),
+                        addr: addr,
+                    }))
+    }
+}
@@ -0,0 +1,122 @@
+/**
+    xenstore-rs provides a Rust based xenstore implementation.
+    Copyright (C) 2016 Star Lab Corp.
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License along
+    with this program; if not, see <http://www.gnu.org/licenses/>.
+**/
+
+use libc;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+use super::ring::{Interface, RingServer, SharedPage};
+use super::super::message::{self, EvtChnPort};
+use super::super::store::DOM0_DOMAIN_ID;
+use super::super::system::System;
+
+const XENBUS_BACKEND_PATH: &'static str = "/dev/xen/xenbus_backend";
+const PAGE_SIZE: usize = 4096;
+
+/// `IOCTL_XENBUS_BACKEND_EVTCHN`, which is `_IOC(_IOC_NONE, 'B', 0, 0)`
+const IOCTL_XENBUS_BACKEND_EVTCHN: libc::c_ulong = (b'B' as libc::c_ulong) << 8;
+
+/// The kernel's own xenstore page, handed to us by the xenbus backend device
+pub struct BackendPage {
+    _file: File,
+    addr: *mut libc::c_void,
+}
+
+impl BackendPage {
+    /// Map the kernel's xenstore page, returning it along with the event
+    /// channel port the kernel expects to be notified on
+    pub fn open() -> io::Result<(BackendPage, EvtChnPort)> {
+        let file = try!(OpenOptions::new().read(true).write(true).open(XENBUS_BACKEND_PATH));
+
+        let port = try!(backend_port(&file));
+
+        let addr = unsafe {
+            libc::mmap(ptr::null_mut(),
+                       PAGE_SIZE,
+                       libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_SHARED,
+                       file.as_raw_fd(),
+                       0)
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok((BackendPage {
+                _file: file,
+                addr: addr,
+            },
+            port))
+    }
+}
+
+/// The event channel port the kernel expects to be notified on for its
+/// xenstore ring
+fn backend_port(file: &File) -> io::Result<EvtChnPort> {
+    let port = unsafe { libc::ioctl(file.as_raw_fd(), IOCTL_XENBUS_BACKEND_EVTCHN as _) };
+    if port < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(port as EvtChnPort)
+}
+
+impl SharedPage for BackendPage {
+    fn interface(&self) -> *mut Interface {
+        self.addr as *mut Interface
+    }
+}
+
+// The mapping is owned by the ring server thread servicing it.
+unsafe impl Send for BackendPage {}
+
+impl Drop for BackendPage {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.addr, PAGE_SIZE);
+        }
+    }
+}
+
+/// Serve the local kernel's xenbus requests from `server`
+pub fn attach(server: &mut RingServer) -> io::Result<()> {
+    let (page, port) = try!(BackendPage::open());
+    server.attach(DOM0_DOMAIN_ID, Box::new(page), port)
+}
+
+/// Check if the local kernel has a xenbus backend for us to serve, which is
+/// the case when we're running in dom0.
+pub fn available() -> bool {
+    Path::new(XENBUS_BACKEND_PATH).exists()
+}
+
+/// Introduce dom0 with the event channel its kernel uses for xenstore and
+/// give it its domain path, so that xenbus clients in dom0 work as soon as
+/// the ring transport picks it up.
+///
+/// The kernel's page is reached through the backend device rather than by
+/// frame number, so dom0 is introduced without one.
+pub fn introduce_dom0(system: &mut System) -> io::Result<bool> {
+    let file = try!(OpenOptions::new().read(true).write(true).open(XENBUS_BACKEND_PATH));
+    let port = try!(backend_port(&file));
+
+    let conn = system.new_connection(DOM0_DOMAIN_ID);
+    message::introduce_domain(system, conn, DOM0_DOMAIN_ID, 0, port)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+}
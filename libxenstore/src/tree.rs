@@ -0,0 +1,526 @@
+/**
+    xenstore-rs provides a Rust based xenstore implementation.
+    Copyright (C) 2016 Star Lab Corp.
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License along
+    with this program; if not, see <http://www.gnu.org/licenses/>.
+**/
+#[cfg(test)]
+extern crate quickcheck;
+
+use std::borrow::Borrow;
+use std::cmp::{self, Ordering};
+use std::fmt;
+use std::iter::FromIterator;
+use std::sync::Arc;
+
+type Link<K, V> = Option<Arc<Node<K, V>>>;
+
+struct Node<K, V> {
+    // shared between every version of the tree that holds it, so that
+    // rebuilding a node never copies the key or value
+    entry: Arc<(K, V)>,
+    height: usize,
+    left: Link<K, V>,
+    right: Link<K, V>,
+}
+
+/// The `Tree` type.
+///
+/// A persistent ordered map, built as an AVL tree whose nodes are shared
+/// between copies. Cloning a `Tree` is O(1) and changing one only rebuilds
+/// the O(log n) nodes along the path to the change, so the store and the
+/// changes made in transactions can be copied freely.
+pub struct Tree<K, V> {
+    root: Link<K, V>,
+    len: usize,
+}
+
+// copying a node only copies the links to its entry and children
+impl<K, V> Clone for Node<K, V> {
+    fn clone(&self) -> Node<K, V> {
+        Node {
+            entry: self.entry.clone(),
+            height: self.height,
+            left: self.left.clone(),
+            right: self.right.clone(),
+        }
+    }
+}
+
+fn height<K, V>(link: &Link<K, V>) -> usize {
+    link.as_ref().map_or(0, |node| node.height)
+}
+
+fn node<K, V>(entry: Arc<(K, V)>, left: Link<K, V>, right: Link<K, V>) -> Arc<Node<K, V>> {
+    Arc::new(Node {
+                 entry: entry,
+                 height: 1 + cmp::max(height(&left), height(&right)),
+                 left: left,
+                 right: right,
+             })
+}
+
+/// Build a node, rotating if one side has grown two taller than the other
+fn balance<K, V>(entry: Arc<(K, V)>, left: Link<K, V>, right: Link<K, V>) -> Arc<Node<K, V>> {
+    let (hl, hr) = (height(&left), height(&right));
+
+    if hl > hr + 1 {
+        let l = left.unwrap();
+        if height(&l.left) >= height(&l.right) {
+            node(l.entry.clone(),
+                 l.left.clone(),
+                 Some(node(entry, l.right.clone(), right)))
+        } else {
+            let lr = l.right.as_ref().unwrap();
+            node(lr.entry.clone(),
+                 Some(node(l.entry.clone(), l.left.clone(), lr.left.clone())),
+                 Some(node(entry, lr.right.clone(), right)))
+        }
+    } else if hr > hl + 1 {
+        let r = right.unwrap();
+        if height(&r.right) >= height(&r.left) {
+            node(r.entry.clone(),
+                 Some(node(entry, left, r.left.clone())),
+                 r.right.clone())
+        } else {
+            let rl = r.left.as_ref().unwrap();
+            node(rl.entry.clone(),
+                 Some(node(entry, left, rl.left.clone())),
+                 Some(node(r.entry.clone(), rl.right.clone(), r.right.clone())))
+        }
+    } else {
+        node(entry, left, right)
+    }
+}
+
+/// Insert `entry`, returning the new subtree and the entry it replaced
+fn insert<K: Ord, V>(link: &Link<K, V>,
+                     entry: Arc<(K, V)>)
+                     -> (Arc<Node<K, V>>, Option<Arc<(K, V)>>) {
+    let n = match *link {
+        Some(ref n) => n,
+        None => return (node(entry, None, None), None),
+    };
+
+    match entry.0.cmp(&n.entry.0) {
+        Ordering::Less => {
+            let (left, old) = insert(&n.left, entry);
+            (balance(n.entry.clone(), Some(left), n.right.clone()), old)
+        }
+        Ordering::Greater => {
+            let (right, old) = insert(&n.right, entry);
+            (balance(n.entry.clone(), n.left.clone(), Some(right)), old)
+        }
+        Ordering::Equal => {
+            (node(entry, n.left.clone(), n.right.clone()), Some(n.entry.clone()))
+        }
+    }
+}
+
+/// Take the smallest entry out of the subtree
+fn remove_min<K, V>(n: &Arc<Node<K, V>>) -> (Arc<(K, V)>, Link<K, V>) {
+    match n.left {
+        None => (n.entry.clone(), n.right.clone()),
+        Some(ref left) => {
+            let (min, left) = remove_min(left);
+            (min, Some(balance(n.entry.clone(), left, n.right.clone())))
+        }
+    }
+}
+
+/// Remove `key`, returning the new subtree and the removed entry if it was there
+fn remove<K, V, Q: ?Sized>(link: &Link<K, V>, key: &Q) -> Option<(Link<K, V>, Arc<(K, V)>)>
+    where K: Borrow<Q>,
+          Q: Ord
+{
+    let n = match *link {
+        Some(ref n) => n,
+        None => return None,
+    };
+
+    match key.cmp(n.entry.0.borrow()) {
+        Ordering::Less => {
+            remove(&n.left, key).map(|(left, old)| {
+                (Some(balance(n.entry.clone(), left, n.right.clone())), old)
+            })
+        }
+        Ordering::Greater => {
+            remove(&n.right, key).map(|(right, old)| {
+                (Some(balance(n.entry.clone(), n.left.clone(), right)), old)
+            })
+        }
+        Ordering::Equal => {
+            let rest = match (&n.left, &n.right) {
+                (&None, right) => right.clone(),
+                (left, &None) => left.clone(),
+                (left, &Some(ref right)) => {
+                    let (min, right) = remove_min(right);
+                    Some(balance(min, left.clone(), right))
+                }
+            };
+            Some((rest, n.entry.clone()))
+        }
+    }
+}
+
+impl<K: Ord, V> Tree<K, V> {
+    pub fn new() -> Tree<K, V> {
+        Tree { root: None, len: 0 }
+    }
+
+    /// The number of entries in the tree.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Look up the value stored for `key`.
+    pub fn get<Q: ?Sized + Ord>(&self, key: &Q) -> Option<&V>
+        where K: Borrow<Q>
+    {
+        let mut link = &self.root;
+        while let Some(ref n) = *link {
+            link = match key.cmp(n.entry.0.borrow()) {
+                Ordering::Less => &n.left,
+                Ordering::Greater => &n.right,
+                Ordering::Equal => return Some(&n.entry.1),
+            };
+        }
+        None
+    }
+
+    /// Look up the value stored for `key` to change it in place.
+    ///
+    /// The nodes on the way to it, and the entry itself, are copied first
+    /// only if copies of the tree share them, so those are left as they were.
+    pub fn get_mut<Q: ?Sized + Ord>(&mut self, key: &Q) -> Option<&mut V>
+        where K: Borrow<Q> + Clone,
+              V: Clone
+    {
+        let mut link = &mut self.root;
+        loop {
+            let n = match *link {
+                Some(ref mut n) => Arc::make_mut(n),
+                None => return None,
+            };
+            link = match key.cmp(n.entry.0.borrow()) {
+                Ordering::Less => &mut n.left,
+                Ordering::Greater => &mut n.right,
+                Ordering::Equal => return Some(&mut Arc::make_mut(&mut n.entry).1),
+            };
+        }
+    }
+
+    pub fn contains_key<Q: ?Sized + Ord>(&self, key: &Q) -> bool
+        where K: Borrow<Q>
+    {
+        self.get(key).is_some()
+    }
+
+    /// Store `value` for `key`, returning whether it replaced an existing value.
+    ///
+    /// Copies of the tree made before the insert are left as they were.
+    pub fn insert(&mut self, key: K, value: V) -> bool {
+        let (root, old) = insert(&self.root, Arc::new((key, value)));
+        self.root = Some(root);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old.is_some()
+    }
+
+    /// Remove `key`, returning whether it was in the tree.
+    ///
+    /// Copies of the tree made before the removal are left as they were.
+    pub fn remove<Q: ?Sized + Ord>(&mut self, key: &Q) -> bool
+        where K: Borrow<Q>
+    {
+        match remove(&self.root, key) {
+            Some((root, _)) => {
+                self.root = root;
+                self.len -= 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Iterate over the entries in key order.
+    pub fn iter(&self) -> Iter<K, V> {
+        let mut iter = Iter {
+            stack: Vec::new(),
+            remaining: self.len,
+        };
+        iter.push_left(&self.root);
+        iter
+    }
+
+    /// Iterate over the entries from `key` onwards in key order.
+    pub fn range_from(&self, key: &K) -> Range<K, V> {
+        let mut range = Range { stack: Vec::new() };
+        let mut link = &self.root;
+        while let Some(ref n) = *link {
+            if *key <= n.entry.0 {
+                range.stack.push(n);
+                link = &n.left;
+            } else {
+                link = &n.right;
+            }
+        }
+        range
+    }
+
+    /// Iterate over the keys in order.
+    pub fn keys(&self) -> Keys<K, V> {
+        Keys(self.iter())
+    }
+
+    /// Iterate over the values in key order.
+    pub fn values(&self) -> Values<K, V> {
+        Values(self.iter())
+    }
+}
+
+impl<K, V> Clone for Tree<K, V> {
+    fn clone(&self) -> Tree<K, V> {
+        Tree {
+            root: self.root.clone(),
+            len: self.len,
+        }
+    }
+}
+
+impl<K: Ord + fmt::Debug, V: fmt::Debug> fmt::Debug for Tree<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<K: Ord, V> Default for Tree<K, V> {
+    fn default() -> Tree<K, V> {
+        Tree::new()
+    }
+}
+
+impl<'a, K: Ord, V> IntoIterator for &'a Tree<K, V> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Iter<'a, K, V> {
+        self.iter()
+    }
+}
+
+impl<K: Ord, V> FromIterator<(K, V)> for Tree<K, V> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Tree<K, V> {
+        let mut tree = Tree::new();
+        tree.extend(iter);
+        tree
+    }
+}
+
+impl<K: Ord, V> Extend<(K, V)> for Tree<K, V> {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        for (key, value) in iter {
+            self.insert(key, value);
+        }
+    }
+}
+
+/// An iterator over the entries of a `Tree` in key order
+pub struct Iter<'a, K: 'a, V: 'a> {
+    stack: Vec<&'a Node<K, V>>,
+    remaining: usize,
+}
+
+impl<'a, K, V> Iter<'a, K, V> {
+    fn push_left(&mut self, mut link: &'a Link<K, V>) {
+        while let Some(ref n) = *link {
+            self.stack.push(n);
+            link = &n.left;
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        self.stack.pop().map(|n| {
+            self.push_left(&n.right);
+            self.remaining -= 1;
+            (&n.entry.0, &n.entry.1)
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for Iter<'a, K, V> {}
+
+/// An iterator over the entries of a `Tree` from a given key onwards
+pub struct Range<'a, K: 'a, V: 'a> {
+    stack: Vec<&'a Node<K, V>>,
+}
+
+impl<'a, K, V> Iterator for Range<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        self.stack.pop().map(|n| {
+            let mut link = &n.right;
+            while let Some(ref right) = *link {
+                self.stack.push(right);
+                link = &right.left;
+            }
+            (&n.entry.0, &n.entry.1)
+        })
+    }
+}
+
+/// An iterator over the keys of a `Tree` in order
+pub struct Keys<'a, K: 'a, V: 'a>(Iter<'a, K, V>);
+
+impl<'a, K, V> Iterator for Keys<'a, K, V> {
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<&'a K> {
+        self.0.next().map(|(key, _)| key)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for Keys<'a, K, V> {}
+
+/// An iterator over the values of a `Tree` in key order
+pub struct Values<'a, K: 'a, V: 'a>(Iter<'a, K, V>);
+
+impl<'a, K, V> Iterator for Values<'a, K, V> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<&'a V> {
+        self.0.next().map(|(_, value)| value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for Values<'a, K, V> {}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use super::*;
+    use super::quickcheck::quickcheck;
+
+    /// Check the AVL invariants and that the cached heights are right
+    fn check<K: Ord, V>(link: &Link<K, V>) -> usize {
+        match *link {
+            None => 0,
+            Some(ref n) => {
+                let (hl, hr) = (check(&n.left), check(&n.right));
+                assert!(hl <= hr + 1 && hr <= hl + 1);
+                assert_eq!(n.height, 1 + cmp::max(hl, hr));
+                n.height
+            }
+        }
+    }
+
+    #[test]
+    fn insert_get_remove() {
+        let mut tree = Tree::new();
+        assert!(tree.is_empty());
+
+        assert!(!tree.insert(2, "two"));
+        assert!(!tree.insert(1, "one"));
+        assert!(tree.insert(2, "deux"));
+        assert_eq!(tree.len(), 2);
+        assert_eq!(tree.get(&2), Some(&"deux"));
+        assert_eq!(tree.get(&3), None);
+
+        assert!(tree.remove(&1));
+        assert!(!tree.remove(&1));
+        assert_eq!(tree.len(), 1);
+        assert!(!tree.contains_key(&1));
+    }
+
+    #[test]
+    fn clones_are_independent() {
+        let mut tree = (0..100).map(|i| (i, i)).collect::<Tree<_, _>>();
+        let before = tree.clone();
+
+        tree.insert(100, 100);
+        tree.remove(&0);
+        tree.insert(50, 0);
+
+        assert_eq!(before.len(), 100);
+        assert_eq!(before.get(&0), Some(&0));
+        assert_eq!(before.get(&50), Some(&50));
+        assert_eq!(before.get(&100), None);
+        assert_eq!(tree.get(&50), Some(&0));
+    }
+
+    #[test]
+    fn get_mut_leaves_clones_alone() {
+        let mut tree = (0..100).map(|i| (i, vec![i])).collect::<Tree<_, _>>();
+        let before = tree.clone();
+
+        tree.get_mut(&50).unwrap().push(0);
+        assert!(tree.get_mut(&100).is_none());
+
+        assert_eq!(tree.get(&50), Some(&vec![50, 0]));
+        assert_eq!(before.get(&50), Some(&vec![50]));
+        check(&tree.root);
+    }
+
+    #[test]
+    fn ordered_iteration() {
+        let tree = vec![(3, 'c'), (1, 'a'), (2, 'b')].into_iter().collect::<Tree<_, _>>();
+
+        assert_eq!(tree.keys().cloned().collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(tree.values().cloned().collect::<Vec<_>>(), vec!['a', 'b', 'c']);
+        assert_eq!(tree.iter().len(), 3);
+    }
+
+    #[test]
+    fn matches_btreemap() {
+        fn prop(ops: Vec<(bool, u8)>, from: u8) -> bool {
+            let mut tree = Tree::new();
+            let mut map = BTreeMap::new();
+
+            for (add, key) in ops {
+                if add {
+                    assert_eq!(tree.insert(key, ()), map.insert(key, ()).is_some());
+                } else {
+                    assert_eq!(tree.remove(&key), map.remove(&key).is_some());
+                }
+                check(&tree.root);
+            }
+
+            tree.len() == map.len() && tree.keys().eq(map.keys()) &&
+            tree.range_from(&from).map(|(key, _)| key).eq(map.keys().filter(|key| **key >= from))
+        }
+        quickcheck(prop as fn(Vec<(bool, u8)>, u8) -> bool);
+    }
+}
@@ -16,16 +16,113 @@
     with this program; if not, see <http://www.gnu.org/licenses/>.
 **/
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::collections::hash_set::Iter;
+use std::iter::FromIterator;
+use std::slice;
+use std::sync::Arc;
+use std::vec;
+use super::authz::{Authorizer, PermissionAuthorizer};
 use super::error::{Error, Result};
-use super::path::Path;
+use super::path::{self, Path};
+use super::quota::Quota;
 use super::store::{self, AppliedChange};
+use super::tree::Tree;
 use super::wire;
 use super::connection::ConnId;
 
+/// Whether `name` matches `glob`, where `*` stands for any run of
+/// characters
+///
+/// Only the last `*` seen is ever backtracked to, as whatever an earlier one
+/// could take the later one can take as well, so this takes no more than
+/// the product of the two lengths.
+fn glob_matches(glob: &[u8], name: &[u8]) -> bool {
+    let (mut g, mut n) = (0, 0);
+    // where the glob carries on after the last `*`, and where the name
+    // carries on after what that `*` has taken so far
+    let mut star = None;
+
+    while n < name.len() {
+        if g < glob.len() && glob[g] == b'*' {
+            star = Some((g + 1, n));
+            g += 1;
+        } else if g < glob.len() && glob[g] == name[n] {
+            g += 1;
+            n += 1;
+        } else if let Some((after, taken)) = star {
+            star = Some((after, taken + 1));
+            g = after;
+            n = taken + 1;
+        } else {
+            return false;
+        }
+    }
+
+    glob[g..].iter().all(|&c| c == b'*')
+}
+
+/// The components of a path, `/` having none
+fn components(bytes: &[u8]) -> Vec<&[u8]> {
+    if bytes == b"/" {
+        return Vec::new();
+    }
+    bytes.split(|b| *b == b'/').skip(1).collect()
+}
+
+/// A watch path whose components may be globs, where `*` matches any run of
+/// characters within a single component. `/local/domain/*/device` matches
+/// the device directory of every domain.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Pattern(String);
+
+impl Pattern {
+    fn try_from(dom_id: wire::DomainId, s: &str) -> Result<Pattern> {
+        // with something in place of the globs it has to be a valid path
+        try!(Path::try_from(dom_id, &s.replace('*', "_")));
+
+        if s.starts_with('/') {
+            Ok(Pattern(s.to_owned()))
+        } else {
+            let domain_path = path::get_domain_path(dom_id);
+            Ok(Pattern(format!("{}/{}", String::from_utf8_lossy(domain_path.as_bytes()), s)))
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+
+    /// The path at or above `path` that this matches, if there is one.
+    pub fn matched(&self, path: &Path) -> Option<Path> {
+        let globs = components(self.as_bytes());
+        let names = components(path.as_bytes());
+        if names.len() < globs.len() ||
+           !globs.iter().zip(&names).all(|(glob, name)| glob_matches(glob, name)) {
+            return None;
+        }
+
+        // a path's parents start with the path itself
+        path.clone().into_iter().nth(names.len() - globs.len())
+    }
+
+    /// Whether `path` is above a path this might match, so removing it
+    /// would take any such path with it.
+    pub fn is_below(&self, path: &Path) -> bool {
+        let globs = components(self.as_bytes());
+        let names = components(path.as_bytes());
+        names.len() < globs.len() &&
+        names.iter().zip(&globs).all(|(name, glob)| glob_matches(glob, name))
+    }
+}
+
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum WPath {
     Normal(Path),
+    /// Paths matching a pattern, which only dom0 may watch and only when
+    /// wildcard watches are turned on
+    Wildcard(Pattern),
     IntroduceDomain,
     ReleaseDomain,
 }
@@ -35,67 +132,417 @@ impl WPath {
         match s {
             "@introduceDomain" => Ok(WPath::IntroduceDomain),
             "@releaseDomain" => Ok(WPath::ReleaseDomain),
+            _ if s.contains('*') => Pattern::try_from(dom_id, s).map(WPath::Wildcard),
             _ => Path::try_from(dom_id, s).map(WPath::Normal),
         }
     }
 
+    /// Check if this is one of the special paths that report domains coming
+    /// and going rather than changes to the store.
+    pub fn is_special(&self) -> bool {
+        match *self {
+            WPath::Normal(_) | WPath::Wildcard(_) => false,
+            WPath::IntroduceDomain | WPath::ReleaseDomain => true,
+        }
+    }
+
     pub fn as_bytes(&self) -> &[u8] {
         match *self {
             WPath::Normal(ref path) => path.as_bytes(),
+            WPath::Wildcard(ref pattern) => pattern.as_bytes(),
             WPath::IntroduceDomain => "@introduceDomain".as_bytes(),
             WPath::ReleaseDomain => "@releaseDomain".as_bytes(),
         }
     }
+
+    /// The path as `dom_id` sees it, without its domain path in front
+    pub fn as_relative_bytes(&self, dom_id: wire::DomainId) -> &[u8] {
+        let bytes = self.as_bytes();
+        let domain_path = path::get_domain_path(dom_id);
+        let prefix = domain_path.as_bytes();
+        if bytes.len() > prefix.len() && bytes.starts_with(prefix) && bytes[prefix.len()] == b'/' {
+            &bytes[prefix.len() + 1..]
+        } else {
+            bytes
+        }
+    }
 }
 
+/// A watch token is opaque to the daemon, it is only handed back to the
+/// client in the watch's events.
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct WToken(Vec<u8>);
+
+impl WToken {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl<'a> From<&'a [u8]> for WToken {
+    fn from(bytes: &'a [u8]) -> WToken {
+        WToken(bytes.to_owned())
+    }
+}
+
+impl<'a> From<&'a str> for WToken {
+    fn from(s: &'a str) -> WToken {
+        WToken(s.as_bytes().to_owned())
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct Watch {
     pub conn: ConnId,
     pub node: WPath,
-    pub token: WPath,
+    pub token: WToken,
+    /// the client gave a path relative to its domain path, so its events
+    /// are reported the same way
+    pub relative: bool,
+    /// the domain an @introduceDomain or @releaseDomain event is about,
+    /// when watchers are told
+    pub domain: Option<wire::DomainId>,
+}
+
+// a watch is the same whichever way its path was given, so `relative`
+// plays no part in finding it again
+impl PartialEq for Watch {
+    fn eq(&self, other: &Watch) -> bool {
+        self.conn == other.conn && self.node == other.node && self.token == other.token &&
+        self.domain == other.domain
+    }
+}
+
+impl Eq for Watch {}
+
+impl Hash for Watch {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.conn.hash(state);
+        self.node.hash(state);
+        self.token.hash(state);
+        self.domain.hash(state);
+    }
 }
 
 impl Watch {
-    pub fn new(conn: ConnId, node: WPath, token: WPath) -> Watch {
+    pub fn new(conn: ConnId, node: WPath, token: WToken) -> Watch {
         Watch {
             conn: conn,
             node: node,
             token: token,
+            relative: false,
+            domain: None,
         }
     }
 
-    pub fn matches(&self, change: &AppliedChange) -> bool {
+    /// The path an event for `change` names, if it fires this watch.
+    ///
+    /// A watch on a path fires for changes to that path and to anything
+    /// beneath it, its event naming the node that changed, and when a subtree
+    /// holding the path is removed, its event naming the watched path. A
+    /// wildcard watch does the same for every path its pattern matches, and
+    /// its event names the path that matched, or the removed path above it.
+    /// A node whose list of children changed is left to the event for the
+    /// child. Only changes that `authorizer` lets the watcher read, acting for
+    /// `target` if it has one, are seen.
+    pub fn fired_by(&self,
+                    change: &AppliedChange,
+                    authorizer: &Authorizer,
+                    target: Option<wire::DomainId>)
+                    -> Option<WPath> {
+        let readable = || change.perms_ok(authorizer, self.conn.dom_id, target, store::Perm::Read);
         match (change, &self.node) {
-            (&AppliedChange::Write(ref cpath, _), &WPath::Normal(ref wpath)) => {
-                cpath == wpath && change.perms_ok(self.conn.dom_id, store::Perm::Read)
+            (&AppliedChange::Write(_, _, store::Written::ChildrenChanged), _) => None,
+            (&AppliedChange::RemoveSubtree(ref cpath, _), &WPath::Normal(ref wpath))
+                if wpath.is_child(cpath) && readable() => Some(self.node.clone()),
+            (&AppliedChange::Write(ref cpath, _, _), &WPath::Normal(ref wpath)) |
+            (&AppliedChange::Remove(ref cpath, _), &WPath::Normal(ref wpath)) |
+            (&AppliedChange::RemoveSubtree(ref cpath, _), &WPath::Normal(ref wpath))
+                if cpath.is_child(wpath) && readable() => Some(WPath::Normal(cpath.clone())),
+            (&AppliedChange::RemoveSubtree(ref cpath, _), &WPath::Wildcard(ref pattern)) => {
+                match pattern.matched(cpath) {
+                    Some(matched) if readable() => Some(WPath::Normal(matched)),
+                    None if pattern.is_below(cpath) && readable() => {
+                        Some(WPath::Normal(cpath.clone()))
+                    }
+                    _ => None,
+                }
+            }
+            (&AppliedChange::Write(ref cpath, _, _), &WPath::Wildcard(ref pattern)) |
+            (&AppliedChange::Remove(ref cpath, _), &WPath::Wildcard(ref pattern)) => {
+                match pattern.matched(cpath) {
+                    Some(matched) if readable() => Some(WPath::Normal(matched)),
+                    _ => None,
+                }
             }
-            (&AppliedChange::IntroduceDomain, &WPath::IntroduceDomain) => true,
-            (&AppliedChange::ReleaseDomain, &WPath::ReleaseDomain) => true,
-            (_, _) => false,
+            (&AppliedChange::IntroduceDomain(_), &WPath::IntroduceDomain) |
+            (&AppliedChange::ReleaseDomain(_), &WPath::ReleaseDomain) => Some(self.node.clone()),
+            (_, _) => None,
+        }
+    }
+}
+
+/// The `Events` type.
+///
+/// Fired watches waiting to be delivered, in the order they fired. A watch
+/// is only told once about each path in a batch of changes, so an event
+/// that fires again keeps its first place.
+#[derive(Clone, Debug, Default)]
+pub struct Events {
+    events: Vec<Watch>,
+    fired: HashSet<Watch>,
+}
+
+impl Events {
+    pub fn new() -> Events {
+        Events::default()
+    }
+
+    /// Queue the event `watch` unless the same one is already queued.
+    pub fn push(&mut self, watch: Watch) {
+        if self.fired.insert(watch.clone()) {
+            self.events.push(watch);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    pub fn contains(&self, watch: &Watch) -> bool {
+        self.fired.contains(watch)
+    }
+
+    /// Iterate over the events in the order they fired.
+    pub fn iter(&self) -> slice::Iter<Watch> {
+        self.events.iter()
+    }
+}
+
+impl Extend<Watch> for Events {
+    fn extend<I: IntoIterator<Item = Watch>>(&mut self, iter: I) {
+        for watch in iter {
+            self.push(watch);
         }
     }
 }
 
+impl FromIterator<Watch> for Events {
+    fn from_iter<I: IntoIterator<Item = Watch>>(iter: I) -> Events {
+        let mut events = Events::new();
+        events.extend(iter);
+        events
+    }
+}
+
+impl IntoIterator for Events {
+    type Item = Watch;
+    type IntoIter = vec::IntoIter<Watch>;
+
+    fn into_iter(self) -> vec::IntoIter<Watch> {
+        self.events.into_iter()
+    }
+}
+
 pub struct WatchList {
     watches: HashSet<Watch>,
+    // the watches on each path, so a change only looks at the watches on
+    // its own path and the paths above it
+    by_path: Tree<Path, HashSet<Watch>>,
+    // the watches on @introduceDomain and @releaseDomain
+    special: HashSet<Watch>,
+    // the wildcard watches, which every change to the store has to look at
+    wildcards: HashSet<Watch>,
+    // how many watches each domain has, the one count both its quota and
+    // the usage reported for it go by
+    counts: HashMap<wire::DomainId, usize>,
+    quota: Quota,
+    // tell watchers which domain a domain event is about
+    domain_ids: bool,
+    // let dom0 register wildcard watches
+    allow_wildcards: bool,
+    // who may see which changes, the same as who may read them from the store
+    authorizer: Arc<Authorizer>,
+    // the domain each connection acts for, which it may see changes for too
+    targets: HashMap<ConnId, wire::DomainId>,
 }
 
 impl WatchList {
     pub fn new() -> WatchList {
-        WatchList { watches: HashSet::new() }
+        WatchList::with_quota(Quota::new())
+    }
+
+    /// Create a new `WatchList` limiting unprivileged domains to `quota`.
+    pub fn with_quota(quota: Quota) -> WatchList {
+        WatchList {
+            watches: HashSet::new(),
+            by_path: Tree::new(),
+            special: HashSet::new(),
+            wildcards: HashSet::new(),
+            counts: HashMap::new(),
+            quota: quota,
+            domain_ids: false,
+            allow_wildcards: false,
+            authorizer: Arc::new(PermissionAuthorizer),
+            targets: HashMap::new(),
+        }
+    }
+
+    pub fn quota(&self) -> &Quota {
+        &self.quota
+    }
+
+    /// Choose whether @introduceDomain and @releaseDomain events carry the
+    /// id of the domain they are about.
+    ///
+    /// Without the id every such event for a watch looks the same, so ones
+    /// that fire together are merged into one.
+    pub fn set_domain_ids(&mut self, domain_ids: bool) {
+        self.domain_ids = domain_ids;
+    }
+
+    /// Only tell watchers about the changes `authorizer` lets them read.
+    ///
+    /// `System` hands over the store's authorizer, so that watches and
+    /// reads agree.
+    pub fn set_authorizer(&mut self, authorizer: Arc<Authorizer>) {
+        self.authorizer = authorizer;
+    }
+
+    /// Let the watches of each connection in `targets` see the changes the
+    /// domain it acts for can read, as `System` keeps track of them.
+    pub fn set_targets(&mut self, targets: HashMap<ConnId, wire::DomainId>) {
+        self.targets = targets;
+    }
+
+    /// Choose whether dom0 may register wildcard watches.
+    ///
+    /// Wildcard watches already registered stay put when they are turned
+    /// off, only new ones are refused.
+    pub fn set_wildcards(&mut self, wildcards: bool) {
+        self.allow_wildcards = wildcards;
+    }
+
+    /// Register a watch, returning it so the caller can queue the initial
+    /// event that the protocol requires for every new watch.
+    pub fn watch(&mut self, conn: ConnId, node: WPath, token: WToken) -> Result<Watch> {
+        self.add(Watch::new(conn, node, token))
     }
 
-    pub fn watch(&mut self, conn: ConnId, node: WPath, token: WPath) -> Result<()> {
-        if !self.watches.insert(Watch::new(conn, node.clone(), token)) {
+    /// Register a watch built by the caller, such as one on a relative path.
+    ///
+    /// Only dom0 may watch the special paths, guests aren't told when other
+    /// domains come and go. Only dom0 may register wildcard watches too, and
+    /// only once they have been turned on with `set_wildcards`.
+    pub fn add(&mut self, watch: Watch) -> Result<Watch> {
+        let conn = watch.conn;
+        if let WPath::Wildcard(_) = watch.node {
+            if !self.allow_wildcards {
+                return Err(Error::EINVAL(format!("wildcard watches are turned off")));
+            }
+        }
+        let dom0_only = match watch.node {
+            WPath::Normal(_) => false,
+            _ => true,
+        };
+        if dom0_only && conn.dom_id != store::DOM0_DOMAIN_ID {
+            return Err(Error::EACCES(format!("domain {} may not watch {:?}",
+                                             conn.dom_id,
+                                             watch.node)));
+        }
+
+        try!(self.quota.check_watches(conn.dom_id, self.count(conn.dom_id)));
+
+        if !self.watches.insert(watch.clone()) {
             return Err(Error::EEXIST(format!("watch {:?} already exists for connection {:?}",
-                                             node,
+                                             watch.node,
                                              conn)));
         }
-        Ok(())
+        *self.counts.entry(conn.dom_id).or_insert(0) += 1;
+        self.index(&watch);
+        Ok(watch)
+    }
+
+    /// The number of watches `dom_id` has registered.
+    pub fn count(&self, dom_id: wire::DomainId) -> usize {
+        self.counts.get(&dom_id).cloned().unwrap_or(0)
+    }
+
+    /// How many watches each domain that has any has registered.
+    pub fn count_by_domain(&self) -> Vec<(wire::DomainId, usize)> {
+        self.counts.iter().map(|(dom_id, count)| (*dom_id, *count)).collect()
+    }
+
+    fn index(&mut self, watch: &Watch) {
+        match watch.node {
+            WPath::Normal(ref path) => {
+                if let Some(watches) = self.by_path.get_mut(path) {
+                    watches.insert(watch.clone());
+                    return;
+                }
+                let mut watches = HashSet::new();
+                watches.insert(watch.clone());
+                self.by_path.insert(path.clone(), watches);
+            }
+            WPath::Wildcard(_) => {
+                self.wildcards.insert(watch.clone());
+            }
+            _ => {
+                self.special.insert(watch.clone());
+            }
+        }
+    }
+
+    fn unindex(&mut self, watch: &Watch) {
+        match watch.node {
+            WPath::Normal(ref path) => {
+                let emptied = match self.by_path.get_mut(path) {
+                    Some(watches) => {
+                        watches.remove(watch);
+                        watches.is_empty()
+                    }
+                    None => false,
+                };
+                if emptied {
+                    self.by_path.remove(path);
+                }
+            }
+            WPath::Wildcard(_) => {
+                self.wildcards.remove(watch);
+            }
+            _ => {
+                self.special.remove(watch);
+            }
+        }
+    }
+
+    fn remove(&mut self, watch: &Watch) -> bool {
+        if !self.watches.remove(watch) {
+            return false;
+        }
+        self.unindex(watch);
+
+        let dom_id = watch.conn.dom_id;
+        let left = self.count(dom_id) - 1;
+        if left == 0 {
+            self.counts.remove(&dom_id);
+        } else {
+            self.counts.insert(dom_id, left);
+        }
+        true
+    }
+
+    /// Iterate over every registered watch.
+    pub fn iter(&self) -> Iter<Watch> {
+        self.watches.iter()
     }
 
-    pub fn unwatch(&mut self, conn: ConnId, node: WPath, token: WPath) -> Result<()> {
-        if !self.watches.remove(&Watch::new(conn, node.clone(), token)) {
+    pub fn unwatch(&mut self, conn: ConnId, node: WPath, token: WToken) -> Result<()> {
+        if !self.remove(&Watch::new(conn, node.clone(), token)) {
             return Err(Error::ENOENT(format!("watch {:?} did not exist for connection {:?}",
                                              node,
                                              conn)));
@@ -110,24 +557,84 @@ impl WatchList {
             .cloned()
             .collect::<Vec<Watch>>();
         for watch in to_remove {
-            self.watches.remove(&watch);
+            self.remove(&watch);
         }
         Ok(())
     }
 
-    pub fn fire_single(&self, single: &AppliedChange) -> HashSet<Watch> {
-        self.watches
+    pub fn reset_domain(&mut self, dom_id: wire::DomainId) -> Result<()> {
+        let to_remove = self.watches
             .iter()
-            .filter(|watch| watch.matches(single))
+            .filter(|watch| watch.conn.dom_id == dom_id)
             .cloned()
-            .collect::<HashSet<Watch>>()
+            .collect::<Vec<Watch>>();
+        for watch in to_remove {
+            self.remove(&watch);
+        }
+        Ok(())
     }
 
-    pub fn fire(&self, applied_changes: Option<Vec<AppliedChange>>) -> HashSet<Watch> {
-        if let Some(changes) = applied_changes {
-            changes.iter().flat_map(|change| self.fire_single(&change)).collect::<HashSet<Watch>>()
+    /// The watches that `change` might fire: those on its path or above it,
+    /// for a removed subtree those below it too, and the wildcard watches.
+    fn candidates(&self, change: &AppliedChange) -> Vec<&Watch> {
+        let path = match change.path() {
+            Some(path) => path,
+            None => return self.special.iter().collect(),
+        };
+
+        let mut candidates = path.clone()
+            .into_iter()
+            .filter_map(|parent| self.by_path.get(&parent))
+            .flat_map(|watches| watches.iter())
+            .chain(self.wildcards.iter())
+            .collect::<Vec<_>>();
+
+        if let AppliedChange::RemoveSubtree(_, _) = *change {
+            // everything below a path sorts directly after it
+            candidates.extend(self.by_path
+                                  .range_from(path)
+                                  .skip_while(|&(watched, _)| watched == path)
+                                  .take_while(|&(watched, _)| watched.is_child(path))
+                                  .flat_map(|(_, watches)| watches.iter()));
+        }
+        candidates
+    }
+
+    /// The watches `single` fires, ordered by the path their events name.
+    pub fn fire_single(&self, single: &AppliedChange) -> Events {
+        let domain = match *single {
+            AppliedChange::IntroduceDomain(dom_id) |
+            AppliedChange::ReleaseDomain(dom_id) if self.domain_ids => Some(dom_id),
+            _ => None,
+        };
+
+        let mut fired = self.candidates(single)
+            .into_iter()
+            .filter_map(|watch| {
+                let target = self.targets.get(&watch.conn).cloned();
+                watch.fired_by(single, &*self.authorizer, target).map(|node| {
+                    Watch {
+                        node: node,
+                        domain: domain,
+                        ..watch.clone()
+                    }
+                })
+            })
+            .collect::<Vec<Watch>>();
+        fired.sort_by(|a, b| {
+            (a.node.as_bytes(), a.token.as_bytes()).cmp(&(b.node.as_bytes(), b.token.as_bytes()))
+        });
+        fired.into_iter().collect()
+    }
+
+    /// The watches a batch of changes fires, in the path order of the changes,
+    /// each once for every path its events name.
+    pub fn fire(&self, applied_changes: Option<Vec<AppliedChange>>) -> Events {
+        if let Some(mut changes) = applied_changes {
+            changes.sort_by(|a, b| a.path().cmp(&b.path()));
+            changes.iter().flat_map(|change| self.fire_single(change)).collect()
         } else {
-            HashSet::new()
+            Events::new()
         }
     }
 }
@@ -139,7 +646,8 @@ mod test {
     use super::super::connection::ConnId;
     use self::mio::Token;
     use super::super::path::Path;
-    use super::super::store::{self, Value, DOM0_DOMAIN_ID, Store, AppliedChange, ChangeSet};
+    use super::super::store::{self, Value, DOM0_DOMAIN_ID, Store, AppliedChange, ChangeSet,
+                              Written};
     use super::*;
 
     #[test]
@@ -151,7 +659,7 @@ mod test {
 
         watch_list.watch(ConnId::new(Token(DOM0_DOMAIN_ID as usize), DOM0_DOMAIN_ID),
                          WPath::Normal(path.clone()),
-                         WPath::Normal(path.clone()))
+                         WToken::from("token"))
             .unwrap();
 
         let changes = store.write(&ChangeSet::new(&store),
@@ -160,7 +668,7 @@ mod test {
                                   value.clone())
             .unwrap();
 
-        let applied = store.apply(changes);
+        let applied = store.apply(changes).ok();
         let watches = watch_list.fire(applied);
 
         assert_eq!(watches.len(), 1);
@@ -168,11 +676,134 @@ mod test {
                                          conn: ConnId::new(Token(DOM0_DOMAIN_ID as usize),
                                                            DOM0_DOMAIN_ID),
                                          node: WPath::Normal(path.clone()),
-                                         token: WPath::Normal(path),
+                                         token: WToken::from("token"),
+                                         relative: false,
+                                         domain: None,
                                      }),
                    true);
     }
 
+    #[test]
+    fn watch_below_removed_subtree() {
+        let mut watch_list = WatchList::new();
+        let mut store = Store::new();
+        let path = Path::try_from(DOM0_DOMAIN_ID, "/root/file/path").unwrap();
+        let other = Path::try_from(DOM0_DOMAIN_ID, "/other").unwrap();
+        let conn = ConnId::new(Token(DOM0_DOMAIN_ID as usize), DOM0_DOMAIN_ID);
+
+        watch_list.watch(conn, WPath::Normal(path.clone()), WToken::from("token"))
+            .unwrap();
+        watch_list.watch(conn, WPath::Normal(other.clone()), WToken::from("token"))
+            .unwrap();
+
+        let changes = store.write(&ChangeSet::new(&store),
+                                  DOM0_DOMAIN_ID,
+                                  path.clone(),
+                                  Value::from("value"))
+            .unwrap();
+        store.apply(changes).unwrap();
+
+        // removing /root fires the watch on the path below it
+        let changes = store.rm(&ChangeSet::new(&store),
+                               DOM0_DOMAIN_ID,
+                               &Path::try_from(DOM0_DOMAIN_ID, "/root").unwrap())
+            .unwrap();
+        let watches = watch_list.fire(store.apply(changes).ok());
+
+        assert_eq!(watches.len(), 1);
+        assert!(watches.contains(&Watch::new(conn,
+                                             WPath::Normal(path.clone()),
+                                             WToken::from("token"))));
+    }
+
+    #[test]
+    fn events_in_path_order() {
+        let mut watch_list = WatchList::new();
+        let mut store = Store::new();
+        let conn = ConnId::new(Token(DOM0_DOMAIN_ID as usize), DOM0_DOMAIN_ID);
+
+        for &(path, token) in &[("/b", "b"), ("/", "root"), ("/a", "a")] {
+            let path = Path::try_from(DOM0_DOMAIN_ID, path).unwrap();
+            watch_list.watch(conn, WPath::Normal(path.clone()), WToken::from(token)).unwrap();
+            if token != "root" {
+                let changes = store.mkdir(&ChangeSet::new(&store), DOM0_DOMAIN_ID, path).unwrap();
+                store.apply(changes).unwrap();
+            }
+        }
+
+        // several changes below each watch, made out of order
+        let mut changes = ChangeSet::new(&store);
+        for path in &["/b/y", "/a/x", "/b/x", "/a/y"] {
+            let path = Path::try_from(DOM0_DOMAIN_ID, path).unwrap();
+            changes = store.write(&changes, DOM0_DOMAIN_ID, path, Value::from("value")).unwrap();
+        }
+        let watches = watch_list.fire(store.apply(changes).ok());
+
+        // each names the node that changed
+        let fired = watches.iter()
+            .map(|watch| {
+                     (String::from_utf8(watch.node.as_bytes().to_vec()).unwrap(),
+                      String::from_utf8(watch.token.as_bytes().to_vec()).unwrap())
+                 })
+            .collect::<Vec<_>>();
+        let expected = vec![("/a/x", "a"),
+                            ("/a/x", "root"),
+                            ("/a/y", "a"),
+                            ("/a/y", "root"),
+                            ("/b/x", "b"),
+                            ("/b/x", "root"),
+                            ("/b/y", "b"),
+                            ("/b/y", "root")];
+        assert_eq!(fired,
+                   expected.into_iter()
+                       .map(|(path, token)| (path.to_owned(), token.to_owned()))
+                       .collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn unwatched_watches_stop_firing() {
+        let mut watch_list = WatchList::new();
+        let conn = ConnId::new(Token(DOM0_DOMAIN_ID as usize), DOM0_DOMAIN_ID);
+        let path = Path::try_from(DOM0_DOMAIN_ID, "/a/b").unwrap();
+        let change = || {
+            let written = Written::Created(Value::new());
+            Some(vec![AppliedChange::Write(path.push("c"), Vec::new(), written)])
+        };
+
+        for token in &["one", "two"] {
+            watch_list.watch(conn, WPath::Normal(path.clone()), WToken::from(*token)).unwrap();
+        }
+        assert_eq!(watch_list.fire(change()).len(), 2);
+
+        watch_list.unwatch(conn, WPath::Normal(path.clone()), WToken::from("one")).unwrap();
+        assert_eq!(watch_list.fire(change()).len(), 1);
+
+        watch_list.reset(conn).unwrap();
+        assert_eq!(watch_list.fire(change()).len(), 0);
+    }
+
+    #[test]
+    fn basic_watch_returns_initial_event() {
+        let mut watch_list = WatchList::new();
+        let path = Path::try_from(DOM0_DOMAIN_ID, "/root/file/path").unwrap();
+        let conn = ConnId::new(Token(DOM0_DOMAIN_ID as usize), DOM0_DOMAIN_ID);
+
+        let watch = watch_list.watch(conn,
+                                     WPath::Normal(path.clone()),
+                                     WToken::from("token"))
+            .unwrap();
+
+        assert_eq!(watch,
+                   Watch::new(conn, WPath::Normal(path.clone()), WToken::from("token")));
+
+        // registering the same watch again does not fire anything
+        match watch_list.watch(conn, WPath::Normal(path.clone()), WToken::from("token")) {
+            Err(Error::EEXIST(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "registered the same watch twice"),
+        }
+    }
+
     #[test]
     fn basic_watch_no_permission() {
         let mut watch_list = WatchList::new();
@@ -182,11 +813,11 @@ mod test {
 
         watch_list.watch(ConnId::new(Token(DOM0_DOMAIN_ID as usize), DOM0_DOMAIN_ID),
                          WPath::Normal(path.clone()),
-                         WPath::Normal(path.clone()))
+                         WToken::from("token"))
             .unwrap();
         watch_list.watch(ConnId::new(Token(1), 1),
                          WPath::Normal(path.clone()),
-                         WPath::Normal(path.clone()))
+                         WToken::from("token"))
             .unwrap();
 
         let changes = store.write(&ChangeSet::new(&store),
@@ -195,7 +826,7 @@ mod test {
                                   value.clone())
             .unwrap();
 
-        let applied = store.apply(changes);
+        let applied = store.apply(changes).ok();
         let watches = watch_list.fire(applied);
 
         assert_eq!(watches.len(), 1);
@@ -203,7 +834,9 @@ mod test {
                                          conn: ConnId::new(Token(DOM0_DOMAIN_ID as usize),
                                                            DOM0_DOMAIN_ID),
                                          node: WPath::Normal(path.clone()),
-                                         token: WPath::Normal(path),
+                                         token: WToken::from("token"),
+                                         relative: false,
+                                         domain: None,
                                      }),
                    true);
     }
@@ -217,11 +850,11 @@ mod test {
 
         watch_list.watch(ConnId::new(Token(DOM0_DOMAIN_ID as usize), DOM0_DOMAIN_ID),
                          WPath::Normal(path.clone()),
-                         WPath::Normal(path.clone()))
+                         WToken::from("token"))
             .unwrap();
         watch_list.watch(ConnId::new(Token(1), 1),
                          WPath::Normal(path.clone()),
-                         WPath::Normal(path.clone()))
+                         WToken::from("token"))
             .unwrap();
 
         let changes = store.write(&ChangeSet::new(&store),
@@ -239,18 +872,18 @@ mod test {
                                            }])
             .unwrap();
 
-        let applied = store.apply(changes);
+        let applied = store.apply(changes).ok();
         let watches = watch_list.fire(applied);
 
         assert_eq!(watches.len(), 2);
         assert_eq!(watches.contains(&Watch::new(ConnId::new(Token(DOM0_DOMAIN_ID as usize),
                                                             DOM0_DOMAIN_ID),
                                                 WPath::Normal(path.clone()),
-                                                WPath::Normal(path.clone()))),
+                                                WToken::from("token"))),
                    true);
         assert_eq!(watches.contains(&Watch::new(ConnId::new(Token(1), 1),
                                                 WPath::Normal(path.clone()),
-                                                WPath::Normal(path.clone()))),
+                                                WToken::from("token"))),
                    true);
     }
 
@@ -263,7 +896,7 @@ mod test {
 
         watch_list.watch(ConnId::new(Token(DOM0_DOMAIN_ID as usize), DOM0_DOMAIN_ID),
                          WPath::Normal(path.parent().unwrap()),
-                         WPath::Normal(path.parent().unwrap()))
+                         WToken::from("token"))
             .unwrap();
 
         let changes = store.write(&ChangeSet::new(&store),
@@ -272,28 +905,129 @@ mod test {
                                   value.clone())
             .unwrap();
 
-        let applied = store.apply(changes);
+        let applied = store.apply(changes).ok();
+        let watches = watch_list.fire(applied);
+
+        // the parent was created along with the child
+        assert_eq!(watches.len(), 2);
+        for node in &[path.parent().unwrap(), path.clone()] {
+            assert_eq!(watches.contains(&Watch {
+                                             conn: ConnId::new(Token(DOM0_DOMAIN_ID as usize),
+                                                               DOM0_DOMAIN_ID),
+                                             node: WPath::Normal(node.clone()),
+                                             token: WToken::from("token"),
+                                             relative: false,
+                                             domain: None,
+                                         }),
+                       true);
+        }
+
+        // changing the child fires the parent watch even though the
+        // parent itself is untouched, naming the child
+        let changes = store.write(&ChangeSet::new(&store),
+                                  DOM0_DOMAIN_ID,
+                                  path.clone(),
+                                  Value::from("value 2"))
+            .unwrap();
+
+        let applied = store.apply(changes).ok();
         let watches = watch_list.fire(applied);
 
         assert_eq!(watches.len(), 1);
-        assert_eq!(watches.contains(&Watch {
-                                         conn: ConnId::new(Token(DOM0_DOMAIN_ID as usize),
-                                                           DOM0_DOMAIN_ID),
-                                         node: WPath::Normal(path.parent().unwrap()),
-                                         token: WPath::Normal(path.parent().unwrap()),
-                                     }),
+        assert_eq!(watches.contains(&Watch::new(ConnId::new(Token(DOM0_DOMAIN_ID as usize),
+                                                            DOM0_DOMAIN_ID),
+                                                WPath::Normal(path.clone()),
+                                                WToken::from("token"))),
                    true);
+    }
+
+    #[test]
+    fn deep_watch_write() {
+        let mut watch_list = WatchList::new();
+        let mut store = Store::new();
+        let top = Path::try_from(DOM0_DOMAIN_ID, "/root").unwrap();
+        let path = Path::try_from(DOM0_DOMAIN_ID, "/root/file/path/deep").unwrap();
+        let sibling = Path::try_from(DOM0_DOMAIN_ID, "/rootless").unwrap();
+
+        watch_list.watch(ConnId::new(Token(DOM0_DOMAIN_ID as usize), DOM0_DOMAIN_ID),
+                         WPath::Normal(top.clone()),
+                         WToken::from("token"))
+            .unwrap();
+        watch_list.watch(ConnId::new(Token(DOM0_DOMAIN_ID as usize), DOM0_DOMAIN_ID),
+                         WPath::Normal(sibling.clone()),
+                         WToken::from("token"))
+            .unwrap();
+
+        let changes = store.write(&ChangeSet::new(&store),
+                                  DOM0_DOMAIN_ID,
+                                  path.clone(),
+                                  Value::from("value"))
+            .unwrap();
+        store.apply(changes).unwrap();
 
+        // only the grandchild itself changes this time
         let changes = store.write(&ChangeSet::new(&store),
                                   DOM0_DOMAIN_ID,
                                   path.clone(),
                                   Value::from("value 2"))
             .unwrap();
 
-        let applied = store.apply(changes);
+        let applied = store.apply(changes).ok();
+        let watches = watch_list.fire(applied);
+
+        // a path sharing a prefix is not an ancestor, and the event names
+        // the grandchild
+        assert_eq!(watches.len(), 1);
+        assert_eq!(watches.contains(&Watch::new(ConnId::new(Token(DOM0_DOMAIN_ID as usize),
+                                                            DOM0_DOMAIN_ID),
+                                                WPath::Normal(path.clone()),
+                                                WToken::from("token"))),
+                   true);
+    }
+
+    #[test]
+    fn deep_watch_remove() {
+        let mut watch_list = WatchList::new();
+        let mut store = Store::new();
+        let top = Path::try_from(DOM0_DOMAIN_ID, "/root").unwrap();
+        let path = Path::try_from(DOM0_DOMAIN_ID, "/root/file/path/deep").unwrap();
+
+        let changes = store.write(&ChangeSet::new(&store),
+                                  DOM0_DOMAIN_ID,
+                                  path.clone(),
+                                  Value::from("value"))
+            .unwrap();
+        store.apply(changes).unwrap();
+
+        watch_list.watch(ConnId::new(Token(DOM0_DOMAIN_ID as usize), DOM0_DOMAIN_ID),
+                         WPath::Normal(top.clone()),
+                         WToken::from("token"))
+            .unwrap();
+        watch_list.watch(ConnId::new(Token(DOM0_DOMAIN_ID as usize), DOM0_DOMAIN_ID),
+                         WPath::Normal(path.clone()),
+                         WToken::from("token"))
+            .unwrap();
+
+        // removing a directory in the middle fires both the watch above it,
+        // naming the directory, and the watch on the node removed beneath it,
+        // naming the watched node
+        let middle = path.parent().unwrap();
+        let changes = store.rm(&ChangeSet::new(&store), DOM0_DOMAIN_ID, &middle).unwrap();
+
+        let applied = store.apply(changes).ok();
         let watches = watch_list.fire(applied);
 
-        assert_eq!(watches.len(), 0);
+        assert_eq!(watches.len(), 2);
+        assert_eq!(watches.contains(&Watch::new(ConnId::new(Token(DOM0_DOMAIN_ID as usize),
+                                                            DOM0_DOMAIN_ID),
+                                                WPath::Normal(middle.clone()),
+                                                WToken::from("token"))),
+                   true);
+        assert_eq!(watches.contains(&Watch::new(ConnId::new(Token(DOM0_DOMAIN_ID as usize),
+                                                            DOM0_DOMAIN_ID),
+                                                WPath::Normal(path.clone()),
+                                                WToken::from("token"))),
+                   true);
     }
 
     #[test]
@@ -302,14 +1036,16 @@ mod test {
         let mut store = Store::new();
         let path = Path::try_from(DOM0_DOMAIN_ID, "/root/file/path").unwrap();
         let value = Value::from("value");
+        let conn = ConnId::new(Token(DOM0_DOMAIN_ID as usize), DOM0_DOMAIN_ID);
+        let event = |node: &Path, token: &str| {
+            Watch::new(conn, WPath::Normal(node.clone()), WToken::from(token))
+        };
 
-        watch_list.watch(ConnId::new(Token(DOM0_DOMAIN_ID as usize), DOM0_DOMAIN_ID),
+        watch_list.watch(conn,
                          WPath::Normal(path.parent().unwrap()),
-                         WPath::Normal(path.parent().unwrap()))
+                         WToken::from("parent"))
             .unwrap();
-        watch_list.watch(ConnId::new(Token(DOM0_DOMAIN_ID as usize), DOM0_DOMAIN_ID),
-                         WPath::Normal(path.clone()),
-                         WPath::Normal(path.clone()))
+        watch_list.watch(conn, WPath::Normal(path.clone()), WToken::from("child"))
             .unwrap();
 
         let changes = store.write(&ChangeSet::new(&store),
@@ -318,38 +1054,24 @@ mod test {
                                   value.clone())
             .unwrap();
 
-        let applied = store.apply(changes);
+        let applied = store.apply(changes).ok();
         let watches = watch_list.fire(applied);
 
-        assert_eq!(watches.len(), 2);
-        assert_eq!(watches.contains(&Watch {
-                                         conn: ConnId::new(Token(DOM0_DOMAIN_ID as usize),
-                                                           DOM0_DOMAIN_ID),
-                                         node: WPath::Normal(path.parent().unwrap()),
-                                         token: WPath::Normal(path.parent().unwrap()),
-                                     }),
-                   true);
-        assert_eq!(watches.contains(&Watch {
-                                         conn: ConnId::new(Token(DOM0_DOMAIN_ID as usize),
-                                                           DOM0_DOMAIN_ID),
-                                         node: WPath::Normal(path.clone()),
-                                         token: WPath::Normal(path.clone()),
-                                     }),
-                   true);
+        // the parent was created along with the child
+        assert_eq!(watches.len(), 3);
+        assert_eq!(watches.contains(&event(&path.parent().unwrap(), "parent")), true);
+        assert_eq!(watches.contains(&event(&path, "parent")), true);
+        assert_eq!(watches.contains(&event(&path, "child")), true);
 
         let changes = store.rm(&ChangeSet::new(&store), DOM0_DOMAIN_ID, &path).unwrap();
 
-        let applied = store.apply(changes);
+        let applied = store.apply(changes).ok();
         let watches = watch_list.fire(applied);
 
-        assert_eq!(watches.len(), 1);
-        assert_eq!(watches.contains(&Watch {
-                                         conn: ConnId::new(Token(DOM0_DOMAIN_ID as usize),
-                                                           DOM0_DOMAIN_ID),
-                                         node: WPath::Normal(path.parent().unwrap()),
-                                         token: WPath::Normal(path.parent().unwrap()),
-                                     }),
-                   true);
+        // both name the removed node
+        assert_eq!(watches.len(), 2);
+        assert_eq!(watches.contains(&event(&path, "parent")), true);
+        assert_eq!(watches.contains(&event(&path, "child")), true);
     }
 
     #[test]
@@ -358,21 +1080,23 @@ mod test {
 
         watch_list.watch(ConnId::new(Token(DOM0_DOMAIN_ID as usize), DOM0_DOMAIN_ID),
                          WPath::IntroduceDomain,
-                         WPath::IntroduceDomain)
+                         WToken::from("token"))
             .unwrap();
         watch_list.watch(ConnId::new(Token(DOM0_DOMAIN_ID as usize), DOM0_DOMAIN_ID),
                          WPath::ReleaseDomain,
-                         WPath::ReleaseDomain)
+                         WToken::from("token"))
             .unwrap();
 
-        let watches = watch_list.fire_single(&AppliedChange::IntroduceDomain);
+        let watches = watch_list.fire_single(&AppliedChange::IntroduceDomain(1));
 
         assert_eq!(watches.len(), 1);
         assert_eq!(watches.contains(&Watch {
                                          conn: ConnId::new(Token(DOM0_DOMAIN_ID as usize),
                                                            DOM0_DOMAIN_ID),
                                          node: WPath::IntroduceDomain,
-                                         token: WPath::IntroduceDomain,
+                                         token: WToken::from("token"),
+                                         relative: false,
+                                         domain: None,
                                      }),
                    true);
     }
@@ -383,50 +1107,378 @@ mod test {
 
         watch_list.watch(ConnId::new(Token(DOM0_DOMAIN_ID as usize), DOM0_DOMAIN_ID),
                          WPath::IntroduceDomain,
-                         WPath::IntroduceDomain)
+                         WToken::from("token"))
             .unwrap();
         watch_list.watch(ConnId::new(Token(DOM0_DOMAIN_ID as usize), DOM0_DOMAIN_ID),
                          WPath::ReleaseDomain,
-                         WPath::ReleaseDomain)
+                         WToken::from("token"))
             .unwrap();
 
-        let watches = watch_list.fire_single(&AppliedChange::ReleaseDomain);
+        let watches = watch_list.fire_single(&AppliedChange::ReleaseDomain(1));
 
         assert_eq!(watches.len(), 1);
         assert_eq!(watches.contains(&Watch {
                                          conn: ConnId::new(Token(DOM0_DOMAIN_ID as usize),
                                                            DOM0_DOMAIN_ID),
                                          node: WPath::ReleaseDomain,
-                                         token: WPath::ReleaseDomain,
+                                         token: WToken::from("token"),
+                                         relative: false,
+                                         domain: None,
                                      }),
                    true);
     }
 
+    #[test]
+    fn special_watches_are_for_dom0() {
+        let mut watch_list = WatchList::new();
+
+        for node in vec![WPath::IntroduceDomain, WPath::ReleaseDomain] {
+            match watch_list.watch(ConnId::new(Token(1), 1), node.clone(), WToken::from("token")) {
+                Err(Error::EACCES(_)) => assert!(true),
+                Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+                Ok(_) => assert!(false, "a guest watched {:?}", node),
+            }
+        }
+        assert_eq!(watch_list.watches.len(), 0);
+    }
+
     #[test]
     fn basic_watch_reset() {
         let mut watch_list = WatchList::new();
 
         watch_list.watch(ConnId::new(Token(DOM0_DOMAIN_ID as usize), DOM0_DOMAIN_ID),
                          WPath::IntroduceDomain,
-                         WPath::IntroduceDomain)
+                         WToken::from("token"))
             .unwrap();
         watch_list.watch(ConnId::new(Token(DOM0_DOMAIN_ID as usize), DOM0_DOMAIN_ID),
                          WPath::ReleaseDomain,
-                         WPath::ReleaseDomain)
+                         WToken::from("token"))
             .unwrap();
-        watch_list.watch(ConnId::new(Token(1 as usize), 1),
+        watch_list.watch(ConnId::new(Token(1), DOM0_DOMAIN_ID),
                          WPath::ReleaseDomain,
-                         WPath::ReleaseDomain)
+                         WToken::from("token"))
             .unwrap();
 
         watch_list.reset(ConnId::new(Token(DOM0_DOMAIN_ID as usize), DOM0_DOMAIN_ID)).unwrap();
 
         assert_eq!(watch_list.watches.len(), 1);
         assert_eq!(watch_list.watches.contains(&Watch {
-                                                    conn: ConnId::new(Token(1 as usize), 1),
+                                                    conn: ConnId::new(Token(1),
+                                                                      DOM0_DOMAIN_ID),
                                                     node: WPath::ReleaseDomain,
-                                                    token: WPath::ReleaseDomain,
+                                                    token: WToken::from("token"),
+                                                    relative: false,
+                                                    domain: None,
                                                 }),
                    true);
     }
+
+    #[test]
+    fn basic_watch_reset_domain() {
+        let mut watch_list = WatchList::new();
+        let path = Path::try_from(1, "device").unwrap();
+
+        watch_list.watch(ConnId::new(Token(DOM0_DOMAIN_ID as usize), DOM0_DOMAIN_ID),
+                         WPath::ReleaseDomain,
+                         WToken::from("token"))
+            .unwrap();
+        watch_list.watch(ConnId::new(Token(1 as usize), 1),
+                         WPath::Normal(path.clone()),
+                         WToken::from("token"))
+            .unwrap();
+        watch_list.watch(ConnId::new(Token(2 as usize), 1),
+                         WPath::Normal(path.clone()),
+                         WToken::from("token"))
+            .unwrap();
+
+        watch_list.reset_domain(1).unwrap();
+
+        assert_eq!(watch_list.watches.len(), 1);
+        assert_eq!(watch_list.watches.contains(&Watch {
+                                                    conn: ConnId::new(Token(DOM0_DOMAIN_ID as
+                                                                            usize),
+                                                                      DOM0_DOMAIN_ID),
+                                                    node: WPath::ReleaseDomain,
+                                                    token: WToken::from("token"),
+                                                    relative: false,
+                                                    domain: None,
+                                                }),
+                   true);
+    }
+
+    #[test]
+    fn watch_quota() {
+        let mut watch_list = WatchList::with_quota(Quota { max_watches: 1, ..Quota::new() });
+        let path = Path::try_from(DOM0_DOMAIN_ID, "/root/file/path").unwrap();
+
+        watch_list.watch(ConnId::new(Token(1), 1),
+                         WPath::Normal(path.clone()),
+                         WToken::from("token"))
+            .unwrap();
+
+        match watch_list.watch(ConnId::new(Token(2), 1),
+                               WPath::Normal(path.clone()),
+                               WToken::from("other")) {
+            Err(Error::E2BIG(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "registered more watches than the quota"),
+        }
+
+        // dom0 is never limited
+        for token in 3..5 {
+            watch_list.watch(ConnId::new(Token(token), DOM0_DOMAIN_ID),
+                             WPath::Normal(path.clone()),
+                             WToken::from("token"))
+                .unwrap();
+        }
+        assert_eq!(watch_list.count(1), 1);
+        assert_eq!(watch_list.count(DOM0_DOMAIN_ID), 2);
+        let mut counts = watch_list.count_by_domain();
+        counts.sort();
+        assert_eq!(counts, vec![(DOM0_DOMAIN_ID, 2), (1, 1)]);
+
+        // removing a watch makes room for another
+        watch_list.reset(ConnId::new(Token(1), 1)).unwrap();
+        assert_eq!(watch_list.count(1), 0);
+        watch_list.watch(ConnId::new(Token(2), 1),
+                         WPath::Normal(path.clone()),
+                         WToken::from("other"))
+            .unwrap();
+    }
+
+    #[test]
+    fn relative_watch() {
+        let mut watch_list = WatchList::new();
+        let conn = ConnId::new(Token(1), 1);
+        let path = Path::try_from(1, "device/vif").unwrap();
+
+        let watch = watch_list.add(Watch {
+                                       relative: true,
+                                       ..Watch::new(conn,
+                                                    WPath::Normal(path.clone()),
+                                                    WToken::from("token"))
+                                   })
+            .unwrap();
+        assert_eq!(watch.node.as_bytes(), &b"/local/domain/1/device/vif"[..]);
+        assert_eq!(watch.node.as_relative_bytes(conn.dom_id), &b"device/vif"[..]);
+
+        // it's the same watch when given by its absolute path
+        watch_list.unwatch(conn, WPath::Normal(path), WToken::from("token")).unwrap();
+        assert_eq!(watch_list.watches.len(), 0);
+    }
+
+    #[test]
+    fn domain_ids() {
+        let mut watch_list = WatchList::new();
+        let conn = ConnId::new(Token(DOM0_DOMAIN_ID as usize), DOM0_DOMAIN_ID);
+        watch_list.watch(conn, WPath::ReleaseDomain, WToken::from("token")).unwrap();
+
+        let released = || {
+            Some(vec![AppliedChange::ReleaseDomain(1), AppliedChange::ReleaseDomain(2)])
+        };
+        let domains = |watches: Events| {
+            let mut domains = watches.iter().map(|watch| watch.domain).collect::<Vec<_>>();
+            domains.sort();
+            domains
+        };
+
+        // without the ids both releases look the same and fire once
+        assert_eq!(domains(watch_list.fire(released())), vec![None]);
+
+        watch_list.set_domain_ids(true);
+        assert_eq!(domains(watch_list.fire(released())), vec![Some(1), Some(2)]);
+    }
+
+    #[test]
+    fn wildcards() {
+        let mut watch_list = WatchList::new();
+        let mut store = Store::new();
+        let dom0 = ConnId::new(Token(0), DOM0_DOMAIN_ID);
+        let guest = ConnId::new(Token(1), 1);
+        let node = WPath::try_from(DOM0_DOMAIN_ID, "/local/domain/*/device").unwrap();
+
+        // refused until they are turned on, and then to guests all the same
+        match watch_list.watch(dom0, node.clone(), WToken::from("token")) {
+            Err(Error::EINVAL(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "registered a wildcard watch while they were off"),
+        }
+        watch_list.set_wildcards(true);
+        match watch_list.watch(guest, node.clone(), WToken::from("token")) {
+            Err(Error::EACCES(_)) => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "a guest registered a wildcard watch"),
+        }
+        watch_list.watch(dom0, node.clone(), WToken::from("token")).unwrap();
+
+        let write = |store: &mut Store, path: &str| {
+            let path = Path::try_from(DOM0_DOMAIN_ID, path).unwrap();
+            let changes = store.write(&ChangeSet::new(store), DOM0_DOMAIN_ID, path, Value::new())
+                .unwrap();
+            store.apply(changes).ok()
+        };
+        let paths = |watches: Events| {
+            watches.iter()
+                .map(|watch| String::from_utf8(watch.node.as_bytes().to_vec()).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        // each event names the path that matched
+        assert_eq!(paths(watch_list.fire(write(&mut store, "/local/domain/1/device/vif/0"))),
+                   vec!["/local/domain/1/device"]);
+        assert_eq!(paths(watch_list.fire(write(&mut store, "/local/domain/2/device"))),
+                   vec!["/local/domain/2/device"]);
+        assert!(watch_list.fire(write(&mut store, "/local/domain/2/name")).is_empty());
+        assert!(watch_list.fire(write(&mut store, "/local/domain/3")).is_empty());
+
+        // and removing what's above a match names the removed path
+        let domain = Path::try_from(DOM0_DOMAIN_ID, "/local/domain/1").unwrap();
+        let changes = store.rm(&ChangeSet::new(&store), DOM0_DOMAIN_ID, &domain).unwrap();
+        assert_eq!(paths(watch_list.fire(store.apply(changes).ok())),
+                   vec!["/local/domain/1"]);
+
+        watch_list.unwatch(dom0, node, WToken::from("token")).unwrap();
+        assert!(watch_list.fire(write(&mut store, "/local/domain/2/device/vbd")).is_empty());
+    }
+
+    #[test]
+    fn authorizer_hides_events() {
+        // keeps everyone, dom0 included, away from anything below /secret
+        struct NoSecrets;
+
+        impl Authorizer for NoSecrets {
+            fn check(&self,
+                     _: wire::DomainId,
+                     _: Option<wire::DomainId>,
+                     _: store::Perm,
+                     path: &Path,
+                     _: &[store::Permission])
+                     -> Result<()> {
+                if path.as_bytes().starts_with(b"/secret") {
+                    Err(Error::EACCES(format!("{:?} is secret", path)))
+                } else {
+                    Ok(())
+                }
+            }
+
+            fn check_set_perms(&self,
+                               _: wire::DomainId,
+                               _: Option<wire::DomainId>,
+                               _: &Path,
+                               _: &[store::Permission],
+                               _: &[store::Permission])
+                               -> Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut watch_list = WatchList::new();
+        let mut store = Store::new();
+        let dom0 = ConnId::new(Token(DOM0_DOMAIN_ID as usize), DOM0_DOMAIN_ID);
+        watch_list.watch(dom0,
+                         WPath::Normal(Path::try_from(DOM0_DOMAIN_ID, "/").unwrap()),
+                         WToken::from("token"))
+            .unwrap();
+        watch_list.set_authorizer(Arc::new(NoSecrets));
+
+        let mut write = |path: &str| {
+            let path = Path::try_from(DOM0_DOMAIN_ID, path).unwrap();
+            let changes = store.write(&ChangeSet::new(&store), DOM0_DOMAIN_ID, path, Value::new())
+                .unwrap();
+            store.apply(changes).ok()
+        };
+
+        assert_eq!(watch_list.fire(write("/public")).len(), 1);
+        assert!(watch_list.fire(write("/secret")).is_empty());
+    }
+
+    #[test]
+    fn removals_need_read_permission() {
+        let mut watch_list = WatchList::new();
+        let mut store = Store::new();
+        let dom0 = ConnId::new(Token(DOM0_DOMAIN_ID as usize), DOM0_DOMAIN_ID);
+        let guest = ConnId::new(Token(1), 1);
+        let top = Path::try_from(DOM0_DOMAIN_ID, "/secret").unwrap();
+        let path = top.push("node");
+        for conn in &[dom0, guest] {
+            watch_list.watch(*conn, WPath::Normal(path.clone()), WToken::from("token")).unwrap();
+        }
+
+        let write = |store: &mut Store| {
+            let changes = store.write(&ChangeSet::new(store),
+                                      DOM0_DOMAIN_ID,
+                                      path.clone(),
+                                      Value::new())
+                .unwrap();
+            store.apply(changes).ok()
+        };
+        let rm = |store: &mut Store, path: &Path| {
+            let changes = store.rm(&ChangeSet::new(store), DOM0_DOMAIN_ID, path).unwrap();
+            store.apply(changes).ok()
+        };
+        let conns = |watches: Events| watches.iter().map(|watch| watch.conn).collect::<Vec<_>>();
+
+        // what dom0 writes there only dom0 may read
+        assert_eq!(conns(watch_list.fire(write(&mut store))), vec![dom0]);
+
+        // so the guest isn't told when it's removed
+        assert_eq!(conns(watch_list.fire(rm(&mut store, &path))), vec![dom0]);
+
+        // nor when the subtree above it goes
+        write(&mut store);
+        assert_eq!(conns(watch_list.fire(rm(&mut store, &top))), vec![dom0]);
+    }
+
+    #[test]
+    fn targets_see_events() {
+        let mut watch_list = WatchList::new();
+        let conn = ConnId::new(Token(2), 2);
+        let path = Path::try_from(DOM0_DOMAIN_ID, "/local/domain/1").unwrap();
+        let change = || {
+            let owned_by_1 = vec![store::Permission {
+                                      id: 1,
+                                      perm: store::Perm::None,
+                                  }];
+            let written = Written::Created(Value::new());
+            Some(vec![AppliedChange::Write(path.push("foo"), owned_by_1, written)])
+        };
+        watch_list.watch(conn, WPath::Normal(path.clone()), WToken::from("token")).unwrap();
+
+        // domain 2 can't read what domain 1 writes
+        assert!(watch_list.fire(change()).is_empty());
+
+        // unless its connection acts for domain 1
+        let mut targets = HashMap::new();
+        targets.insert(conn, 1);
+        watch_list.set_targets(targets);
+        assert_eq!(watch_list.fire(change()).len(), 1);
+    }
+
+    #[test]
+    fn globs() {
+        for &(glob, name) in &[("*", ""),
+                               ("*", "vif"),
+                               ("v*", "vif"),
+                               ("*f", "vif"),
+                               ("v*f", "vif"),
+                               ("*i*", "vif"),
+                               ("**", "vif"),
+                               ("a*b*c", "aXbYbZc"),
+                               ("a*b", "abab")] {
+            assert!(glob_matches(glob.as_bytes(), name.as_bytes()),
+                    "{} should match {}",
+                    glob,
+                    name);
+        }
+        for &(glob, name) in &[("", "vif"), ("v*", "if"), ("*x*", "vif"), ("a*b*c", "aXbYcZ")] {
+            assert!(!glob_matches(glob.as_bytes(), name.as_bytes()),
+                    "{} shouldn't match {}",
+                    glob,
+                    name);
+        }
+
+        // which would take forever if every star tried every split
+        let glob = (0..50).map(|_| "*a").collect::<String>() + "b";
+        assert!(!glob_matches(glob.as_bytes(), &[b'a'; 200]));
+    }
 }
@@ -18,7 +18,10 @@
 #[cfg(test)]
 extern crate quickcheck;
 
-use bytes::{Buf, BufMut, BytesMut, LittleEndian};
+use bytes::{Buf, BufMut, Bytes, BytesMut, LittleEndian};
+use error::Error;
+use std::error;
+use std::fmt;
 use std::io;
 use tokio_io::codec::{Decoder, Encoder};
 
@@ -27,6 +30,8 @@ use self::quickcheck::{Arbitrary, Gen};
 
 /// XenStore message types
 pub const XS_DEBUG: u32 = 0;
+// XS_DEBUG was renamed when it grew subcommands
+pub const XS_CONTROL: u32 = XS_DEBUG;
 pub const XS_DIRECTORY: u32 = 1;
 pub const XS_READ: u32 = 2;
 pub const XS_GET_PERMS: u32 = 3;
@@ -48,6 +53,11 @@ pub const XS_RESUME: u32 = 18;
 pub const XS_SET_TARGET: u32 = 19;
 pub const XS_RESTRICT: u32 = 20;
 pub const XS_RESET_WATCHES: u32 = 21;
+pub const XS_DIRECTORY_PART: u32 = 22;
+// XS_GET_FEATURE (23), XS_SET_FEATURE (24) and XS_SET_QUOTA (26) aren't handled
+pub const XS_GET_QUOTA: u32 = 25;
+// one past the last message type the protocol defines
+pub const XS_TYPE_COUNT: u32 = 27;
 pub const XS_INVALID: u32 = 0xffff;
 
 /// XenStore error types
@@ -116,15 +126,19 @@ impl Header {
 
     /// Output the header as a vector of bytes
     pub fn to_vec(&self) -> Vec<u8> {
-        let mut ret = vec![];
-        ret.put_u32::<LittleEndian>(self.msg_type);
-        ret.put_u32::<LittleEndian>(self.req_id);
-        ret.put_u32::<LittleEndian>(self.tx_id);
-        ret.put_u32::<LittleEndian>(self.len);
-
+        let mut ret = Vec::with_capacity(HEADER_SIZE);
+        self.put(&mut ret);
         ret
     }
 
+    /// Write the header straight into `buf`
+    pub fn put<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u32::<LittleEndian>(self.msg_type);
+        buf.put_u32::<LittleEndian>(self.req_id);
+        buf.put_u32::<LittleEndian>(self.tx_id);
+        buf.put_u32::<LittleEndian>(self.len);
+    }
+
     /// Provide the length that the body should be
     pub fn len(&self) -> usize {
         self.len as usize
@@ -143,55 +157,105 @@ impl Arbitrary for Header {
     }
 }
 
+/// The payload of a message, kept as slices of the buffer it arrived in
+/// so that parsing it doesn't copy it. Fields carry their own NUL
+/// separators, so the body goes out as the fields laid end to end.
 #[derive(Clone, Debug, PartialEq)]
-pub struct Body(pub Vec<Vec<u8>>);
+pub struct Body(pub Vec<Bytes>);
 
 impl Body {
     pub fn parse(header: &Header, body: &[u8]) -> io::Result<Body> {
+        Body::from_bytes(header, Bytes::from(body))
+    }
+
+    /// Take ownership of `body` as the payload, without copying it
+    pub fn from_bytes(header: &Header, body: Bytes) -> io::Result<Body> {
         if header.len as usize != body.len() {
             return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
                                       format!("expected {} bytes", header.len)));
         }
 
-        // break the payload at NULL characters
-        let res: Vec<Vec<u8>> = body.split(|b| *b == b'\0')
-            .filter(|f| f.len() != 0)
-            .map(|f| f.to_owned())
-            .collect();
+        // keep the payload whole, values may contain NULL characters
+        if body.is_empty() {
+            Ok(Body(vec![]))
+        } else {
+            Ok(Body(vec![body]))
+        }
+    }
+
+    /// Break the body at NULL characters, skipping empty fields
+    pub fn fields(&self) -> Vec<&[u8]> {
+        self.0
+            .iter()
+            .flat_map(|field| field.split(|b| *b == b'\0'))
+            .filter(|f| !f.is_empty())
+            .collect()
+    }
+
+    /// Break the body at NULL characters, keeping empty fields, for requests
+    /// whose arguments are told apart by where they are. The NULL ending the
+    /// last field doesn't start another one.
+    pub fn positional_fields(&self) -> Vec<Vec<u8>> {
+        let mut bytes = self.to_vec();
+        if bytes.last() == Some(&b'\0') {
+            bytes.pop();
+        }
+
+        bytes.split(|b| *b == b'\0').map(|field| field.to_vec()).collect()
+    }
 
-        Ok(Body(res))
+    /// Split the body at its first NULL character into what comes before it
+    /// and, if there is one, everything after it. A body read off the wire
+    /// is a single field, which is split without copying it.
+    pub fn split_first(&self) -> (Bytes, Option<Bytes>) {
+        let whole = match self.0.len() {
+            0 => Bytes::new(),
+            1 => self.0[0].clone(),
+            _ => Bytes::from(self.to_vec()),
+        };
+
+        match whole.iter().position(|b| *b == b'\0') {
+            Some(sep) => (whole.slice_to(sep), Some(whole.slice_from(sep + 1))),
+            None => (whole, None),
+        }
     }
 
     /// Output the body as a vector of bytes
     pub fn to_vec(&self) -> Vec<u8> {
-        let mut ret = Vec::<u8>::with_capacity(BODY_SIZE);
+        let mut ret = Vec::<u8>::with_capacity(self.len());
+        self.put(&mut ret);
+        ret
+    }
 
-        // every field is separated by a NULL byte
+    /// Write the body straight into `buf`, a field at a time
+    pub fn put<B: BufMut>(&self, buf: &mut B) {
         for field in &self.0 {
-            if !field.is_empty() {
-                ret.extend_from_slice(&field);
-            }
+            buf.put_slice(field);
         }
-
-        ret
     }
 
     /// Provide the length of the body in bytes
     pub fn len(&self) -> usize {
-        // walk over all items in the body and add 1 for the separator
-        self.0
-            .iter()
-            .filter(|i| !i.is_empty())
-            .map(|i| i.iter().count())
-            .fold(0, |acc, x| acc + x)
+        self.0.iter().map(|field| field.len()).fold(0, |acc, x| acc + x)
+    }
+}
+
+impl From<Vec<Vec<u8>>> for Body {
+    fn from(fields: Vec<Vec<u8>>) -> Body {
+        Body(fields.into_iter().map(Bytes::from).collect())
     }
 }
 
 #[cfg(test)]
 mod tests {
 
-    use super::{Body, Header};
+    use bytes::{Bytes, BytesMut};
+    use std::io;
+    use error::Error;
+    use super::{Body, Header, Rejected, XenStoreCodec, HEADER_SIZE, XENSTORE_PAYLOAD_MAX,
+                XS_TYPE_COUNT};
     use super::quickcheck::{quickcheck, Arbitrary, Gen};
+    use tokio_io::codec::{Decoder, Encoder};
 
     #[test]
     fn header_parse_values() {
@@ -204,6 +268,120 @@ mod tests {
         assert_eq!(header.len, 4);
     }
 
+    #[test]
+    fn decode_payload_max() {
+        let header = Header {
+            msg_type: 2,
+            req_id: 0,
+            tx_id: 0,
+            len: XENSTORE_PAYLOAD_MAX as u32,
+        };
+        let mut buf = BytesMut::from(header.to_vec());
+        buf.extend(vec![b'a'; XENSTORE_PAYLOAD_MAX]);
+        assert!(XenStoreCodec.decode(&mut buf).unwrap().is_some());
+
+        // one byte more is rejected without waiting for the body
+        let header = Header { len: XENSTORE_PAYLOAD_MAX as u32 + 1, ..header };
+        let mut buf = BytesMut::from(header.to_vec());
+        match XenStoreCodec.decode(&mut buf) {
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData => assert!(true),
+            Err(ref e) => assert!(false, format!("unexpected error returned {:?}", e)),
+            Ok(_) => assert!(false, "decoded an oversized message"),
+        }
+    }
+
+    #[test]
+    fn decode_unknown_type() {
+        let header = Header {
+            msg_type: XS_TYPE_COUNT,
+            req_id: 9,
+            tx_id: 0,
+            len: 0,
+        };
+        let mut buf = BytesMut::from(header.to_vec());
+        match XenStoreCodec.decode(&mut buf) {
+            Err(ref e) => {
+                match Rejected::find(e) {
+                    Some(&Rejected { header: ref rejected, err: Error::ENOSYS(_) }) => {
+                        assert_eq!(*rejected, header)
+                    }
+                    _ => assert!(false, format!("unexpected error returned {:?}", e)),
+                }
+            }
+            Ok(_) => assert!(false, "decoded an unknown message type"),
+        }
+    }
+
+    #[test]
+    fn codec_round_trip() {
+        let header = Header {
+            msg_type: 11,
+            req_id: 1,
+            tx_id: 0,
+            len: 8,
+        };
+        let body = Body::from(vec![b"/a\0".to_vec(), b"value".to_vec(), vec![]]);
+
+        let mut buf = BytesMut::new();
+        XenStoreCodec.encode((header.clone(), body), &mut buf).unwrap();
+        assert_eq!(buf.len(), HEADER_SIZE + 8);
+
+        let (decoded_header, decoded_body) = XenStoreCodec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(decoded_header, header);
+        assert_eq!(decoded_body.to_vec(), b"/a\0value".to_vec());
+        assert!(buf.is_empty());
+    }
+
+    fn message(req_id: u32, body: &[u8]) -> Vec<u8> {
+        let mut bytes = Header {
+                msg_type: 2,
+                req_id: req_id,
+                tx_id: 0,
+                len: body.len() as u32,
+            }
+            .to_vec();
+        bytes.extend_from_slice(body);
+        bytes
+    }
+
+    #[test]
+    fn decode_coalesced() {
+        // several messages arriving in one read come out one at a time
+        let mut buf = BytesMut::new();
+        buf.extend(message(1, b"/a\0"));
+        buf.extend(message(2, b""));
+        buf.extend(message(3, b"/b/c\0"));
+
+        let mut decoded = Vec::new();
+        while let Some((header, body)) = XenStoreCodec.decode(&mut buf).unwrap() {
+            decoded.push((header.req_id, body.to_vec()));
+        }
+
+        assert_eq!(decoded,
+                   vec![(1, b"/a\0".to_vec()), (2, vec![]), (3, b"/b/c\0".to_vec())]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn decode_fragmented() {
+        // a message trickling in a byte at a time isn't decoded until it's
+        // all there, and the start of the next one is left alone
+        let mut bytes = message(1, b"/a/b\0");
+        bytes.extend(&message(2, b"/c\0")[..HEADER_SIZE - 1]);
+
+        let mut buf = BytesMut::new();
+        let mut decoded = Vec::new();
+        for byte in bytes {
+            buf.extend(vec![byte]);
+            if let Some((header, body)) = XenStoreCodec.decode(&mut buf).unwrap() {
+                decoded.push((header.req_id, body.to_vec()));
+            }
+        }
+
+        assert_eq!(decoded, vec![(1, b"/a/b\0".to_vec())]);
+        assert_eq!(buf.len(), HEADER_SIZE - 1);
+    }
+
     #[test]
     fn header_idempotent() {
         fn prop(hdr: Header) -> bool {
@@ -246,8 +424,7 @@ mod tests {
 
         impl Arbitrary for BodyBytes {
             fn arbitrary<G: Gen>(g: &mut G) -> BodyBytes {
-                let size = g.gen_range(0, 4096);
-                let mut vec = Vec::<u8>::with_capacity(size);
+                let mut vec = vec![0; g.gen_range(0, 4096)];
                 g.fill_bytes(&mut vec);
 
                 BodyBytes(vec)
@@ -267,54 +444,142 @@ mod tests {
             };
 
             // did it parse
-            Body::parse(&header, &bytes).is_ok()
+            // and came back unchanged
+            Body::parse(&header, &bytes).map(|body| body.to_vec() == bytes).unwrap_or(false)
         }
 
         quickcheck(prop as fn(BodyBytes) -> bool);
     }
 
+    #[test]
+    fn body_fields() {
+        let payload = b"/path\0\0va\0lue";
+        let header = Header {
+            msg_type: 0,
+            req_id: 0,
+            tx_id: 0,
+            len: payload.len() as u32,
+        };
+        let body = Body::parse(&header, payload).unwrap();
+
+        assert_eq!(body.to_vec(), payload.to_vec());
+        assert_eq!(body.fields(), vec![&b"/path"[..], &b"va"[..], &b"lue"[..]]);
+        assert_eq!(body.positional_fields(),
+                   vec![b"/path".to_vec(), Vec::new(), b"va".to_vec(), b"lue".to_vec()]);
+
+        // a trailing NULL ends the last field, even an empty one
+        let payload = b"/path\0\0";
+        let header = Header { len: payload.len() as u32, ..header };
+        let body = Body::parse(&header, payload).unwrap();
+        assert_eq!(body.positional_fields(), vec![b"/path".to_vec(), Vec::new()]);
+    }
+
+    #[test]
+    fn body_split_first() {
+        let payload = b"/path\0va\0lue";
+        let header = Header {
+            msg_type: 0,
+            req_id: 0,
+            tx_id: 0,
+            len: payload.len() as u32,
+        };
+        let body = Body::parse(&header, payload).unwrap();
+        assert_eq!(body.split_first(),
+                   (Bytes::from(&b"/path"[..]), Some(Bytes::from(&b"va\0lue"[..]))));
+
+        // split the same way when built up from several fields
+        let body = Body::from(vec![b"/pa".to_vec(), b"th\0".to_vec()]);
+        assert_eq!(body.split_first(), (Bytes::from(&b"/path"[..]), Some(Bytes::new())));
+
+        let body = Body::from(vec![b"/path".to_vec()]);
+        assert_eq!(body.split_first(), (Bytes::from(&b"/path"[..]), None));
+    }
+
     #[test]
     fn body_len() {
 
         impl Arbitrary for Body {
             fn arbitrary<G: Gen>(g: &mut G) -> Body {
                 let fields = g.gen_range(0, 128);
-                let size = g.gen_range(0, 32);
                 let mut vec = Vec::<Vec<u8>>::with_capacity(fields);
                 for _ in 0..fields {
                     // 128 fields of 0 to 32 bytes each
                     // keeps it below 4096
-                    let mut field = Vec::<u8>::with_capacity(size);
+                    let mut field = vec![0; g.gen_range(0, 32)];
                     g.fill_bytes(&mut field);
                     vec.push(field);
                 }
 
-                Body(vec)
+                Body::from(vec)
             }
         }
 
         fn prop(body: Body) -> bool {
-            // get the reported length
-            let length = body.len();
-
-            // get the byte vector
-            let bytes = body.0;
-
-            let mut total = 0;
-            for field in bytes {
-                // size of the field plus the NULL sep
-                if !field.is_empty() {
-                    total += field.len() + 1
-                }
-            }
+            // the length is what goes out on the wire, separators included
+            let bytes = body.to_vec();
+            let header = Header {
+                msg_type: 0,
+                req_id: 0,
+                tx_id: 0,
+                len: body.len() as u32,
+            };
 
-            length == total
+            let mut buf = BytesMut::new();
+            XenStoreCodec.encode((header.clone(), body.clone()), &mut buf).unwrap();
+
+            // and it survives the trip through the codec
+            body.len() == bytes.len() && buf.len() == HEADER_SIZE + bytes.len() &&
+            XenStoreCodec.decode(&mut buf)
+                .unwrap()
+                .map(|(decoded_header, decoded_body)| {
+                         decoded_header == header && decoded_body.to_vec() == bytes
+                     })
+                .unwrap_or(false)
         }
 
         quickcheck(prop as fn(Body) -> bool);
     }
 }
 
+/// The `Rejected` type.
+///
+/// A message the codec or a server won't go any further with, kept with
+/// the header it arrived under so the sender can be told which of its
+/// requests was turned away before it is disconnected.
+#[derive(Debug)]
+pub struct Rejected {
+    pub header: Header,
+    pub err: Error,
+}
+
+impl Rejected {
+    /// Find the message that was turned away behind an I/O error, if any
+    pub fn find(err: &io::Error) -> Option<&Rejected> {
+        err.get_ref().and_then(|err| err.downcast_ref::<Rejected>())
+    }
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "rejected message type {}: {}", self.header.msg_type, self.err)
+    }
+}
+
+impl error::Error for Rejected {
+    fn description(&self) -> &str {
+        self.err.description()
+    }
+}
+
+/// Turn away the message with `header` because of `err`
+pub fn reject(header: Header, err: Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,
+                   Rejected {
+                       header: header,
+                       err: err,
+                   })
+}
+
 /// This tracks our wire codec
 pub struct XenStoreCodec;
 
@@ -331,6 +596,22 @@ impl Decoder for XenStoreCodec {
 
         let header = Header::parse(&buf)?;
 
+        // refuse oversized messages up front rather than buffering them
+        if header.len() > XENSTORE_PAYLOAD_MAX {
+            let err = Error::EINVAL(format!("message body of {} bytes is larger than {}",
+                                            header.len(),
+                                            XENSTORE_PAYLOAD_MAX));
+            return Err(reject(header, err));
+        }
+
+        // nor is there any telling where a message the protocol doesn't
+        // define ends, so don't go looking for the next one. C xenstored
+        // answers these with ENOSYS too.
+        if header.msg_type >= XS_TYPE_COUNT {
+            let err = Error::ENOSYS(format!("unknown message type {}", header.msg_type));
+            return Err(reject(header, err));
+        }
+
         // We must get the full body size
         if buf.len() < header.len() + HEADER_SIZE {
             // not a full message
@@ -339,7 +620,8 @@ impl Decoder for XenStoreCodec {
 
         buf.split_to(HEADER_SIZE);
 
-        let body = Body::parse(&header, &buf.split_to(header.len()))?;
+        // the body shares the receive buffer rather than being copied out
+        let body = Body::from_bytes(&header, buf.split_to(header.len()).freeze())?;
         Ok(Some((header, body)))
     }
 }
@@ -349,8 +631,9 @@ impl Encoder for XenStoreCodec {
     type Error = io::Error;
 
     fn encode(&mut self, msg: (Header, Body), buf: &mut BytesMut) -> io::Result<()> {
-        buf.extend(msg.0.to_vec());
-        buf.extend(msg.1.to_vec());
+        buf.reserve(HEADER_SIZE + msg.1.len());
+        msg.0.put(buf);
+        msg.1.put(buf);
         Ok(())
     }
 }
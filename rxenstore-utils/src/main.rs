@@ -0,0 +1,462 @@
+/**
+    xenstore-rs provides a Rust based xenstore implementation.
+    Copyright (C) 2016 Star Lab Corp.
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License along
+    with this program; if not, see <http://www.gnu.org/licenses/>.
+**/
+#[macro_use]
+extern crate clap;
+extern crate futures;
+extern crate libxenstore;
+extern crate tokio_core;
+extern crate tokio_uds;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use futures::{future, stream, Future, Stream};
+use futures::future::{Either, Loop};
+use libxenstore::client::{Client, Response};
+use libxenstore::persistence;
+use libxenstore::store::Permission;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::process;
+use std::rc::Rc;
+use std::time::Duration;
+use tokio_core::reactor::{Core, Handle, Timeout};
+use tokio_uds::UnixStream;
+
+const UDS_PATH: &'static str = "/var/run/xenstored/socket";
+
+/// How many nodes `dump` and `restore` work on at once
+const MAX_IN_FLIGHT: usize = 32;
+
+/// The lines to print once a command is done, or why it failed
+type Output = Box<Future<Item = Vec<String>, Error = String>>;
+
+fn paths<'a>(m: &'a ArgMatches) -> Vec<&'a str> {
+    m.values_of("path").map(|paths| paths.collect()).unwrap_or(Vec::new())
+}
+
+/// Say which path a failed request was for
+fn for_path<T: 'static>(path: &str, res: Response<T>) -> Box<Future<Item = T, Error = String>> {
+    let path = path.to_owned();
+    Box::new(res.map_err(move |e| format!("{}: {}", path, e)))
+}
+
+/// What `ls` prints about each node
+#[derive(Clone, Copy)]
+struct Listing {
+    recursive: bool,
+    full: bool,
+    values: bool,
+    perms: bool,
+}
+
+fn ls(client: &Client, m: &ArgMatches) -> Output {
+    let path = m.value_of("path").unwrap_or("/");
+    let listing = Listing {
+        recursive: m.is_present("recursive"),
+        full: m.is_present("full"),
+        values: m.is_present("values"),
+        perms: m.is_present("perms"),
+    };
+
+    list(client, path.to_owned(), 0, listing)
+}
+
+/// List the children of `path`, which is `depth` levels below where we started
+fn list(client: &Client, path: String, depth: usize, listing: Listing) -> Output {
+    let client = client.clone();
+    Box::new(for_path(&path, client.directory(&path)).and_then(move |children| {
+        let nodes = children.into_iter()
+            .map(|child| {
+                let child_path = if path.ends_with('/') {
+                    format!("{}{}", path, child)
+                } else {
+                    format!("{}/{}", path, child)
+                };
+
+                // like xenstore-ls, nest children under their parents unless
+                // full paths were asked for
+                let name = if listing.full {
+                    child_path.clone()
+                } else {
+                    format!("{:width$}{}", "", child, width = depth)
+                };
+
+                let value: Box<Future<Item = String, Error = String>> = if listing.values {
+                    let read = for_path(&child_path, client.read(&child_path));
+                    Box::new(read.map(|value| format!(" = {:?}", String::from_utf8_lossy(&value))))
+                } else {
+                    Box::new(future::ok(String::new()))
+                };
+
+                let perms: Box<Future<Item = String, Error = String>> = if listing.perms {
+                    Box::new(for_path(&child_path, client.get_perms(&child_path)).map(|perms| {
+                        let perms = perms.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+                        format!(" ({})", perms.join(","))
+                    }))
+                } else {
+                    Box::new(future::ok(String::new()))
+                };
+
+                let below = if listing.recursive {
+                    list(&client, child_path, depth + 1, listing)
+                } else {
+                    Box::new(future::ok(Vec::new()))
+                };
+
+                value.join3(perms, below).map(move |(value, perms, below)| {
+                    let mut lines = vec![format!("{}{}{}", name, value, perms)];
+                    lines.extend(below);
+                    lines
+                })
+            })
+            .collect::<Vec<_>>();
+
+        future::join_all(nodes).map(|nodes| nodes.into_iter().flat_map(|lines| lines).collect())
+    }))
+}
+
+fn read(client: &Client, m: &ArgMatches) -> Output {
+    let reads = paths(m)
+        .into_iter()
+        .map(|path| {
+                 for_path(path, client.read(path))
+                     .map(|value| String::from_utf8_lossy(&value).into_owned())
+             })
+        .collect::<Vec<_>>();
+    Box::new(future::join_all(reads))
+}
+
+fn write(client: &Client, m: &ArgMatches) -> Output {
+    let args = m.values_of("pairs").map(|args| args.collect()).unwrap_or(Vec::<&str>::new());
+    if args.len() % 2 != 0 {
+        return Box::new(future::err(format!("every path needs a value to write")));
+    }
+
+    let writes = args.chunks(2)
+        .map(|pair| for_path(pair[0], client.write(pair[0], pair[1].as_bytes())))
+        .collect::<Vec<_>>();
+    Box::new(future::join_all(writes).map(|_| Vec::new()))
+}
+
+fn rm(client: &Client, m: &ArgMatches) -> Output {
+    let removes = paths(m)
+        .into_iter()
+        .map(|path| for_path(path, client.rm(path)))
+        .collect::<Vec<_>>();
+    Box::new(future::join_all(removes).map(|_| Vec::new()))
+}
+
+fn mkdir(client: &Client, m: &ArgMatches) -> Output {
+    let mkdirs = paths(m)
+        .into_iter()
+        .map(|path| for_path(path, client.mkdir(path)))
+        .collect::<Vec<_>>();
+    Box::new(future::join_all(mkdirs).map(|_| Vec::new()))
+}
+
+fn chmod(client: &Client, m: &ArgMatches) -> Output {
+    let path = m.value_of("path").unwrap_or("/");
+    let specs = m.values_of("perms").map(|specs| specs.collect()).unwrap_or(Vec::<&str>::new());
+
+    let perms = match specs.iter().map(|spec| Permission::try_from(spec)).collect() {
+        Ok(perms) => perms,
+        Err(e) => return Box::new(future::err(format!("{}", e))),
+    };
+
+    Box::new(set_perms(client, path.to_owned(), Rc::new(perms), m.is_present("recursive"))
+                 .map(|_| Vec::new()))
+}
+
+/// Set the permissions of `path`, and of everything below it if `recursive`
+fn set_perms(client: &Client,
+             path: String,
+             perms: Rc<Vec<Permission>>,
+             recursive: bool)
+             -> Box<Future<Item = (), Error = String>> {
+    let set = for_path(&path, client.set_perms(&path, &perms));
+    if !recursive {
+        return set;
+    }
+
+    let client = client.clone();
+    let children = for_path(&path, client.directory(&path));
+    Box::new(set.join(children).and_then(move |(_, children)| {
+        let below = children.into_iter()
+            .map(|child| {
+                let child_path = if path.ends_with('/') {
+                    format!("{}{}", path, child)
+                } else {
+                    format!("{}/{}", path, child)
+                };
+                set_perms(&client, child_path, perms.clone(), true)
+            })
+            .collect::<Vec<_>>();
+
+        future::join_all(below).map(|_| ())
+    }))
+}
+
+/// A node as `dump` lists it, along with the paths of its children
+fn dump_node(client: &Client,
+             path: String)
+             -> Box<Future<Item = (String, String, Vec<String>), Error = String>> {
+    let value = for_path(&path, client.read(&path));
+    let perms = for_path(&path, client.get_perms(&path));
+    let children = for_path(&path, client.directory(&path));
+    Box::new(value.join3(perms, children).map(move |(value, perms, children)| {
+        let line = persistence::text_line(&path, &value, &perms);
+        let children = children.into_iter()
+            .map(|child| if path.ends_with('/') {
+                     format!("{}{}", path, child)
+                 } else {
+                     format!("{}/{}", path, child)
+                 })
+            .collect();
+        (path, line, children)
+    }))
+}
+
+/// List the node at the path and every node below it the way
+/// `xenstore-ls -fp` does, so that `restore` gets the permissions of the
+/// node we started from too
+fn dump(client: &Client, m: &ArgMatches) -> Output {
+    let path = m.value_of("path").unwrap_or("/").to_owned();
+    let client = client.clone();
+
+    // go over the tree a level at a time, looking up no more than so many
+    // nodes at once however wide it gets
+    let walk = future::loop_fn((vec![path], Vec::new()), move |(level, mut found)| {
+        let client = client.clone();
+        stream::iter_ok::<_, String>(level)
+            .map(move |path| dump_node(&client, path))
+            .buffered(MAX_IN_FLIGHT)
+            .collect()
+            .map(move |nodes| {
+                let mut next = Vec::new();
+                for (path, line, children) in nodes {
+                    found.push((path, line));
+                    next.extend(children);
+                }
+                if next.is_empty() {
+                    Loop::Break(found)
+                } else {
+                    Loop::Continue((next, found))
+                }
+            })
+    });
+
+    // parents come before their children, which are listed together
+    Box::new(walk.map(|mut found| {
+        found.sort_by(|a, b| a.0.split('/').cmp(b.0.split('/')));
+        found.into_iter().map(|(_, line)| line).collect()
+    }))
+}
+
+fn restore(client: &Client, m: &ArgMatches) -> Output {
+    let file = m.value_of("file").unwrap();
+    let mut text = String::new();
+    let read = File::open(file).and_then(|mut f| f.read_to_string(&mut text));
+    let nodes = match read.and_then(|_| persistence::parse_text(&text)) {
+        Ok(nodes) => nodes,
+        Err(e) => return Box::new(future::err(format!("{}: {}", file, e))),
+    };
+
+    // parents come before their children in a dump, so writing the nodes in
+    // order leaves each parent with its own value and permissions. The
+    // client sends requests in the order they are made, which `buffered`
+    // keeps to.
+    let client = client.clone();
+    let writes = stream::iter_ok::<_, String>(nodes)
+        .map(move |node| {
+            let client = client.clone();
+            let path = String::from_utf8_lossy(node.path.as_bytes()).into_owned();
+            let written = for_path(&path, client.write(&path, node.value.as_bytes()));
+            written.and_then(move |_| for_path(&path, client.set_perms(&path, &node.permissions)))
+        })
+        .buffered(MAX_IN_FLIGHT)
+        .for_each(|_| Ok(()));
+    Box::new(writes.map(|_| Vec::new()))
+}
+
+fn watch(client: &Client, m: &ArgMatches, handle: &Handle) -> Output {
+    let path = m.value_of("path").unwrap_or("/").to_owned();
+
+    // like xenstore-watch, the path doubles as the token
+    let token = path.clone();
+    let events = client.watch(&path, &token).map_err(move |e| format!("{}: {}", path, e));
+    let events: Box<Stream<Item = String, Error = String>> = if m.is_present("count") {
+        Box::new(events.take(value_t_or_exit!(m, "count", u64)))
+    } else {
+        Box::new(events)
+    };
+
+    // print each event as soon as it arrives
+    let printed = events.for_each(move |event| {
+        println!("{} {}", event, token);
+        io::stdout().flush().map_err(|e| format!("unable to print event: {}", e))
+    });
+
+    if !m.is_present("timeout") {
+        return Box::new(printed.map(|_| Vec::new()));
+    }
+
+    let secs = value_t_or_exit!(m, "timeout", u64);
+    let timeout = match Timeout::new(Duration::from_secs(secs), handle) {
+        Ok(timeout) => timeout.map_err(|e| format!("timer failed: {}", e)),
+        Err(e) => return Box::new(future::err(format!("unable to set a timeout: {}", e))),
+    };
+
+    // running out of time is only a failure when waiting for a number of events
+    let counting = m.is_present("count");
+    let finished = printed.select2(timeout).then(move |res| match res {
+        Ok(Either::A(_)) => Ok(Vec::new()),
+        Ok(Either::B(_)) if !counting => Ok(Vec::new()),
+        Ok(Either::B(_)) => Err(format!("timed out waiting for events")),
+        Err(Either::A((e, _))) |
+        Err(Either::B((e, _))) => Err(e),
+    });
+    Box::new(finished)
+}
+
+fn main() {
+    let path = Arg::with_name("path").help("Path of the node").required(true);
+
+    let m = App::new("rxenstore")
+        .version(crate_version!())
+        .max_term_width(72)
+        .about("Inspect and change the contents of xenstore")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(Arg::with_name("socket")
+                 .help("Talk to the daemon listening on this socket")
+                 .long("socket")
+                 .short("s")
+                 .takes_value(true)
+                 .value_name("PATH")
+                 .default_value(UDS_PATH))
+        .subcommand(SubCommand::with_name("ls")
+                        .about("List the children of a node")
+                        .arg(path.clone().required(false))
+                        .arg(Arg::with_name("recursive")
+                                 .help("List every node below the path")
+                                 .long("recursive")
+                                 .short("R"))
+                        .arg(Arg::with_name("full")
+                                 .help("Print full paths rather than nesting children")
+                                 .long("full")
+                                 .short("f"))
+                        .arg(Arg::with_name("values")
+                                 .help("Print the value of each node")
+                                 .long("values")
+                                 .short("v"))
+                        .arg(Arg::with_name("perms")
+                                 .help("Print the permissions of each node")
+                                 .long("perms")
+                                 .short("p")))
+        .subcommand(SubCommand::with_name("read")
+                        .about("Print the values of nodes")
+                        .arg(path.clone().multiple(true)))
+        .subcommand(SubCommand::with_name("write")
+                        .about("Write values to nodes, creating them if needed")
+                        .arg(Arg::with_name("pairs")
+                                 .help("Paths each followed by the value to write")
+                                 .value_names(&["path", "value"])
+                                 .required(true)
+                                 .multiple(true)))
+        .subcommand(SubCommand::with_name("rm")
+                        .about("Remove nodes along with their children")
+                        .arg(path.clone().multiple(true)))
+        .subcommand(SubCommand::with_name("mkdir")
+                        .about("Create empty nodes")
+                        .arg(path.clone().multiple(true)))
+        .subcommand(SubCommand::with_name("chmod")
+                        .about("Set the permissions of a node, owner first")
+                        .arg(path.clone())
+                        .arg(Arg::with_name("perms")
+                                 .help("Permissions such as n0, r1, w2 or b3")
+                                 .required(true)
+                                 .multiple(true))
+                        .arg(Arg::with_name("recursive")
+                                 .help("Also set the permissions of every node below the path")
+                                 .long("recursive")
+                                 .short("r")))
+        .subcommand(SubCommand::with_name("dump")
+                        .about("Print every node below a path as xenstore-ls -fp does, to be \
+                                read back by restore")
+                        .arg(path.clone().required(false)))
+        .subcommand(SubCommand::with_name("restore")
+                        .about("Write back the nodes listed by dump or xenstore-ls -fp")
+                        .arg(Arg::with_name("file")
+                                 .help("File holding the listing")
+                                 .required(true)))
+        .subcommand(SubCommand::with_name("watch")
+                        .about("Print changes to a node and its children as they happen")
+                        .arg(path.clone())
+                        .arg(Arg::with_name("count")
+                                 .help("Stop after this many events")
+                                 .long("count")
+                                 .short("n")
+                                 .takes_value(true)
+                                 .value_name("N"))
+                        .arg(Arg::with_name("timeout")
+                                 .help("Stop after this many seconds")
+                                 .long("timeout")
+                                 .short("t")
+                                 .takes_value(true)
+                                 .value_name("SECS")))
+        .get_matches();
+
+    let mut core = Core::new().expect("Failed to create the event loop");
+    let handle = core.handle();
+
+    let socket = m.value_of("socket").unwrap_or(UDS_PATH);
+    let stream = match UnixStream::connect(socket, &handle) {
+        Ok(stream) => stream,
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "rxenstore: unable to connect to {}: {}", socket, e);
+            process::exit(1);
+        }
+    };
+
+    // requests fail on their own if the connection goes away
+    let (client, driver) = Client::new(stream);
+    handle.spawn(driver.map_err(|_| ()));
+
+    let output = match m.subcommand() {
+        ("ls", Some(m)) => ls(&client, m),
+        ("read", Some(m)) => read(&client, m),
+        ("write", Some(m)) => write(&client, m),
+        ("rm", Some(m)) => rm(&client, m),
+        ("mkdir", Some(m)) => mkdir(&client, m),
+        ("chmod", Some(m)) => chmod(&client, m),
+        ("dump", Some(m)) => dump(&client, m),
+        ("restore", Some(m)) => restore(&client, m),
+        ("watch", Some(m)) => watch(&client, m, &handle),
+        _ => unreachable!(),
+    };
+
+    match core.run(output) {
+        Ok(lines) => {
+            for line in lines {
+                println!("{}", line);
+            }
+        }
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "rxenstore: {}", e);
+            process::exit(1);
+        }
+    }
+}
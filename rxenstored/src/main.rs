@@ -17,36 +17,150 @@
 **/
 #[macro_use]
 extern crate clap;
+extern crate futures;
 extern crate libxenstore;
 #[macro_use]
 extern crate log;
 extern crate nix;
-extern crate stderrlog;
-extern crate tokio_uds_proto;
+extern crate tokio_core;
+extern crate tokio_signal;
+extern crate tokio_uds;
 
 use clap::{Arg, App};
+use futures::{future, Future, Stream};
+use libxenstore::authz::{Authorizer, PermissionAuthorizer};
+use libxenstore::config;
+use libxenstore::domain;
+use libxenstore::logger;
+use libxenstore::migration;
+use libxenstore::path as xs_path;
+use libxenstore::persistence;
 use libxenstore::server::*;
 use libxenstore::store;
 use libxenstore::system;
 use libxenstore::transaction;
+use libxenstore::transport::{ring, xenbus};
 use libxenstore::watch;
-use nix::sys::signal::{self, sigaction, SigAction, SigHandler, SaFlags, SigSet};
-use std::fs::{DirBuilder, remove_file};
-use std::path::PathBuf;
+#[cfg(feature = "tcp")]
+use libxenstore::wire;
+use std::env;
+use std::fs::{DirBuilder, File, remove_file, rename};
+use std::io::{self, Read, Write};
+#[cfg(feature = "tcp")]
+use std::net::TcpListener;
+use std::os::unix::net::UnixListener;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 use std::sync::{Arc, Mutex};
-use tokio_uds_proto::UnixServer;
+use std::time::{Duration, Instant};
+use tokio_core::reactor::{Core, Interval};
+use tokio_signal::unix::{Signal, SIGHUP, SIGINT, SIGTERM};
+use workers::{Listener, Workers};
+
+mod systemd;
+mod workers;
 
 const UDS_PATH: &'static str = "/var/run/xenstored/socket";
+const UDS_RO_PATH: &'static str = "/var/run/xenstored/socket_ro";
+const LIVE_UPDATE_PATH: &'static str = "/var/run/xenstored/state";
+
+/// How long connections get to deliver what they have queued when we shut down
+const SHUTDOWN_GRACE_SECS: u64 = 5;
+
+/// Replace this process with the current binary, passing it our state.
+///
+/// Only returns if the state couldn't be handed over, in which case we carry
+/// on serving as before.
+fn live_update(sys: &mut system::System, uds_paths: &[PathBuf]) {
+    if let Err(e) = exec_update(sys, uds_paths) {
+        error!("failed to live update: {}", e);
+        sys.cancel_live_update();
+    }
+}
+
+fn exec_update(sys: &system::System, uds_paths: &[PathBuf]) -> io::Result<()> {
+    let bytes = try!(migration::dump(sys));
+    try!(File::create(LIVE_UPDATE_PATH).and_then(|mut file| file.write_all(&bytes)));
+    let exe = try!(env::current_exe());
+
+    // drop any --restore we were started with, the new state replaces it
+    let mut args = Vec::new();
+    let mut given = env::args_os().skip(1);
+    while let Some(arg) = given.next() {
+        if arg.to_str() == Some("--restore") {
+            given.next();
+        } else if !arg.to_str().map_or(false, |arg| arg.starts_with("--restore=")) {
+            args.push(arg);
+        }
+    }
+
+    // the new instance binds the sockets afresh, but we still need them if
+    // it can't be started
+    if let Err(e) = set_sockets_aside(uds_paths) {
+        let _ = remove_file(LIVE_UPDATE_PATH);
+        return Err(e);
+    }
+
+    info!("live updating to {}", exe.display());
+    let err = Command::new(exe).args(&args).arg("--restore").arg(LIVE_UPDATE_PATH).exec();
+
+    put_sockets_back(uds_paths);
+    let _ = remove_file(LIVE_UPDATE_PATH);
+    Err(err)
+}
+
+/// Where a unix socket waits while a live update is under way
+fn aside_path(uds_path: &Path) -> PathBuf {
+    let mut aside = uds_path.as_os_str().to_owned();
+    aside.push(".live-update");
+    PathBuf::from(aside)
+}
 
-extern "C" fn cleanup_handler(_: nix::c_int) {
-    let uds_path = PathBuf::from(UDS_PATH);
-    remove_file(&uds_path).ok().expect("Failed to remove unix socket");
-    std::process::exit(0);
+/// Move the unix sockets out of the way of the instance we are updating to
+fn set_sockets_aside(uds_paths: &[PathBuf]) -> io::Result<()> {
+    for (moved, uds_path) in uds_paths.iter().enumerate() {
+        if let Err(e) = rename(uds_path, aside_path(uds_path)) {
+            put_sockets_back(&uds_paths[..moved]);
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Move the unix sockets back after a live update failed
+fn put_sockets_back(uds_paths: &[PathBuf]) {
+    for uds_path in uds_paths {
+        if let Err(e) = rename(aside_path(uds_path), uds_path) {
+            error!("failed to put back {}: {}", uds_path.display(), e);
+        }
+    }
+}
+
+/// The unix sockets named by `arg`, or else those in the configuration, or
+/// else `default`
+fn socket_paths(m: &clap::ArgMatches,
+                arg: &str,
+                configured: &[PathBuf],
+                default: &str)
+                -> Vec<PathBuf> {
+    match m.values_of(arg) {
+        Some(paths) => paths.map(PathBuf::from).collect(),
+        None if !configured.is_empty() => configured.to_vec(),
+        None => vec![PathBuf::from(default)],
+    }
+}
+
+/// Remove the unix sockets we've been listening on
+fn remove_sockets(uds_paths: &[PathBuf]) {
+    for uds_path in uds_paths {
+        remove_file(uds_path).ok().expect("Failed to remove unix socket");
+    }
 }
 
 fn main() {
 
-    let m = App::new("rxenstored")
+    let app = App::new("rxenstored")
         .version(crate_version!())
         .max_term_width(72)
         .about("Daemon that provides info and configuration space for itself and the system")
@@ -55,44 +169,397 @@ fn main() {
                  .help("Provide multiple times to increase verbosity of log output")
                  .short("v")
                  .multiple(true))
-        .get_matches();
+        .arg(Arg::with_name("config")
+                 .help("Set up the store, quotas and sockets as described in this file. \
+                        Its nodes are left out when the store is loaded from --store-file \
+                        or carried over by a live update")
+                 .long("config")
+                 .takes_value(true)
+                 .value_name("FILE"))
+        .arg(Arg::with_name("socket-path")
+                 .help("Serve clients on this unix socket, may be given more than once")
+                 .long("socket-path")
+                 .takes_value(true)
+                 .value_name("PATH")
+                 .multiple(true)
+                 .number_of_values(1))
+        .arg(Arg::with_name("socket-ro-path")
+                 .help("Serve read-only clients on this unix socket, may be given more than once")
+                 .long("socket-ro-path")
+                 .takes_value(true)
+                 .value_name("PATH")
+                 .multiple(true)
+                 .number_of_values(1))
+        .arg(Arg::with_name("worker-threads")
+                 .help("Serve socket clients from this many threads")
+                 .long("worker-threads")
+                 .takes_value(true)
+                 .value_name("N"))
+        .arg(Arg::with_name("xenbus")
+                 .help("Also serve the local kernel's xenbus requests")
+                 .long("xenbus"))
+        .arg(Arg::with_name("introduce-dom0")
+                 .help("Introduce dom0 and create /local/domain/0 at startup, which is done \
+                        anyway when the kernel's xenbus backend is there")
+                 .long("introduce-dom0"))
+        .arg(Arg::with_name("transaction-quota")
+                 .help("Maximum number of transactions a guest may have open at once")
+                 .long("transaction-quota")
+                 .takes_value(true)
+                 .value_name("N"))
+        .arg(Arg::with_name("watch-quota")
+                 .help("Maximum number of watches a guest may register")
+                 .long("watch-quota")
+                 .takes_value(true)
+                 .value_name("N"))
+        .arg(Arg::with_name("transaction-timeout")
+                 .help("Abort transactions that have been open for this many seconds")
+                 .long("transaction-timeout")
+                 .takes_value(true)
+                 .value_name("SECS"))
+        .arg(Arg::with_name("store-file")
+                 .help("Keep the store in this file so it survives restarts")
+                 .long("store-file")
+                 .takes_value(true)
+                 .value_name("PATH"))
+        .arg(Arg::with_name("save-every")
+                 .help("Save the store after this many changes")
+                 .long("save-every")
+                 .takes_value(true)
+                 .value_name("N")
+                 .requires("store-file"))
+        .arg(Arg::with_name("restore")
+                 .help("Pick up where a live updated daemon left off")
+                 .long("restore")
+                 .takes_value(true)
+                 .value_name("PATH"))
+        .arg(Arg::with_name("release-cleanup")
+                 .help("Remove a domain's path from the store when it is released")
+                 .long("release-cleanup"))
+        .arg(Arg::with_name("protect")
+                 .help("Only let dom0 write this node or create nodes directly below it, may be \
+                        given more than once to replace the default of /, /tool, /libxl, /vm \
+                        and /local")
+                 .long("protect")
+                 .takes_value(true)
+                 .value_name("PATH")
+                 .multiple(true)
+                 .number_of_values(1))
+        .arg(Arg::with_name("domain-ids")
+                 .help("Name the domain in @introduceDomain and @releaseDomain events")
+                 .long("domain-ids"))
+        .arg(Arg::with_name("wildcard-watches")
+                 .help("Let dom0 watch every path matching a pattern such as \
+                        /local/domain/*/device")
+                 .long("wildcard-watches"))
+        .arg(Arg::with_name("access-log")
+                 .help("Record every request in this access log, which can also be turned on \
+                        and off with the tracelog control command")
+                 .long("access-log")
+                 .takes_value(true)
+                 .value_name("FILE"));
+
+    #[cfg(feature = "tcp")]
+    let app = app.arg(Arg::with_name("tcp-listen")
+                          .help("Also serve clients over TCP on this address, may be given more \
+                                 than once")
+                          .long("tcp-listen")
+                          .takes_value(true)
+                          .value_name("ADDR")
+                          .multiple(true)
+                          .number_of_values(1)
+                          .requires("tcp-domain-id"))
+        .arg(Arg::with_name("tcp-domain-id")
+                 .help("The domain that clients connecting over TCP act as, which has to be \
+                        given as nobody checks who they are")
+                 .long("tcp-domain-id")
+                 .takes_value(true)
+                 .value_name("DOMID")
+                 .requires("tcp-listen"));
+
+    let m = app.get_matches();
 
-    stderrlog::new()
-        .module(module_path!())
-        .module("libxenstore")
-        .verbosity(m.occurrences_of("verbose") as usize)
-        .quiet(m.is_present("quiet"))
-        .init()
-        .unwrap();
+    let level = if m.is_present("quiet") {
+        log::LogLevelFilter::Off
+    } else {
+        logger::verbosity(m.occurrences_of("verbose") as usize)
+    };
+    let log_handle = logger::init(&[module_path!(), "libxenstore"], level).unwrap();
 
-    let action = SigAction::new(SigHandler::Handler(cleanup_handler),
-                                SaFlags::empty(),
-                                SigSet::empty());
+    let config = match m.value_of("config") {
+        Some(file) => {
+            info!("reading the configuration in {}", file);
+            config::Config::load(file).ok().expect("Failed to read the configuration")
+        }
+        None => config::Config::new(),
+    };
 
-    unsafe {
-        sigaction(signal::SIGINT, &action).ok().expect("Failed to register SIGINT handler");
-        sigaction(signal::SIGTERM, &action).ok().expect("Failed to register SIGTERM handler");
+    // systemd may have opened our Unix Sockets already, otherwise we need to
+    // create the paths to where they will live. Sockets given on the command
+    // line replace those in the configuration.
+    let activated = systemd::listen_fds();
+    let (rw_paths, ro_paths) = if activated.is_empty() {
+        (socket_paths(&m, "socket-path", &config.socket_paths, UDS_PATH),
+         socket_paths(&m, "socket-ro-path", &config.socket_ro_paths, UDS_RO_PATH))
+    } else {
+        (Vec::new(), Vec::new())
+    };
+    let uds_paths = rw_paths.iter().chain(ro_paths.iter()).cloned().collect::<Vec<_>>();
+
+    for uds_path in &uds_paths {
+        if let Some(uds_dir) = uds_path.parent() {
+            DirBuilder::new()
+                .recursive(true)
+                .create(uds_dir)
+                .ok()
+                .expect("Failed to created directory for unix socket");
+        }
     }
 
-    // where our Unix Socket will live, we need to create the path to it
-    let uds_path = PathBuf::from(UDS_PATH);
-    let uds_dir = uds_path.parent().unwrap();
+    let transactions = if m.is_present("transaction-quota") {
+        transaction::TransactionList::with_quota(value_t_or_exit!(m, "transaction-quota", usize))
+    } else {
+        transaction::TransactionList::new()
+    };
 
-    DirBuilder::new()
-        .recursive(true)
-        .create(uds_dir)
-        .ok()
-        .expect("Failed to created directory for unix socket");
+    let mut quota = config.quota;
+    if m.is_present("watch-quota") {
+        quota.max_watches = value_t_or_exit!(m, "watch-quota", usize);
+    }
 
-    let listener = UnixServer::new(XenStoreProto, uds_path.clone());
+    // however the store comes about, the same policy decides who gets at it
+    let authorizer: Arc<Authorizer> = Arc::new(PermissionAuthorizer);
+    let store_file = m.value_of("store-file").map(PathBuf::from);
+    let mut system = match m.value_of("restore") {
+        Some(state) => {
+            info!("restoring the state left by a live update in {}", state);
+            let mut bytes = Vec::new();
+            File::open(state)
+                .and_then(|mut file| file.read_to_end(&mut bytes))
+                .ok()
+                .expect("Failed to read the live update state");
+            let system = migration::restore(&bytes, quota, authorizer, transactions)
+                .ok()
+                .expect("Failed to restore the live update state");
+            remove_file(state).ok().expect("Failed to remove the live update state");
+            for uds_path in &uds_paths {
+                let _ = remove_file(aside_path(uds_path));
+            }
+            system
+        }
+        None => {
+            let store = match store_file {
+                Some(ref file) if file.exists() => {
+                    info!("loading the store from {}", file.display());
+                    store::Store::load(file, quota, authorizer)
+                        .ok()
+                        .expect("Failed to load the store")
+                }
+                _ => config.store_builder().quota(quota).authorizer(authorizer).build(),
+            };
+            let watches = watch::WatchList::with_quota(quota);
+            let domains = domain::DomainList::new();
+            system::System::new(store, watches, transactions, domains)
+        }
+    };
 
-    let store = store::Store::new();
-    let watches = watch::WatchList::new();
-    let transactions = transaction::TransactionList::new();
-    let system = system::System::new(store, watches, transactions);
+    if let Some(file) = store_file {
+        let every = if m.is_present("save-every") {
+            value_t_or_exit!(m, "save-every", u64)
+        } else {
+            persistence::DEFAULT_SAVE_INTERVAL
+        };
+        let persister = system.do_all(|store, _, _, _| {
+            persistence::Persister::new(file, every, store)
+        });
+        system.set_persister(persister);
+    }
+    system.set_log_handle(log_handle);
+    if let Some(file) = m.value_of("access-log") {
+        system.start_tracelog(Some(PathBuf::from(file)))
+            .ok()
+            .expect("Failed to open the access log");
+    }
+    if let Some(paths) = m.values_of("protect") {
+        let protected = paths.map(|path| xs_path::Path::try_from(store::DOM0_DOMAIN_ID, path))
+            .collect::<Result<Vec<_>, _>>()
+            .ok()
+            .expect("Invalid path to protect");
+        system.do_domain_mut(|_, store| store.set_protected(protected));
+    }
+    system.set_release_cleanup(m.is_present("release-cleanup"));
+    system.set_domain_ids(m.is_present("domain-ids"))
+        .ok()
+        .expect("Failed to advertise domain ids in watch events");
+    system.set_wildcard_watches(m.is_present("wildcard-watches"))
+        .ok()
+        .expect("Failed to advertise wildcard watches");
+
+    // the kernel in dom0 talks to us over its own ring as soon as dom0 has
+    // been introduced, which nothing else is going to do for us
+    if m.is_present("introduce-dom0") {
+        xenbus::introduce_dom0(&mut system).ok().expect("Failed to introduce dom0");
+    } else if xenbus::available() {
+        if let Err(e) = xenbus::introduce_dom0(&mut system) {
+            warn!("unable to introduce dom0: {}", e);
+        }
+    }
     let system = Arc::new(Mutex::new(system));
 
-    listener.serve(move || Ok(XenStoredService { system: system.clone() }));
+    // guest domains talk to us over their shared rings when we're running on Xen
+    if Path::new("/dev/xen/evtchn").exists() {
+        let mut rings = ring::RingServer::new(system.clone())
+            .ok()
+            .expect("Failed to start the ring transport");
+
+        if m.is_present("xenbus") {
+            xenbus::attach(&mut rings).ok().expect("Failed to attach to the xenbus backend");
+        }
+
+        rings.spawn();
+    } else if m.is_present("xenbus") {
+        panic!("--xenbus requires /dev/xen/evtchn");
+    } else {
+        info!("/dev/xen/evtchn not found, only serving the unix socket");
+    }
+
+    let mut core = Core::new().ok().expect("Failed to create the event loop");
+    let handle = core.handle();
+
+    // abort any transactions left open for too long so stuck guests can't
+    // hold on to them forever
+    let timeout = if m.is_present("transaction-timeout") {
+        value_t_or_exit!(m, "transaction-timeout", u64)
+    } else {
+        transaction::DEFAULT_TRANSACTION_TIMEOUT
+    };
+    let reaper_system = system.clone();
+    let reaper = Interval::new(Duration::from_secs(1), &handle)
+        .ok()
+        .expect("Failed to create the transaction reaper")
+        .for_each(move |_| {
+            let mut sys = reaper_system.lock().unwrap();
+            let expired = sys.do_transaction_mut(|txns, _| {
+                txns.expire(Duration::from_secs(timeout))
+            });
+            for (conn, tx_id) in expired {
+                warn!("aborted transaction {} for {:?} after {}s", tx_id, conn, timeout);
+            }
+            Ok(())
+        });
+    handle.spawn(reaper.map_err(|e| error!("transaction reaper failed: {}", e)));
+
+    // hand everything over to a fresh copy of ourselves once a live update
+    // has been asked for
+    let update_system = system.clone();
+    let update_paths = uds_paths.clone();
+    let update = Interval::new(Duration::from_millis(100), &handle)
+        .ok()
+        .expect("Failed to create the live update timer")
+        .for_each(move |_| {
+            let mut sys = update_system.lock().unwrap();
+            if sys.live_update_requested() {
+                live_update(&mut sys, &update_paths);
+            }
+            Ok(())
+        });
+    handle.spawn(update.map_err(|e| error!("live update timer failed: {}", e)));
+
+    // SIGINT and SIGTERM stop the event loop, SIGHUP saves the store
+    let reload_system = system.clone();
+    let stop = Signal::new(SIGINT, &handle)
+        .flatten_stream()
+        .select(Signal::new(SIGTERM, &handle).flatten_stream())
+        .select(Signal::new(SIGHUP, &handle).flatten_stream())
+        .filter(move |sig| if *sig == SIGHUP {
+                    info!("saving the store on SIGHUP");
+                    if let Err(e) = reload_system.lock().unwrap().save() {
+                        error!("failed to save the store: {}", e);
+                    }
+                    false
+                } else {
+                    true
+                })
+        .into_future()
+        .map(|(sig, _)| info!("shutting down on signal {}", sig.unwrap_or(0)))
+        .map_err(|(e, _)| e);
+
+    // every connection gets its own ConnId and outbound queue from its
+    // socket's service so that watch events can be written back to it
+    // alongside its responses, and clients on the read-only sockets can
+    // look but not touch
+    let mut listeners = Vec::new();
+    for uds_path in rw_paths.iter().chain(ro_paths.iter()) {
+        let listener = UnixListener::bind(uds_path).ok().expect("Failed to bind the unix socket");
+        let new_service = if ro_paths.contains(uds_path) {
+            XenStoredNewService::read_only(system.clone())
+        } else {
+            XenStoredNewService::new(system.clone())
+        };
+        listeners.push((Listener::Unix(listener), new_service));
+    }
+    for socket in activated {
+        info!("serving the {} socket passed in by systemd", socket.name);
+        let new_service = if socket.read_only() {
+            XenStoredNewService::read_only(system.clone())
+        } else {
+            XenStoredNewService::new(system.clone())
+        };
+        listeners.push((Listener::Unix(socket.listener), new_service));
+    }
+
+    // remote clients all act as the same domain, which they can't pick
+    // themselves and we won't make dom0 for them
+    #[cfg(feature = "tcp")]
+    {
+        if let Some(addrs) = m.values_of("tcp-listen") {
+            let dom_id = value_t_or_exit!(m, "tcp-domain-id", wire::DomainId);
+            for addr in addrs {
+                let listener = TcpListener::bind(addr)
+                    .ok()
+                    .expect("Failed to bind the TCP socket");
+                info!("serving domain {} over TCP on {}", dom_id, addr);
+
+                let new_service = XenStoredNewService::with_domain(system.clone(), dom_id);
+                listeners.push((Listener::Tcp(listener), new_service));
+            }
+        }
+    }
 
-    remove_file(&uds_path).ok().expect("Failed to remove unix socket");
+    // every worker accepts on all of the sockets, so connections end up
+    // spread over the threads
+    let threads = if m.is_present("worker-threads") {
+        value_t_or_exit!(m, "worker-threads", usize)
+    } else {
+        workers::DEFAULT_WORKER_THREADS
+    };
+    let mut workers = Workers::spawn(threads, listeners)
+        .ok()
+        .expect("Failed to start the worker threads");
+
+    // everything is set up by the time the event loop gets to this
+    handle.spawn(future::lazy(|| {
+                                  systemd::notify("READY=1");
+                                  Ok(())
+                              }));
+
+    core.run(stop).ok().expect("Failed to wait for a signal");
+
+    // stop taking new connections, but let the ones we already have send
+    // out their responses and events before hanging up on them
+    systemd::notify("STOPPING=1");
+    workers.stop_accepting();
+    system.lock().unwrap().close_outboxes();
+    let deadline = Instant::now() + Duration::from_secs(SHUTDOWN_GRACE_SECS);
+    while workers.connections() > 0 && Instant::now() < deadline {
+        core.turn(Some(Duration::from_millis(100)));
+    }
+    if workers.connections() > 0 {
+        warn!("gave up waiting on {} connections", workers.connections());
+    }
+
+    if let Err(e) = system.lock().unwrap().save() {
+        error!("failed to save the store: {}", e);
+    }
+    remove_sockets(&uds_paths);
 }
@@ -0,0 +1,157 @@
+/**
+    xenstore-rs provides a Rust based xenstore implementation.
+    Copyright (C) 2016 Star Lab Corp.
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License along
+    with this program; if not, see <http://www.gnu.org/licenses/>.
+**/
+
+// rxenstored for a xenstore stub domain. There are no sockets, files or
+// signals in there, so every client, dom0 included, talks to us over its
+// shared ring.
+
+#[macro_use]
+extern crate clap;
+extern crate libxenstore;
+#[macro_use]
+extern crate log;
+
+use clap::{Arg, App};
+use libxenstore::config;
+use libxenstore::domain;
+use libxenstore::logger;
+use libxenstore::message;
+use libxenstore::store;
+use libxenstore::system;
+use libxenstore::transaction;
+use libxenstore::transport::ring;
+use libxenstore::transport::stubdom::{StubEventChannel, StubMapper};
+use libxenstore::watch;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+fn main() {
+    let m = App::new("rxenstored-stubdom")
+        .version(crate_version!())
+        .max_term_width(72)
+        .about("Daemon that provides info and configuration space for the system from a \
+                xenstore stub domain")
+        .arg(Arg::with_name("quiet").help("Silences all log messages").short("q"))
+        .arg(Arg::with_name("verbose")
+                 .help("Provide multiple times to increase verbosity of log output")
+                 .short("v")
+                 .multiple(true))
+        .arg(Arg::with_name("event")
+                 .help("The event channel port dom0's kernel notifies us on")
+                 .long("event")
+                 .takes_value(true)
+                 .value_name("PORT")
+                 .required(true))
+        .arg(Arg::with_name("transaction-quota")
+                 .help("Maximum number of transactions a guest may have open at once")
+                 .long("transaction-quota")
+                 .takes_value(true)
+                 .value_name("N"))
+        .arg(Arg::with_name("transaction-timeout")
+                 .help("Abort transactions that have been open for this many seconds")
+                 .long("transaction-timeout")
+                 .takes_value(true)
+                 .value_name("SECS"))
+        .arg(Arg::with_name("watch-quota")
+                 .help("Maximum number of watches a guest may register")
+                 .long("watch-quota")
+                 .takes_value(true)
+                 .value_name("N"))
+        .arg(Arg::with_name("domain-ids")
+                 .help("Name the domain in @introduceDomain and @releaseDomain events")
+                 .long("domain-ids"))
+        .arg(Arg::with_name("wildcard-watches")
+                 .help("Let dom0 watch every path matching a pattern such as \
+                        /local/domain/*/device")
+                 .long("wildcard-watches"))
+        .get_matches();
+
+    let level = if m.is_present("quiet") {
+        log::LogLevelFilter::Off
+    } else {
+        logger::verbosity(m.occurrences_of("verbose") as usize)
+    };
+    let log_handle = logger::init(&[module_path!(), "libxenstore"], level).unwrap();
+
+    let transactions = if m.is_present("transaction-quota") {
+        transaction::TransactionList::with_quota(value_t_or_exit!(m, "transaction-quota", usize))
+    } else {
+        transaction::TransactionList::new()
+    };
+
+    let config = config::Config::new();
+    let mut quota = config.quota;
+    if m.is_present("watch-quota") {
+        quota.max_watches = value_t_or_exit!(m, "watch-quota", usize);
+    }
+
+    let store = config.store_builder().quota(quota).build();
+    let watches = watch::WatchList::with_quota(quota);
+    let domains = domain::DomainList::new();
+    let mut system = system::System::new(store, watches, transactions, domains);
+
+    system.set_log_handle(log_handle);
+    system.set_domain_ids(m.is_present("domain-ids"))
+        .ok()
+        .expect("Failed to advertise domain ids in watch events");
+    system.set_wildcard_watches(m.is_present("wildcard-watches"))
+        .ok()
+        .expect("Failed to advertise wildcard watches");
+
+    // nobody else is there to introduce dom0, whose page the toolstack has
+    // granted us like any guest's
+    let port = value_t_or_exit!(m, "event", message::EvtChnPort);
+    let conn = system.new_connection(store::DOM0_DOMAIN_ID);
+    message::introduce_domain(&mut system, conn, store::DOM0_DOMAIN_ID, 0, port)
+        .ok()
+        .expect("Failed to introduce dom0");
+    let system = Arc::new(Mutex::new(system));
+
+    // abort any transactions left open for too long so stuck guests can't
+    // hold on to them forever
+    let timeout = if m.is_present("transaction-timeout") {
+        value_t_or_exit!(m, "transaction-timeout", u64)
+    } else {
+        transaction::DEFAULT_TRANSACTION_TIMEOUT
+    };
+    let reaper_system = system.clone();
+    thread::Builder::new()
+        .name("reaper".to_owned())
+        .spawn(move || loop {
+                   thread::sleep(Duration::from_secs(1));
+                   let mut sys = reaper_system.lock().unwrap();
+                   let expired = sys.do_transaction_mut(|txns, _| {
+                       txns.expire(Duration::from_secs(timeout))
+                   });
+                   for (conn, tx_id) in expired {
+                       warn!("aborted transaction {} for {:?} after {}s", tx_id, conn, timeout);
+                   }
+               })
+        .ok()
+        .expect("Failed to start the transaction reaper");
+
+    let evtchn = StubEventChannel::open().ok().expect("Failed to open the event channels");
+    let mapper = StubMapper::open().ok().expect("Failed to open the grant table");
+    let mut rings = ring::RingServer::with_backends(system, Box::new(evtchn), Box::new(mapper));
+
+    info!("serving dom0 on event channel {}", port);
+    if let Err(e) = rings.run() {
+        error!("ring transport failed: {}", e);
+    }
+}
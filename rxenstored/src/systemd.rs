@@ -0,0 +1,89 @@
+/**
+    xenstore-rs provides a Rust based xenstore implementation.
+    Copyright (C) 2016 Star Lab Corp.
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License along
+    with this program; if not, see <http://www.gnu.org/licenses/>.
+**/
+
+// Just enough of systemd's socket activation and readiness protocols to run
+// as a `Type=notify` service with its sockets passed in from `.socket` units.
+
+use nix::unistd::getpid;
+use std::env;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::{UnixDatagram, UnixListener};
+
+/// The first file descriptor systemd passes to us
+const LISTEN_FDS_START: RawFd = 3;
+
+/// A socket systemd opened for us, named by its `FileDescriptorName=`
+pub struct Activated {
+    pub name: String,
+    pub listener: UnixListener,
+}
+
+impl Activated {
+    /// Sockets named like C xenstored's `socket_ro` only serve reads
+    pub fn read_only(&self) -> bool {
+        self.name.ends_with("_ro")
+    }
+}
+
+/// Take the listening sockets systemd passed to us, if we were activated.
+///
+/// The environment is left as it is so that a live updated copy of the daemon,
+/// which keeps our pid and inherits the descriptors, picks them up again.
+pub fn listen_fds() -> Vec<Activated> {
+    let ours = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<i32>().ok())
+        .map_or(false, |pid| pid == getpid());
+    if !ours {
+        return Vec::new();
+    }
+
+    let count = env::var("LISTEN_FDS").ok().and_then(|fds| fds.parse::<RawFd>().ok()).unwrap_or(0);
+    let names = env::var("LISTEN_FDNAMES").unwrap_or(String::new());
+    let mut names = names.split(':');
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            Activated {
+                name: names.next().unwrap_or("unknown").to_owned(),
+                listener: unsafe { UnixListener::from_raw_fd(fd) },
+            }
+        })
+        .collect()
+}
+
+/// Tell systemd about a change in our state, e.g. `READY=1`.
+///
+/// Does nothing unless we were started by systemd with `NOTIFY_SOCKET` set.
+pub fn notify(state: &str) {
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+
+    // std can only address sockets in the filesystem
+    if path.starts_with('@') {
+        warn!("can't notify systemd over abstract socket {}", path);
+        return;
+    }
+
+    let sent = UnixDatagram::unbound().and_then(|sock| sock.send_to(state.as_bytes(), &path));
+    if let Err(e) = sent {
+        warn!("failed to notify systemd of {}: {}", state, e);
+    }
+}
@@ -0,0 +1,208 @@
+/**
+    xenstore-rs provides a Rust based xenstore implementation.
+    Copyright (C) 2016 Star Lab Corp.
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License along
+    with this program; if not, see <http://www.gnu.org/licenses/>.
+**/
+
+// Spread the client connections over several event loops, each on its own
+// thread, so a host with hundreds of them isn't held to a single core.
+
+use futures::{future, Future, Stream};
+use futures::sync::oneshot;
+use libxenstore::server::XenStoredNewService;
+use std::cell::Cell;
+use std::io;
+#[cfg(feature = "tcp")]
+use std::net::TcpListener;
+use std::os::unix::net::UnixListener;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+#[cfg(feature = "tcp")]
+use tokio_core;
+use tokio_core::reactor::{Core, Handle};
+use tokio_uds;
+
+/// How many event loops serve clients unless told otherwise
+pub const DEFAULT_WORKER_THREADS: usize = 4;
+
+type Connection = Box<Future<Item = (), Error = io::Error>>;
+
+/// A socket clients connect to, which every worker accepts on
+pub enum Listener {
+    Unix(UnixListener),
+    #[cfg(feature = "tcp")]
+    Tcp(TcpListener),
+}
+
+impl Listener {
+    fn try_clone(&self) -> io::Result<Listener> {
+        match *self {
+            Listener::Unix(ref listener) => listener.try_clone().map(Listener::Unix),
+            #[cfg(feature = "tcp")]
+            Listener::Tcp(ref listener) => listener.try_clone().map(Listener::Tcp),
+        }
+    }
+}
+
+/// The `Workers` type.
+///
+/// Threads that each run an event loop accepting on every listener, with a
+/// task for every connection they accept. Connections on different threads
+/// share the `System`, and watch events reach them through their outboxes,
+/// which wake the task serving them wherever it runs.
+pub struct Workers {
+    stops: Vec<oneshot::Sender<()>>,
+    connections: Arc<AtomicUsize>,
+}
+
+impl Workers {
+    /// Start `count` threads serving clients on `listeners`, each with the
+    /// service it is paired with.
+    pub fn spawn(count: usize,
+                 listeners: Vec<(Listener, XenStoredNewService)>)
+                 -> io::Result<Workers> {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let mut stops = Vec::new();
+
+        for n in 0..count {
+            let mut ours = Vec::new();
+            for &(ref listener, ref new_service) in &listeners {
+                ours.push((try!(listener.try_clone()), new_service.clone()));
+            }
+
+            let (stop_tx, stop_rx) = oneshot::channel();
+            let connections = connections.clone();
+            try!(thread::Builder::new()
+                     .name(format!("worker-{}", n))
+                     .spawn(move || if let Err(e) = run(ours, stop_rx, connections) {
+                                error!("worker {} failed: {}", n, e);
+                            }));
+            stops.push(stop_tx);
+        }
+
+        Ok(Workers {
+               stops: stops,
+               connections: connections,
+           })
+    }
+
+    /// The connections open across every worker
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+
+    /// Stop accepting connections, leaving the ones already open to finish
+    pub fn stop_accepting(&mut self) {
+        for stop in self.stops.drain(..) {
+            let _ = stop.send(());
+        }
+    }
+}
+
+/// Run a worker's event loop, accepting connections until told to stop and
+/// serving the ones it has from then on until they are all closed
+fn run(listeners: Vec<(Listener, XenStoredNewService)>,
+       stop: oneshot::Receiver<()>,
+       connections: Arc<AtomicUsize>)
+       -> io::Result<()> {
+    let mut core = try!(Core::new());
+    let handle = core.handle();
+    let counter = Counter {
+        all: connections,
+        ours: Rc::new(Cell::new(0)),
+    };
+
+    let mut servers = Vec::new();
+    for (listener, new_service) in listeners {
+        let server = match listener {
+            Listener::Unix(listener) => {
+                let listener = try!(tokio_uds::UnixListener::from_listener(listener, &handle));
+                let incoming = listener.incoming()
+                    .map(move |(stream, _)| new_service.serve(stream));
+                accept(incoming, &handle, counter.clone())
+            }
+            #[cfg(feature = "tcp")]
+            Listener::Tcp(listener) => {
+                let addr = try!(listener.local_addr());
+                let listener =
+                    try!(tokio_core::net::TcpListener::from_listener(listener, &addr, &handle));
+                let incoming = listener.incoming().map(move |(stream, peer)| {
+                    debug!("TCP connection from {}", peer);
+                    new_service.serve(stream)
+                });
+                accept(incoming, &handle, counter.clone())
+            }
+        };
+        servers.push(server);
+    }
+
+    // dropping the listeners once told to stop leaves only the connections
+    let serve = future::select_all(servers).map(|_| ()).map_err(|(e, _, _)| e);
+    try!(core.run(serve.select(stop.then(|_| Ok(()))).map(|_| ()).map_err(|(e, _)| e)));
+
+    while counter.ours.get() > 0 {
+        core.turn(None);
+    }
+    Ok(())
+}
+
+/// Counts the connections open on a worker, and across every worker
+#[derive(Clone)]
+struct Counter {
+    all: Arc<AtomicUsize>,
+    ours: Rc<Cell<usize>>,
+}
+
+impl Counter {
+    fn opened(&self) {
+        self.all.fetch_add(1, Ordering::SeqCst);
+        self.ours.set(self.ours.get() + 1);
+    }
+
+    fn closed(&self) {
+        self.all.fetch_sub(1, Ordering::SeqCst);
+        self.ours.set(self.ours.get() - 1);
+    }
+}
+
+/// Serve every connection `incoming` yields in a task of its own.
+///
+/// A connection that can't be accepted is only logged, so the listener
+/// keeps going for everyone else.
+fn accept<S>(incoming: S, handle: &Handle, counter: Counter) -> Connection
+    where S: Stream<Item = Connection, Error = io::Error> + 'static
+{
+    let handle = handle.clone();
+    let incoming = incoming.then(|connection| match connection {
+                                     Ok(connection) => Ok(Some(connection)),
+                                     Err(e) => {
+                                         warn!("failed to accept a connection: {}", e);
+                                         Ok(None)
+                                     }
+                                 })
+        .filter_map(|connection| connection);
+    Box::new(incoming.for_each(move |connection| {
+        counter.opened();
+        let counter = counter.clone();
+        handle.spawn(connection.map_err(|e| warn!("connection failed: {}", e))
+                         .then(move |_| {
+                                   counter.closed();
+                                   Ok(())
+                               }));
+        Ok(())
+    }))
+}
@@ -0,0 +1,454 @@
+/**
+    xenstore-rs provides a Rust based xenstore implementation.
+    Copyright (C) 2016 Star Lab Corp.
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License along
+    with this program; if not, see <http://www.gnu.org/licenses/>.
+**/
+
+// Wire level exchanges with rxenstored over its unix socket, following the
+// semantics laid out in xen's docs/misc/xenstore.txt: error codes, NUL
+// handling, transactions and watches.
+//
+// Each case starts with /conformance removed and runs over a connection of
+// its own. Setting XENSTORED_SOCKET runs the same cases against a xenstored
+// already serving that socket instead, such as C xenstored, so that the two
+// can be compared. Mind that the cases are free to change /conformance.
+
+extern crate libxenstore;
+
+use libxenstore::wire;
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long to wait on the daemon before calling a case failed
+const TIMEOUT_SECS: u64 = 5;
+
+/// A message as it goes over the wire
+#[derive(Clone, Debug, PartialEq)]
+struct Msg {
+    msg_type: u32,
+    req_id: u32,
+    tx_id: u32,
+    body: Vec<u8>,
+}
+
+/// What a request should be answered with
+enum Reply {
+    /// "OK", with the type of the request
+    Ok,
+    /// exactly these bytes, with the type of the request
+    Body(&'static [u8]),
+    /// an error of this name
+    Error(&'static str),
+    /// the id of a new transaction, used by the requests that follow
+    Started,
+}
+
+/// The transaction a request is sent in
+#[derive(Clone, Copy)]
+enum Tx {
+    None,
+    /// the last one started in the case, even if it has ended
+    Last,
+}
+
+enum Step {
+    Request(u32, Tx, &'static [u8], Reply),
+    /// a watch event with this body, whether it came before or after the
+    /// reply to the request that fired it
+    Event(&'static [u8]),
+}
+
+fn req(msg_type: u32, body: &'static [u8], reply: Reply) -> Step {
+    Step::Request(msg_type, Tx::None, body, reply)
+}
+
+fn in_tx(msg_type: u32, body: &'static [u8], reply: Reply) -> Step {
+    Step::Request(msg_type, Tx::Last, body, reply)
+}
+
+fn event(body: &'static [u8]) -> Step {
+    Step::Event(body)
+}
+
+fn cases() -> Vec<(&'static str, Vec<Step>)> {
+    use libxenstore::wire::*;
+    use Reply::*;
+
+    vec![("read what was written",
+          vec![req(XS_WRITE, b"/conformance/a\0value", Ok),
+               req(XS_READ, b"/conformance/a\0", Body(b"value"))]),
+         ("values are bytes and may hold NULs",
+          vec![req(XS_WRITE, b"/conformance/a\0with\0nul", Ok),
+               req(XS_READ, b"/conformance/a\0", Body(b"with\0nul"))]),
+         ("an empty value",
+          vec![req(XS_WRITE, b"/conformance/a\0", Ok),
+               req(XS_READ, b"/conformance/a\0", Body(b""))]),
+         ("missing nodes",
+          vec![req(XS_READ, b"/conformance/missing\0", Error("ENOENT")),
+               req(XS_DIRECTORY, b"/conformance/missing\0", Error("ENOENT")),
+               req(XS_GET_PERMS, b"/conformance/missing\0", Error("ENOENT"))]),
+         ("writes create missing parents",
+          vec![req(XS_WRITE, b"/conformance/p/q/r\0x", Ok),
+               req(XS_READ, b"/conformance/p/q\0", Body(b"")),
+               req(XS_DIRECTORY, b"/conformance/p\0", Body(b"q\0"))]),
+         ("directories list their children",
+          vec![req(XS_MKDIR, b"/conformance/d/a\0", Ok),
+               req(XS_MKDIR, b"/conformance/d/b\0", Ok),
+               req(XS_MKDIR, b"/conformance/d/b\0", Ok),
+               req(XS_DIRECTORY, b"/conformance/d\0", Body(b"a\0b\0")),
+               req(XS_DIRECTORY, b"/conformance/d/a\0", Body(b""))]),
+         ("removing takes everything below",
+          vec![req(XS_WRITE, b"/conformance/r/s/t\0x", Ok),
+               req(XS_RM, b"/conformance/r\0", Ok),
+               req(XS_READ, b"/conformance/r/s\0", Error("ENOENT")),
+               req(XS_DIRECTORY, b"/conformance\0", Body(b""))]),
+         ("removing what isn't there",
+          vec![req(XS_MKDIR, b"/conformance\0", Ok),
+               req(XS_RM, b"/conformance/none\0", Ok),
+               req(XS_RM, b"/conformance/none/below\0", Error("ENOENT"))]),
+         ("the root stays", vec![req(XS_RM, b"/\0", Error("EINVAL"))]),
+         ("malformed paths",
+          vec![req(XS_READ, b"/conformance/\0", Error("EINVAL")),
+               req(XS_READ, b"/conformance//a\0", Error("EINVAL")),
+               req(XS_READ, b"\0", Error("EINVAL")),
+               req(XS_WRITE, b"/conformance/a/\0x", Error("EINVAL"))]),
+         ("permissions",
+          vec![req(XS_WRITE, b"/conformance/perm\0x", Ok),
+               req(XS_GET_PERMS, b"/conformance/perm\0", Body(b"n0\0")),
+               req(XS_SET_PERMS, b"/conformance/perm\0n0\0r5\0", Ok),
+               req(XS_GET_PERMS, b"/conformance/perm\0", Body(b"n0\0r5\0")),
+               req(XS_SET_PERMS, b"/conformance/perm\0x0\0", Error("EINVAL")),
+               req(XS_SET_PERMS, b"/conformance/perm\0", Error("EINVAL"))]),
+         ("transactions are isolated until they end",
+          vec![req(XS_WRITE, b"/conformance/t\0old", Ok),
+               req(XS_TRANSACTION_START, b"\0", Started),
+               in_tx(XS_WRITE, b"/conformance/t\0new", Ok),
+               req(XS_READ, b"/conformance/t\0", Body(b"old")),
+               in_tx(XS_READ, b"/conformance/t\0", Body(b"new")),
+               in_tx(XS_TRANSACTION_END, b"T\0", Ok),
+               req(XS_READ, b"/conformance/t\0", Body(b"new"))]),
+         ("aborted transactions leave the store alone",
+          vec![req(XS_WRITE, b"/conformance/t\0old", Ok),
+               req(XS_TRANSACTION_START, b"\0", Started),
+               in_tx(XS_WRITE, b"/conformance/t\0new", Ok),
+               in_tx(XS_RM, b"/conformance/t\0", Ok),
+               in_tx(XS_READ, b"/conformance/t\0", Error("ENOENT")),
+               in_tx(XS_TRANSACTION_END, b"F\0", Ok),
+               req(XS_READ, b"/conformance/t\0", Body(b"old"))]),
+         ("conflicting transactions are retried",
+          vec![req(XS_WRITE, b"/conformance/t\0old", Ok),
+               req(XS_TRANSACTION_START, b"\0", Started),
+               in_tx(XS_READ, b"/conformance/t\0", Body(b"old")),
+               req(XS_WRITE, b"/conformance/t\0other", Ok),
+               in_tx(XS_WRITE, b"/conformance/t\0new", Ok),
+               in_tx(XS_TRANSACTION_END, b"T\0", Error("EAGAIN")),
+               req(XS_READ, b"/conformance/t\0", Body(b"other"))]),
+         ("transactions end with T or F",
+          vec![req(XS_TRANSACTION_END, b"T\0", Error("EINVAL")),
+               req(XS_TRANSACTION_START, b"\0", Started),
+               in_tx(XS_TRANSACTION_END, b"X\0", Error("EINVAL")),
+               in_tx(XS_TRANSACTION_END, b"F\0", Ok)]),
+         ("ended transactions are unknown",
+          vec![req(XS_TRANSACTION_START, b"\0", Started),
+               in_tx(XS_TRANSACTION_END, b"F\0", Ok),
+               in_tx(XS_READ, b"/\0", Error("ENOENT")),
+               in_tx(XS_WRITE, b"/conformance/t\0x", Error("ENOENT")),
+               in_tx(XS_TRANSACTION_END, b"F\0", Error("ENOENT"))]),
+         ("watches fire once when set",
+          vec![req(XS_WATCH, b"/conformance/w\0tok\0", Ok),
+               event(b"/conformance/w\0tok\0"),
+               req(XS_WATCH, b"/conformance/w\0tok\0", Error("EEXIST"))]),
+         ("watches fire for changes",
+          vec![req(XS_WATCH, b"/conformance/w\0tok\0", Ok),
+               event(b"/conformance/w\0tok\0"),
+               req(XS_WRITE, b"/conformance/w\0x", Ok),
+               event(b"/conformance/w\0tok\0"),
+               req(XS_SET_PERMS, b"/conformance/w\0n0\0", Ok),
+               event(b"/conformance/w\0tok\0"),
+               req(XS_RM, b"/conformance/w\0", Ok),
+               event(b"/conformance/w\0tok\0")]),
+         ("watches fire when a parent is removed",
+          vec![req(XS_WRITE, b"/conformance/w/x\0x", Ok),
+               req(XS_WATCH, b"/conformance/w/x\0tok\0", Ok),
+               event(b"/conformance/w/x\0tok\0"),
+               req(XS_RM, b"/conformance/w\0", Ok),
+               event(b"/conformance/w/x\0tok\0")]),
+         ("watches fire once a transaction ends",
+          vec![req(XS_WATCH, b"/conformance/w\0tok\0", Ok),
+               event(b"/conformance/w\0tok\0"),
+               req(XS_TRANSACTION_START, b"\0", Started),
+               in_tx(XS_WRITE, b"/conformance/w\0x", Ok),
+               req(XS_READ, b"/conformance/w\0", Error("ENOENT")),
+               in_tx(XS_TRANSACTION_END, b"T\0", Ok),
+               event(b"/conformance/w\0tok\0")]),
+         ("unwatched watches stay quiet",
+          vec![req(XS_WATCH, b"/conformance/w\0tok\0", Ok),
+               event(b"/conformance/w\0tok\0"),
+               req(XS_UNWATCH, b"/conformance/w\0tok\0", Ok),
+               req(XS_WRITE, b"/conformance/w\0x", Ok),
+               req(XS_UNWATCH, b"/conformance/w\0tok\0", Error("ENOENT"))]),
+         ("watch requests take a path and a token",
+          vec![req(XS_WATCH, b"/conformance/w\0", Error("EINVAL")),
+               req(XS_WATCH, b"/conformance/w\0tok\0extra\0", Error("EINVAL"))]),
+         ("domains",
+          vec![req(XS_GET_DOMAIN_PATH, b"7\0", Body(b"/local/domain/7\0")),
+               req(XS_GET_DOMAIN_PATH, b"seven\0", Error("EINVAL")),
+               req(XS_IS_DOMAIN_INTRODUCED, b"7\0", Body(b"F\0")),
+               req(XS_RELEASE, b"7\0", Error("ENOENT"))]),
+         ("unknown requests", vec![req(XS_TYPE_COUNT + 100, b"\0", Error("ENOSYS"))])]
+}
+
+/// The daemon the cases run against, stopped once dropped if we started it
+struct Daemon {
+    child: Option<Child>,
+    dir: Option<PathBuf>,
+    socket: PathBuf,
+}
+
+impl Daemon {
+    fn start() -> Daemon {
+        if let Some(socket) = env::var_os("XENSTORED_SOCKET") {
+            return Daemon {
+                       child: None,
+                       dir: None,
+                       socket: PathBuf::from(socket),
+                   };
+        }
+
+        // cargo puts the daemon next to the deps directory the tests run from
+        let exe = env::current_exe().unwrap();
+        let bin = exe.parent().and_then(|deps| deps.parent()).unwrap().join("rxenstored");
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let dir = env::temp_dir().join(format!("rxenstored-conformance-{}-{}",
+                                               now.as_secs(),
+                                               now.subsec_nanos()));
+        let socket = dir.join("socket");
+        let child = Command::new(&bin)
+            .arg("--quiet")
+            .arg("--socket-path")
+            .arg(&socket)
+            .arg("--socket-ro-path")
+            .arg(dir.join("socket_ro"))
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start rxenstored");
+
+        Daemon {
+            child: Some(child),
+            dir: Some(dir),
+            socket: socket,
+        }
+    }
+
+    fn connect(&self) -> Conn {
+        // the daemon may still be binding its socket
+        for _ in 0..TIMEOUT_SECS * 20 {
+            if let Ok(stream) = UnixStream::connect(&self.socket) {
+                stream.set_read_timeout(Some(Duration::from_secs(TIMEOUT_SECS))).unwrap();
+                return Conn {
+                           stream: stream,
+                           req_id: 0,
+                           events: Vec::new(),
+                       };
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        panic!("Failed to connect to {}", self.socket.display());
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        if let Some(ref mut child) = self.child {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        if let Some(ref dir) = self.dir {
+            let _ = fs::remove_dir_all(dir);
+        }
+    }
+}
+
+fn put_u32(bytes: &mut Vec<u8>, value: u32) {
+    for shift in &[0, 8, 16, 24] {
+        bytes.push((value >> *shift) as u8);
+    }
+}
+
+fn get_u32(bytes: &[u8]) -> u32 {
+    bytes.iter().rev().fold(0, |value, byte| value << 8 | *byte as u32)
+}
+
+struct Conn {
+    stream: UnixStream,
+    req_id: u32,
+    // watch events that arrived while waiting on a reply
+    events: Vec<Vec<u8>>,
+}
+
+impl Conn {
+    fn send(&mut self, msg_type: u32, tx_id: u32, body: &[u8]) -> Result<u32, String> {
+        self.req_id += 1;
+        let mut bytes = Vec::with_capacity(wire::HEADER_SIZE + body.len());
+        put_u32(&mut bytes, msg_type);
+        put_u32(&mut bytes, self.req_id);
+        put_u32(&mut bytes, tx_id);
+        put_u32(&mut bytes, body.len() as u32);
+        bytes.extend_from_slice(body);
+        try!(self.stream.write_all(&bytes).map_err(|e| format!("failed to send: {}", e)));
+        Ok(self.req_id)
+    }
+
+    fn recv(&mut self) -> Result<Msg, String> {
+        let mut header = [0; wire::HEADER_SIZE];
+        try!(self.stream
+                 .read_exact(&mut header)
+                 .map_err(|e| format!("no message came: {}", e)));
+        let mut body = vec![0; get_u32(&header[12..16]) as usize];
+        try!(self.stream
+                 .read_exact(&mut body)
+                 .map_err(|e| format!("the message was cut short: {}", e)));
+
+        Ok(Msg {
+               msg_type: get_u32(&header[0..4]),
+               req_id: get_u32(&header[4..8]),
+               tx_id: get_u32(&header[8..12]),
+               body: body,
+           })
+    }
+
+    /// The reply to the request `req_id`, setting aside any watch events
+    /// that come first
+    fn reply(&mut self, req_id: u32) -> Result<Msg, String> {
+        loop {
+            let msg = try!(self.recv());
+            if msg.msg_type == wire::XS_WATCH_EVENT {
+                self.events.push(msg.body);
+            } else if msg.req_id != req_id {
+                return Err(format!("got {:?} in reply to request {}", msg, req_id));
+            } else {
+                return Ok(msg);
+            }
+        }
+    }
+
+    /// The next watch event, whether it was set aside or is still to come
+    fn event(&mut self) -> Result<Vec<u8>, String> {
+        if !self.events.is_empty() {
+            return Ok(self.events.remove(0));
+        }
+
+        let msg = try!(self.recv());
+        if msg.msg_type == wire::XS_WATCH_EVENT {
+            Ok(msg.body)
+        } else {
+            Err(format!("got {:?} rather than a watch event", msg))
+        }
+    }
+}
+
+fn run(conn: &mut Conn, steps: &[Step]) -> Result<(), String> {
+    let mut last_tx = 0;
+
+    for (i, step) in steps.iter().enumerate() {
+        let fail = |what: String| format!("step {}: {}", i + 1, what);
+
+        let (msg_type, tx, body, reply) = match *step {
+            Step::Request(msg_type, tx, body, ref reply) => (msg_type, tx, body, reply),
+            Step::Event(expected) => {
+                let got = try!(conn.event().map_err(&fail));
+                if got != expected {
+                    return Err(fail(format!("expected the watch event {:?}, got {:?}",
+                                            String::from_utf8_lossy(expected),
+                                            String::from_utf8_lossy(&got))));
+                }
+                continue;
+            }
+        };
+
+        let tx_id = match tx {
+            Tx::None => 0,
+            Tx::Last => last_tx,
+        };
+        let req_id = try!(conn.send(msg_type, tx_id, body).map_err(&fail));
+        let got = try!(conn.reply(req_id).map_err(&fail));
+
+        let expected = match *reply {
+            Reply::Ok => (msg_type, b"OK\0".to_vec()),
+            Reply::Body(body) => (msg_type, body.to_vec()),
+            Reply::Error(name) => (wire::XS_ERROR, format!("{}\0", name).into_bytes()),
+            Reply::Started => {
+                let id = String::from_utf8_lossy(&got.body).trim_right_matches('\0').parse::<u32>();
+                match (got.msg_type, id) {
+                    (wire::XS_TRANSACTION_START, Ok(id)) => last_tx = id,
+                    _ => return Err(fail(format!("expected a transaction id, got {:?}", got))),
+                }
+                (got.msg_type, got.body.clone())
+            }
+        };
+
+        if (got.msg_type, got.body.clone()) != expected {
+            return Err(fail(format!("expected type {} {:?}, got type {} {:?}",
+                                    expected.0,
+                                    String::from_utf8_lossy(&expected.1),
+                                    got.msg_type,
+                                    String::from_utf8_lossy(&got.body))));
+        }
+        if got.tx_id != tx_id {
+            return Err(fail(format!("the reply was for transaction {} rather than {}",
+                                    got.tx_id,
+                                    tx_id)));
+        }
+    }
+
+    if !conn.events.is_empty() {
+        return Err(format!("unexpected watch events {:?}", conn.events));
+    }
+    Ok(())
+}
+
+#[test]
+fn conformance() {
+    let daemon = Daemon::start();
+
+    let mut failures = Vec::new();
+    for (name, steps) in cases() {
+        let mut conn = daemon.connect();
+
+        // every case starts out without anything left by the one before
+        let cleanup = conn.send(wire::XS_RM, 0, b"/conformance\0")
+            .and_then(|req_id| conn.reply(req_id));
+        if let Err(e) = cleanup {
+            panic!("Failed to clean up before {:?}: {}", name, e);
+        }
+
+        if let Err(e) = run(&mut conn, &steps) {
+            failures.push(format!("{}: {}", name, e));
+        }
+    }
+
+    assert!(failures.is_empty(),
+            "{} cases failed:\n{}",
+            failures.len(),
+            failures.join("\n"));
+}